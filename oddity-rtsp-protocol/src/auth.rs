@@ -0,0 +1,353 @@
+use std::fmt;
+use std::str::FromStr;
+
+use base64::engine::{general_purpose::STANDARD, Engine};
+
+use super::Error;
+
+/// Credentials sent by the client in the `Authorization` header (RFC
+/// 2326 Section 12.5).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Authorization {
+    /// Basic credentials (RFC 7617).
+    Basic { username: String, password: String },
+    /// Digest response (RFC 7616, or RFC 2617 without `qop`).
+    Digest(DigestResponse),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DigestResponse {
+    pub username: String,
+    pub realm: String,
+    pub nonce: String,
+    pub uri: String,
+    pub response: String,
+    pub algorithm: Option<DigestAlgorithm>,
+    pub qop: Option<String>,
+    pub nc: Option<String>,
+    pub cnonce: Option<String>,
+    pub opaque: Option<String>,
+}
+
+/// Hash algorithm used for Digest authentication. Clients that do not
+/// specify an algorithm use MD5.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DigestAlgorithm {
+    #[default]
+    Md5,
+    Sha256,
+}
+
+/// Challenge sent by the server in the `WWW-Authenticate` header of a
+/// `401 Unauthorized` response.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Challenge {
+    Basic {
+        realm: String,
+    },
+    Digest {
+        realm: String,
+        nonce: String,
+        algorithm: DigestAlgorithm,
+        /// Set if the client used a nonce that expired, so it can retry
+        /// with the new one without asking the user again.
+        stale: bool,
+    },
+}
+
+impl fmt::Display for Authorization {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Authorization::Basic { username, password } => write!(
+                f,
+                "Basic {}",
+                STANDARD.encode(format!("{}:{}", username, password))
+            ),
+            Authorization::Digest(response) => {
+                write!(
+                    f,
+                    "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", \
+                     response=\"{}\"",
+                    response.username,
+                    response.realm,
+                    response.nonce,
+                    response.uri,
+                    response.response,
+                )?;
+                if let Some(algorithm) = response.algorithm {
+                    write!(f, ", algorithm={}", algorithm)?;
+                }
+                if let Some(qop) = response.qop.as_ref() {
+                    write!(f, ", qop={}", qop)?;
+                }
+                if let Some(nc) = response.nc.as_ref() {
+                    write!(f, ", nc={}", nc)?;
+                }
+                if let Some(cnonce) = response.cnonce.as_ref() {
+                    write!(f, ", cnonce=\"{}\"", cnonce)?;
+                }
+                if let Some(opaque) = response.opaque.as_ref() {
+                    write!(f, ", opaque=\"{}\"", opaque)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl FromStr for Authorization {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let malformed = || Error::AuthorizationMalformed {
+            value: s.to_string(),
+        };
+        let (scheme, credentials) = s.trim().split_once(' ').ok_or_else(malformed)?;
+        if scheme.eq_ignore_ascii_case("Basic") {
+            let credentials = STANDARD
+                .decode(credentials.trim())
+                .ok()
+                .and_then(|credentials| String::from_utf8(credentials).ok())
+                .ok_or_else(malformed)?;
+            let (username, password) = credentials.split_once(':').ok_or_else(malformed)?;
+            Ok(Authorization::Basic {
+                username: username.to_string(),
+                password: password.to_string(),
+            })
+        } else if scheme.eq_ignore_ascii_case("Digest") {
+            let mut response = DigestResponse::default();
+            let (mut username, mut realm, mut nonce, mut uri, mut digest) =
+                (None, None, None, None, None);
+            for (var, val) in parse_auth_params(credentials).ok_or_else(malformed)? {
+                match var.to_ascii_lowercase().as_str() {
+                    "username" => username = Some(val),
+                    "realm" => realm = Some(val),
+                    "nonce" => nonce = Some(val),
+                    "uri" => uri = Some(val),
+                    "response" => digest = Some(val),
+                    "algorithm" => response.algorithm = Some(val.parse()?),
+                    "qop" => response.qop = Some(val),
+                    "nc" => response.nc = Some(val),
+                    "cnonce" => response.cnonce = Some(val),
+                    "opaque" => response.opaque = Some(val),
+                    // Unknown parameters must be ignored (RFC 7616 Section
+                    // 3.4).
+                    _ => {}
+                }
+            }
+            response.username = username.ok_or_else(malformed)?;
+            response.realm = realm.ok_or_else(malformed)?;
+            response.nonce = nonce.ok_or_else(malformed)?;
+            response.uri = uri.ok_or_else(malformed)?;
+            response.response = digest.ok_or_else(malformed)?;
+            Ok(Authorization::Digest(response))
+        } else {
+            Err(Error::AuthorizationSchemeUnknown {
+                value: s.to_string(),
+            })
+        }
+    }
+}
+
+impl fmt::Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DigestAlgorithm::Md5 => write!(f, "MD5"),
+            DigestAlgorithm::Sha256 => write!(f, "SHA-256"),
+        }
+    }
+}
+
+impl FromStr for DigestAlgorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("MD5") {
+            Ok(DigestAlgorithm::Md5)
+        } else if s.eq_ignore_ascii_case("SHA-256") {
+            Ok(DigestAlgorithm::Sha256)
+        } else {
+            Err(Error::AuthorizationAlgorithmUnknown {
+                value: s.to_string(),
+            })
+        }
+    }
+}
+
+impl fmt::Display for Challenge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Challenge::Basic { realm } => write!(f, "Basic realm=\"{}\"", realm),
+            Challenge::Digest {
+                realm,
+                nonce,
+                algorithm,
+                stale,
+            } => {
+                write!(
+                    f,
+                    "Digest realm=\"{}\", nonce=\"{}\", algorithm={}, qop=\"auth\"",
+                    realm, nonce, algorithm,
+                )?;
+                if *stale {
+                    write!(f, ", stale=true")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Parse a comma-separated list of authentication parameters, in which
+/// values may be quoted strings (RFC 7235 Section 2.1).
+pub(super) fn parse_auth_params(s: &str) -> Option<Vec<(String, String)>> {
+    let mut params = Vec::new();
+    let mut rest = s.trim();
+    while !rest.is_empty() {
+        let (var, after_var) = rest.split_once('=')?;
+        let after_var = after_var.trim_start();
+        let (val, after_val) = match after_var.strip_prefix('"') {
+            Some(quoted) => {
+                let mut val = String::new();
+                let mut chars = quoted.char_indices();
+                let end = loop {
+                    match chars.next()? {
+                        (_, '\\') => val.push(chars.next()?.1),
+                        (i, '"') => break i + 1,
+                        (_, c) => val.push(c),
+                    }
+                };
+                (val, &quoted[end..])
+            }
+            None => {
+                let end = after_var.find(',').unwrap_or(after_var.len());
+                (after_var[..end].trim().to_string(), &after_var[end..])
+            }
+        };
+        params.push((var.trim().to_string(), val));
+        rest = after_val.trim_start();
+        match rest.strip_prefix(',') {
+            Some(after_comma) => rest = after_comma.trim_start(),
+            None if rest.is_empty() => {}
+            None => return None,
+        }
+    }
+    Some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_basic() {
+        assert_eq!(
+            "Basic dXNlcjpwYXNzOndvcmQ="
+                .parse::<Authorization>()
+                .unwrap(),
+            Authorization::Basic {
+                username: "user".to_string(),
+                password: "pass:word".to_string(),
+            },
+        );
+    }
+
+    #[test]
+    fn parse_basic_malformed() {
+        assert!(matches!(
+            "Basic !!!".parse::<Authorization>(),
+            Err(Error::AuthorizationMalformed { .. }),
+        ));
+    }
+
+    #[test]
+    fn parse_digest() {
+        let authorization = "Digest username=\"user\", realm=\"oddity\", \
+                             nonce=\"abc\", uri=\"rtsp://localhost/file\", \
+                             response=\"0123\", algorithm=SHA-256, qop=auth, \
+                             nc=00000001, cnonce=\"x,y\"";
+        assert_eq!(
+            authorization.parse::<Authorization>().unwrap(),
+            Authorization::Digest(DigestResponse {
+                username: "user".to_string(),
+                realm: "oddity".to_string(),
+                nonce: "abc".to_string(),
+                uri: "rtsp://localhost/file".to_string(),
+                response: "0123".to_string(),
+                algorithm: Some(DigestAlgorithm::Sha256),
+                qop: Some("auth".to_string()),
+                nc: Some("00000001".to_string()),
+                cnonce: Some("x,y".to_string()),
+                opaque: None,
+            }),
+        );
+    }
+
+    #[test]
+    fn parse_digest_without_qop() {
+        let authorization = "Digest username=\"user\",realm=\"oddity\",nonce=\"abc\",\
+                             uri=\"rtsp://localhost/file\",response=\"0123\"";
+        match authorization.parse::<Authorization>().unwrap() {
+            Authorization::Digest(response) => {
+                assert_eq!(response.algorithm, None);
+                assert_eq!(response.qop, None);
+            }
+            _ => panic!("expected digest"),
+        }
+    }
+
+    #[test]
+    fn parse_digest_missing_response() {
+        assert!(matches!(
+            "Digest username=\"user\", realm=\"oddity\", nonce=\"abc\", uri=\"/\""
+                .parse::<Authorization>(),
+            Err(Error::AuthorizationMalformed { .. }),
+        ));
+    }
+
+    #[test]
+    fn parse_unknown_scheme() {
+        assert!(matches!(
+            "Bearer abc".parse::<Authorization>(),
+            Err(Error::AuthorizationSchemeUnknown { .. }),
+        ));
+    }
+
+    #[test]
+    fn format_and_parse_digest() {
+        let authorization = Authorization::Digest(DigestResponse {
+            username: "user".to_string(),
+            realm: "oddity".to_string(),
+            nonce: "abc".to_string(),
+            uri: "rtsp://localhost/file".to_string(),
+            response: "0123".to_string(),
+            algorithm: Some(DigestAlgorithm::Md5),
+            ..Default::default()
+        });
+        assert_eq!(
+            authorization.to_string().parse::<Authorization>().unwrap(),
+            authorization,
+        );
+    }
+
+    #[test]
+    fn format_challenge() {
+        assert_eq!(
+            Challenge::Digest {
+                realm: "oddity".to_string(),
+                nonce: "abc".to_string(),
+                algorithm: DigestAlgorithm::Sha256,
+                stale: true,
+            }
+            .to_string(),
+            "Digest realm=\"oddity\", nonce=\"abc\", algorithm=SHA-256, qop=\"auth\", stale=true",
+        );
+        assert_eq!(
+            Challenge::Basic {
+                realm: "oddity".to_string()
+            }
+            .to_string(),
+            "Basic realm=\"oddity\"",
+        );
+    }
+}
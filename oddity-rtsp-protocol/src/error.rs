@@ -111,6 +111,13 @@ pub enum Error {
     RtpInfoParameterInvalid { value: String },
     /// RTP Info contains unexpected extra parameter.
     RtpInfoParameterUnexpected { value: String },
+    /// Authorization header is malformed or misses a required Digest
+    /// parameter.
+    AuthorizationMalformed { value: String },
+    /// Authorization header uses a scheme other than `Basic` or `Digest`.
+    AuthorizationSchemeUnknown { value: String },
+    /// Digest algorithm is not supported. Use either `MD5` or `SHA-256`.
+    AuthorizationAlgorithmUnknown { value: String },
     /// Underlying socket was shut down. This is not really an error and
     /// consumers are expected to handle it gracefully.
     Shutdown,
@@ -213,6 +220,15 @@ impl fmt::Display for Error {
             Error::RtpInfoParameterUnexpected { value } => {
                 write!(f, "rtp info contains unexpected parameter: {}", &value)
             }
+            Error::AuthorizationMalformed { value } => {
+                write!(f, "authorization malformed: {}", value)
+            }
+            Error::AuthorizationSchemeUnknown { value } => {
+                write!(f, "authorization scheme unknown: {}", value)
+            }
+            Error::AuthorizationAlgorithmUnknown { value } => {
+                write!(f, "authorization algorithm unknown: {}", value)
+            }
             Error::Shutdown => write!(f, "underlying socket was shut down"),
             Error::Io(err) => write!(f, "{}", err),
         }
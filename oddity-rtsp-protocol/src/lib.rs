@@ -1,3 +1,4 @@
+mod auth;
 mod buffer;
 mod error;
 mod interleaved;
@@ -14,6 +15,10 @@ mod transport;
 #[cfg(feature = "tokio-codec")]
 mod tokio;
 
+#[cfg(feature = "client")]
+mod client;
+
+pub use auth::{Authorization, Challenge, DigestAlgorithm, DigestResponse};
 pub use error::{Error, Result};
 pub use interleaved::{MaybeInterleaved, RequestMaybeInterleaved, ResponseMaybeInterleaved};
 pub use io::{AsClient, AsServer, Target};
@@ -28,3 +33,6 @@ pub use transport::{Channel, Lower, Parameter, Port, Transport};
 
 #[cfg(feature = "tokio-codec")]
 pub use tokio::Codec;
+
+#[cfg(feature = "client")]
+pub use client::{Client, ClientError, RtpStream};
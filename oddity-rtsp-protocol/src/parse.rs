@@ -638,6 +638,21 @@ Content-Length: 16\r\n\
         test_example_request_play(&request);
     }
 
+    #[test]
+    fn parse_request_session_with_timeout() {
+        let request = RequestParser::new()
+            .parse_and_into_request(
+                b"GET_PARAMETER rtsp://example.com/stream/0 RTSP/1.0\r\n\
+CSeq: 2\r\n\
+Session: 1234abcd;timeout=60\r\n\
+\r\n"
+                    .as_slice(),
+            )
+            .unwrap();
+        assert_eq!(request.method, Method::GetParameter);
+        assert_eq!(request.session(), Some("1234abcd"));
+    }
+
     #[test]
     fn parse_play_request_partial_piece1_ln() {
         parse_play_request_partial_piece1(&request_play_ln());
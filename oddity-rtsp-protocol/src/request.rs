@@ -1,6 +1,7 @@
 use std::fmt;
 
 use super::{
+    auth::Authorization,
     message::{Bytes, Headers, Message, Method, Uri, Version},
     range::Range,
     transport::Transport,
@@ -43,6 +44,10 @@ impl Request {
         self.headers.get("Require").map(|val| val.as_str())
     }
 
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.get("Content-Type").map(|val| val.as_str())
+    }
+
     pub fn accept(&self) -> Vec<&str> {
         self.headers
             .get("Accept")
@@ -50,8 +55,13 @@ impl Request {
             .unwrap_or_default()
     }
 
+    /// Session identifier without any parameters such as `timeout` (RFC
+    /// 2326 Section 12.37), which some clients echo back.
     pub fn session(&self) -> Option<&str> {
-        self.headers.get("Session").map(|val| val.as_str())
+        self.headers
+            .get("Session")
+            .and_then(|val| val.split(';').next())
+            .map(|val| val.trim())
     }
 
     pub fn transport(&self) -> Result<Vec<Transport>, Error> {
@@ -68,6 +78,10 @@ impl Request {
     pub fn range(&self) -> Option<Result<Range, Error>> {
         self.headers.get("Range").map(|value| value.parse())
     }
+
+    pub fn authorization(&self) -> Option<Result<Authorization, Error>> {
+        self.headers.get("Authorization").map(|value| value.parse())
+    }
 }
 
 impl fmt::Display for Request {
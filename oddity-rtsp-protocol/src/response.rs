@@ -1,6 +1,7 @@
 use std::fmt;
 
 use super::{
+    auth::Challenge,
     message::{
         status_to_code, status_to_reason, Bytes, Headers, Message, Status, StatusCategory,
         StatusCode, Version,
@@ -147,6 +148,10 @@ impl ResponseBuilder {
         self
     }
 
+    pub fn with_www_authenticate(self, challenge: &Challenge) -> ResponseBuilder {
+        self.with_header("WWW-Authenticate", challenge)
+    }
+
     pub fn with_body(mut self, body: Bytes, content_type: &str) -> ResponseBuilder {
         self = self
             .with_header("Content-Length", body.len())
@@ -325,7 +325,9 @@ impl FromStr for Parameter {
                     .unwrap_or(val)
                     .strip_suffix('"')
                     .unwrap_or(val);
-                let method = parse_or_err(var, val)?;
+                // Clients commonly send the mode in lowercase (for example
+                // `mode=record`), so the method is matched case-insensitively.
+                let method = parse_or_err(var, &val.to_ascii_uppercase())?;
                 Ok(Parameter::Mode(method))
             }
             _ => Err(Error::TransportParameterUnknown {
@@ -562,6 +564,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_mode_method_lowercase() {
+        assert_eq!(
+            "RTP/AVP/TCP;unicast;interleaved=0-1;mode=record"
+                .parse::<Transport>()
+                .unwrap(),
+            Transport::new()
+                .with_lower_protocol(Lower::Tcp)
+                .with_parameter(Parameter::Unicast)
+                .with_parameter(Parameter::Interleaved(Channel::Range(0, 1)))
+                .with_parameter(Parameter::Mode(Method::Record)),
+        );
+    }
+
     #[test]
     fn parse_rfc2326_section_12_39_examples() {
         assert_eq!(
@@ -0,0 +1,323 @@
+//! HTTP API to inspect and control the server while it runs: list sources
+//! and sessions, add and remove sources, and tear down sessions.
+//!
+//! * `GET /sources`: all sources and their status.
+//! * `POST /sources`: add a source. The body is a media item, in the same
+//!   form as in the config file.
+//! * `DELETE /sources/<path>`: stop and remove the source at `<path>`.
+//! * `GET /sessions`: all sessions, with peer address, transport and the
+//!   number of bytes sent.
+//! * `DELETE /sessions/<id>`: tear down a session.
+//! * `GET /metrics`: metrics in the Prometheus text format.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use axum::extract::{Path, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get};
+use axum::{Json, Router};
+
+use serde::Serialize;
+
+use tokio::net;
+use tokio::sync::RwLock;
+
+use crate::app::auth::constant_time_eq;
+use crate::app::config::Item;
+use crate::app::AppContext;
+use crate::media::MediaDescriptor;
+use crate::runtime::task_manager::{Task, TaskContext};
+use crate::runtime::Runtime;
+use crate::session::{SessionId, SessionInfo};
+use crate::source::source_manager::RegisterSourceError;
+use crate::source::{self, SourceInfo};
+
+pub struct AdminServer {
+    worker: Task,
+}
+
+impl AdminServer {
+    pub async fn start(
+        host: IpAddr,
+        port: u16,
+        token: Option<String>,
+        context: Arc<RwLock<AppContext>>,
+        runtime: &Runtime,
+    ) -> Result<Self, io::Error> {
+        tracing::trace!(%host, port, "starting admin server");
+        let listener = match net::TcpListener::bind((host, port)).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                tracing::error!(%err, %host, port, "failed to listen for admin requests");
+                return Err(err);
+            }
+        };
+        tracing::info!(%host, port, "admin server listening for requests");
+
+        let state = AdminState {
+            context,
+            token: token.map(Arc::from),
+        };
+        let router = Router::new()
+            .route("/sources", get(list_sources).post(add_source))
+            .route("/sources/*path", delete(remove_source))
+            .route("/sessions", get(list_sessions))
+            .route("/sessions/:id", delete(teardown_session))
+            .route("/metrics", get(metrics))
+            .route_layer(middleware::from_fn_with_state(state.clone(), authorize))
+            .with_state(state);
+
+        let worker = runtime
+            .task()
+            .spawn(move |task_context| Self::run(listener, router, task_context))
+            .await;
+        tracing::trace!(%host, port, "started admin server");
+
+        Ok(Self { worker })
+    }
+
+    pub async fn stop(&mut self) {
+        tracing::trace!("sending stop signal to admin server");
+        self.worker.stop().await;
+        tracing::trace!("admin server stopped");
+    }
+
+    async fn run(listener: net::TcpListener, router: Router, mut task_context: TaskContext) {
+        let shutdown = async move { task_context.wait_for_stop().await };
+        if let Err(err) = axum::serve(listener, router)
+            .with_graceful_shutdown(shutdown)
+            .await
+        {
+            tracing::error!(%err, "admin server failed");
+        }
+    }
+}
+
+#[derive(Clone)]
+struct AdminState {
+    context: Arc<RwLock<AppContext>>,
+    token: Option<Arc<str>>,
+}
+
+/// Reject requests without the bearer token, if there is one.
+async fn authorize(State(state): State<AdminState>, request: Request, next: Next) -> Response {
+    if let Some(token) = state.token.as_ref() {
+        let authorized = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()));
+        if !authorized {
+            return error(StatusCode::UNAUTHORIZED, "unauthorized");
+        }
+    }
+    next.run(request).await
+}
+
+async fn list_sources(State(state): State<AdminState>) -> Json<Vec<SourceView>> {
+    let sources = state.context.read().await.source_manager.list().await;
+    Json(sources.into_iter().map(SourceView::from).collect())
+}
+
+async fn add_source(State(state): State<AdminState>, Json(item): Json<Item>) -> Response {
+    let descriptor = match item.as_media_descriptor() {
+        Ok(descriptor) => descriptor,
+        Err(err) => return error(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+    let multicast = match item.as_multicast_group() {
+        Ok(multicast) => multicast,
+        Err(err) => return error(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+    let record = match item.as_record_settings() {
+        Ok(record) => record,
+        Err(err) => return error(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+    let path = source::normalize_path(item.path.clone());
+
+    let context = state.context.read().await;
+    tracing::info!(%item, "registering source through admin api");
+    match context
+        .start_source(
+            &item.name,
+            path.clone(),
+            descriptor,
+            multicast,
+            record,
+            item.auth.as_ref(),
+        )
+        .await
+    {
+        Ok(()) => {}
+        Err(RegisterSourceError::AlreadyRegistered) => {
+            return error(StatusCode::CONFLICT, "source already registered");
+        }
+        Err(err) => {
+            tracing::error!(%item, %err, "failed to register source");
+            return error(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string());
+        }
+    }
+
+    let source = context
+        .source_manager
+        .list()
+        .await
+        .into_iter()
+        .find(|source| source.path == path);
+    match source {
+        Some(source) => (StatusCode::CREATED, Json(SourceView::from(source))).into_response(),
+        None => StatusCode::CREATED.into_response(),
+    }
+}
+
+async fn remove_source(State(state): State<AdminState>, Path(path): Path<String>) -> Response {
+    let path = source::normalize_path(path);
+    let context = state.context.read().await;
+    tracing::info!(%path, "unregistering source through admin api");
+    if context.unregister_source(&path).await {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        error(StatusCode::NOT_FOUND, "source not found")
+    }
+}
+
+async fn list_sessions(State(state): State<AdminState>) -> Json<Vec<SessionView>> {
+    let sessions = state.context.read().await.session_manager.list().await;
+    Json(sessions.into_iter().map(SessionView::from).collect())
+}
+
+async fn teardown_session(State(state): State<AdminState>, Path(id): Path<String>) -> Response {
+    let id = SessionId::from(id.as_str());
+    tracing::info!(session_id = %id, "tearing down session through admin api");
+    if state
+        .context
+        .read()
+        .await
+        .session_manager
+        .teardown(&id)
+        .await
+    {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        error(StatusCode::NOT_FOUND, "session not found")
+    }
+}
+
+async fn metrics(State(state): State<AdminState>) -> Response {
+    let context = state.context.read().await;
+    let mut sessions_per_path = BTreeMap::new();
+    for session in context.session_manager.list().await {
+        *sessions_per_path.entry(session.source).or_default() += 1;
+    }
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        context.metrics.render(&sessions_per_path),
+    )
+        .into_response()
+}
+
+fn error(status: StatusCode, message: &str) -> Response {
+    (
+        status,
+        Json(ErrorView {
+            error: message.to_string(),
+        }),
+    )
+        .into_response()
+}
+
+#[derive(Serialize)]
+struct ErrorView {
+    error: String,
+}
+
+#[derive(Serialize)]
+struct SourceView {
+    name: String,
+    path: String,
+    kind: &'static str,
+    /// Where the media is read from. Passwords in URLs are left out.
+    location: Option<String>,
+    /// `ready` once the source has media, `waiting` before that.
+    status: &'static str,
+    tracks: Vec<&'static str>,
+    multicast: Option<String>,
+    publishing: bool,
+    recording: bool,
+}
+
+impl From<SourceInfo> for SourceView {
+    fn from(info: SourceInfo) -> Self {
+        let (kind, location) = match &info.descriptor {
+            MediaDescriptor::File(path) => ("file", Some(path.display().to_string())),
+            MediaDescriptor::Vod(path) => ("vod", Some(path.display().to_string())),
+            MediaDescriptor::Stream(url) => {
+                let mut url_safe = url.clone();
+                let _ = url_safe.set_password(None);
+                ("stream", Some(url_safe.to_string()))
+            }
+            MediaDescriptor::Publish => ("publish", None),
+        };
+        Self {
+            name: info.name,
+            path: info.path,
+            kind,
+            location,
+            status: if info.tracks.is_some() {
+                "ready"
+            } else {
+                "waiting"
+            },
+            tracks: info.tracks.unwrap_or_default(),
+            multicast: info
+                .multicast
+                .map(|group| format!("{}:{}", group.address, group.port)),
+            publishing: info.publishing,
+            recording: info.recording,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SessionView {
+    id: String,
+    source: String,
+    /// `play` or `record`.
+    kind: &'static str,
+    peer_addr: Option<String>,
+    transports: Vec<TransportView>,
+    bytes_sent: u64,
+    packets_sent: u64,
+}
+
+#[derive(Serialize)]
+struct TransportView {
+    track: usize,
+    transport: String,
+}
+
+impl From<SessionInfo> for SessionView {
+    fn from(info: SessionInfo) -> Self {
+        Self {
+            id: info.id.to_string(),
+            source: info.source,
+            kind: if info.recording { "record" } else { "play" },
+            peer_addr: info.peer_addr.map(|peer_addr| peer_addr.to_string()),
+            transports: info
+                .transports
+                .into_iter()
+                .map(|(track, transport)| TransportView {
+                    track,
+                    transport: transport.to_string(),
+                })
+                .collect(),
+            bytes_sent: info.bytes_sent,
+            packets_sent: info.packets_sent,
+        }
+    }
+}
@@ -0,0 +1,507 @@
+//! Basic (RFC 7617) and Digest (RFC 7616) authentication of requests
+//! for media items that have users configured, and of clients that
+//! publish to paths that are not configured.
+//!
+//! Nonces are stateless: they hold the time at which they were issued
+//! and a hash of that time with a secret that is generated at startup.
+//! This means nonces can be replayed until they expire.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use oddity_rtsp_protocol::{
+    Authorization, Challenge, DigestAlgorithm, DigestResponse, Error, Method, Request, Uri,
+};
+
+use crate::app::config::{Auth, AuthScheme};
+use crate::media::sdp;
+use crate::session::SessionInfo;
+use crate::source::source_manager::is_path_within;
+
+/// Realm that clients show to users when asking for credentials.
+const REALM: &str = env!("CARGO_PKG_NAME");
+
+/// How long a nonce can be used. Clients that use an expired nonce are
+/// challenged again with `stale` set.
+const NONCE_LIFETIME: Duration = Duration::from_secs(300);
+
+/// Number of hexadecimal digits of the timestamp part of a nonce.
+const NONCE_TIMESTAMP_LEN: usize = 16;
+
+pub struct Authenticator {
+    secret: [u8; 32],
+    paths: HashMap<String, PathAuth>,
+    /// Users that may publish below the prefix, to paths that do not have
+    /// users of their own.
+    publish: Option<(String, PathAuth)>,
+}
+
+/// Users of a media item, or of publishing below the publish prefix.
+pub struct PathAuth {
+    scheme: AuthScheme,
+    /// Passwords by username.
+    users: HashMap<String, String>,
+}
+
+impl Authenticator {
+    pub fn new() -> Self {
+        Self {
+            secret: rand::random(),
+            paths: HashMap::new(),
+            publish: None,
+        }
+    }
+
+    /// Require clients that publish to paths below `prefix` to
+    /// authenticate, including paths that do not exist yet. Media items
+    /// with users of their own require those instead.
+    pub fn register_publish(&mut self, prefix: &str, auth: &Auth) {
+        self.publish = Some((prefix.to_string(), PathAuth::new(auth)));
+    }
+
+    /// Stop requiring clients to authenticate for the media item at `path`.
+    pub fn unregister(&mut self, path: &str) {
+        let _ = self.paths.remove(path);
+    }
+
+    /// Require clients to authenticate as one of the users of `auth` to
+    /// access the media item at `path`, or not at all if `auth` is `None`.
+    /// Returns what was required before, which can be put back with
+    /// [`Authenticator::restore`].
+    pub fn replace(&mut self, path: &str, auth: Option<&Auth>) -> Option<PathAuth> {
+        match auth {
+            Some(auth) => self.paths.insert(path.to_string(), PathAuth::new(auth)),
+            None => self.paths.remove(path),
+        }
+    }
+
+    /// Put back what [`Authenticator::replace`] returned.
+    pub fn restore(&mut self, path: &str, previous: Option<PathAuth>) {
+        match previous {
+            Some(path_auth) => {
+                let _ = self.paths.insert(path.to_string(), path_auth);
+            }
+            None => self.unregister(path),
+        }
+    }
+
+    /// Check the credentials of the request against the users of the media
+    /// item it refers to. If the request is not authorized, the challenge
+    /// to send to the client is returned.
+    pub fn authorize(&self, request: &Request) -> Result<(), Challenge> {
+        let (path, _) = sdp::split_track_path(request.path());
+        let publishing = matches!(request.method, Method::Announce | Method::Record);
+        self.authorize_at(request, path, publishing)
+    }
+
+    /// Check the credentials of a request that refers to an existing
+    /// session against the users of the media item of the session, rather
+    /// than of the media item that the request points to.
+    pub fn authorize_session(
+        &self,
+        request: &Request,
+        session: &SessionInfo,
+    ) -> Result<(), Challenge> {
+        self.authorize_at(request, &session.source, session.recording)
+    }
+
+    fn authorize_at(
+        &self,
+        request: &Request,
+        path: &str,
+        publishing: bool,
+    ) -> Result<(), Challenge> {
+        let path_auth = match self.paths.get(path) {
+            Some(path_auth) => path_auth,
+            None => match self.publish.as_ref() {
+                Some((prefix, publish_auth)) if publishing && is_path_within(path, prefix) => {
+                    publish_auth
+                }
+                _ => return Ok(()),
+            },
+        };
+        self.check(
+            path_auth,
+            path,
+            request.authorization(),
+            &request.method.to_string(),
+            request.uri(),
+        )
+    }
+
+    /// Check credentials for the media item at `path`, for requests that
+    /// are not RTSP requests, such as HTTP requests for HLS. The digest is
+    /// computed over `method` and must be for `uri`.
+    pub fn authorize_path(
+        &self,
+        path: &str,
+        authorization: Option<Result<Authorization, Error>>,
+        method: &str,
+        uri: &Uri,
+    ) -> Result<(), Challenge> {
+        match self.paths.get(path) {
+            Some(path_auth) => self.check(path_auth, path, authorization, method, uri),
+            None => Ok(()),
+        }
+    }
+
+    fn check(
+        &self,
+        path_auth: &PathAuth,
+        path: &str,
+        authorization: Option<Result<Authorization, Error>>,
+        method: &str,
+        uri: &Uri,
+    ) -> Result<(), Challenge> {
+        let authorization = match authorization {
+            Some(Ok(authorization)) => authorization,
+            Some(Err(err)) => {
+                tracing::debug!(%err, "failed to parse authorization");
+                return Err(self.challenge(path_auth.scheme, false));
+            }
+            None => return Err(self.challenge(path_auth.scheme, false)),
+        };
+
+        match (path_auth.scheme, authorization) {
+            (AuthScheme::Basic, Authorization::Basic { username, password }) => {
+                match path_auth.users.get(&username) {
+                    Some(expected)
+                        if constant_time_eq(expected.as_bytes(), password.as_bytes()) =>
+                    {
+                        Ok(())
+                    }
+                    _ => {
+                        tracing::debug!(%username, path, "basic authentication failed");
+                        Err(self.challenge(path_auth.scheme, false))
+                    }
+                }
+            }
+            (AuthScheme::Digest | AuthScheme::DigestSha256, Authorization::Digest(response)) => {
+                let algorithm = digest_algorithm(path_auth.scheme);
+                let password = match path_auth.users.get(&response.username) {
+                    Some(password) => password,
+                    None => {
+                        tracing::debug!(username = response.username, path, "user unknown");
+                        return Err(self.challenge(path_auth.scheme, false));
+                    }
+                };
+                if response.realm != REALM
+                    || response.algorithm.unwrap_or_default() != algorithm
+                    || response.qop.as_deref().is_some_and(|qop| qop != "auth")
+                {
+                    tracing::debug!(path, "digest does not match challenge");
+                    return Err(self.challenge(path_auth.scheme, false));
+                }
+                // The digest must be for this request, otherwise it could
+                // be taken from a request for another path (RFC 7616
+                // Section 3.4.6).
+                if !is_digest_uri_of(&response.uri, uri) {
+                    tracing::debug!(path, digest_uri = response.uri, "digest is for other uri");
+                    return Err(self.challenge(path_auth.scheme, false));
+                }
+                let expected = match digest_response(algorithm, &response, password, method) {
+                    Some(expected) => expected,
+                    None => return Err(self.challenge(path_auth.scheme, false)),
+                };
+                if !constant_time_eq(expected.as_bytes(), response.response.as_bytes()) {
+                    tracing::debug!(
+                        username = response.username,
+                        path,
+                        "digest authentication failed"
+                    );
+                    return Err(self.challenge(path_auth.scheme, false));
+                }
+                match self.verify_nonce(&response.nonce) {
+                    NonceState::Valid => Ok(()),
+                    // The client knows the password, so let it retry with a new
+                    // nonce without bothering the user (RFC 7616 Section 3.3).
+                    NonceState::Expired => Err(self.challenge(path_auth.scheme, true)),
+                    NonceState::Invalid => {
+                        tracing::debug!(path, "client used nonce that was not issued by us");
+                        Err(self.challenge(path_auth.scheme, false))
+                    }
+                }
+            }
+            _ => {
+                tracing::debug!(path, "client used wrong authentication scheme");
+                Err(self.challenge(path_auth.scheme, false))
+            }
+        }
+    }
+
+    fn challenge(&self, scheme: AuthScheme, stale: bool) -> Challenge {
+        match scheme {
+            AuthScheme::Basic => Challenge::Basic {
+                realm: REALM.to_string(),
+            },
+            AuthScheme::Digest | AuthScheme::DigestSha256 => Challenge::Digest {
+                realm: REALM.to_string(),
+                nonce: self.make_nonce(now()),
+                algorithm: digest_algorithm(scheme),
+                stale,
+            },
+        }
+    }
+
+    fn make_nonce(&self, timestamp: u64) -> String {
+        let timestamp = format!("{:0width$x}", timestamp, width = NONCE_TIMESTAMP_LEN);
+        let signature = self.sign(&timestamp);
+        format!("{}{}", timestamp, signature)
+    }
+
+    fn verify_nonce(&self, nonce: &str) -> NonceState {
+        let (timestamp, signature) = match nonce.get(..NONCE_TIMESTAMP_LEN) {
+            Some(timestamp) => (timestamp, &nonce[NONCE_TIMESTAMP_LEN..]),
+            None => return NonceState::Invalid,
+        };
+        if !constant_time_eq(self.sign(timestamp).as_bytes(), signature.as_bytes()) {
+            return NonceState::Invalid;
+        }
+        match u64::from_str_radix(timestamp, 16) {
+            Ok(timestamp) if now().saturating_sub(timestamp) <= NONCE_LIFETIME.as_secs() => {
+                NonceState::Valid
+            }
+            Ok(_) => NonceState::Expired,
+            Err(_) => NonceState::Invalid,
+        }
+    }
+
+    fn sign(&self, timestamp: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.secret);
+        hasher.update(timestamp.as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+}
+
+impl Default for Authenticator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PathAuth {
+    fn new(auth: &Auth) -> Self {
+        Self {
+            scheme: auth.scheme,
+            users: auth
+                .users
+                .iter()
+                .map(|user| (user.username.clone(), user.password.clone()))
+                .collect(),
+        }
+    }
+}
+
+enum NonceState {
+    Valid,
+    Expired,
+    Invalid,
+}
+
+fn digest_algorithm(scheme: AuthScheme) -> DigestAlgorithm {
+    match scheme {
+        AuthScheme::DigestSha256 => DigestAlgorithm::Sha256,
+        _ => DigestAlgorithm::Md5,
+    }
+}
+
+/// Calculate the expected response of a Digest authorization (RFC 7616
+/// Section 3.4.1), or RFC 2069 style if the client did not send `qop`.
+/// Returns `None` if `qop` is present but `nc` or `cnonce` is not.
+fn digest_response(
+    algorithm: DigestAlgorithm,
+    response: &DigestResponse,
+    password: &str,
+    method: &str,
+) -> Option<String> {
+    let hash = |data: String| match algorithm {
+        DigestAlgorithm::Md5 => format!("{:x}", Md5::digest(data.as_bytes())),
+        DigestAlgorithm::Sha256 => format!("{:x}", Sha256::digest(data.as_bytes())),
+    };
+    let ha1 = hash(format!(
+        "{}:{}:{}",
+        response.username, response.realm, password
+    ));
+    let ha2 = hash(format!("{}:{}", method, response.uri));
+    Some(match response.qop.as_ref() {
+        Some(qop) => hash(format!(
+            "{}:{}:{}:{}:{}:{}",
+            ha1,
+            response.nonce,
+            response.nc.as_ref()?,
+            response.cnonce.as_ref()?,
+            qop,
+            ha2,
+        )),
+        None => hash(format!("{}:{}:{}", ha1, response.nonce, ha2)),
+    })
+}
+
+/// Whether the `uri` of a digest response refers to the request URI.
+fn is_digest_uri_of(digest_uri: &str, uri: &Uri) -> bool {
+    digest_uri
+        .parse::<Uri>()
+        .is_ok_and(|digest_uri| digest_uri == *uri)
+}
+
+pub(super) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use oddity_rtsp_protocol::{Headers, Version};
+
+    use crate::app::config::User;
+
+    #[test]
+    fn digest_response_rfc2617_example() {
+        let response = DigestResponse {
+            username: "Mufasa".to_string(),
+            realm: "testrealm@host.com".to_string(),
+            nonce: "dcd98b7102dd2f0e8b11d0f600bfb0c093".to_string(),
+            uri: "/dir/index.html".to_string(),
+            qop: Some("auth".to_string()),
+            nc: Some("00000001".to_string()),
+            cnonce: Some("0a4f113b".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            digest_response(DigestAlgorithm::Md5, &response, "Circle Of Life", "GET").as_deref(),
+            Some("6629fae49393a05397450978507c4ef1"),
+        );
+    }
+
+    #[test]
+    fn digest_uri_must_be_request_uri() {
+        let uri: Uri = "rtsp://localhost:554/cam".parse().unwrap();
+        assert!(is_digest_uri_of("rtsp://localhost:554/cam", &uri));
+        assert!(is_digest_uri_of("rtsp://LOCALHOST:554/cam", &uri));
+        assert!(!is_digest_uri_of("rtsp://localhost:554/other", &uri));
+        assert!(!is_digest_uri_of("/cam", &uri));
+        assert!(!is_digest_uri_of("", &uri));
+    }
+
+    #[test]
+    fn publish_users_required_below_prefix() {
+        let mut authenticator = Authenticator::new();
+        authenticator.register_publish(
+            "/live",
+            &Auth {
+                scheme: AuthScheme::Basic,
+                users: vec![User {
+                    username: "publisher".to_string(),
+                    password: "secret".to_string(),
+                }],
+            },
+        );
+        let request = |method, uri: &str| Request {
+            method,
+            uri: uri.parse().unwrap(),
+            version: Version::V1,
+            headers: Headers::new(),
+            body: None,
+        };
+        assert!(authenticator
+            .authorize(&request(Method::Announce, "rtsp://localhost/live/new"))
+            .is_err());
+        assert!(authenticator
+            .authorize(&request(Method::Record, "rtsp://localhost/live/new"))
+            .is_err());
+        assert!(authenticator
+            .authorize(&request(Method::Describe, "rtsp://localhost/live/new"))
+            .is_ok());
+        assert!(authenticator
+            .authorize(&request(Method::Announce, "rtsp://localhost/other"))
+            .is_ok());
+
+        let mut announce = request(Method::Announce, "rtsp://localhost/live/new");
+        announce.headers.insert(
+            "Authorization".to_string(),
+            "Basic cHVibGlzaGVyOnNlY3JldA==".to_string(),
+        );
+        assert!(authenticator.authorize(&announce).is_ok());
+    }
+
+    #[test]
+    fn session_requests_checked_against_session_source() {
+        let mut authenticator = Authenticator::new();
+        let _ = authenticator.replace(
+            "/cam",
+            Some(&Auth {
+                scheme: AuthScheme::Basic,
+                users: vec![User {
+                    username: "viewer".to_string(),
+                    password: "secret".to_string(),
+                }],
+            }),
+        );
+        let session = |source: &str| SessionInfo {
+            id: "1234".into(),
+            source: source.to_string(),
+            recording: false,
+            peer_addr: None,
+            transports: Vec::new(),
+            bytes_sent: 0,
+            packets_sent: 0,
+        };
+        // The request points to a path without users, but the session plays
+        // a source with users.
+        let mut teardown = Request {
+            method: Method::Teardown,
+            uri: "rtsp://localhost/open".parse().unwrap(),
+            version: Version::V1,
+            headers: Headers::new(),
+            body: None,
+        };
+        assert!(authenticator.authorize(&teardown).is_ok());
+        assert!(authenticator
+            .authorize_session(&teardown, &session("/cam"))
+            .is_err());
+        assert!(authenticator
+            .authorize_session(&teardown, &session("/open"))
+            .is_ok());
+
+        teardown.headers.insert(
+            "Authorization".to_string(),
+            "Basic dmlld2VyOnNlY3JldA==".to_string(),
+        );
+        assert!(authenticator
+            .authorize_session(&teardown, &session("/cam"))
+            .is_ok());
+    }
+
+    #[test]
+    fn nonce_signed_and_expires() {
+        let authenticator = Authenticator::new();
+        assert!(matches!(
+            authenticator.verify_nonce(&authenticator.make_nonce(now())),
+            NonceState::Valid,
+        ));
+        assert!(matches!(
+            authenticator.verify_nonce(&authenticator.make_nonce(now() - 3600)),
+            NonceState::Expired,
+        ));
+        assert!(matches!(
+            Authenticator::new().verify_nonce(&authenticator.make_nonce(now())),
+            NonceState::Invalid,
+        ));
+        assert!(matches!(
+            authenticator.verify_nonce("abc"),
+            NonceState::Invalid,
+        ));
+    }
+}
@@ -1,16 +1,37 @@
 use std::error::Error;
 use std::fmt;
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use serde::Deserialize;
 
 use config::{Config, ConfigError};
 
+use crate::media::video::rtp_h264::PacketizationMode;
+use crate::media::video::rtp_muxer::RtpMuxerSettings;
 use crate::media::MediaDescriptor;
+use crate::net::connection::{SendQueueSettings, SlowClientPolicy};
+use crate::net::server::TlsSettings;
+use crate::net::tls::{self, TlsError};
+use crate::source;
+use crate::source::hls::HlsSettings;
+use crate::source::multicast::MulticastGroup;
+use crate::source::recorder::RecordSettings;
+use crate::source::source_manager::PublishSettings;
 
 #[derive(Debug, Deserialize)]
 pub struct AppConfig {
     pub server: Server,
+    /// HTTP API to inspect and control the server. Disabled if left out.
+    #[serde(default)]
+    pub admin: Option<Admin>,
+    /// HTTP server that serves every source as HLS. Disabled if left out.
+    #[serde(default)]
+    pub hls: Option<Hls>,
+    /// HTTP server for WebRTC viewers (WHEP). Disabled if left out.
+    #[serde(default)]
+    pub whep: Option<Whep>,
     pub media: Vec<Item>,
 }
 
@@ -18,24 +39,472 @@ pub struct AppConfig {
 pub struct Server {
     pub host: String,
     pub port: u16,
+    #[serde(default)]
+    pub udp: Udp,
+    #[serde(default)]
+    pub rtp: Rtp,
+    #[serde(default)]
+    pub send_queue: SendQueue,
+    /// RTSP over TLS. Disabled if left out.
+    #[serde(default)]
+    pub tls: Option<Tls>,
+    /// RTSP over WebSocket. Disabled if left out.
+    #[serde(default)]
+    pub websocket: Option<WebSocket>,
+    /// Publishing to paths that are not configured. Disabled if left out.
+    #[serde(default)]
+    pub publish: Option<Publish>,
 }
 
+/// RTSP over TLS (`rtsps://`) on `port`, next to plain RTSP. `cert` is a
+/// PEM file with the certificate chain, and `key` a PEM file with the
+/// private key.
+#[derive(Debug, Deserialize)]
+pub struct Tls {
+    #[serde(default = "Tls::default_port")]
+    pub port: u16,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl Tls {
+    /// Port 322 is registered for RTSP over TLS.
+    fn default_port() -> u16 {
+        322
+    }
+
+    pub fn as_tls_settings(&self) -> Result<TlsSettings, TlsError> {
+        Ok(TlsSettings {
+            port: self.port,
+            acceptor: tls::load_acceptor(&self.cert, &self.key)?,
+        })
+    }
+}
+
+/// RTSP over WebSocket on `port`, next to plain RTSP, for players in
+/// browsers.
+#[derive(Debug, Deserialize)]
+pub struct WebSocket {
+    pub port: u16,
+}
+
+/// Clients may publish to paths below `prefix` that are not configured.
+/// The server creates a publish source for such a path when a client
+/// announces media for it, and removes it when the client disconnects.
+/// `max_sources` limits how many of those exist at a time. If `auth` is
+/// set, clients must authenticate to publish below `prefix`, except to
+/// media items that have users of their own.
+#[derive(Debug, Deserialize)]
+pub struct Publish {
+    pub prefix: String,
+    #[serde(default = "Publish::default_max_sources")]
+    pub max_sources: usize,
+    #[serde(default)]
+    pub auth: Option<Auth>,
+}
+
+impl Publish {
+    fn default_max_sources() -> usize {
+        16
+    }
+
+    pub fn as_publish_settings(&self) -> Result<PublishSettings, Box<dyn Error>> {
+        if self.max_sources == 0 {
+            return Err("publish max_sources must be at least 1".into());
+        }
+        let prefix = source::normalize_path(self.prefix.clone());
+        Ok(PublishSettings {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            max_sources: self.max_sources,
+        })
+    }
+}
+
+/// Range of server ports used for RTP and RTCP when a client asks for
+/// RTP over UDP. Each track of a session uses two ports: an even port
+/// for RTP and the next one for RTCP.
+#[derive(Debug, Deserialize)]
+pub struct Udp {
+    pub port_min: u16,
+    pub port_max: u16,
+}
+
+impl Default for Udp {
+    fn default() -> Self {
+        Self {
+            port_min: 8000,
+            port_max: 8999,
+        }
+    }
+}
+
+/// Controls how media is packetized into RTP. `mtu` is the maximum size
+/// of an RTP packet including its header, and `packetization_mode` is the
+/// H.264 packetization mode (0 or 1) as defined in RFC 6184.
+#[derive(Debug, Deserialize)]
+pub struct Rtp {
+    pub mtu: usize,
+    pub packetization_mode: usize,
+}
+
+impl Rtp {
+    /// Anything smaller than this cannot even hold a reasonable slice of
+    /// a fragmented NAL unit.
+    const MIN_MTU: usize = 64;
+
+    pub fn as_muxer_settings(&self) -> Result<RtpMuxerSettings, Box<dyn Error>> {
+        if self.mtu < Self::MIN_MTU {
+            return Err(format!("rtp mtu must be at least {}: {}", Self::MIN_MTU, self.mtu).into());
+        }
+        let packetization_mode = PacketizationMode::from_sdp_value(self.packetization_mode)
+            .ok_or_else(|| {
+                format!(
+                    "unsupported packetization mode: {}",
+                    self.packetization_mode
+                )
+            })?;
+        Ok(RtpMuxerSettings {
+            mtu: self.mtu,
+            packetization_mode,
+        })
+    }
+}
+
+impl Default for Rtp {
+    fn default() -> Self {
+        let settings = RtpMuxerSettings::default();
+        Self {
+            mtu: settings.mtu,
+            packetization_mode: settings.packetization_mode.as_sdp_value(),
+        }
+    }
+}
+
+/// Address of the HTTP admin API. If `token` is set, requests must carry
+/// it as a bearer token in the `Authorization` header. The API can add
+/// sources that read any local file, so without a token, it only listens
+/// on a loopback address.
 #[derive(Deserialize)]
+pub struct Admin {
+    pub host: String,
+    pub port: u16,
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+impl Admin {
+    /// Address to listen on. Fails if there is no token, unless the
+    /// address is a loopback address.
+    pub fn as_address(&self) -> Result<IpAddr, Box<dyn Error>> {
+        let host: IpAddr = self.host.parse()?;
+        if self.token.is_none() && !host.is_loopback() {
+            return Err(
+                format!("admin token must be set if host is not loopback: {}", host).into(),
+            );
+        }
+        Ok(host)
+    }
+}
+
+impl fmt::Debug for Admin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{{ host: {:?}, port: {:?}, token: {} }}",
+            self.host,
+            self.port,
+            if self.token.is_some() {
+                "<set>"
+            } else {
+                "<none>"
+            },
+        )
+    }
+}
+
+/// Address of the HLS server, and how sources are packaged: segments of
+/// `segment_secs`, with `playlist_size` segments in the playlist. Setting
+/// `part_ms` enables low-latency HLS with parts of that duration.
+#[derive(Debug, Deserialize)]
+pub struct Hls {
+    pub host: String,
+    pub port: u16,
+    #[serde(default = "Hls::default_segment_secs")]
+    pub segment_secs: u64,
+    #[serde(default = "Hls::default_playlist_size")]
+    pub playlist_size: usize,
+    #[serde(default)]
+    pub part_ms: Option<u64>,
+}
+
+impl Hls {
+    fn default_segment_secs() -> u64 {
+        2
+    }
+
+    fn default_playlist_size() -> usize {
+        6
+    }
+
+    pub fn as_hls_settings(&self) -> Result<HlsSettings, Box<dyn Error>> {
+        if self.segment_secs == 0 {
+            return Err("hls segment_secs must be at least 1".into());
+        }
+        // Clients start a few segments from the end of the playlist, so a
+        // shorter one leaves them nothing to buffer.
+        if self.playlist_size < 3 {
+            return Err("hls playlist_size must be at least 3".into());
+        }
+        if let Some(part_ms) = self.part_ms {
+            if part_ms < 100 || part_ms >= self.segment_secs * 1000 {
+                return Err(
+                    "hls part_ms must be at least 100 and shorter than segment_secs".into(),
+                );
+            }
+        }
+        Ok(HlsSettings {
+            segment_duration: Duration::from_secs(self.segment_secs),
+            playlist_size: self.playlist_size,
+            part_duration: self.part_ms.map(Duration::from_millis),
+        })
+    }
+}
+
+/// Address of the WHEP server. Media goes out over UDP from a port per
+/// viewer on `host`. Browsers are told to connect to `candidate`, which is
+/// `host` unless set, and must be set if `host` is unspecified (such as
+/// `0.0.0.0`) or not reachable by browsers (behind NAT).
+#[derive(Debug, Deserialize)]
+pub struct Whep {
+    pub host: String,
+    pub port: u16,
+    #[serde(default)]
+    pub candidate: Option<String>,
+}
+
+impl Whep {
+    /// Address to listen on, and address that browsers connect to.
+    pub fn as_addresses(&self) -> Result<(IpAddr, IpAddr), Box<dyn Error>> {
+        let host: IpAddr = self.host.parse()?;
+        let candidate = match self.candidate.as_ref() {
+            Some(candidate) => candidate.parse()?,
+            None if host.is_unspecified() => {
+                return Err("whep candidate must be set if host is unspecified".into())
+            }
+            None => host,
+        };
+        Ok((host, candidate))
+    }
+}
+
+/// Media sent over the RTSP connection (RTP over TCP) is queued per
+/// connection. `size` is the maximum number of packets in the queue, and
+/// `slow_client` decides what happens when a client does not read fast
+/// enough to keep the queue from filling up.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct SendQueue {
+    pub size: usize,
+    pub slow_client: SlowClient,
+    /// Used with `slow_client: disconnect`.
+    pub disconnect_after_secs: u64,
+}
+
+impl SendQueue {
+    pub fn as_send_queue_settings(&self) -> Result<SendQueueSettings, Box<dyn Error>> {
+        if self.size == 0 {
+            return Err("send queue size must be at least 1".into());
+        }
+        let slow_client_policy = match self.slow_client {
+            SlowClient::DropUntilKeyframe => SlowClientPolicy::DropUntilKeyframe,
+            SlowClient::Disconnect => {
+                SlowClientPolicy::Disconnect(Duration::from_secs(self.disconnect_after_secs))
+            }
+            SlowClient::Block => SlowClientPolicy::Block,
+        };
+        Ok(SendQueueSettings {
+            size: self.size,
+            slow_client_policy,
+        })
+    }
+}
+
+impl Default for SendQueue {
+    fn default() -> Self {
+        Self {
+            size: SendQueueSettings::default().size,
+            slow_client: SlowClient::default(),
+            disconnect_after_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowClient {
+    /// Drop media until the next keyframe.
+    #[default]
+    DropUntilKeyframe,
+    /// Drop media until the next keyframe, and disconnect the client if it
+    /// lags behind for longer than `disconnect_after_secs`.
+    Disconnect,
+    /// Wait for the client, at the risk of falling behind the source.
+    Block,
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
 pub struct Item {
     pub name: String,
     pub path: String,
     pub kind: MediaKind,
+    /// Where to read the media from. Not used for published media.
+    #[serde(default)]
     pub source: String,
+    #[serde(default)]
+    pub multicast: Option<Multicast>,
+    /// Credentials that clients need to access the media item. Anyone can
+    /// access it if left out.
+    #[serde(default)]
+    pub auth: Option<Auth>,
+    /// Record the media item to disk. Not recorded if left out.
+    #[serde(default)]
+    pub record: Option<Record>,
+}
+
+/// Multicast group to deliver a media item to. RTP of the first track is
+/// sent to `port` and RTCP to `port + 1`, so `port` should be even. Every
+/// further track uses the next two ports.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Multicast {
+    pub address: IpAddr,
+    pub port: u16,
+    #[serde(default = "Multicast::default_ttl")]
+    pub ttl: u32,
+}
+
+impl Multicast {
+    fn default_ttl() -> u32 {
+        16
+    }
+}
+
+/// Records a media item as fragmented MP4 segments of `segment_secs`
+/// each in `directory`. The oldest segments are deleted once there are
+/// more than `max_segments`, or when they are older than `max_age_secs`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Record {
+    pub directory: PathBuf,
+    #[serde(default = "Record::default_segment_secs")]
+    pub segment_secs: u64,
+    #[serde(default)]
+    pub max_segments: Option<usize>,
+    #[serde(default)]
+    pub max_age_secs: Option<u64>,
+}
+
+impl Record {
+    fn default_segment_secs() -> u64 {
+        60
+    }
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+pub struct Auth {
+    #[serde(default)]
+    pub scheme: AuthScheme,
+    pub users: Vec<User>,
+}
+
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{{ scheme: {:?}, users: {:?} }}",
+            self.scheme,
+            self.users
+                .iter()
+                .map(|user| user.username.as_str())
+                .collect::<Vec<_>>(),
+        )
+    }
+}
+
+/// Authentication scheme that clients are challenged with. Digest uses
+/// MD5 by default since that is what most clients support.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthScheme {
+    /// Basic authentication sends the password in the clear, so it should
+    /// only be used on trusted networks.
+    Basic,
+    #[default]
+    Digest,
+    DigestSha256,
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+pub struct User {
+    pub username: String,
+    pub password: String,
 }
 
 impl Item {
     pub fn as_media_descriptor(&self) -> Result<MediaDescriptor, Box<dyn Error>> {
         Ok(match self.kind {
             MediaKind::File => MediaDescriptor::File(PathBuf::from(self.source.to_string())),
+            MediaKind::Vod => MediaDescriptor::Vod(PathBuf::from(self.source.to_string())),
             MediaKind::Stream => MediaDescriptor::Stream(self.source.parse()?),
+            MediaKind::Publish => MediaDescriptor::Publish,
         })
     }
 
+    pub fn as_multicast_group(&self) -> Result<Option<MulticastGroup>, Box<dyn Error>> {
+        let multicast = match self.multicast.as_ref() {
+            Some(multicast) => multicast,
+            None => return Ok(None),
+        };
+        if matches!(self.kind, MediaKind::Vod) {
+            return Err("multicast is not supported for media played on demand".into());
+        }
+        if !multicast.address.is_multicast() {
+            return Err(format!("not a multicast address: {}", multicast.address).into());
+        }
+        if multicast.port % 2 != 0 || multicast.port == 0 {
+            return Err(format!(
+                "multicast port must be even and non-zero: {}",
+                multicast.port
+            )
+            .into());
+        }
+        Ok(Some(MulticastGroup {
+            address: multicast.address,
+            port: multicast.port,
+            ttl: multicast.ttl,
+        }))
+    }
+
+    pub fn as_record_settings(&self) -> Result<Option<RecordSettings>, Box<dyn Error>> {
+        let record = match self.record.as_ref() {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+        if matches!(self.kind, MediaKind::Vod) {
+            return Err("recording is not supported for media played on demand".into());
+        }
+        if record.segment_secs == 0 {
+            return Err("record segment_secs must be at least 1".into());
+        }
+        if record.max_segments == Some(0) {
+            return Err("record max_segments must be at least 1".into());
+        }
+        Ok(Some(RecordSettings {
+            directory: record.directory.clone(),
+            segment_duration: Duration::from_secs(record.segment_secs),
+            max_segments: record.max_segments,
+            max_age: record.max_age_secs.map(Duration::from_secs),
+        }))
+    }
+
     fn source_safe_display(&self) -> String {
         if matches!(self.kind, MediaKind::Stream) {
             video_rs::Url::parse(&self.source)
@@ -68,27 +537,36 @@ impl std::fmt::Debug for Item {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{{ name: {:?}, path: {:?}, kind: {:?}, source: {:?} }}",
+            "{{ name: {:?}, path: {:?}, kind: {:?}, source: {:?}, multicast: {:?}, auth: {:?}, record: {:?} }}",
             self.name,
             self.path,
             self.kind,
             self.source_safe_display(),
+            self.multicast,
+            self.auth,
+            self.record,
         )
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum MediaKind {
     File,
+    /// File that every client plays on its own and can seek in.
+    Vod,
     Stream,
+    /// Media that a client publishes with ANNOUNCE and RECORD.
+    Publish,
 }
 
 impl fmt::Display for MediaKind {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             MediaKind::File => write!(f, "file"),
+            MediaKind::Vod => write!(f, "file on demand"),
             MediaKind::Stream => write!(f, "live stream"),
+            MediaKind::Publish => write!(f, "published"),
         }
     }
 }
@@ -99,7 +577,16 @@ impl Default for AppConfig {
             server: Server {
                 host: "127.0.0.1".to_string(),
                 port: 554,
+                udp: Udp::default(),
+                rtp: Rtp::default(),
+                send_queue: SendQueue::default(),
+                tls: None,
+                websocket: None,
+                publish: None,
             },
+            admin: None,
+            hls: None,
+            whep: None,
             media: Vec::new(),
         }
     }
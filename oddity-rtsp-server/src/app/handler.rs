@@ -1,14 +1,25 @@
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 use tokio::sync::{RwLock, RwLockReadGuard};
 
-use oddity_rtsp_protocol::{Error, Method, Range, Request, Response, RtpInfo, Status, Transport};
+use oddity_rtsp_protocol::{
+    Challenge, Error, Method, Range, Request, Response, RtpInfo, Status, Transport,
+};
 
 use crate::app::AppContext;
-use crate::net::connection::ResponseSenderTx;
+use crate::media::sdp::{self, SdpError};
+use crate::metrics::Metrics;
+use crate::net::connection::{InterleavedRoutes, ResponseSenderTx};
+use crate::net::udp::UdpSocketPairAllocator;
+use crate::session::record::RecordTrack;
 use crate::session::session_manager::RegisterSessionError;
-use crate::session::setup::{SessionSetup, SessionSetupError};
-use crate::session::{PlaySessionError, SessionId};
+use crate::session::setup::{RecordSetup, SessionSetup, SessionSetupError};
+use crate::session::{
+    PauseSessionError, PlaySessionError, RecordSessionError, Session, SessionId, SetupTrackError,
+};
+use crate::source::publish::PublishError;
+use crate::source::source_manager::AnnounceSourceError;
 
 /// Identifies the server by its product name and version. We use
 /// the built-in `concat` and `env` macros to construct this string
@@ -18,15 +29,50 @@ static SERVER: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSI
 
 pub struct AppHandler {
     context: Arc<RwLock<AppContext>>,
+    udp_allocator: UdpSocketPairAllocator,
+    metrics: Arc<Metrics>,
 }
 
 impl AppHandler {
-    pub fn new(context: Arc<RwLock<AppContext>>) -> Self {
-        Self { context }
+    pub fn new(
+        context: Arc<RwLock<AppContext>>,
+        udp_allocator: UdpSocketPairAllocator,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            context,
+            udp_allocator,
+            metrics,
+        }
+    }
+
+    /// Handle a request of the client at `peer_addr`. Media of sessions
+    /// set up over `secure` connections stays on the connection.
+    pub async fn handle(
+        &self,
+        request: &Request,
+        peer_addr: Option<SocketAddr>,
+        secure: bool,
+        responder: &ResponseSenderTx,
+        interleaved_routes: &mut InterleavedRoutes,
+    ) -> Response {
+        let response = self
+            .handle_request(request, peer_addr, secure, responder, interleaved_routes)
+            .await;
+        self.metrics.response(response.status);
+        response
     }
 
-    pub async fn handle(&self, request: &Request, responder: &ResponseSenderTx) -> Response {
+    async fn handle_request(
+        &self,
+        request: &Request,
+        peer_addr: Option<SocketAddr>,
+        secure: bool,
+        responder: &ResponseSenderTx,
+        interleaved_routes: &mut InterleavedRoutes,
+    ) -> Response {
         tracing::trace!(%request, "handling request");
+        let peer_ip_addr = peer_addr.map(|peer_addr| peer_addr.ip());
 
         // Check the Require header and make sure all requested options are
         // supported or return response with 551 Option Not Supported.
@@ -34,6 +80,38 @@ impl AppHandler {
             return reply_option_not_supported(request);
         }
 
+        if is_request_authorization_required(request) {
+            let context = self.use_context().await;
+            // Requests that refer to a session are checked against the source
+            // of the session, so that they cannot control sessions of sources
+            // that the client has no access to.
+            let session = match request.session() {
+                Some(session_id) => context.session_manager.info(&session_id.into()).await,
+                None => None,
+            };
+            let authenticator = context.authenticator.read().await;
+            let authorized = match session.as_ref() {
+                Some(session) => authenticator.authorize_session(request, session),
+                None => authenticator.authorize(request),
+            };
+            if let Err(challenge) = authorized {
+                return reply_unauthorized(request, &challenge);
+            }
+        }
+
+        // Any request that refers to a session is a sign of life of the client
+        // and refreshes the session timeout (RFC 2326 Section 12.37).
+        let session_alive = match request.session() {
+            Some(session_id) => Some(
+                self.use_context()
+                    .await
+                    .session_manager
+                    .keep_alive(&session_id.into())
+                    .await,
+            ),
+            None => None,
+        };
+
         match request.method {
             /* Stateless */
             Method::Options => {
@@ -42,7 +120,51 @@ impl AppHandler {
             }
             Method::Announce => {
                 tracing::trace!("handling ANNOUNCE request");
-                reply_method_not_supported(request)
+                if !is_request_content_type_supported(request) {
+                    return reply_unsupported_media_type(request);
+                }
+                let contents = match request
+                    .body
+                    .as_ref()
+                    .and_then(|body| std::str::from_utf8(body).ok())
+                {
+                    Some(contents) => contents,
+                    None => return reply_bad_request(request),
+                };
+                let announcement = match sdp::parse_announcement(contents) {
+                    Ok(announcement) => announcement,
+                    Err(SdpError::CodecNotSupported) => {
+                        tracing::debug!(%request, "client announced media without supported codecs");
+                        return reply_unsupported_media_type(request);
+                    }
+                    Err(err) => {
+                        tracing::debug!(%request, %err, "failed to parse announced SDP");
+                        return reply_bad_request(request);
+                    }
+                };
+
+                match self
+                    .use_context()
+                    .await
+                    .source_manager
+                    .announce(request.path(), announcement, responder)
+                    .await
+                {
+                    Ok(()) => reply_to_announce(request),
+                    Err(AnnounceSourceError::NotFound) => reply_not_found(request),
+                    Err(AnnounceSourceError::TooManySources) => reply_service_unavailable(request),
+                    Err(AnnounceSourceError::Publish(PublishError::NotPublishable)) => {
+                        reply_method_not_supported(request)
+                    }
+                    Err(AnnounceSourceError::Publish(PublishError::AlreadyPublishing)) => {
+                        tracing::debug!(%request, "another client is already publishing");
+                        reply_not_valid_in_this_state(request)
+                    }
+                    Err(err) => {
+                        tracing::error!(%request, %err, "failed to announce media");
+                        reply_internal_server_error(request)
+                    }
+                }
             }
             Method::Describe => {
                 tracing::trace!("handling DESCRIBE request");
@@ -76,7 +198,15 @@ impl AppHandler {
             }
             Method::GetParameter => {
                 tracing::trace!("handling GET_PARAMETER request");
-                reply_method_not_supported(request)
+                if request.body.as_ref().is_some_and(|body| !body.is_empty()) {
+                    // We do not have any parameters, so only the empty
+                    // GET_PARAMETER that clients use as a keep-alive is supported.
+                    return reply_invalid_parameter(request);
+                }
+                match session_alive {
+                    Some(false) => reply_session_not_found(request),
+                    _ => reply_to_get_parameter(request),
+                }
             }
             Method::SetParameter => {
                 tracing::trace!("handling SET_PARAMETER request");
@@ -84,14 +214,6 @@ impl AppHandler {
             }
             /* Stateful */
             Method::Setup => {
-                if request.session().is_some() {
-                    // RFC specification allows negatively responding to SETUP request with Session
-                    // IDs by responding with 459 Aggregate Operation Not Allowed. By handling this
-                    // here we don't have to deal with clients trying to change transport parameters
-                    // on media items that are already playing.
-                    return reply_aggregate_operation_not_allowed(request);
-                }
-
                 let transport = match request.transport() {
                     Ok(transport) => transport,
                     Err(_) => {
@@ -101,11 +223,29 @@ impl AppHandler {
                     }
                 };
 
+                if RecordSetup::is_requested(&transport) {
+                    return self
+                        .setup_record(
+                            request,
+                            transport,
+                            peer_addr,
+                            secure,
+                            responder,
+                            interleaved_routes,
+                        )
+                        .await;
+                }
+
+                // Tracks are set up on `<path>/trackID=N`. Setting up the source
+                // path itself refers to the first track.
+                let (source_path, track) = sdp::split_track_path(request.path());
+                let track = track.unwrap_or(0);
+
                 let mut source_delegate = match self
                     .use_context()
                     .await
                     .source_manager
-                    .subscribe(request.path())
+                    .subscribe(source_path)
                     .await
                 {
                     Some(source_delegate) => source_delegate,
@@ -114,6 +254,8 @@ impl AppHandler {
                     }
                 };
 
+                // Make sure the source is up and running before setting up a
+                // session for it.
                 let media_info = match source_delegate.media_info().await {
                     Some(media_info) => media_info,
                     None => {
@@ -124,11 +266,20 @@ impl AppHandler {
                         return reply_internal_server_error(request);
                     }
                 };
+                if track >= media_info.streams.len() {
+                    tracing::debug!(%request, track, "source does not have track");
+                    return reply_not_found(request);
+                }
 
                 let session_setup = match SessionSetup::from_rtsp_candidate_transports(
                     transport,
-                    media_info,
+                    track,
+                    source_delegate.multicast(),
                     responder.clone(),
+                    interleaved_routes,
+                    peer_ip_addr,
+                    secure,
+                    &self.udp_allocator,
                 )
                 .await
                 {
@@ -137,21 +288,55 @@ impl AppHandler {
                     | Err(SessionSetupError::DestinationInvalid) => {
                         return reply_unsupported_transport(request);
                     }
-                    Err(SessionSetupError::Media(err)) => {
+                    Err(SessionSetupError::Socket(err)) => {
                         tracing::error!(
                           %request, %err,
-                          "failed to setup session for media source",
+                          "failed to allocate udp sockets for session",
                         );
                         return reply_internal_server_error(request);
                     }
                 };
 
                 let transport = session_setup.rtsp_transport.clone();
+                if let Some(session_id) = request.session() {
+                    // Further tracks are added to the existing session. Tracks of other
+                    // sources, tracks that were set up before and changing between
+                    // unicast and multicast are refused with 459 Aggregate Operation
+                    // Not Allowed.
+                    let session_id = session_id.into();
+                    return match self
+                        .use_context()
+                        .await
+                        .session_manager
+                        .setup_track(&session_id, source_path, track, session_setup)
+                        .await
+                    {
+                        Some(Ok(())) => reply_to_setup(request, &session_id, &transport),
+                        Some(Err(SetupTrackError::NotAllowed)) => {
+                            reply_aggregate_operation_not_allowed(request)
+                        }
+                        Some(Err(SetupTrackError::ControlBroken)) => {
+                            tracing::error!(
+                                %request,
+                                "session control channel unexpectedly broke",
+                            );
+                            reply_internal_server_error(request)
+                        }
+                        None => reply_session_not_found(request),
+                    };
+                }
+
                 match self
                     .use_context()
                     .await
                     .session_manager
-                    .setup(source_delegate, session_setup)
+                    .setup(
+                        source_path.to_string(),
+                        source_delegate,
+                        track,
+                        session_setup,
+                        peer_addr,
+                    )
                     .await
                 {
                     // Session was successfully registered!
@@ -198,19 +383,23 @@ impl AppHandler {
                         .play(&session_id.into(), range.clone())
                         .await
                     {
-                        Some(Ok(stream_state)) => {
-                            // Either just echo back the range the client requested, since
-                            // we accepted it it will be correct or just generate a generic
-                            // `now-` range.
-                            let range = range.unwrap_or_else(Range::new_for_live);
-                            // Construct RTP-Info based on the request URI, and the stream
-                            // state, which includes the last RTP sequence number, and the
-                            // current RTP timestamp.
-                            let rtp_info = RtpInfo::new_with_timing(
-                                &request.uri().to_string(),
-                                stream_state.rtp_seq,
-                                stream_state.rtp_timestamp,
-                            );
+                        Some(Ok((range, stream_states))) => {
+                            // Construct RTP-Info for every track based on the request URI,
+                            // and the stream state of the track, which includes the last RTP
+                            // sequence number, and the current RTP timestamp.
+                            let uri = request.uri().to_string();
+                            let (base_uri, _) = sdp::split_track_path(&uri);
+                            let base_uri = base_uri.trim_end_matches('/');
+                            let rtp_info = stream_states
+                                .into_iter()
+                                .map(|(track, stream_state)| {
+                                    RtpInfo::new_with_timing(
+                                        &format!("{}/{}", base_uri, sdp::track_control(track)),
+                                        stream_state.rtp_seq,
+                                        stream_state.rtp_timestamp,
+                                    )
+                                })
+                                .collect::<Vec<_>>();
                             reply_to_play(request, range, rtp_info)
                         }
                         Some(Err(PlaySessionError::RangeNotSupported)) => {
@@ -220,6 +409,10 @@ impl AppHandler {
                             );
                             reply_header_field_not_valid(request)
                         }
+                        Some(Err(PlaySessionError::RangeInvalid)) => reply_invalid_range(request),
+                        Some(Err(PlaySessionError::Recording)) => {
+                            reply_not_valid_in_this_state(request)
+                        }
                         Some(Err(PlaySessionError::ControlBroken)) => {
                             tracing::error!(
                                 %request,
@@ -227,6 +420,10 @@ impl AppHandler {
                             );
                             reply_internal_server_error(request)
                         }
+                        Some(Err(PlaySessionError::ControlTimeout)) => {
+                            tracing::error!(%request, "session did not reply in time");
+                            reply_internal_server_error(request)
+                        }
                         None => reply_session_not_found(request),
                     }
                 } else {
@@ -235,11 +432,57 @@ impl AppHandler {
             }
             Method::Pause => {
                 tracing::trace!("handling PAUSE request");
-                reply_method_not_supported(request)
+                if let Some(session_id) = request.session() {
+                    match self
+                        .use_context()
+                        .await
+                        .session_manager
+                        .pause(&session_id.into())
+                        .await
+                    {
+                        Some(Ok(())) => reply_to_pause(request),
+                        Some(Err(PauseSessionError::Recording)) => {
+                            reply_not_valid_in_this_state(request)
+                        }
+                        Some(Err(PauseSessionError::ControlBroken)) => {
+                            tracing::error!(
+                                %request,
+                                "session control channel unexpectedly broke",
+                            );
+                            reply_internal_server_error(request)
+                        }
+                        None => reply_session_not_found(request),
+                    }
+                } else {
+                    reply_session_not_found(request)
+                }
             }
             Method::Record => {
                 tracing::trace!("handling RECORD request");
-                reply_method_not_supported(request)
+                if let Some(session_id) = request.session() {
+                    match self
+                        .use_context()
+                        .await
+                        .session_manager
+                        .record(&session_id.into())
+                        .await
+                    {
+                        Some(Ok(())) => reply_to_record(request),
+                        Some(Err(RecordSessionError::NotRecording)) => {
+                            reply_not_valid_in_this_state(request)
+                        }
+                        Some(Err(RecordSessionError::ControlBroken)) => {
+                            tracing::error!(
+                                %request,
+                                "session control channel unexpectedly broke",
+                            );
+                            reply_internal_server_error(request)
+                        }
+                        None => reply_session_not_found(request),
+                    }
+                } else {
+                    reply_session_not_found(request)
+                }
             }
             Method::Teardown => {
                 tracing::trace!("handling TEARDOWN request");
@@ -269,6 +512,106 @@ impl AppHandler {
         }
     }
 
+    /// Set up a track that the client is going to publish. The first track
+    /// creates a record session, and further tracks are added to it.
+    async fn setup_record(
+        &self,
+        request: &Request,
+        transport: Vec<Transport>,
+        peer_addr: Option<SocketAddr>,
+        secure: bool,
+        responder: &ResponseSenderTx,
+        interleaved_routes: &mut InterleavedRoutes,
+    ) -> Response {
+        let publisher = match self
+            .use_context()
+            .await
+            .source_manager
+            .publisher(request.path(), responder)
+            .await
+        {
+            Some(Ok(publisher)) => publisher,
+            Some(Err(err)) => {
+                tracing::debug!(%request, %err, "client tried to record without announcing");
+                return reply_not_valid_in_this_state(request);
+            }
+            None => return reply_not_found(request),
+        };
+
+        let index = match publisher.track(request.path()) {
+            Some(index) => index,
+            None => {
+                tracing::debug!(%request, "no announced track for path");
+                return reply_not_found(request);
+            }
+        };
+
+        let record_setup = match RecordSetup::from_rtsp_candidate_transports(
+            transport,
+            index,
+            interleaved_routes,
+            peer_addr.map(|peer_addr| peer_addr.ip()),
+            secure,
+            &self.udp_allocator,
+        )
+        .await
+        {
+            Ok(record_setup) => record_setup,
+            Err(SessionSetupError::TransportNotSupported)
+            | Err(SessionSetupError::DestinationInvalid) => {
+                return reply_unsupported_transport(request);
+            }
+            Err(SessionSetupError::Socket(err)) => {
+                tracing::error!(
+                  %request, %err,
+                  "failed to allocate udp sockets for session",
+                );
+                return reply_internal_server_error(request);
+            }
+        };
+
+        let transport = record_setup.rtsp_transport;
+        let track = RecordTrack {
+            index,
+            input: record_setup.input,
+            transport: transport.clone(),
+        };
+        let context = self.use_context().await;
+        match request.session() {
+            Some(session_id) => {
+                let session_id = session_id.into();
+                match context.session_manager.add_track(&session_id, track).await {
+                    Some(Ok(())) => reply_to_setup(request, &session_id, &transport),
+                    Some(Err(RecordSessionError::NotRecording)) => {
+                        reply_aggregate_operation_not_allowed(request)
+                    }
+                    Some(Err(RecordSessionError::ControlBroken)) => {
+                        tracing::error!(
+                            %request,
+                            "session control channel unexpectedly broke",
+                        );
+                        reply_internal_server_error(request)
+                    }
+                    None => reply_session_not_found(request),
+                }
+            }
+            None => match context
+                .session_manager
+                .setup_record(publisher, track, responder.clone(), peer_addr)
+                .await
+            {
+                Ok(session_id) => reply_to_setup(request, &session_id, &transport),
+                Err(RegisterSessionError::AlreadyRegistered) => {
+                    tracing::error!(
+                        %request,
+                        "session id already present (collision)",
+                    );
+                    reply_internal_server_error(request)
+                }
+            },
+        }
+    }
+
     #[inline]
     async fn use_context(&self) -> RwLockReadGuard<'_, AppContext> {
         self.context.read().await
@@ -281,40 +624,114 @@ fn is_request_require_supported(request: &Request) -> bool {
     request.require().is_none()
 }
 
+#[inline]
+fn is_request_authorization_required(request: &Request) -> bool {
+    // Every supported request but `OPTIONS` refers to a source, directly or
+    // through its session.
+    matches!(
+        request.method,
+        Method::Describe
+            | Method::Announce
+            | Method::Setup
+            | Method::Play
+            | Method::Pause
+            | Method::Record
+            | Method::Teardown
+            | Method::GetParameter
+    )
+}
+
 #[inline]
 fn is_request_one_of_content_types_supported(request: &Request) -> bool {
     // We only support SDP
     request.accept().contains(&"application/sdp")
 }
 
+#[inline]
+fn is_request_content_type_supported(request: &Request) -> bool {
+    // We only support SDP, and leaving out the content type is tolerated.
+    request.content_type().is_none_or(|content_type| {
+        content_type
+            .split(';')
+            .next()
+            .is_some_and(|content_type| content_type.trim() == "application/sdp")
+    })
+}
+
 #[inline]
 fn reply_to_options_with_supported_methods(request: &Request) -> Response {
     Response::ok()
         .with_cseq_of(request)
         .with_header("Server", SERVER)
-        .with_header("Public", "OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN")
+        .with_header(
+            "Public",
+            "OPTIONS, DESCRIBE, ANNOUNCE, SETUP, PLAY, PAUSE, RECORD, TEARDOWN, GET_PARAMETER",
+        )
         .build()
 }
 
 #[inline]
 fn reply_to_describe_with_media_sdp(request: &Request, sdp_contents: String) -> Response {
+    // The control attributes of the tracks are relative to the base URL,
+    // which is the request URL with a trailing slash (RFC 2326 Appendix
+    // C.1.1).
+    let content_base = format!("{}/", request.uri().to_string().trim_end_matches('/'));
     Response::ok()
         .with_cseq_of(request)
         .with_header("Server", SERVER)
+        .with_header("Content-Base", content_base)
         .with_sdp(sdp_contents)
         .build()
 }
 
+#[inline]
+fn reply_to_announce(request: &Request) -> Response {
+    Response::ok()
+        .with_cseq_of(request)
+        .with_header("Server", SERVER)
+        .build()
+}
+
 #[inline]
 fn reply_to_setup(request: &Request, session_id: &SessionId, transport: &Transport) -> Response {
     Response::ok()
         .with_cseq_of(request)
         .with_header("Server", SERVER)
-        .with_header("Session", session_id)
+        .with_header(
+            "Session",
+            format!("{};timeout={}", session_id, Session::TIMEOUT.as_secs()),
+        )
         .with_header("Transport", transport)
         .build()
 }
 
+#[inline]
+fn reply_to_get_parameter(request: &Request) -> Response {
+    let response = Response::ok()
+        .with_cseq_of(request)
+        .with_header("Server", SERVER);
+    match request.session() {
+        Some(session_id) => response.with_header("Session", session_id).build(),
+        None => response.build(),
+    }
+}
+
+#[inline]
+fn reply_to_pause(request: &Request) -> Response {
+    Response::ok()
+        .with_cseq_of(request)
+        .with_header("Server", SERVER)
+        .build()
+}
+
+#[inline]
+fn reply_to_record(request: &Request) -> Response {
+    Response::ok()
+        .with_cseq_of(request)
+        .with_header("Server", SERVER)
+        .build()
+}
+
 #[inline]
 fn reply_to_teardown(request: &Request) -> Response {
     Response::ok()
@@ -324,10 +741,10 @@ fn reply_to_teardown(request: &Request) -> Response {
 }
 
 #[inline]
-fn reply_to_play(request: &Request, range: Range, rtp_info: RtpInfo) -> Response {
+fn reply_to_play(request: &Request, range: Range, rtp_info: Vec<RtpInfo>) -> Response {
     Response::ok()
         .with_cseq_of(request)
-        .with_rtp_info([rtp_info])
+        .with_rtp_info(rtp_info)
         .with_header("Server", SERVER)
         .with_header("Range", range)
         .build()
@@ -377,6 +794,29 @@ fn reply_method_not_valid(request: &Request) -> Response {
         .build()
 }
 
+#[inline]
+fn reply_not_valid_in_this_state(request: &Request) -> Response {
+    tracing::debug!(
+    %request,
+    method = %request.method,
+    "method not valid in this state");
+    Response::error(Status::MethodNotValidInThisState)
+        .with_cseq_of(request)
+        .with_header("Server", SERVER)
+        .build()
+}
+
+#[inline]
+fn reply_unsupported_media_type(request: &Request) -> Response {
+    tracing::debug!(
+    %request,
+    "server only accepts `application/sdp` with supported codecs");
+    Response::error(Status::UnsupportedMediaType)
+        .with_cseq_of(request)
+        .with_header("Server", SERVER)
+        .build()
+}
+
 #[inline]
 fn reply_not_acceptable(request: &Request) -> Response {
     tracing::debug!(
@@ -389,6 +829,19 @@ fn reply_not_acceptable(request: &Request) -> Response {
         .build()
 }
 
+#[inline]
+fn reply_unauthorized(request: &Request, challenge: &Challenge) -> Response {
+    tracing::debug!(
+    %request,
+    path = request.path(),
+    "client not authorized");
+    Response::error(Status::Unauthorized)
+        .with_cseq_of(request)
+        .with_header("Server", SERVER)
+        .with_www_authenticate(challenge)
+        .build()
+}
+
 #[inline]
 fn reply_not_found(request: &Request) -> Response {
     tracing::debug!(
@@ -423,6 +876,17 @@ fn reply_unsupported_transport(request: &Request) -> Response {
         .build()
 }
 
+#[inline]
+fn reply_invalid_parameter(request: &Request) -> Response {
+    tracing::debug!(
+    %request,
+    "client asked for parameters, but there are none");
+    Response::error(Status::InvalidParameter)
+        .with_cseq_of(request)
+        .with_header("Server", SERVER)
+        .build()
+}
+
 #[inline]
 fn reply_session_not_found(request: &Request) -> Response {
     tracing::debug!(
@@ -456,6 +920,28 @@ fn reply_header_field_not_valid(request: &Request) -> Response {
         .build()
 }
 
+#[inline]
+fn reply_invalid_range(request: &Request) -> Response {
+    tracing::debug!(
+    %request,
+    "client provided range that is not within media");
+    Response::error(Status::InvalidRange)
+        .with_cseq_of(request)
+        .with_header("Server", SERVER)
+        .build()
+}
+
+#[inline]
+fn reply_service_unavailable(request: &Request) -> Response {
+    tracing::debug!(
+    %request,
+    "refusing to create another source");
+    Response::error(Status::ServiceUnavailable)
+        .with_cseq_of(request)
+        .with_header("Server", SERVER)
+        .build()
+}
+
 #[inline]
 fn reply_internal_server_error(request: &Request) -> Response {
     Response::error(Status::InternalServerError)
@@ -0,0 +1,251 @@
+//! HTTP server for sources packaged as HLS. The playlist of the source at
+//! `<path>` is at `/hls/<path>/index.m3u8`, and the segments it lists are
+//! next to it. Sources with users configured require the same credentials
+//! as they do over RTSP.
+//!
+//! With low-latency HLS, clients can block on the playlist until a segment
+//! or part is ready with the `_HLS_msn` and `_HLS_part` query parameters,
+//! and parts are at `<sequence>.<part>.m4s`.
+
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use axum::extract::{Path, RawQuery, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+
+use tokio::net;
+use tokio::sync::RwLock;
+
+use crate::app::AppContext;
+use crate::runtime::task_manager::{Task, TaskContext};
+use crate::runtime::Runtime;
+use crate::source;
+use crate::source::hls::HlsPlaylist;
+
+const PLAYLIST: &str = "index.m3u8";
+
+pub struct HlsServer {
+    worker: Task,
+}
+
+impl HlsServer {
+    pub async fn start(
+        host: IpAddr,
+        port: u16,
+        context: Arc<RwLock<AppContext>>,
+        runtime: &Runtime,
+    ) -> Result<Self, io::Error> {
+        tracing::trace!(%host, port, "starting hls server");
+        let listener = match net::TcpListener::bind((host, port)).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                tracing::error!(%err, %host, port, "failed to listen for hls requests");
+                return Err(err);
+            }
+        };
+        tracing::info!(%host, port, "hls server listening for requests");
+
+        let router = Router::new()
+            .route("/hls/*path", get(serve).options(preflight))
+            .with_state(context);
+
+        let worker = runtime
+            .task()
+            .spawn(move |task_context| Self::run(listener, router, task_context))
+            .await;
+        tracing::trace!(%host, port, "started hls server");
+
+        Ok(Self { worker })
+    }
+
+    pub async fn stop(&mut self) {
+        tracing::trace!("sending stop signal to hls server");
+        self.worker.stop().await;
+        tracing::trace!("hls server stopped");
+    }
+
+    async fn run(listener: net::TcpListener, router: Router, mut task_context: TaskContext) {
+        let shutdown = async move { task_context.wait_for_stop().await };
+        if let Err(err) = axum::serve(listener, router)
+            .with_graceful_shutdown(shutdown)
+            .await
+        {
+            tracing::error!(%err, "hls server failed");
+        }
+    }
+}
+
+async fn serve(
+    State(context): State<Arc<RwLock<AppContext>>>,
+    Path(path): Path<String>,
+    RawQuery(query): RawQuery,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Response {
+    let Some((source_path, file)) = path.rsplit_once('/') else {
+        return not_found();
+    };
+    let source_path = source::normalize_path(source_path.to_string());
+
+    let playlist = {
+        let context = context.read().await;
+        let authorization = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .map(str::parse);
+        if let Err(challenge) = context.authenticator.read().await.authorize_path(
+            &source_path,
+            authorization,
+            "GET",
+            &uri,
+        ) {
+            tracing::debug!(path = %source_path, "hls client not authorized");
+            return with_cors(
+                (
+                    StatusCode::UNAUTHORIZED,
+                    [(header::WWW_AUTHENTICATE, challenge.to_string())],
+                )
+                    .into_response(),
+            );
+        }
+        match context.source_manager.hls(&source_path).await {
+            Some(playlist) => playlist,
+            None => return not_found(),
+        }
+    };
+
+    let response = if file == PLAYLIST {
+        if playlist.is_low_latency() {
+            if let Some((sequence, part)) = blocking_reload(query.as_deref()) {
+                if let Err(status) = wait_for(&playlist, sequence, part).await {
+                    return with_cors(status.into_response());
+                }
+            }
+        }
+        match playlist.media_playlist() {
+            Some(media_playlist) => (
+                [
+                    (header::CONTENT_TYPE, "application/vnd.apple.mpegurl"),
+                    (header::CACHE_CONTROL, "no-cache"),
+                ],
+                media_playlist,
+            )
+                .into_response(),
+            None => {
+                // The source has not produced a complete segment yet. Clients
+                // retry the playlist, so this is not an error.
+                tracing::trace!(path = %source_path, "hls playlist not ready");
+                return not_found();
+            }
+        }
+    } else if let Some(id) = file
+        .strip_prefix("init-")
+        .and_then(|file| file.strip_suffix(".mp4"))
+        .and_then(|id| id.parse().ok())
+    {
+        match playlist.init(id) {
+            Some(init) => segment_response("video/mp4", init),
+            None => return not_found(),
+        }
+    } else if let Some((sequence, part)) = file
+        .strip_suffix(".m4s")
+        .and_then(|file| file.split_once('.'))
+        .and_then(|(sequence, part)| sequence.parse().ok().zip(part.parse().ok()))
+    {
+        // The preload hint points at the next part before it exists, and
+        // clients expect the request to block until it does.
+        if playlist.is_low_latency() {
+            if let Err(status) = wait_for(&playlist, sequence, Some(part)).await {
+                return with_cors(status.into_response());
+            }
+        }
+        match playlist.part(sequence, part) {
+            Some(part) => segment_response("video/iso.segment", part),
+            None => return not_found(),
+        }
+    } else if let Some(sequence) = file
+        .strip_suffix(".m4s")
+        .and_then(|sequence| sequence.parse().ok())
+    {
+        match playlist.segment(sequence) {
+            Some(segment) => segment_response("video/iso.segment", segment),
+            None => return not_found(),
+        }
+    } else {
+        return not_found();
+    };
+    with_cors(response)
+}
+
+/// Segment and part to wait for before responding with the playlist, from
+/// the `_HLS_msn` and `_HLS_part` query parameters.
+fn blocking_reload(query: Option<&str>) -> Option<(u64, Option<usize>)> {
+    let mut sequence = None;
+    let mut part = None;
+    for (key, value) in query?.split('&').filter_map(|pair| pair.split_once('=')) {
+        match key {
+            "_HLS_msn" => sequence = value.parse().ok(),
+            "_HLS_part" => part = value.parse().ok(),
+            _ => {}
+        }
+    }
+    Some((sequence?, part))
+}
+
+/// Wait until the playlist has the segment or part. Clients give up after
+/// three target durations, so waiting any longer is pointless.
+async fn wait_for(
+    playlist: &HlsPlaylist,
+    sequence: u64,
+    part: Option<usize>,
+) -> Result<(), StatusCode> {
+    match tokio::time::timeout(playlist.max_wait(), playlist.wait_for(sequence, part)).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(StatusCode::BAD_REQUEST),
+        Err(_) => Err(StatusCode::SERVICE_UNAVAILABLE),
+    }
+}
+
+/// Browsers ask before sending credentials to another origin.
+async fn preflight() -> Response {
+    let mut response = StatusCode::NO_CONTENT.into_response();
+    let headers = response.headers_mut();
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_METHODS,
+        HeaderValue::from_static("GET, OPTIONS"),
+    );
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_HEADERS,
+        HeaderValue::from_static("Authorization"),
+    );
+    with_cors(response)
+}
+
+/// Segments never change once they are listed, so clients may cache them.
+fn segment_response(content_type: &'static str, data: bytes::Bytes) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CACHE_CONTROL, "max-age=3600"),
+        ],
+        data,
+    )
+        .into_response()
+}
+
+fn not_found() -> Response {
+    with_cors(StatusCode::NOT_FOUND.into_response())
+}
+
+/// Players on web pages are usually served from another origin.
+fn with_cors(mut response: Response) -> Response {
+    response.headers_mut().insert(
+        header::ACCESS_CONTROL_ALLOW_ORIGIN,
+        HeaderValue::from_static("*"),
+    );
+    response
+}
@@ -1,17 +1,32 @@
+pub mod admin;
+pub mod auth;
 pub mod config;
 pub mod handler;
+pub mod hls;
+pub mod whep;
 
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::sync::Arc;
 
 use tokio::sync::RwLock;
 
-use crate::app::config::AppConfig;
+use crate::app::admin::AdminServer;
+use crate::app::auth::Authenticator;
+use crate::app::config::{AppConfig, Auth, Item};
 use crate::app::handler::AppHandler;
+use crate::app::hls::HlsServer;
+use crate::app::whep::WhepServer;
+use crate::media::MediaDescriptor;
+use crate::metrics::Metrics;
 use crate::net::server::Server;
+use crate::net::udp::UdpSocketPairAllocator;
 use crate::runtime::Runtime;
 use crate::session::session_manager::SessionManager;
-use crate::source::source_manager::SourceManager;
+use crate::source::multicast::MulticastGroup;
+use crate::source::recorder::RecordSettings;
+use crate::source::source_manager::{RegisterSourceError, SourceManager};
+use crate::source::{self, SourcePath, SourcePathRef};
 
 macro_rules! handle_err {
     ($rt:ident, $expr:expr) => {
@@ -27,34 +42,130 @@ macro_rules! handle_err {
 
 pub struct App {
     server: Server,
+    admin: Option<AdminServer>,
+    hls: Option<HlsServer>,
+    whep: Option<WhepServer>,
     context: Arc<RwLock<AppContext>>,
+    /// Media items of the config file that are registered, by path.
+    media: BTreeMap<SourcePath, Item>,
     runtime: Arc<Runtime>,
 }
 
 impl App {
     pub async fn start(config: AppConfig) -> Result<App, Box<dyn Error>> {
         let runtime = Arc::new(Runtime::new());
+        let metrics = Arc::new(Metrics::default());
 
-        let mut context = initialize_context(runtime.clone()).await;
-        handle_err!(
+        let context = handle_err!(
             runtime,
-            register_sources_with_context(&config, &mut context,).await
+            initialize_context(&config, metrics.clone(), runtime.clone()).await
+        )?;
+        let media = handle_err!(
+            runtime,
+            register_sources_with_context(&config, &context).await
         )?;
 
         let context = Arc::new(RwLock::new(context));
         let server = handle_err!(
             runtime,
-            initialize_server(&config, context.clone(), runtime.clone(),).await
+            initialize_server(&config, context.clone(), metrics, runtime.clone()).await
+        )?;
+        let admin = handle_err!(
+            runtime,
+            initialize_admin(&config, context.clone(), runtime.as_ref()).await
+        )?;
+        let hls = handle_err!(
+            runtime,
+            initialize_hls(&config, context.clone(), runtime.as_ref()).await
+        )?;
+        let whep = handle_err!(
+            runtime,
+            initialize_whep(&config, context.clone(), runtime.clone()).await
         )?;
 
         Ok(Self {
             server,
+            admin,
+            hls,
+            whep,
             context,
+            media,
             runtime,
         })
     }
 
+    /// Apply the media items of a reloaded config file. Sources that were
+    /// removed are stopped and their sessions torn down, new sources are
+    /// started, and changed sources are restarted. Sources that did not
+    /// change are left alone, and so are their sessions. Only credentials
+    /// changed means the source keeps running too.
+    ///
+    /// Server settings are not reloaded, those require a restart.
+    pub async fn reload(&mut self, config: AppConfig) {
+        let mut media = BTreeMap::new();
+        for item in config.media {
+            let path = source::normalize_path(item.path.clone());
+            if media.insert(path.clone(), item).is_some() {
+                tracing::warn!(%path, "media item path appears more than once, using last");
+            }
+        }
+
+        let context = self.context.read().await;
+        let previous = std::mem::take(&mut self.media);
+        let mut failed = Vec::new();
+        for change in diff_media(&previous, &media) {
+            match change {
+                MediaChange::Removed(item) => {
+                    tracing::info!(%item, "source removed from config");
+                    context
+                        .unregister_source(&source::normalize_path(item.path.clone()))
+                        .await;
+                }
+                MediaChange::AuthChanged(item) => {
+                    tracing::info!(%item, "credentials of source changed");
+                    let path = source::normalize_path(item.path.clone());
+                    let _ = context
+                        .authenticator
+                        .write()
+                        .await
+                        .replace(&path, item.auth.as_ref());
+                }
+                MediaChange::Changed(item) => {
+                    tracing::info!(%item, "source changed in config, restarting");
+                    let path = source::normalize_path(item.path.clone());
+                    context.unregister_source(&path).await;
+                    if let Err(err) = context.register_source(item).await {
+                        tracing::error!(%item, %err, "failed to register changed source");
+                        failed.push(path);
+                    }
+                }
+                MediaChange::Added(item) => {
+                    tracing::info!(%item, "source added to config");
+                    if let Err(err) = context.register_source(item).await {
+                        tracing::error!(%item, %err, "failed to register new source");
+                        failed.push(source::normalize_path(item.path.clone()));
+                    }
+                }
+            }
+        }
+        for path in failed {
+            let _ = media.remove(&path);
+        }
+        self.media = media;
+
+        tracing::info!("reloaded media from config (server settings require a restart)");
+    }
+
     pub async fn stop(&mut self) {
+        if let Some(admin) = self.admin.as_mut() {
+            admin.stop().await;
+        }
+        if let Some(hls) = self.hls.as_mut() {
+            hls.stop().await;
+        }
+        if let Some(whep) = self.whep.as_mut() {
+            whep.stop().await;
+        }
         self.server.stop().await;
         self.context.write().await.session_manager.stop().await;
         self.context.write().await.source_manager.stop().await;
@@ -65,47 +176,323 @@ impl App {
 async fn initialize_server(
     config: &AppConfig,
     context: Arc<RwLock<AppContext>>,
+    metrics: Arc<Metrics>,
     runtime: Arc<Runtime>,
 ) -> Result<Server, Box<dyn Error>> {
-    let handler = AppHandler::new(context.clone());
+    let host = config.server.host.parse()?;
+    let udp_allocator = UdpSocketPairAllocator::new(
+        host,
+        config.server.udp.port_min..=config.server.udp.port_max,
+    );
+    let send_queue_settings = config.server.send_queue.as_send_queue_settings()?;
+    let tls_settings = config
+        .server
+        .tls
+        .as_ref()
+        .map(|tls| tls.as_tls_settings())
+        .transpose()?;
+    let handler = AppHandler::new(context.clone(), udp_allocator, metrics.clone());
     Server::start(
-        config.server.host.parse()?,
+        host,
         config.server.port,
+        tls_settings,
+        config
+            .server
+            .websocket
+            .as_ref()
+            .map(|websocket| websocket.port),
         handler,
+        send_queue_settings,
+        metrics,
         runtime.clone(),
     )
     .await
     .map_err(|err| err.into())
 }
 
-async fn initialize_context(runtime: Arc<Runtime>) -> AppContext {
-    AppContext {
-        source_manager: SourceManager::start(runtime.clone()).await,
-        session_manager: SessionManager::start(runtime.clone()).await,
+async fn initialize_admin(
+    config: &AppConfig,
+    context: Arc<RwLock<AppContext>>,
+    runtime: &Runtime,
+) -> Result<Option<AdminServer>, Box<dyn Error>> {
+    let admin = match config.admin.as_ref() {
+        Some(admin) => admin,
+        None => return Ok(None),
+    };
+    let host = admin.as_address()?;
+    let admin_server =
+        AdminServer::start(host, admin.port, admin.token.clone(), context, runtime).await?;
+    Ok(Some(admin_server))
+}
+
+async fn initialize_hls(
+    config: &AppConfig,
+    context: Arc<RwLock<AppContext>>,
+    runtime: &Runtime,
+) -> Result<Option<HlsServer>, Box<dyn Error>> {
+    let hls = match config.hls.as_ref() {
+        Some(hls) => hls,
+        None => return Ok(None),
+    };
+    let host = hls.host.parse()?;
+    let hls_server = HlsServer::start(host, hls.port, context, runtime).await?;
+    Ok(Some(hls_server))
+}
+
+async fn initialize_whep(
+    config: &AppConfig,
+    context: Arc<RwLock<AppContext>>,
+    runtime: Arc<Runtime>,
+) -> Result<Option<WhepServer>, Box<dyn Error>> {
+    let whep = match config.whep.as_ref() {
+        Some(whep) => whep,
+        None => return Ok(None),
+    };
+    let (host, candidate) = whep.as_addresses()?;
+    let whep_server = WhepServer::start(host, whep.port, candidate, context, runtime).await?;
+    Ok(Some(whep_server))
+}
+
+async fn initialize_context(
+    config: &AppConfig,
+    metrics: Arc<Metrics>,
+    runtime: Arc<Runtime>,
+) -> Result<AppContext, Box<dyn Error>> {
+    let muxer_settings = config.server.rtp.as_muxer_settings()?;
+    let hls_settings = config
+        .hls
+        .as_ref()
+        .map(|hls| hls.as_hls_settings())
+        .transpose()?;
+    let publish_settings = config
+        .server
+        .publish
+        .as_ref()
+        .map(|publish| publish.as_publish_settings())
+        .transpose()?;
+    let mut authenticator = Authenticator::new();
+    if let Some((settings, auth)) = publish_settings.as_ref().zip(
+        config
+            .server
+            .publish
+            .as_ref()
+            .and_then(|publish| publish.auth.as_ref()),
+    ) {
+        authenticator.register_publish(&settings.prefix, auth);
     }
+    Ok(AppContext {
+        source_manager: SourceManager::start(
+            runtime.clone(),
+            muxer_settings,
+            hls_settings,
+            publish_settings,
+            metrics.clone(),
+        )
+        .await,
+        session_manager: SessionManager::start(runtime.clone()).await,
+        authenticator: RwLock::new(authenticator),
+        metrics,
+    })
 }
 
 async fn register_sources_with_context(
     config: &AppConfig,
-    context: &mut AppContext,
-) -> Result<(), Box<dyn Error>> {
+    context: &AppContext,
+) -> Result<BTreeMap<SourcePath, Item>, Box<dyn Error>> {
     tracing::trace!("registering sources");
+    let mut media = BTreeMap::new();
     for item in config.media.iter() {
         tracing::info!(%item, "registering source");
-        context
-            .source_manager
-            .register_and_start(
-                item.name.as_str(),
-                item.path.clone(),
-                item.as_media_descriptor()?,
-            )
-            .await?;
+        context.register_source(item).await?;
+        media.insert(source::normalize_path(item.path.clone()), item.clone());
     }
     tracing::trace!("registered sources");
-    Ok(())
+    Ok(media)
+}
+
+/// What changed about a media item between two versions of the config
+/// file.
+#[derive(Debug, PartialEq)]
+enum MediaChange<'a> {
+    Added(&'a Item),
+    /// The previous version of an item that is gone.
+    Removed(&'a Item),
+    Changed(&'a Item),
+    /// Only the credentials changed, the source can keep running.
+    AuthChanged(&'a Item),
+}
+
+/// Compare the media items of two versions of the config file, both by
+/// path. Items that did not change are left out.
+fn diff_media<'a>(
+    previous: &'a BTreeMap<SourcePath, Item>,
+    media: &'a BTreeMap<SourcePath, Item>,
+) -> Vec<MediaChange<'a>> {
+    let removed = previous
+        .iter()
+        .filter(|(path, _)| !media.contains_key(*path))
+        .map(|(_, item)| MediaChange::Removed(item));
+    let changed = media
+        .iter()
+        .filter_map(|(path, item)| match previous.get(path) {
+            Some(previous_item) if previous_item == item => None,
+            Some(previous_item) if only_auth_differs(previous_item, item) => {
+                Some(MediaChange::AuthChanged(item))
+            }
+            Some(_) => Some(MediaChange::Changed(item)),
+            None => Some(MediaChange::Added(item)),
+        });
+    removed.chain(changed).collect()
 }
 
+fn only_auth_differs(a: &Item, b: &Item) -> bool {
+    Item {
+        auth: None,
+        ..a.clone()
+    } == Item {
+        auth: None,
+        ..b.clone()
+    }
+}
+
+/// Shared by everything that serves clients. The authenticator has a lock
+/// of its own, so that the context itself only needs to be locked for
+/// writing when the server stops. Starting and stopping sources can take a
+/// while, and clients are served in the meantime.
 pub struct AppContext {
     source_manager: SourceManager,
     session_manager: SessionManager,
+    authenticator: RwLock<Authenticator>,
+    metrics: Arc<Metrics>,
+}
+
+impl AppContext {
+    /// Register and start the source of a media item, and require clients
+    /// to authenticate for it if the item has credentials.
+    async fn register_source(&self, item: &Item) -> Result<(), Box<dyn Error>> {
+        self.start_source(
+            item.name.as_str(),
+            source::normalize_path(item.path.clone()),
+            item.as_media_descriptor()?,
+            item.as_multicast_group()?,
+            item.as_record_settings()?,
+            item.auth.as_ref(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Register and start a source, with `auth` as the users that clients
+    /// must authenticate as. The users are in place before the source
+    /// starts, so that no client can get to it without them.
+    async fn start_source(
+        &self,
+        name: &str,
+        path: SourcePath,
+        descriptor: MediaDescriptor,
+        multicast: Option<MulticastGroup>,
+        record: Option<RecordSettings>,
+        auth: Option<&Auth>,
+    ) -> Result<(), RegisterSourceError> {
+        let previous = self.authenticator.write().await.replace(&path, auth);
+        let result = self
+            .source_manager
+            .register_and_start(name, path.clone(), descriptor, multicast, record)
+            .await;
+        if result.is_err() {
+            // There may be a source at the path already, which keeps its
+            // users.
+            self.authenticator.write().await.restore(&path, previous);
+        }
+        result
+    }
+
+    /// Tear down the sessions of the source at `path`, then stop and remove
+    /// the source. Returns `false` if there is no such source.
+    async fn unregister_source(&self, path: &SourcePathRef) -> bool {
+        let sessions = self.session_manager.teardown_source(path).await;
+        if sessions > 0 {
+            tracing::info!(%path, sessions, "tore down sessions of source");
+        }
+        let unregistered = self.source_manager.unregister(path).await;
+        self.authenticator.write().await.unregister(path);
+        unregistered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::app::config::{AuthScheme, MediaKind, User};
+
+    fn item(path: &str, source: &str, password: Option<&str>) -> Item {
+        Item {
+            name: path.to_string(),
+            path: path.to_string(),
+            kind: MediaKind::Stream,
+            source: source.to_string(),
+            multicast: None,
+            auth: password.map(|password| Auth {
+                scheme: AuthScheme::Digest,
+                users: vec![User {
+                    username: "viewer".to_string(),
+                    password: password.to_string(),
+                }],
+            }),
+            record: None,
+        }
+    }
+
+    fn media(items: &[Item]) -> BTreeMap<SourcePath, Item> {
+        items
+            .iter()
+            .map(|item| (source::normalize_path(item.path.clone()), item.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn diff_media_added_removed_changed() {
+        let previous = media(&[
+            item("/a", "rtsp://10.0.0.1/a", None),
+            item("/b", "rtsp://10.0.0.1/b", None),
+            item("/c", "rtsp://10.0.0.1/c", None),
+        ]);
+        let current = media(&[
+            item("/b", "rtsp://10.0.0.1/b", None),
+            item("/c", "rtsp://10.0.0.2/c", None),
+            item("/d", "rtsp://10.0.0.1/d", None),
+        ]);
+        assert_eq!(
+            diff_media(&previous, &current),
+            vec![
+                MediaChange::Removed(&previous["/a"]),
+                MediaChange::Changed(&current["/c"]),
+                MediaChange::Added(&current["/d"]),
+            ],
+        );
+    }
+
+    #[test]
+    fn diff_media_auth_only() {
+        let previous = media(&[
+            item("/a", "rtsp://10.0.0.1/a", None),
+            item("/b", "rtsp://10.0.0.1/b", Some("old")),
+            item("/c", "rtsp://10.0.0.1/c", Some("old")),
+        ]);
+        let current = media(&[
+            item("/a", "rtsp://10.0.0.1/a", Some("new")),
+            item("/b", "rtsp://10.0.0.1/b", Some("new")),
+            item("/c", "rtsp://10.0.0.2/c", Some("new")),
+        ]);
+        assert_eq!(
+            diff_media(&previous, &current),
+            vec![
+                MediaChange::AuthChanged(&current["/a"]),
+                MediaChange::AuthChanged(&current["/b"]),
+                MediaChange::Changed(&current["/c"]),
+            ],
+        );
+        assert!(diff_media(&current, &current).is_empty());
+    }
 }
@@ -0,0 +1,327 @@
+//! WHEP (WebRTC-HTTP Egress Protocol) server, for browsers that play
+//! sources over WebRTC. A browser posts its offer to `/whep/<path>`, and
+//! gets the answer and the URL of its session back. Deleting the session
+//! URL ends the session. Sources with users configured require the same
+//! credentials as they do over RTSP.
+
+use std::collections::HashMap;
+use std::future::IntoFuture;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, post};
+use axum::Router;
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+use tokio::net;
+use tokio::select;
+use tokio::sync::{mpsc, Mutex, RwLock};
+
+use crate::app::AppContext;
+use crate::media::sdp::SdpError;
+use crate::net::webrtc::{DtlsIdentity, IceCredentials};
+use crate::runtime::task_manager::{Task, TaskContext};
+use crate::runtime::Runtime;
+use crate::session::whep::{self, WhepSession, WhepSessionEndedRx, WhepSessionId};
+use crate::source;
+
+const SESSIONS_PATH: &str = "/whep-sessions";
+
+type WhepSessionMap = Arc<Mutex<HashMap<WhepSessionId, WhepSession>>>;
+
+pub struct WhepServer {
+    sessions: WhepSessionMap,
+    worker: Task,
+}
+
+#[derive(Clone)]
+struct WhepState {
+    context: Arc<RwLock<AppContext>>,
+    sessions: WhepSessionMap,
+    ended_tx: whep::WhepSessionEndedTx,
+    /// Address that browsers send media packets to. Every session gets its
+    /// own port.
+    candidate: IpAddr,
+    /// Address to receive media packets on.
+    media_host: IpAddr,
+    identity: Arc<DtlsIdentity>,
+    runtime: Arc<Runtime>,
+}
+
+impl WhepServer {
+    pub async fn start(
+        host: IpAddr,
+        port: u16,
+        candidate: IpAddr,
+        context: Arc<RwLock<AppContext>>,
+        runtime: Arc<Runtime>,
+    ) -> Result<Self, io::Error> {
+        tracing::trace!(%host, port, "starting whep server");
+        let identity = DtlsIdentity::generate().map_err(|err| io::Error::other(err.to_string()))?;
+        let listener = match net::TcpListener::bind((host, port)).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                tracing::error!(%err, %host, port, "failed to listen for whep requests");
+                return Err(err);
+            }
+        };
+        tracing::info!(%host, port, %candidate, "whep server listening for requests");
+
+        let sessions = WhepSessionMap::default();
+        let (ended_tx, ended_rx) = mpsc::unbounded_channel();
+        let router = Router::new()
+            .route("/whep/*path", post(offer).options(preflight))
+            .route(
+                &format!("{SESSIONS_PATH}/:id"),
+                delete(end_session).patch(patch_session).options(preflight),
+            )
+            .with_state(WhepState {
+                context,
+                sessions: sessions.clone(),
+                ended_tx,
+                candidate,
+                media_host: host,
+                identity: Arc::new(identity),
+                runtime: runtime.clone(),
+            });
+
+        let worker = runtime
+            .task()
+            .spawn({
+                let sessions = sessions.clone();
+                move |task_context| Self::run(listener, router, sessions, ended_rx, task_context)
+            })
+            .await;
+        tracing::trace!(%host, port, "started whep server");
+
+        Ok(Self { sessions, worker })
+    }
+
+    pub async fn stop(&mut self) {
+        tracing::trace!("sending stop signal to whep server");
+        self.worker.stop().await;
+        for (_, mut session) in self.sessions.lock().await.drain() {
+            session.stop().await;
+        }
+        tracing::trace!("whep server stopped");
+    }
+
+    async fn run(
+        listener: net::TcpListener,
+        router: Router,
+        sessions: WhepSessionMap,
+        mut ended_rx: WhepSessionEndedRx,
+        mut task_context: TaskContext,
+    ) {
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = axum::serve(listener, router)
+            .with_graceful_shutdown(async move {
+                let _ = stop_rx.await;
+            })
+            .into_future();
+        tokio::pin!(server);
+
+        loop {
+            select! {
+                result = &mut server => {
+                    if let Err(err) = result {
+                        tracing::error!(%err, "whep server failed");
+                    }
+                    break;
+                },
+                // CANCEL SAFETY: `mpsc::UnboundedReceiver::recv` is cancel safe.
+                Some(id) = ended_rx.recv() => {
+                    // Sessions that end on their own (because the browser is
+                    // gone) are forgotten right away.
+                    if sessions.lock().await.remove(&id).is_some() {
+                        tracing::trace!(%id, "whep session ended");
+                    }
+                },
+                // CANCEL SAFETY: `TaskContext::wait_for_stop` is cancel safe.
+                _ = task_context.wait_for_stop() => {
+                    let _ = stop_tx.send(());
+                    if let Err(err) = server.await {
+                        tracing::error!(%err, "whep server failed");
+                    }
+                    break;
+                },
+            }
+        }
+    }
+}
+
+async fn offer(
+    State(state): State<WhepState>,
+    Path(path): Path<String>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(str::trim);
+    if content_type != Some("application/sdp") {
+        return with_cors(StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response());
+    }
+    let path = source::normalize_path(path);
+
+    let (description, source_delegate) = {
+        let context = state.context.read().await;
+        let authorization = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .map(str::parse);
+        if let Err(challenge) =
+            context
+                .authenticator
+                .read()
+                .await
+                .authorize_path(&path, authorization, "POST", &uri)
+        {
+            tracing::debug!(%path, "whep client not authorized");
+            return with_cors(
+                (
+                    StatusCode::UNAUTHORIZED,
+                    [(header::WWW_AUTHENTICATE, challenge.to_string())],
+                )
+                    .into_response(),
+            );
+        }
+        let Some(source_delegate) = context.source_manager.subscribe(&path).await else {
+            return with_cors(StatusCode::NOT_FOUND.into_response());
+        };
+        // Media played on demand has no shared stream to forward.
+        if source_delegate.vod().is_some() {
+            return with_cors(StatusCode::NOT_FOUND.into_response());
+        }
+        match context.source_manager.describe(&path).await {
+            Some(Ok(description)) => (description, source_delegate),
+            Some(Err(SdpError::MediaInfoUnavailable)) => {
+                return with_cors(StatusCode::SERVICE_UNAVAILABLE.into_response())
+            }
+            Some(Err(err)) => {
+                tracing::error!(%path, %err, "failed to describe source for whep");
+                return with_cors(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+            }
+            None => return with_cors(StatusCode::NOT_FOUND.into_response()),
+        }
+    };
+
+    let socket = match net::UdpSocket::bind((state.media_host, 0)).await {
+        Ok(socket) => socket,
+        Err(err) => {
+            tracing::error!(%err, "failed to bind whep media socket");
+            return with_cors(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+    let candidate = match socket.local_addr() {
+        Ok(local_addr) => SocketAddr::new(state.candidate, local_addr.port()),
+        Err(err) => {
+            tracing::error!(%err, "failed to bind whep media socket");
+            return with_cors(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+    let local = IceCredentials::generate();
+    let negotiated = match whep::negotiate(
+        &body,
+        &description,
+        &local,
+        state.identity.fingerprint(),
+        candidate,
+    ) {
+        Ok(negotiated) => negotiated,
+        Err(err) => {
+            tracing::debug!(%path, %err, "failed to negotiate with whep client");
+            return with_cors((StatusCode::NOT_ACCEPTABLE, err.to_string()).into_response());
+        }
+    };
+
+    let id: WhepSessionId = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(24)
+        .map(char::from)
+        .collect();
+    let session = WhepSession::start(
+        id.clone(),
+        path.clone(),
+        &negotiated,
+        source_delegate,
+        socket,
+        local,
+        state.identity.clone(),
+        state.ended_tx.clone(),
+        state.runtime.clone(),
+    )
+    .await;
+    state.sessions.lock().await.insert(id.clone(), session);
+    tracing::info!(%id, %path, %candidate, "whep session created");
+
+    with_cors(
+        (
+            StatusCode::CREATED,
+            [
+                (header::CONTENT_TYPE, "application/sdp".to_string()),
+                (header::LOCATION, format!("{SESSIONS_PATH}/{id}")),
+            ],
+            negotiated.answer.to_string(),
+        )
+            .into_response(),
+    )
+}
+
+async fn end_session(State(state): State<WhepState>, Path(id): Path<String>) -> Response {
+    let session = state.sessions.lock().await.remove(&id);
+    match session {
+        Some(mut session) => {
+            session.stop().await;
+            tracing::info!(%id, "whep session deleted");
+            with_cors(StatusCode::OK.into_response())
+        }
+        None => with_cors(StatusCode::NOT_FOUND.into_response()),
+    }
+}
+
+/// The server is ICE-lite and lists its only candidate in the answer, and
+/// it learns the address of the browser from its connectivity checks. So
+/// there is nothing to trickle, and ICE restarts are not supported.
+async fn patch_session() -> Response {
+    with_cors(StatusCode::METHOD_NOT_ALLOWED.into_response())
+}
+
+/// Browsers ask before posting an offer to another origin.
+async fn preflight() -> Response {
+    let mut response = StatusCode::NO_CONTENT.into_response();
+    let headers = response.headers_mut();
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_METHODS,
+        HeaderValue::from_static("POST, DELETE, OPTIONS"),
+    );
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_HEADERS,
+        HeaderValue::from_static("Authorization, Content-Type"),
+    );
+    with_cors(response)
+}
+
+/// Players on web pages are usually served from another origin, and need to
+/// read the session URL.
+fn with_cors(mut response: Response) -> Response {
+    let headers = response.headers_mut();
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_ORIGIN,
+        HeaderValue::from_static("*"),
+    );
+    headers.insert(
+        header::ACCESS_CONTROL_EXPOSE_HEADERS,
+        HeaderValue::from_static("Location"),
+    );
+    response
+}
@@ -1,5 +1,6 @@
 mod app;
 mod media;
+mod metrics;
 mod net;
 mod runtime;
 mod session;
@@ -7,7 +8,7 @@ mod source;
 
 use std::env;
 use std::error::Error;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process;
 
 use config::ConfigError;
@@ -36,15 +37,15 @@ async fn main() {
     on_error_exit!(initialize_tracing());
     on_error_exit!(initialize_media());
 
-    let config = on_error_exit!(initialize_and_read_config());
+    let config_file = config_file();
+    let config = on_error_exit!(read_config(&config_file));
     tracing::debug!(?config, "loaded config file");
 
     tracing::trace!("starting app");
     let mut app = on_error_exit!(App::start(config).await);
     tracing::trace!("started app");
 
-    tracing::trace!("waiting for ctrl+C...");
-    on_error_exit!(ctrl_c().await);
+    on_error_exit!(run_until_stopped(&mut app, &config_file).await);
 
     tracing::trace!("stopping app");
     app.stop().await;
@@ -61,9 +62,48 @@ fn initialize_media() -> Result<(), Box<dyn Error>> {
     video::init()
 }
 
-fn initialize_and_read_config() -> Result<AppConfig, ConfigError> {
-    let config_file = env::args().nth(1).unwrap_or("default.yaml".to_string());
-    let config_file = Path::new(&config_file);
+/// Wait for ctrl+C. On SIGHUP, the config file is read again and the
+/// media in it applied to the running app.
+#[cfg(unix)]
+async fn run_until_stopped(app: &mut App, config_file: &Path) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    loop {
+        tracing::trace!("waiting for ctrl+C or SIGHUP...");
+        tokio::select! {
+            // CANCEL SAFETY: `ctrl_c` is cancel safe.
+            result = ctrl_c() => {
+                return result;
+            },
+            // CANCEL SAFETY: `Signal::recv` is cancel safe.
+            _ = hangup.recv() => {
+                tracing::info!(config_file=%config_file.display(), "received SIGHUP, reloading config");
+                match read_config(config_file) {
+                    Ok(config) => {
+                        tracing::debug!(?config, "reloaded config file");
+                        app.reload(config).await;
+                    }
+                    Err(err) => {
+                        tracing::error!(%err, "failed to reload config, keeping current config");
+                    }
+                }
+            },
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn run_until_stopped(_app: &mut App, _config_file: &Path) -> std::io::Result<()> {
+    tracing::trace!("waiting for ctrl+C...");
+    ctrl_c().await
+}
+
+fn config_file() -> PathBuf {
+    PathBuf::from(env::args().nth(1).unwrap_or("default.yaml".to_string()))
+}
+
+fn read_config(config_file: &Path) -> Result<AppConfig, ConfigError> {
     tracing::trace!(config_file=%config_file.display(), "loading config");
 
     AppConfig::from_file(config_file)
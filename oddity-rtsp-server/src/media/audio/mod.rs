@@ -0,0 +1 @@
+pub mod rtp_audio;
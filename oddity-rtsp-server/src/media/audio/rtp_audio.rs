@@ -0,0 +1,235 @@
+//! Pure Rust RTP packetizer for audio. Supports AAC in the `AAC-hbr` mode
+//! of RFC 3640, Opus (RFC 7587) and G.711 (RFC 3551).
+
+use crate::media::video::rtp_h264::DEFAULT_MTU;
+
+/// Size of the fixed RTP header (RFC 3550 Section 5.1).
+const RTP_HEADER_LEN: usize = 12;
+
+/// RTP version 2, no padding, no extension, no CSRCs.
+const RTP_VERSION: u8 = 0x80;
+
+/// Size of the AU-headers-length field in bits, for a single AU header
+/// with a 13-bit size and a 3-bit index (RFC 3640 Section 3.2.1).
+const AAC_AU_HEADERS_LEN_BITS: u16 = 16;
+
+/// Size of the AU-headers-length field and a single AU header in bytes.
+const AAC_AU_HEADERS_LEN: usize = 4;
+
+/// Largest AU that fits in the 13-bit size of an AU header.
+const AAC_MAX_AU_SIZE: usize = (1 << 13) - 1;
+
+/// Payload format of the audio that is packetized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioPayloadFormat {
+    /// Raw AAC frames, or AAC frames with an ADTS header, which is
+    /// stripped.
+    Aac,
+    Opus,
+    /// Interleaved G.711 samples, one byte per sample per channel.
+    G711 {
+        channels: usize,
+    },
+}
+
+/// Packetizes audio frames into RTP packets.
+pub struct AudioPacketizer {
+    format: AudioPayloadFormat,
+    payload_type: u8,
+    ssrc: u32,
+    seq: u16,
+    mtu: usize,
+}
+
+impl AudioPacketizer {
+    pub fn new(format: AudioPayloadFormat, payload_type: u8, ssrc: u32, initial_seq: u16) -> Self {
+        Self {
+            format,
+            payload_type,
+            ssrc,
+            seq: initial_seq,
+            mtu: DEFAULT_MTU,
+        }
+    }
+
+    /// Set the maximum size of RTP packets, including the RTP header.
+    pub fn with_mtu(mut self, mtu: usize) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    /// Sequence number of the next packet.
+    #[inline]
+    pub fn seq(&self) -> u16 {
+        self.seq
+    }
+
+    /// Packetize a single audio frame. AAC frames that do not fit in a
+    /// single packet are fragmented, and the marker bit is set on the last
+    /// fragment. G.711 is split into multiple packets with increasing
+    /// timestamps instead. Opus frames are always sent as-is.
+    pub fn packetize(&mut self, frame: &[u8], timestamp: u32) -> Vec<Vec<u8>> {
+        let max_payload_len = self.mtu.saturating_sub(RTP_HEADER_LEN);
+        match self.format {
+            AudioPayloadFormat::Aac => self.packetize_aac(frame, timestamp, max_payload_len),
+            AudioPayloadFormat::Opus => vec![self.make_packet(frame, timestamp, false)],
+            AudioPayloadFormat::G711 { channels } => {
+                let bytes_per_sample = channels.max(1);
+                let max_payload_len =
+                    (max_payload_len / bytes_per_sample).max(1) * bytes_per_sample;
+                let mut timestamp = timestamp;
+                frame
+                    .chunks(max_payload_len)
+                    .map(|samples| {
+                        let packet = self.make_packet(samples, timestamp, false);
+                        timestamp =
+                            timestamp.wrapping_add((samples.len() / bytes_per_sample) as u32);
+                        packet
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    fn packetize_aac(
+        &mut self,
+        frame: &[u8],
+        timestamp: u32,
+        max_payload_len: usize,
+    ) -> Vec<Vec<u8>> {
+        let au = strip_adts(frame);
+        if au.is_empty() || au.len() > AAC_MAX_AU_SIZE {
+            return Vec::new();
+        }
+
+        // Every fragment carries the size of the whole AU, and the index is
+        // always zero since there is only one AU per packet (RFC 3640
+        // Section 3.2.3).
+        let au_header = ((au.len() as u16) << 3).to_be_bytes();
+        let max_fragment_len = max_payload_len.saturating_sub(AAC_AU_HEADERS_LEN).max(1);
+        let num_fragments = au.len().div_ceil(max_fragment_len);
+        au.chunks(max_fragment_len)
+            .enumerate()
+            .map(|(i, fragment)| {
+                let mut payload = Vec::with_capacity(AAC_AU_HEADERS_LEN + fragment.len());
+                payload.extend_from_slice(&AAC_AU_HEADERS_LEN_BITS.to_be_bytes());
+                payload.extend_from_slice(&au_header);
+                payload.extend_from_slice(fragment);
+                self.make_packet(&payload, timestamp, i + 1 == num_fragments)
+            })
+            .collect()
+    }
+
+    fn make_packet(&mut self, payload: &[u8], timestamp: u32, marker: bool) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(RTP_HEADER_LEN + payload.len());
+        packet.push(RTP_VERSION);
+        packet.push(((marker as u8) << 7) | (self.payload_type & 0x7f));
+        packet.extend_from_slice(&self.seq.to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        packet.extend_from_slice(payload);
+        self.seq = self.seq.wrapping_add(1);
+        packet
+    }
+}
+
+/// Strip the ADTS header of an AAC frame if it has one (ISO/IEC 14496-3
+/// Section 1.A.2.2). The header is 7 bytes, or 9 bytes with a CRC.
+pub fn strip_adts(frame: &[u8]) -> &[u8] {
+    if frame.len() >= 7 && frame[0] == 0xff && frame[1] & 0xf6 == 0xf0 {
+        let protection_absent = frame[1] & 0x01 != 0;
+        let header_len = if protection_absent { 7 } else { 9 };
+        frame.get(header_len..).unwrap_or_default()
+    } else {
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AudioPacketizer, AudioPayloadFormat, RTP_HEADER_LEN};
+
+    fn marker(packet: &[u8]) -> bool {
+        packet[1] & 0x80 != 0
+    }
+
+    fn timestamp(packet: &[u8]) -> u32 {
+        u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]])
+    }
+
+    #[test]
+    fn aac_single_packet() {
+        let mut packetizer = AudioPacketizer::new(AudioPayloadFormat::Aac, 96, 1, 0);
+        let packets = packetizer.packetize(&[0x21, 0x10, 0x05], 1024);
+        assert_eq!(packets.len(), 1);
+        assert!(marker(&packets[0]));
+        assert_eq!(timestamp(&packets[0]), 1024);
+        assert_eq!(
+            &packets[0][RTP_HEADER_LEN..],
+            &[0x00, 0x10, 0x00, 0x18, 0x21, 0x10, 0x05],
+        );
+    }
+
+    #[test]
+    fn aac_strips_adts_header() {
+        let mut packetizer = AudioPacketizer::new(AudioPayloadFormat::Aac, 96, 1, 0);
+        let frame = [0xff, 0xf1, 0x50, 0x80, 0x01, 0x3f, 0xfc, 0x21, 0x10];
+        let packets = packetizer.packetize(&frame, 0);
+        assert_eq!(
+            &packets[0][RTP_HEADER_LEN..],
+            &[0x00, 0x10, 0x00, 0x10, 0x21, 0x10],
+        );
+    }
+
+    #[test]
+    fn aac_fragmented() {
+        let mut packetizer =
+            AudioPacketizer::new(AudioPayloadFormat::Aac, 96, 1, 0).with_mtu(RTP_HEADER_LEN + 14);
+        let frame = (0..25).collect::<Vec<u8>>();
+        let packets = packetizer.packetize(&frame, 0);
+        assert_eq!(packets.len(), 3);
+        assert_eq!(
+            packets
+                .iter()
+                .map(|packet| marker(packet))
+                .collect::<Vec<_>>(),
+            vec![false, false, true],
+        );
+        // Every fragment has the size of the whole AU in its AU header.
+        for packet in &packets {
+            assert_eq!(
+                &packet[RTP_HEADER_LEN..RTP_HEADER_LEN + 4],
+                &[0x00, 0x10, 0x00, 0xc8]
+            );
+        }
+        let reassembled = packets
+            .iter()
+            .flat_map(|packet| packet[RTP_HEADER_LEN + 4..].to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(reassembled, frame);
+    }
+
+    #[test]
+    fn g711_split_with_increasing_timestamps() {
+        let mut packetizer =
+            AudioPacketizer::new(AudioPayloadFormat::G711 { channels: 1 }, 0, 1, 0)
+                .with_mtu(RTP_HEADER_LEN + 160);
+        let packets = packetizer.packetize(&[0xd5; 400], 8000);
+        assert_eq!(
+            packets
+                .iter()
+                .map(|packet| (packet.len() - RTP_HEADER_LEN, timestamp(packet)))
+                .collect::<Vec<_>>(),
+            vec![(160, 8000), (160, 8160), (80, 8320)],
+        );
+    }
+
+    #[test]
+    fn opus_as_is() {
+        let mut packetizer = AudioPacketizer::new(AudioPayloadFormat::Opus, 96, 1, 7);
+        let packets = packetizer.packetize(&[0xfc, 0xff, 0xfe], 960);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(&packets[0][RTP_HEADER_LEN..], &[0xfc, 0xff, 0xfe]);
+        assert_eq!(packetizer.seq(), 8);
+    }
+}
@@ -1,27 +1,55 @@
+pub mod audio;
+pub mod mp4;
+pub mod rtcp;
 pub mod sdp;
 pub mod video;
 
-use video_rs::stream::StreamInfo;
-
 pub use video_rs::Packet;
 
 use std::fmt;
 use std::path::PathBuf;
 
+use video_rs::ffmpeg::codec::{Id as CodecId, Parameters};
+use video_rs::ffmpeg::media::Type as MediaType;
 use video_rs::{Error, Location, Reader, Url};
 
+use oddity_sdp_protocol::{CodecInfo, G711Law, FMT_RTP_PAYLOAD_DYNAMIC};
+
+use crate::media::video::rtp_h264::ParameterSets;
+
 type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Clone)]
 pub enum MediaDescriptor {
     Stream(Url),
     File(PathBuf),
+    /// File that every session plays on its own, from start to end, so
+    /// that clients can seek in it.
+    Vod(PathBuf),
+    /// Media is pushed to the server by a client with ANNOUNCE and RECORD
+    /// instead of being pulled by the server.
+    Publish,
+}
+
+impl MediaDescriptor {
+    /// Location to read the media from. Published media is not read by
+    /// the server, so it does not have a location.
+    pub fn location(&self) -> Option<Location> {
+        match self {
+            MediaDescriptor::File(path) | MediaDescriptor::Vod(path) => {
+                Some(Location::File(path.clone()))
+            }
+            MediaDescriptor::Stream(url) => Some(Location::Network(url.clone())),
+            MediaDescriptor::Publish => None,
+        }
+    }
 }
 
 impl fmt::Display for MediaDescriptor {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             MediaDescriptor::File(path) => write!(f, "file: {}", path.display()),
+            MediaDescriptor::Vod(path) => write!(f, "vod: {}", path.display()),
             MediaDescriptor::Stream(url) => {
                 write!(f, "stream: {}", {
                     let mut url_safe = url.clone();
@@ -29,6 +57,7 @@ impl fmt::Display for MediaDescriptor {
                     url_safe
                 })
             }
+            MediaDescriptor::Publish => write!(f, "publish"),
         }
     }
 }
@@ -41,29 +70,189 @@ impl fmt::Debug for MediaDescriptor {
     }
 }
 
-impl From<MediaDescriptor> for Location {
-    fn from(descriptor: MediaDescriptor) -> Self {
-        match descriptor {
-            MediaDescriptor::File(path) => Location::File(path),
-            MediaDescriptor::Stream(url) => Location::Network(url),
-        }
-    }
-}
-
 #[derive(Clone)]
 pub struct MediaInfo {
     pub streams: Vec<StreamInfo>,
+    /// Duration in seconds of media that is played on demand. This is
+    /// `None` for live media, including files that are played on repeat.
+    pub duration: Option<f64>,
 }
 
 impl MediaInfo {
-    pub fn from_reader_best_video_stream(reader: &Reader) -> Result<Self> {
+    /// Describe the streams of a reader that are delivered to clients: the
+    /// best video stream, and every audio stream with a supported codec.
+    /// Any other streams are skipped.
+    pub fn from_reader(reader: &Reader) -> Result<Self> {
         let best_video_stream_index = reader.best_video_stream_index()?;
+        let mut streams = vec![StreamInfo::from_stream_info(
+            reader.stream_info(best_video_stream_index)?,
+        )?];
+
+        let audio_stream_indices = reader
+            .input
+            .streams()
+            .filter(|stream| stream.parameters().medium() == MediaType::Audio)
+            .map(|stream| stream.index())
+            .collect::<Vec<_>>();
+        for index in audio_stream_indices {
+            match StreamInfo::from_stream_info(reader.stream_info(index)?) {
+                Ok(stream_info) => streams.push(stream_info),
+                Err(err) => tracing::debug!(index, %err, "skipping unsupported audio stream"),
+            }
+        }
+
         Ok(Self {
-            streams: vec![reader.stream_info(best_video_stream_index)?],
+            streams,
+            duration: None,
         })
     }
 }
 
+/// Describes a single stream of a source. This does not depend on the
+/// backend, so that media that is not read through `video_rs` (such as
+/// published media) can be described as well.
+#[derive(Debug, Clone)]
+pub struct StreamInfo {
+    /// Index of the stream in the packets of the source.
+    pub index: usize,
+    pub codec: Codec,
+}
+
+impl StreamInfo {
+    /// Describe a stream read through `video_rs`. Supported codecs are
+    /// H.264, AAC, Opus and G.711.
+    pub fn from_stream_info(stream_info: video_rs::stream::StreamInfo) -> Result<Self> {
+        let (index, codec_parameters, _) = stream_info.into_parts();
+        let codec = match codec_parameters.id() {
+            CodecId::H264 => Codec::H264(
+                ParameterSets::from_extradata(&extradata(&codec_parameters)?)
+                    .ok_or(Error::InvalidExtraData)?,
+            ),
+            CodecId::AAC => {
+                let (sample_rate, channels) = audio_format(&codec_parameters);
+                Codec::Aac {
+                    sample_rate,
+                    channels,
+                    config: extradata(&codec_parameters)?,
+                }
+            }
+            CodecId::OPUS => Codec::Opus {
+                channels: audio_format(&codec_parameters).1,
+            },
+            id @ (CodecId::PCM_MULAW | CodecId::PCM_ALAW) => {
+                let (sample_rate, channels) = audio_format(&codec_parameters);
+                Codec::G711 {
+                    law: if id == CodecId::PCM_MULAW {
+                        G711Law::MuLaw
+                    } else {
+                        G711Law::ALaw
+                    },
+                    sample_rate,
+                    channels,
+                }
+            }
+            _ => return Err(Error::UnsupportedCodecParameterSets),
+        };
+        Ok(Self { index, codec })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Codec {
+    H264(ParameterSets),
+    /// AAC with its `AudioSpecificConfig` (ISO/IEC 14496-3 Section 1.6.2.1).
+    Aac {
+        sample_rate: u32,
+        channels: u32,
+        config: Vec<u8>,
+    },
+    Opus {
+        channels: u32,
+    },
+    G711 {
+        law: G711Law,
+        sample_rate: u32,
+        channels: u32,
+    },
+}
+
+impl Codec {
+    /// RTP clock rate of the codec. Video is always 90 kHz (RFC 6184
+    /// Section 8.2.1) and Opus is always 48 kHz (RFC 7587 Section 4.1).
+    /// Other audio uses its sample rate.
+    pub fn clock_rate(&self) -> u32 {
+        match self {
+            Codec::H264(_) => 90_000,
+            Codec::Opus { .. } => 48_000,
+            Codec::Aac { sample_rate, .. } | Codec::G711 { sample_rate, .. } => *sample_rate,
+        }
+    }
+
+    /// Short name of the codec, for reporting.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::H264(_) => "h264",
+            Codec::Aac { .. } => "aac",
+            Codec::Opus { .. } => "opus",
+            Codec::G711 {
+                law: G711Law::MuLaw,
+                ..
+            } => "pcmu",
+            Codec::G711 {
+                law: G711Law::ALaw, ..
+            } => "pcma",
+        }
+    }
+
+    #[inline]
+    pub fn is_video(&self) -> bool {
+        matches!(self, Codec::H264(_))
+    }
+
+    /// RTP payload type of the codec, as signaled in the SDP.
+    pub fn payload_type(&self) -> u8 {
+        match self {
+            Codec::G711 {
+                law,
+                sample_rate,
+                channels,
+            } => CodecInfo::g711(*law, *sample_rate, *channels).payload_type() as u8,
+            _ => FMT_RTP_PAYLOAD_DYNAMIC as u8,
+        }
+    }
+}
+
+/// Copy the codec extradata of a stream.
+fn extradata(codec_parameters: &Parameters) -> Result<Vec<u8>> {
+    // SAFETY: The codec parameters are borrowed for the duration of this
+    // function, and ffmpeg guarantees that `extradata` points to at least
+    // `extradata_size` bytes if it is not null.
+    unsafe {
+        let codec_parameters = codec_parameters.as_ptr();
+        if (*codec_parameters).extradata.is_null() || (*codec_parameters).extradata_size <= 0 {
+            return Err(Error::MissingCodecParameters);
+        }
+        Ok(std::slice::from_raw_parts(
+            (*codec_parameters).extradata,
+            (*codec_parameters).extradata_size as usize,
+        )
+        .to_vec())
+    }
+}
+
+/// Sample rate and number of channels of an audio stream.
+fn audio_format(codec_parameters: &Parameters) -> (u32, u32) {
+    // SAFETY: The codec parameters are borrowed for the duration of this
+    // function and we only read plain fields.
+    unsafe {
+        let codec_parameters = codec_parameters.as_ptr();
+        (
+            (*codec_parameters).sample_rate.max(0) as u32,
+            (*codec_parameters).ch_layout.nb_channels.max(1) as u32,
+        )
+    }
+}
+
 #[derive(Clone, Default)]
 pub struct StreamState {
     pub rtp_seq: u16,
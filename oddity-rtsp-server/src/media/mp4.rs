@@ -0,0 +1,733 @@
+//! Pure Rust fragmented MP4 (ISO/IEC 14496-12) writer. A fragmented MP4
+//! file starts with an initialization segment (`ftyp` and `moov`) that
+//! describes the tracks, followed by any number of fragments (`moof` and
+//! `mdat`) that hold the samples. Supports H.264 (ISO/IEC 14496-15), AAC
+//! (ISO/IEC 14496-14) and Opus ("Encapsulation of Opus in ISO Base Media
+//! File Format").
+
+use crate::media::audio::rtp_audio::strip_adts;
+use crate::media::video::rtp_h264::{split_nal_units, ParameterSets};
+use crate::media::Codec;
+
+/// Timescale of the movie header. Durations are not known up front in a
+/// fragmented file, so this is only there to be valid.
+const MOVIE_TIMESCALE: u32 = 1000;
+
+/// Size of the NAL unit length prefix of H.264 samples.
+const NAL_LENGTH_SIZE: usize = 4;
+
+/// Sample flags of a sync sample: does not depend on other samples.
+const SAMPLE_FLAGS_SYNC: u32 = 0x0200_0000;
+
+/// Sample flags of a non-sync sample: depends on other samples.
+const SAMPLE_FLAGS_NON_SYNC: u32 = 0x0101_0000;
+
+/// Unity transformation matrix of track and movie headers.
+const MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+/// Track of a fragmented MP4 file.
+#[derive(Debug, Clone)]
+pub struct Mp4Track {
+    codec: Codec,
+    /// Timescale of the track, which is the RTP clock rate of its codec.
+    pub timescale: u32,
+    width: u16,
+    height: u16,
+}
+
+impl Mp4Track {
+    /// Returns `None` for codecs that cannot be stored in MP4 (G.711).
+    pub fn new(codec: &Codec) -> Option<Self> {
+        let (width, height) = match codec {
+            Codec::H264(parameter_sets) => sps_dimensions(&parameter_sets.sps).unwrap_or((0, 0)),
+            Codec::Aac { .. } | Codec::Opus { .. } => (0, 0),
+            Codec::G711 { .. } => return None,
+        };
+        Some(Self {
+            codec: codec.clone(),
+            timescale: codec.clock_rate(),
+            width,
+            height,
+        })
+    }
+
+    pub fn is_video(&self) -> bool {
+        self.codec.is_video()
+    }
+
+    /// Convert a packet of the codec to a sample: H.264 is stored with
+    /// length prefixed NAL units and AAC without ADTS header.
+    pub fn sample_data(&self, data: &[u8]) -> Vec<u8> {
+        match &self.codec {
+            Codec::H264(parameter_sets) => {
+                let nals = split_nal_units(data, parameter_sets.nal_length_size);
+                let mut sample = Vec::with_capacity(data.len() + nals.len() * NAL_LENGTH_SIZE);
+                for nal in nals {
+                    sample.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+                    sample.extend_from_slice(nal);
+                }
+                sample
+            }
+            Codec::Aac { .. } => strip_adts(data).to_vec(),
+            _ => data.to_vec(),
+        }
+    }
+}
+
+/// Sample of a track in a fragment.
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub data: Vec<u8>,
+    /// Duration in the timescale of the track.
+    pub duration: u32,
+    /// Presentation time minus decode time, in the timescale of the track.
+    pub composition_offset: i32,
+    pub is_sync: bool,
+}
+
+/// Samples of a single track in a fragment. Track numbers start at zero
+/// and follow the order of the tracks in the initialization segment.
+pub struct TrackFragment<'a> {
+    pub track: usize,
+    /// Decode time of the first sample, in the timescale of the track.
+    pub base_decode_time: u64,
+    pub samples: &'a [Sample],
+}
+
+/// Initialization segment (`ftyp` and `moov`) for the given tracks.
+pub fn init_segment(tracks: &[Mp4Track]) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_box(&mut out, b"ftyp", |out| {
+        out.extend_from_slice(b"isom");
+        out.extend_from_slice(&0x200_u32.to_be_bytes());
+        for brand in [b"isom", b"iso6", b"iso2", b"avc1", b"mp41"] {
+            out.extend_from_slice(brand);
+        }
+    });
+    write_box(&mut out, b"moov", |out| {
+        write_full_box(out, b"mvhd", 0, 0, |out| {
+            put_u32(out, 0); // creation_time
+            put_u32(out, 0); // modification_time
+            put_u32(out, MOVIE_TIMESCALE);
+            put_u32(out, 0); // duration
+            put_u32(out, 0x0001_0000); // rate
+            put_u16(out, 0x0100); // volume
+            out.extend_from_slice(&[0; 10]);
+            MATRIX.iter().for_each(|value| put_u32(out, *value));
+            out.extend_from_slice(&[0; 24]);
+            put_u32(out, tracks.len() as u32 + 1); // next_track_ID
+        });
+        for (index, track) in tracks.iter().enumerate() {
+            write_trak(out, index as u32 + 1, track);
+        }
+        write_box(out, b"mvex", |out| {
+            for index in 0..tracks.len() {
+                write_full_box(out, b"trex", 0, 0, |out| {
+                    put_u32(out, index as u32 + 1); // track_ID
+                    put_u32(out, 1); // default_sample_description_index
+                    put_u32(out, 0); // default_sample_duration
+                    put_u32(out, 0); // default_sample_size
+                    put_u32(out, 0); // default_sample_flags
+                });
+            }
+        });
+    });
+    out
+}
+
+/// Fragment (`moof` and `mdat`) with the samples of one or more tracks.
+/// `sequence_number` starts at 1 and goes up by one for every fragment.
+pub fn fragment(sequence_number: u32, tracks: &[TrackFragment]) -> Vec<u8> {
+    // Flags of the track run: data offset, and sample duration, size,
+    // flags and composition time offset for every sample.
+    const TRUN_FLAGS: u32 = 0x000001 | 0x000100 | 0x000200 | 0x000400 | 0x000800;
+    // The data offset of a track run is relative to the start of the
+    // `moof` box.
+    const TFHD_DEFAULT_BASE_IS_MOOF: u32 = 0x020000;
+
+    let mut out = Vec::new();
+    let mut data_offset_positions = Vec::with_capacity(tracks.len());
+    write_box(&mut out, b"moof", |out| {
+        write_full_box(out, b"mfhd", 0, 0, |out| put_u32(out, sequence_number));
+        for track in tracks {
+            write_box(out, b"traf", |out| {
+                write_full_box(out, b"tfhd", 0, TFHD_DEFAULT_BASE_IS_MOOF, |out| {
+                    put_u32(out, track.track as u32 + 1);
+                });
+                write_full_box(out, b"tfdt", 1, 0, |out| {
+                    out.extend_from_slice(&track.base_decode_time.to_be_bytes());
+                });
+                write_full_box(out, b"trun", 1, TRUN_FLAGS, |out| {
+                    put_u32(out, track.samples.len() as u32);
+                    data_offset_positions.push(out.len());
+                    put_u32(out, 0); // data_offset, filled in below
+                    for sample in track.samples {
+                        put_u32(out, sample.duration);
+                        put_u32(out, sample.data.len() as u32);
+                        put_u32(
+                            out,
+                            if sample.is_sync {
+                                SAMPLE_FLAGS_SYNC
+                            } else {
+                                SAMPLE_FLAGS_NON_SYNC
+                            },
+                        );
+                        out.extend_from_slice(&sample.composition_offset.to_be_bytes());
+                    }
+                });
+            });
+        }
+    });
+
+    // Samples of every track follow each other in the `mdat` box, which
+    // comes right after the `moof` box.
+    let mut data_offset = out.len() + 8;
+    for (track, position) in tracks.iter().zip(data_offset_positions) {
+        out[position..position + 4].copy_from_slice(&(data_offset as u32).to_be_bytes());
+        data_offset += track
+            .samples
+            .iter()
+            .map(|sample| sample.data.len())
+            .sum::<usize>();
+    }
+
+    write_box(&mut out, b"mdat", |out| {
+        for track in tracks {
+            for sample in track.samples {
+                out.extend_from_slice(&sample.data);
+            }
+        }
+    });
+    out
+}
+
+fn write_trak(out: &mut Vec<u8>, track_id: u32, track: &Mp4Track) {
+    let is_video = track.is_video();
+    write_box(out, b"trak", |out| {
+        // Track enabled and in movie.
+        write_full_box(out, b"tkhd", 0, 0x000003, |out| {
+            put_u32(out, 0); // creation_time
+            put_u32(out, 0); // modification_time
+            put_u32(out, track_id);
+            put_u32(out, 0); // reserved
+            put_u32(out, 0); // duration
+            out.extend_from_slice(&[0; 8]);
+            put_u16(out, 0); // layer
+            put_u16(out, 0); // alternate_group
+            put_u16(out, if is_video { 0 } else { 0x0100 }); // volume
+            put_u16(out, 0); // reserved
+            MATRIX.iter().for_each(|value| put_u32(out, *value));
+            put_u32(out, (track.width as u32) << 16);
+            put_u32(out, (track.height as u32) << 16);
+        });
+        write_box(out, b"mdia", |out| {
+            write_full_box(out, b"mdhd", 0, 0, |out| {
+                put_u32(out, 0); // creation_time
+                put_u32(out, 0); // modification_time
+                put_u32(out, track.timescale);
+                put_u32(out, 0); // duration
+                put_u16(out, 0x55c4); // language: und
+                put_u16(out, 0); // pre_defined
+            });
+            write_full_box(out, b"hdlr", 0, 0, |out| {
+                put_u32(out, 0); // pre_defined
+                out.extend_from_slice(if is_video { b"vide" } else { b"soun" });
+                out.extend_from_slice(&[0; 12]);
+                out.extend_from_slice(if is_video {
+                    b"VideoHandler\0"
+                } else {
+                    b"SoundHandler\0"
+                });
+            });
+            write_box(out, b"minf", |out| {
+                if is_video {
+                    write_full_box(out, b"vmhd", 0, 0x000001, |out| {
+                        out.extend_from_slice(&[0; 8]); // graphicsmode and opcolor
+                    });
+                } else {
+                    write_full_box(out, b"smhd", 0, 0, |out| {
+                        out.extend_from_slice(&[0; 4]); // balance and reserved
+                    });
+                }
+                write_box(out, b"dinf", |out| {
+                    write_full_box(out, b"dref", 0, 0, |out| {
+                        put_u32(out, 1); // entry_count
+                                         // Media data is in the same file.
+                        write_full_box(out, b"url ", 0, 0x000001, |_| {});
+                    });
+                });
+                write_box(out, b"stbl", |out| {
+                    write_full_box(out, b"stsd", 0, 0, |out| {
+                        put_u32(out, 1); // entry_count
+                        write_sample_entry(out, track);
+                    });
+                    // Samples are in the fragments, so the sample tables of the
+                    // track itself are empty.
+                    for kind in [b"stts", b"stsc", b"stco"] {
+                        write_full_box(out, kind, 0, 0, |out| put_u32(out, 0));
+                    }
+                    write_full_box(out, b"stsz", 0, 0, |out| {
+                        put_u32(out, 0); // sample_size
+                        put_u32(out, 0); // sample_count
+                    });
+                });
+            });
+        });
+    });
+}
+
+fn write_sample_entry(out: &mut Vec<u8>, track: &Mp4Track) {
+    match &track.codec {
+        Codec::H264(parameter_sets) => write_box(out, b"avc1", |out| {
+            out.extend_from_slice(&[0; 6]); // reserved
+            put_u16(out, 1); // data_reference_index
+            out.extend_from_slice(&[0; 16]); // pre_defined and reserved
+            put_u16(out, track.width);
+            put_u16(out, track.height);
+            put_u32(out, 0x0048_0000); // horizresolution: 72 dpi
+            put_u32(out, 0x0048_0000); // vertresolution: 72 dpi
+            put_u32(out, 0); // reserved
+            put_u16(out, 1); // frame_count
+            out.extend_from_slice(&[0; 32]); // compressorname
+            put_u16(out, 0x0018); // depth
+            put_u16(out, 0xffff); // pre_defined
+            write_box(out, b"avcC", |out| write_avc_config(out, parameter_sets));
+        }),
+        Codec::Aac {
+            sample_rate,
+            channels,
+            config,
+        } => write_box(out, b"mp4a", |out| {
+            write_audio_sample_entry(out, *channels, *sample_rate);
+            write_full_box(out, b"esds", 0, 0, |out| write_es_descriptor(out, config));
+        }),
+        Codec::Opus { channels } => write_box(out, b"Opus", |out| {
+            write_audio_sample_entry(out, *channels, 48_000);
+            write_box(out, b"dOps", |out| {
+                out.push(0); // Version
+                out.push(*channels as u8); // OutputChannelCount
+                put_u16(out, 0); // PreSkip
+                put_u32(out, 48_000); // InputSampleRate
+                put_u16(out, 0); // OutputGain
+                out.push(0); // ChannelMappingFamily
+            });
+        }),
+        Codec::G711 { .. } => {}
+    }
+}
+
+fn write_audio_sample_entry(out: &mut Vec<u8>, channels: u32, sample_rate: u32) {
+    out.extend_from_slice(&[0; 6]); // reserved
+    put_u16(out, 1); // data_reference_index
+    out.extend_from_slice(&[0; 8]); // reserved
+    put_u16(out, channels as u16);
+    put_u16(out, 16); // samplesize
+    put_u16(out, 0); // pre_defined
+    put_u16(out, 0); // reserved
+                     // The sample rate is a 16.16 fixed point number, which does not fit
+                     // rates above 65535 Hz. Those are signaled in the codec config anyway.
+    put_u32(out, (sample_rate.min(u16::MAX as u32)) << 16);
+}
+
+/// `AVCDecoderConfigurationRecord` (ISO/IEC 14496-15 Section 5.2.4.1).
+fn write_avc_config(out: &mut Vec<u8>, parameter_sets: &ParameterSets) {
+    let sps = &parameter_sets.sps;
+    out.push(1); // configurationVersion
+    out.push(sps.get(1).copied().unwrap_or_default()); // AVCProfileIndication
+    out.push(sps.get(2).copied().unwrap_or_default()); // profile_compatibility
+    out.push(sps.get(3).copied().unwrap_or_default()); // AVCLevelIndication
+    out.push(0xfc | (NAL_LENGTH_SIZE as u8 - 1)); // lengthSizeMinusOne
+    out.push(0xe0 | 1); // numOfSequenceParameterSets
+    put_u16(out, sps.len() as u16);
+    out.extend_from_slice(sps);
+    out.push(parameter_sets.pps.len() as u8); // numOfPictureParameterSets
+    for pps in parameter_sets.pps.iter() {
+        put_u16(out, pps.len() as u16);
+        out.extend_from_slice(pps);
+    }
+}
+
+/// `ES_Descriptor` with the `AudioSpecificConfig` of AAC (ISO/IEC 14496-1
+/// Section 7.2.6.5).
+fn write_es_descriptor(out: &mut Vec<u8>, config: &[u8]) {
+    const ES_DESCRIPTOR_TAG: u8 = 0x03;
+    const DECODER_CONFIG_DESCRIPTOR_TAG: u8 = 0x04;
+    const DECODER_SPECIFIC_INFO_TAG: u8 = 0x05;
+    const SL_CONFIG_DESCRIPTOR_TAG: u8 = 0x06;
+    // Audio ISO/IEC 14496-3.
+    const OBJECT_TYPE_AAC: u8 = 0x40;
+    // Audio stream, upstream flag not set, reserved bit set.
+    const STREAM_TYPE_AUDIO: u8 = (0x05 << 2) | 1;
+
+    write_descriptor(out, ES_DESCRIPTOR_TAG, |out| {
+        put_u16(out, 0); // ES_ID
+        out.push(0); // flags
+        write_descriptor(out, DECODER_CONFIG_DESCRIPTOR_TAG, |out| {
+            out.push(OBJECT_TYPE_AAC);
+            out.push(STREAM_TYPE_AUDIO);
+            out.extend_from_slice(&[0; 3]); // bufferSizeDB
+            put_u32(out, 0); // maxBitrate
+            put_u32(out, 0); // avgBitrate
+            write_descriptor(out, DECODER_SPECIFIC_INFO_TAG, |out| {
+                out.extend_from_slice(config);
+            });
+        });
+        write_descriptor(out, SL_CONFIG_DESCRIPTOR_TAG, |out| {
+            out.push(0x02); // predefined: reserved for use in MP4 files
+        });
+    });
+}
+
+fn write_descriptor(out: &mut Vec<u8>, tag: u8, body: impl FnOnce(&mut Vec<u8>)) {
+    let mut contents = Vec::new();
+    body(&mut contents);
+    out.push(tag);
+    // Size in the expandable 4 byte form, 7 bits per byte.
+    let len = contents.len() as u32;
+    for shift in [21, 14, 7] {
+        out.push(0x80 | ((len >> shift) & 0x7f) as u8);
+    }
+    out.push((len & 0x7f) as u8);
+    out.extend_from_slice(&contents);
+}
+
+fn write_box(out: &mut Vec<u8>, kind: &[u8; 4], body: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    put_u32(out, 0); // size, filled in below
+    out.extend_from_slice(kind);
+    body(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+fn write_full_box(
+    out: &mut Vec<u8>,
+    kind: &[u8; 4],
+    version: u8,
+    flags: u32,
+    body: impl FnOnce(&mut Vec<u8>),
+) {
+    write_box(out, kind, |out| {
+        put_u32(out, ((version as u32) << 24) | (flags & 0x00ff_ffff));
+        body(out);
+    });
+}
+
+#[inline]
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+#[inline]
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+/// Width and height in pixels of the pictures of an H.264 stream, from
+/// its sequence parameter set (ITU-T H.264 Section 7.3.2.1.1).
+fn sps_dimensions(sps: &[u8]) -> Option<(u16, u16)> {
+    let mut reader = BitReader::new(sps.get(1..)?);
+    let profile_idc = reader.read_bits(8)?;
+    reader.read_bits(16)?; // constraint flags and level_idc
+    reader.read_ue()?; // seq_parameter_set_id
+
+    let mut chroma_format_idc = 1;
+    let mut separate_colour_plane = false;
+    if matches!(
+        profile_idc,
+        100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+    ) {
+        chroma_format_idc = reader.read_ue()?;
+        if chroma_format_idc == 3 {
+            separate_colour_plane = reader.read_bit()?;
+        }
+        reader.read_ue()?; // bit_depth_luma_minus8
+        reader.read_ue()?; // bit_depth_chroma_minus8
+        reader.read_bit()?; // qpprime_y_zero_transform_bypass_flag
+        if reader.read_bit()? {
+            let num_lists = if chroma_format_idc != 3 { 8 } else { 12 };
+            for index in 0..num_lists {
+                if reader.read_bit()? {
+                    reader.skip_scaling_list(if index < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+
+    reader.read_ue()?; // log2_max_frame_num_minus4
+    match reader.read_ue()? {
+        0 => {
+            reader.read_ue()?; // log2_max_pic_order_cnt_lsb_minus4
+        }
+        1 => {
+            reader.read_bit()?; // delta_pic_order_always_zero_flag
+            reader.read_se()?; // offset_for_non_ref_pic
+            reader.read_se()?; // offset_for_top_to_bottom_field
+            for _ in 0..reader.read_ue()? {
+                reader.read_se()?; // offset_for_ref_frame
+            }
+        }
+        _ => {}
+    }
+    reader.read_ue()?; // max_num_ref_frames
+    reader.read_bit()?; // gaps_in_frame_num_value_allowed_flag
+    let width_in_mbs = reader.read_ue()? + 1;
+    let height_in_map_units = reader.read_ue()? + 1;
+    let frame_mbs_only = reader.read_bit()?;
+    if !frame_mbs_only {
+        reader.read_bit()?; // mb_adaptive_frame_field_flag
+    }
+    reader.read_bit()?; // direct_8x8_inference_flag
+    let (mut crop_left, mut crop_right, mut crop_top, mut crop_bottom) = (0, 0, 0, 0);
+    if reader.read_bit()? {
+        crop_left = reader.read_ue()?;
+        crop_right = reader.read_ue()?;
+        crop_top = reader.read_ue()?;
+        crop_bottom = reader.read_ue()?;
+    }
+
+    // See the semantics of frame_crop_left_offset (Section 7.4.2.1.1).
+    let frame_height_factor = if frame_mbs_only { 1 } else { 2 };
+    let (crop_unit_x, crop_unit_y) = if chroma_format_idc == 0 || separate_colour_plane {
+        (1, frame_height_factor)
+    } else {
+        let (sub_width, sub_height) = match chroma_format_idc {
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+        (sub_width, sub_height * frame_height_factor)
+    };
+    let width = (width_in_mbs * 16).checked_sub((crop_left + crop_right) * crop_unit_x)?;
+    let height = (height_in_map_units * 16 * frame_height_factor)
+        .checked_sub((crop_top + crop_bottom) * crop_unit_y)?;
+    Some((u16::try_from(width).ok()?, u16::try_from(height).ok()?))
+}
+
+/// Reads bits and Exp-Golomb codes from the RBSP of a NAL unit, skipping
+/// emulation prevention bytes.
+struct BitReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+    bit: u8,
+    zeros: usize,
+    current: u8,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            offset: 0,
+            bit: 8,
+            zeros: 0,
+            current: 0,
+        }
+    }
+
+    fn read_bit(&mut self) -> Option<bool> {
+        if self.bit == 8 {
+            let mut byte = *self.data.get(self.offset)?;
+            self.offset += 1;
+            if self.zeros >= 2 && byte == 3 {
+                // Emulation prevention byte.
+                byte = *self.data.get(self.offset)?;
+                self.offset += 1;
+                self.zeros = 0;
+            }
+            self.zeros = if byte == 0 { self.zeros + 1 } else { 0 };
+            self.current = byte;
+            self.bit = 0;
+        }
+        let value = (self.current >> (7 - self.bit)) & 1;
+        self.bit += 1;
+        Some(value == 1)
+    }
+
+    fn read_bits(&mut self, count: u32) -> Option<u32> {
+        (0..count).try_fold(0, |value, _| Some((value << 1) | self.read_bit()? as u32))
+    }
+
+    fn read_ue(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while !self.read_bit()? {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+        Some((1 << leading_zeros) - 1 + self.read_bits(leading_zeros)?)
+    }
+
+    fn read_se(&mut self) -> Option<i32> {
+        let value = self.read_ue()? as i64;
+        Some(if value % 2 == 1 {
+            ((value + 1) / 2) as i32
+        } else {
+            -(value / 2) as i32
+        })
+    }
+
+    fn skip_scaling_list(&mut self, size: usize) -> Option<()> {
+        let mut last_scale = 8;
+        let mut next_scale = 8;
+        for _ in 0..size {
+            if next_scale != 0 {
+                let delta_scale = self.read_se()?;
+                next_scale = (last_scale + delta_scale + 256) % 256;
+            }
+            if next_scale != 0 {
+                last_scale = next_scale;
+            }
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Type and size of the boxes at the top level of `data`.
+    fn boxes(data: &[u8]) -> Vec<(String, usize)> {
+        let mut boxes = Vec::new();
+        let mut offset = 0;
+        while offset + 8 <= data.len() {
+            let size = u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+            let kind = String::from_utf8_lossy(&data[offset + 4..offset + 8]).to_string();
+            boxes.push((kind, size));
+            offset += size;
+        }
+        assert_eq!(offset, data.len());
+        boxes
+    }
+
+    fn h264_track() -> Mp4Track {
+        Mp4Track::new(&Codec::H264(ParameterSets {
+            sps: from_hex("6742c01fda014016e4"),
+            pps: vec![vec![0x68, 0xce, 0x3c, 0x80]],
+            nal_length_size: None,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn sps_dimensions_baseline() {
+        assert_eq!(
+            sps_dimensions(&from_hex("6742c01fda014016e4")),
+            Some((1280, 720))
+        );
+    }
+
+    #[test]
+    fn sps_dimensions_high_with_scaling_matrix_and_cropping() {
+        assert_eq!(
+            sps_dimensions(&from_hex("67640028ad843fff80ca501e0089f950")),
+            Some((1920, 1080))
+        );
+    }
+
+    #[test]
+    fn sps_dimensions_truncated() {
+        assert_eq!(sps_dimensions(&from_hex("6742c01f")), None);
+    }
+
+    #[test]
+    fn init_segment_layout() {
+        let aac = Mp4Track::new(&Codec::Aac {
+            sample_rate: 48_000,
+            channels: 2,
+            config: vec![0x11, 0x90],
+        })
+        .unwrap();
+        let init = init_segment(&[h264_track(), aac]);
+        let top = boxes(&init);
+        assert_eq!(top[0], ("ftyp".to_string(), 36));
+        assert_eq!(top[1].0, "moov");
+        let moov = boxes(&init[top[0].1 + 8..]);
+        let kinds = moov
+            .iter()
+            .map(|(kind, _)| kind.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, ["mvhd", "trak", "trak", "mvex"]);
+    }
+
+    #[test]
+    fn g711_is_not_supported() {
+        assert!(Mp4Track::new(&Codec::G711 {
+            law: oddity_sdp_protocol::G711Law::MuLaw,
+            sample_rate: 8000,
+            channels: 1,
+        })
+        .is_none());
+    }
+
+    #[test]
+    fn h264_sample_is_length_prefixed() {
+        let sample = h264_track().sample_data(&[0, 0, 0, 1, 0x65, 0xaa, 0, 0, 1, 0x41, 0xbb]);
+        assert_eq!(sample, [0, 0, 0, 2, 0x65, 0xaa, 0, 0, 0, 2, 0x41, 0xbb]);
+    }
+
+    #[test]
+    fn fragment_data_offsets_point_at_samples() {
+        let video = [Sample {
+            data: vec![1, 2, 3],
+            duration: 3000,
+            composition_offset: 0,
+            is_sync: true,
+        }];
+        let audio = [
+            Sample {
+                data: vec![4, 5],
+                duration: 1024,
+                composition_offset: 0,
+                is_sync: true,
+            },
+            Sample {
+                data: vec![6],
+                duration: 1024,
+                composition_offset: 0,
+                is_sync: true,
+            },
+        ];
+        let data = fragment(
+            1,
+            &[
+                TrackFragment {
+                    track: 0,
+                    base_decode_time: 0,
+                    samples: &video,
+                },
+                TrackFragment {
+                    track: 1,
+                    base_decode_time: 0,
+                    samples: &audio,
+                },
+            ],
+        );
+        let top = boxes(&data);
+        assert_eq!(top[0].0, "moof");
+        assert_eq!(top[1], ("mdat".to_string(), 8 + 6));
+
+        // Find the data offsets of both track runs.
+        let offsets = data
+            .windows(4)
+            .enumerate()
+            .filter(|(_, window)| window == b"trun")
+            .map(|(position, _)| {
+                // Type, version and flags, sample count, then data offset.
+                let at = position + 4 + 4 + 4;
+                u32::from_be_bytes(data[at..at + 4].try_into().unwrap()) as usize
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(data[offsets[0]..offsets[0] + 3], [1, 2, 3]);
+        assert_eq!(data[offsets[1]..offsets[1] + 3], [4, 5, 6]);
+    }
+}
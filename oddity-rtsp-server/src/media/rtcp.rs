@@ -0,0 +1,490 @@
+//! RTCP sender reports (RFC 3550 Section 6.4.1) with an SDES CNAME item
+//! (RFC 3550 Section 6.5.1), so that clients can map RTP timestamps to
+//! wall-clock time and synchronize the tracks of a session.
+//!
+//! Also parses the receiver reports (RFC 3550 Section 6.4.2) and BYE
+//! packets (RFC 3550 Section 6.6) that clients send back.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+
+/// Time between two reports. RFC 3550 Section 6.2 recommends a minimum
+/// of 5 seconds.
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Size of the fixed RTP header (RFC 3550 Section 5.1).
+const RTP_HEADER_LEN: usize = 12;
+
+/// RTCP packet type of sender reports.
+const RTCP_SR: u8 = 200;
+
+/// RTCP packet type of receiver reports.
+const RTCP_RR: u8 = 201;
+
+/// RTCP packet type of source descriptions.
+const RTCP_SDES: u8 = 202;
+
+/// RTCP packet type of BYE packets.
+const RTCP_BYE: u8 = 203;
+
+/// Size of the RTCP header up to and including the SSRC of the sender.
+const RTCP_HEADER_LEN: usize = 8;
+
+/// Size of the sender info in a sender report.
+const SENDER_INFO_LEN: usize = 20;
+
+/// Size of a single report block.
+const REPORT_BLOCK_LEN: usize = 24;
+
+/// SDES item type of the canonical name.
+const SDES_CNAME: u8 = 1;
+
+/// Seconds between the NTP epoch (1900) and the Unix epoch (1970).
+const NTP_UNIX_OFFSET_SECS: u64 = 2_208_988_800;
+
+/// Generate a random CNAME (RFC 7022 Section 4.2). All tracks of a
+/// session must use the same CNAME so that clients know they belong
+/// together.
+pub fn generate_cname() -> String {
+    let cname: [u8; 12] = rand::thread_rng().gen();
+    cname.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// RTCP packet that a client sent. Only the packet types that the server
+/// acts on are parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReceivedRtcp {
+    /// Sender or receiver report of the participant with SSRC `ssrc`.
+    Report { ssrc: u32, blocks: Vec<ReportBlock> },
+    /// The participants with the given SSRCs are leaving.
+    Bye { ssrcs: Vec<u32> },
+}
+
+/// Reception report about a single RTP stream (RFC 3550 Section 6.4.1).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportBlock {
+    /// SSRC of the stream that the report is about.
+    pub ssrc: u32,
+    /// Fraction of packets lost since the previous report, in 1/256.
+    pub fraction_lost: u8,
+    pub cumulative_lost: i32,
+    pub highest_seq: u32,
+    /// Interarrival jitter in RTP timestamp units.
+    pub jitter: u32,
+    /// Middle 32 bits of the NTP timestamp of the last sender report that
+    /// the client received, or zero if it did not receive any.
+    pub last_sr: u32,
+    /// Time between receiving the last sender report and sending this
+    /// report, in 1/65536 seconds.
+    pub delay_since_last_sr: u32,
+}
+
+/// How well a client receives an RTP stream, according to its latest
+/// report.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReceptionStats {
+    /// Fraction of packets lost since the previous report.
+    pub fraction_lost: f64,
+    /// Number of packets lost since the start of the stream.
+    pub cumulative_lost: i32,
+    pub jitter: Duration,
+    /// Round-trip time, if the client received a sender report.
+    pub round_trip_time: Option<Duration>,
+}
+
+/// Parse a compound RTCP packet. Packets of types other than sender
+/// reports, receiver reports and BYE are skipped. Returns `None` if the
+/// packet is malformed.
+pub fn parse(packet: &[u8]) -> Option<Vec<ReceivedRtcp>> {
+    let mut parsed = Vec::new();
+    let mut offset = 0;
+    while offset < packet.len() {
+        let header = packet.get(offset..offset + 4)?;
+        if header[0] >> 6 != 2 {
+            return None;
+        }
+        let count = (header[0] & 0x1f) as usize;
+        // Length is in 32-bit words minus one (RFC 3550 Section 6.4.1).
+        let len = (read_u16(header, 2) as usize + 1) * 4;
+        let body = packet.get(offset..offset + len)?;
+
+        match header[1] {
+            RTCP_SR | RTCP_RR => {
+                let blocks_offset = match header[1] {
+                    RTCP_SR => RTCP_HEADER_LEN + SENDER_INFO_LEN,
+                    _ => RTCP_HEADER_LEN,
+                };
+                if body.len() < blocks_offset + count * REPORT_BLOCK_LEN {
+                    return None;
+                }
+                let blocks = body[blocks_offset..]
+                    .as_chunks::<REPORT_BLOCK_LEN>()
+                    .0
+                    .iter()
+                    .take(count)
+                    .map(parse_report_block)
+                    .collect();
+                parsed.push(ReceivedRtcp::Report {
+                    ssrc: read_u32(body, 4),
+                    blocks,
+                });
+            }
+            RTCP_BYE => {
+                if body.len() < 4 + count * 4 {
+                    return None;
+                }
+                let ssrcs = body[4..]
+                    .as_chunks::<4>()
+                    .0
+                    .iter()
+                    .take(count)
+                    .map(|ssrc| u32::from_be_bytes(*ssrc))
+                    .collect();
+                parsed.push(ReceivedRtcp::Bye { ssrcs });
+            }
+            _ => {}
+        }
+
+        offset += len;
+    }
+    Some(parsed)
+}
+
+fn parse_report_block(block: &[u8; REPORT_BLOCK_LEN]) -> ReportBlock {
+    ReportBlock {
+        ssrc: read_u32(block, 0),
+        fraction_lost: block[4],
+        // 24-bit signed integer.
+        cumulative_lost: (read_u32(block, 4) << 8) as i32 >> 8,
+        highest_seq: read_u32(block, 8),
+        jitter: read_u32(block, 12),
+        last_sr: read_u32(block, 16),
+        delay_since_last_sr: read_u32(block, 20),
+    }
+}
+
+/// Keeps track of the RTP packets sent on a single RTP stream, and
+/// produces sender reports for it.
+pub struct SenderReporter {
+    cname: String,
+    packet_count: u32,
+    octet_count: u32,
+    last: Option<LastSent>,
+}
+
+/// Last RTP packet that was sent.
+struct LastSent {
+    ssrc: u32,
+    timestamp: u32,
+    clock_rate: u32,
+    /// Wall-clock time at which the source received the frame that the
+    /// packet belongs to.
+    received: SystemTime,
+}
+
+impl SenderReporter {
+    pub fn new(cname: &str) -> Self {
+        Self {
+            cname: cname.to_string(),
+            packet_count: 0,
+            octet_count: 0,
+            last: None,
+        }
+    }
+
+    /// Account for an RTP packet that was sent. Packets that are too short
+    /// to be valid RTP are ignored.
+    pub fn sent(&mut self, packet: &[u8], clock_rate: u32, received: SystemTime) {
+        if packet.len() < RTP_HEADER_LEN {
+            return;
+        }
+        let ssrc = read_u32(packet, 8);
+        // The counts start over when the SSRC changes (RFC 3550 Section
+        // 6.4.1).
+        if self.last.as_ref().is_some_and(|last| last.ssrc != ssrc) {
+            self.packet_count = 0;
+            self.octet_count = 0;
+        }
+        self.packet_count = self.packet_count.wrapping_add(1);
+        self.octet_count = self.octet_count.wrapping_add(payload_len(packet) as u32);
+        self.last = Some(LastSent {
+            ssrc,
+            timestamp: read_u32(packet, 4),
+            clock_rate,
+            received,
+        });
+    }
+
+    /// Compound RTCP packet with a sender report and the CNAME, or `None`
+    /// if nothing was sent yet.
+    ///
+    /// The RTP timestamp of the report is that of the last packet sent,
+    /// moved forward by the time that passed since the source received it,
+    /// so that it corresponds to the NTP timestamp `now`.
+    pub fn report(&self, now: SystemTime) -> Option<Vec<u8>> {
+        let last = self.last.as_ref()?;
+        let elapsed = match now.duration_since(last.received) {
+            Ok(elapsed) => elapsed.as_secs_f64(),
+            Err(err) => -err.duration().as_secs_f64(),
+        };
+        let timestamp = last
+            .timestamp
+            .wrapping_add((elapsed * last.clock_rate as f64).round() as i64 as u32);
+
+        let mut packet = Vec::with_capacity(28 + 12 + self.cname.len());
+        // Sender report without report blocks.
+        packet.extend_from_slice(&[0x80, RTCP_SR, 0, 6]);
+        packet.extend_from_slice(&last.ssrc.to_be_bytes());
+        packet.extend_from_slice(&ntp_timestamp(now).to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.packet_count.to_be_bytes());
+        packet.extend_from_slice(&self.octet_count.to_be_bytes());
+
+        // Source description with a single chunk holding the CNAME. The
+        // chunk ends with at least one null octet and is padded to a
+        // multiple of 32 bits (RFC 3550 Section 6.5).
+        let cname = &self.cname.as_bytes()[..self.cname.len().min(u8::MAX as usize)];
+        let chunk_len = (4 + 2 + cname.len() + 1).div_ceil(4) * 4;
+        packet.extend_from_slice(&[0x81, RTCP_SDES]);
+        packet.extend_from_slice(&((chunk_len / 4) as u16).to_be_bytes());
+        packet.extend_from_slice(&last.ssrc.to_be_bytes());
+        packet.extend_from_slice(&[SDES_CNAME, cname.len() as u8]);
+        packet.extend_from_slice(cname);
+        packet.resize(28 + 4 + chunk_len, 0);
+
+        Some(packet)
+    }
+
+    /// Interpret a report block that a client sent at `arrival`. Returns
+    /// `None` if the block is not about this stream.
+    pub fn reception_stats(
+        &self,
+        block: &ReportBlock,
+        arrival: SystemTime,
+    ) -> Option<ReceptionStats> {
+        let last = self.last.as_ref().filter(|last| last.ssrc == block.ssrc)?;
+        // The round-trip time is the arrival time minus the time at which the
+        // last sender report was sent and the time the client held on to it,
+        // all in units of 1/65536 seconds (RFC 3550 Section 6.4.1).
+        let round_trip_time = (block.last_sr != 0)
+            .then(|| {
+                ((ntp_timestamp(arrival) >> 16) as u32)
+                    .wrapping_sub(block.last_sr)
+                    .wrapping_sub(block.delay_since_last_sr)
+            })
+            .filter(|round_trip_time| (*round_trip_time as i32) >= 0)
+            .map(|round_trip_time| Duration::from_secs_f64(round_trip_time as f64 / 65536.0));
+        Some(ReceptionStats {
+            fraction_lost: block.fraction_lost as f64 / 256.0,
+            cumulative_lost: block.cumulative_lost,
+            jitter: Duration::from_secs_f64(block.jitter as f64 / last.clock_rate.max(1) as f64),
+            round_trip_time,
+        })
+    }
+}
+
+/// Size of the payload of an RTP packet, without the header, CSRCs,
+/// header extension and padding.
+fn payload_len(packet: &[u8]) -> usize {
+    let csrc_count = (packet[0] & 0x0f) as usize;
+    let mut header_len = RTP_HEADER_LEN + csrc_count * 4;
+    if packet[0] & 0x10 != 0 && packet.len() >= header_len + 4 {
+        header_len += 4 + read_u16(packet, header_len + 2) as usize * 4;
+    }
+    let padding_len = if packet[0] & 0x20 != 0 {
+        packet.last().copied().unwrap_or_default() as usize
+    } else {
+        0
+    };
+    packet.len().saturating_sub(header_len + padding_len)
+}
+
+/// 64-bit NTP timestamp (RFC 3550 Section 4) of the given time.
+fn ntp_timestamp(time: SystemTime) -> u64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() + NTP_UNIX_OFFSET_SECS;
+    let fraction = ((since_epoch.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    (secs << 32) | fraction
+}
+
+#[inline]
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+#[inline]
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{
+        ntp_timestamp, parse, read_u32, ReceivedRtcp, ReportBlock, SenderReporter, RTCP_SDES,
+        RTCP_SR,
+    };
+
+    fn rtp_packet(ssrc: u32, timestamp: u32, payload_len: usize) -> Vec<u8> {
+        let mut packet = vec![0x80, 96, 0, 1];
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&ssrc.to_be_bytes());
+        packet.resize(12 + payload_len, 0xab);
+        packet
+    }
+
+    #[test]
+    fn no_report_before_first_packet() {
+        let reporter = SenderReporter::new("cname");
+        assert!(reporter.report(UNIX_EPOCH).is_none());
+    }
+
+    #[test]
+    fn ntp_timestamp_of_unix_epoch() {
+        assert_eq!(ntp_timestamp(UNIX_EPOCH), 2_208_988_800 << 32);
+        assert_eq!(
+            ntp_timestamp(UNIX_EPOCH + Duration::from_millis(500)),
+            (2_208_988_800 << 32) | 0x8000_0000,
+        );
+    }
+
+    #[test]
+    fn sender_report_with_cname() {
+        let received = UNIX_EPOCH + Duration::from_secs(1_000);
+        let mut reporter = SenderReporter::new("abc");
+        reporter.sent(&rtp_packet(0x1234, 90_000, 100), 90_000, received);
+        reporter.sent(&rtp_packet(0x1234, 93_000, 50), 90_000, received);
+
+        let report = reporter
+            .report(received + Duration::from_millis(500))
+            .unwrap();
+        assert_eq!(report.len(), 28 + 16);
+
+        assert_eq!(&report[0..4], &[0x80, RTCP_SR, 0, 6]);
+        assert_eq!(read_u32(&report, 4), 0x1234);
+        assert_eq!(read_u32(&report, 8), 2_208_988_800 + 1_000);
+        assert_eq!(read_u32(&report, 12), 0x8000_0000);
+        // Half a second after the last packet at 90 kHz.
+        assert_eq!(read_u32(&report, 16), 93_000 + 45_000);
+        assert_eq!(read_u32(&report, 20), 2);
+        assert_eq!(read_u32(&report, 24), 150);
+
+        assert_eq!(&report[28..32], &[0x81, RTCP_SDES, 0, 3]);
+        assert_eq!(read_u32(&report, 32), 0x1234);
+        assert_eq!(&report[36..], &[1, 3, b'a', b'b', b'c', 0, 0, 0]);
+    }
+
+    #[test]
+    fn sdes_chunk_is_null_terminated() {
+        // The item type, length and a CNAME of two octets exactly fill a
+        // 32-bit word, so the null octet takes another word.
+        let mut reporter = SenderReporter::new("ab");
+        reporter.sent(&rtp_packet(1, 0, 0), 8_000, UNIX_EPOCH);
+        let report = reporter.report(UNIX_EPOCH).unwrap();
+        assert_eq!(&report[28..32], &[0x81, RTCP_SDES, 0, 3]);
+        assert_eq!(&report[36..], &[1, 2, b'a', b'b', 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn counts_reset_on_new_ssrc() {
+        let mut reporter = SenderReporter::new("cname");
+        reporter.sent(&rtp_packet(1, 0, 10), 8_000, UNIX_EPOCH);
+        reporter.sent(&rtp_packet(2, 0, 20), 8_000, UNIX_EPOCH);
+        let report = reporter.report(UNIX_EPOCH).unwrap();
+        assert_eq!(read_u32(&report, 4), 2);
+        assert_eq!(read_u32(&report, 20), 1);
+        assert_eq!(read_u32(&report, 24), 20);
+    }
+
+    #[test]
+    fn parse_receiver_report_and_bye() {
+        let packet = [
+            // Receiver report with one report block.
+            0x81, 201, 0, 7, 0, 0, 0, 0x42, // header
+            0, 0, 0x12, 0x34, // ssrc
+            0x40, 0xff, 0xff, 0xfe, // fraction lost and cumulative lost
+            0, 1, 0x02, 0x03, // highest sequence number
+            0, 0, 0x01, 0x00, // jitter
+            0xb7, 0x05, 0x20, 0x00, // last sr
+            0x00, 0x05, 0x40, 0x00, // delay since last sr
+            // Source description, which is skipped.
+            0x81, 202, 0, 2, 0, 0, 0, 0x42, 1, 1, b'x', 0, //
+            // Bye.
+            0x81, 203, 0, 1, 0, 0, 0, 0x42,
+        ];
+        assert_eq!(
+            parse(&packet).unwrap(),
+            vec![
+                ReceivedRtcp::Report {
+                    ssrc: 0x42,
+                    blocks: vec![ReportBlock {
+                        ssrc: 0x1234,
+                        fraction_lost: 0x40,
+                        cumulative_lost: -2,
+                        highest_seq: 0x10203,
+                        jitter: 256,
+                        last_sr: 0xb705_2000,
+                        delay_since_last_sr: 0x0005_4000,
+                    }],
+                },
+                ReceivedRtcp::Bye { ssrcs: vec![0x42] },
+            ],
+        );
+    }
+
+    #[test]
+    fn parse_truncated() {
+        assert!(parse(&[0x81, 201, 0, 7, 0, 0, 0, 0x42]).is_none());
+        assert!(parse(&[0x81, 201, 0]).is_none());
+    }
+
+    #[test]
+    fn reception_stats_round_trip_time() {
+        let sent = UNIX_EPOCH + Duration::from_secs(1_000);
+        let mut reporter = SenderReporter::new("cname");
+        reporter.sent(&rtp_packet(0x1234, 0, 10), 90_000, sent);
+        let report = reporter.report(sent).unwrap();
+        let last_sr = u32::from_be_bytes([report[10], report[11], report[12], report[13]]);
+
+        // The client held on to the report for a second, and its reply
+        // arrives one and a half seconds after the report was sent.
+        let block = ReportBlock {
+            ssrc: 0x1234,
+            fraction_lost: 64,
+            cumulative_lost: 3,
+            highest_seq: 1,
+            jitter: 9_000,
+            last_sr,
+            delay_since_last_sr: 65536,
+        };
+        let stats = reporter
+            .reception_stats(&block, sent + Duration::from_millis(1_500))
+            .unwrap();
+        assert_eq!(stats.fraction_lost, 0.25);
+        assert_eq!(stats.cumulative_lost, 3);
+        assert_eq!(stats.jitter, Duration::from_millis(100));
+        assert_eq!(stats.round_trip_time, Some(Duration::from_millis(500)));
+
+        let block = ReportBlock {
+            last_sr: 0,
+            ..block.clone()
+        };
+        assert_eq!(
+            reporter
+                .reception_stats(&block, sent)
+                .unwrap()
+                .round_trip_time,
+            None
+        );
+
+        let block = ReportBlock { ssrc: 1, ..block };
+        assert!(reporter.reception_stats(&block, sent).is_none());
+    }
+}
@@ -1,92 +1,252 @@
 use std::error;
 use std::fmt;
 
-use oddity_sdp_protocol::{CodecInfo, Direction, Kind, Protocol, TimeRange};
+use base64::engine::Engine;
 
-use crate::media::video::reader;
-use crate::media::video::rtp_muxer;
-use crate::media::MediaDescriptor;
+use oddity_rtsp_protocol::{NptTime, Range};
+use oddity_sdp_protocol::{CodecInfo, Direction, Kind, Media, Protocol, Tag, TimeRange};
+
+use crate::media::video::rtp_h264::{PacketizationMode, ParameterSets};
+use crate::media::{Codec, MediaInfo, StreamInfo};
 
 pub use oddity_sdp_protocol::Sdp;
 
-/// Create a new SDP description for the given media descriptor. The
-/// SDP contents can be used over RTSP when the client requested a
-/// stream description.
-///
-/// Note: This function only handles the most appropriate video stream
-/// and tosses any audio or other streams.
+/// Prefix of the control attribute of every track. Clients set up a
+/// track by appending its control attribute to the source path, as in
+/// `/path/trackID=1`.
+const TRACK_CONTROL_PREFIX: &str = "trackID=";
+
+/// Control attribute of the given track.
+pub fn track_control(track: usize) -> String {
+    format!("{TRACK_CONTROL_PREFIX}{track}")
+}
+
+/// Split a request path or URL into the source part and the track it
+/// refers to, if any.
+pub fn split_track_path(path: &str) -> (&str, Option<usize>) {
+    path.rsplit_once('/')
+        .and_then(|(base, control)| {
+            let track = control.strip_prefix(TRACK_CONTROL_PREFIX)?.parse().ok()?;
+            Some((base, track))
+        })
+        .map_or((path, None), |(base, track)| (base, Some(track)))
+}
+
+/// Create a new SDP description for the given media. The SDP contents
+/// can be used over RTSP when the client requested a stream description.
+/// Every stream gets its own media description, with a control attribute
+/// that refers to the track (see [`track_control`]).
 ///
 /// # Arguments
 ///
 /// * `name` - Name of stream.
-/// * `descriptor` - Media stream descriptor.
-pub async fn create(name: &str, descriptor: &MediaDescriptor) -> Result<Sdp, SdpError> {
+/// * `media_info` - Media information of the source.
+/// * `packetization_mode` - H.264 packetization mode used by the muxer.
+pub fn create(
+    name: &str,
+    media_info: &MediaInfo,
+    packetization_mode: PacketizationMode,
+) -> Result<Sdp, SdpError> {
     const ORIGIN_DUMMY_HOST: [u8; 4] = [0, 0, 0, 0];
     const TARGET_DUMMY_HOST: [u8; 4] = [0, 0, 0, 0];
     const TARGET_DUMMY_PORT: u16 = 0;
 
-    tracing::trace!("sdp: initializing reader");
-    let reader = reader::backend::make_reader_with_sane_settings(descriptor.clone().into())
-        .await
-        .map_err(SdpError::Media)?;
-    let best_video_stream = reader.best_video_stream_index().map_err(SdpError::Media)?;
-    tracing::trace!(best_video_stream, "sdp: initialized reader");
-
-    tracing::trace!("sdp: initializing muxer");
-    let muxer = rtp_muxer::make_rtp_muxer_builder()
-        .await
-        .and_then(|muxer| muxer.with_stream(reader.stream_info(best_video_stream)?))
-        .map_err(SdpError::Media)?
-        .build();
-    tracing::trace!("sdp: initialized muxer");
-
-    let (sps, pps) = muxer
-        .parameter_sets_h264()
-        .into_iter()
-        // The `parameter_sets` function will return an error if the
-        // underlying stream codec is not supported, we filter out
-        // the stream in that case, and return `CodecNotSupported`.
-        .find_map(Result::ok)
-        .ok_or(SdpError::CodecNotSupported)?;
-    tracing::trace!("sdp: found SPS and PPS");
-
-    // Since the previous call to `parameter_sets_h264` can only
-    // return a result if the underlying stream is H.264, we can
-    // assume H.264 from this point onwards.
-    let codec_info = CodecInfo::h264(sps, pps.as_slice(), muxer.packetization_mode());
-
-    let sdp = Sdp::new(
+    if media_info.streams.is_empty() {
+        return Err(SdpError::CodecNotSupported);
+    }
+
+    let mut sdp = Sdp::new(
         ORIGIN_DUMMY_HOST.into(),
         name.to_string(),
         TARGET_DUMMY_HOST.into(),
-        // Since we support only live streams or playback on repeat,
-        // all streams are basically "live".
+        // Media played on demand has a range instead, so all streams are
+        // "live" as far as the timing is concerned.
         TimeRange::Live,
     );
 
-    let sdp = sdp.with_media(
-        Kind::Video,
-        TARGET_DUMMY_PORT,
-        Protocol::RtpAvp,
-        codec_info,
-        Direction::ReceiveOnly,
-    );
+    // Clients need the range of media played on demand to be able to seek
+    // in it (RFC 2326 Appendix C.1.5).
+    if let Some(duration) = media_info.duration {
+        let range = Range::new(NptTime::Time(0.0), NptTime::Time(duration));
+        sdp = sdp.with_tag(Tag::Value("range".to_string(), range.to_string()));
+    }
+
+    for (track, stream_info) in media_info.streams.iter().enumerate() {
+        let pps;
+        let (kind, codec_info) = match &stream_info.codec {
+            Codec::H264(parameter_sets) => {
+                pps = parameter_sets
+                    .pps
+                    .iter()
+                    .map(Vec::as_slice)
+                    .collect::<Vec<_>>();
+                (
+                    Kind::Video,
+                    CodecInfo::h264(
+                        &parameter_sets.sps,
+                        pps.as_slice(),
+                        packetization_mode.as_sdp_value(),
+                    ),
+                )
+            }
+            Codec::Aac {
+                sample_rate,
+                channels,
+                config,
+            } => (Kind::Audio, CodecInfo::aac(*sample_rate, *channels, config)),
+            Codec::Opus { channels } => (Kind::Audio, CodecInfo::opus(*channels)),
+            Codec::G711 {
+                law,
+                sample_rate,
+                channels,
+            } => (Kind::Audio, CodecInfo::g711(*law, *sample_rate, *channels)),
+        };
+
+        sdp = sdp
+            .with_media(
+                kind,
+                TARGET_DUMMY_PORT,
+                Protocol::RtpAvp,
+                codec_info,
+                Direction::ReceiveOnly,
+            )
+            .with_media_tag(Tag::Value("control".to_string(), track_control(track)));
+    }
 
     tracing::trace!(%sdp, "generated sdp");
     Ok(sdp)
 }
 
+/// Media that a client announced it is going to publish.
+#[derive(Debug, Clone)]
+pub struct Announcement {
+    pub tracks: Vec<AnnouncedTrack>,
+}
+
+impl Announcement {
+    /// Media info of the announced tracks that can be published. The
+    /// index of each stream is the index of its track. Returns `None` if
+    /// the parameter sets of one of the tracks are not known yet.
+    pub fn media_info(&self) -> Option<MediaInfo> {
+        let streams = self
+            .tracks
+            .iter()
+            .enumerate()
+            .filter_map(|(index, track)| match track.codec.as_ref()? {
+                AnnouncedCodec::H264 { parameter_sets } => {
+                    Some(parameter_sets.clone().map(|parameter_sets| StreamInfo {
+                        index,
+                        codec: Codec::H264(parameter_sets),
+                    }))
+                }
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(MediaInfo {
+            streams,
+            duration: None,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnnouncedTrack {
+    /// Value of the `control` attribute, which the client uses to refer to
+    /// the track when setting it up.
+    pub control: Option<String>,
+    pub payload_type: u8,
+    /// Codec of the track, or `None` if the codec is not supported. Data
+    /// that is sent for unsupported tracks is dropped.
+    pub codec: Option<AnnouncedCodec>,
+}
+
+#[derive(Debug, Clone)]
+pub enum AnnouncedCodec {
+    /// H.264 in packetization mode 0 or 1. The parameter sets are only
+    /// known up front if the client included `sprop-parameter-sets`.
+    H264 {
+        parameter_sets: Option<ParameterSets>,
+    },
+}
+
+/// Parse the SDP that a client sent with ANNOUNCE. Every media
+/// description becomes a track, but only H.264 video tracks are
+/// supported.
+pub fn parse_announcement(contents: &str) -> Result<Announcement, SdpError> {
+    let sdp = contents.parse::<Sdp>().map_err(SdpError::Parse)?;
+    tracing::trace!(%sdp, "parsed announced sdp");
+
+    let tracks = sdp
+        .media
+        .iter()
+        .map(|media| AnnouncedTrack {
+            control: media.attribute("control").map(str::to_string),
+            payload_type: media.format as u8,
+            codec: announced_codec(media),
+        })
+        .collect::<Vec<_>>();
+
+    if !tracks.iter().any(|track| track.codec.is_some()) {
+        return Err(SdpError::CodecNotSupported);
+    }
+
+    Ok(Announcement { tracks })
+}
+
+fn announced_codec(media: &Media) -> Option<AnnouncedCodec> {
+    let (_, encoding) = media.attribute("rtpmap")?.split_once(' ')?;
+    let encoding_name = encoding.split('/').next()?;
+    if !matches!(media.kind, Kind::Video) || !encoding_name.eq_ignore_ascii_case("H264") {
+        return None;
+    }
+
+    let fmtp = media
+        .attribute("fmtp")
+        .and_then(|fmtp| fmtp.split_once(' '))
+        .map(|(_, parameters)| parameters)
+        .unwrap_or_default();
+    let fmtp_parameter = |name: &str| {
+        fmtp.split(';')
+            .filter_map(|parameter| parameter.trim().split_once('='))
+            .find(|(var, _)| var.eq_ignore_ascii_case(name))
+            .map(|(_, val)| val.trim())
+    };
+
+    // Interleaved mode is not supported by the depacketizer.
+    let packetization_mode = fmtp_parameter("packetization-mode")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0);
+    PacketizationMode::from_sdp_value(packetization_mode)?;
+
+    // The parameter sets are in Annex B format in the stream itself, so
+    // the parameter sets from the SDP are treated the same way.
+    let parameter_sets = fmtp_parameter("sprop-parameter-sets").and_then(|value| {
+        let mut annex_b = Vec::new();
+        for parameter_set in value.split(',') {
+            let parameter_set = base64::engine::general_purpose::STANDARD
+                .decode(parameter_set)
+                .ok()?;
+            annex_b.extend_from_slice(&[0, 0, 0, 1]);
+            annex_b.extend_from_slice(&parameter_set);
+        }
+        ParameterSets::from_extradata(&annex_b)
+    });
+
+    Some(AnnouncedCodec::H264 { parameter_sets })
+}
+
 #[derive(Debug)]
 pub enum SdpError {
     CodecNotSupported,
-    Media(video_rs::Error),
+    MediaInfoUnavailable,
+    Parse(oddity_sdp_protocol::Error),
 }
 
 impl fmt::Display for SdpError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             SdpError::CodecNotSupported => write!(f, "codec not supported"),
-            SdpError::Media(error) => write!(f, "media error: {}", error),
+            SdpError::MediaInfoUnavailable => write!(f, "media info unavailable"),
+            SdpError::Parse(error) => write!(f, "parse error: {}", error),
         }
     }
 }
@@ -1,2 +1,3 @@
 pub mod reader;
+pub mod rtp_h264;
 pub mod rtp_muxer;
@@ -1,14 +1,30 @@
+use std::collections::HashMap;
 use std::thread;
+use std::time::{Duration, Instant};
 
 use tokio::sync::mpsc;
 use tokio::task;
 
 use video_rs as video;
+use video_rs::Location;
 
 use crate::media::{MediaDescriptor, MediaInfo};
 
 type Result<T> = std::result::Result<T, video::Error>;
 
+/// How the reader goes through the media.
+#[derive(Debug, Clone, Copy)]
+pub enum ReadMode {
+    /// Read packets as they come in.
+    Live,
+    /// Play a file on repeat in real time, pretending that it is a live
+    /// stream.
+    Loop,
+    /// Play a file once in real time, from `start` up to `end` (in
+    /// seconds). Timestamps are kept as they are in the file.
+    Once { start: f64, end: Option<f64> },
+}
+
 pub struct StreamReader {
     pub info: MediaInfo,
     handle: Option<thread::JoinHandle<()>>,
@@ -17,23 +33,37 @@ pub struct StreamReader {
 }
 
 impl StreamReader {
-    pub async fn new(descriptor: &MediaDescriptor) -> Result<Self> {
-        let is_file = matches!(descriptor, MediaDescriptor::File(_));
+    pub async fn new(descriptor: &MediaDescriptor, location: Location) -> Result<Self> {
+        let mode = match location {
+            Location::File(_) => ReadMode::Loop,
+            Location::Network(_) => ReadMode::Live,
+        };
+        Self::new_with_mode(descriptor, location, mode).await
+    }
 
+    pub async fn new_with_mode(
+        descriptor: &MediaDescriptor,
+        location: Location,
+        mode: ReadMode,
+    ) -> Result<Self> {
         tracing::trace!(%descriptor, "initializing reader");
-        let inner = backend::make_reader_with_sane_settings(descriptor.clone().into()).await?;
+        let inner = backend::make_reader_with_sane_settings(location).await?;
         tracing::trace!(%descriptor, "initialized reader");
 
-        let info = MediaInfo::from_reader_best_video_stream(&inner)?;
-        let stream_index = info.streams[0].index;
-        tracing::trace!(%descriptor, stream_index=stream_index, "selected video stream");
+        let info = MediaInfo::from_reader(&inner)?;
+        let stream_indices = info
+            .streams
+            .iter()
+            .map(|stream_info| stream_info.index)
+            .collect::<Vec<_>>();
+        tracing::trace!(%descriptor, ?stream_indices, "selected streams");
 
         let (packet_tx, packet_rx) = mpsc::unbounded_channel();
         let (stop_tx, stop_rx) = mpsc::unbounded_channel();
 
         tracing::trace!(%descriptor, "starting stream reader");
         let handle =
-            thread::spawn(move || Self::run(inner, stream_index, packet_tx, stop_rx, is_file));
+            thread::spawn(move || Self::run(inner, stream_indices, packet_tx, stop_rx, mode));
         tracing::trace!(%descriptor, "started stream reader");
 
         Ok(Self {
@@ -44,32 +74,66 @@ impl StreamReader {
         })
     }
 
+    /// Describe the media at `location` without reading it. The duration
+    /// of the media is included.
+    pub async fn probe(descriptor: &MediaDescriptor, location: Location) -> Result<MediaInfo> {
+        tracing::trace!(%descriptor, "probing media");
+        let inner = backend::make_reader_with_sane_settings(location).await?;
+        let mut info = MediaInfo::from_reader(&inner)?;
+        // The duration is in `AV_TIME_BASE` units, and is not set for media
+        // that does not know its duration.
+        let duration = inner.input.duration();
+        if duration > 0 {
+            info.duration = Some(duration as f64 / video::ffmpeg::ffi::AV_TIME_BASE as f64);
+        }
+        Ok(info)
+    }
+
     pub async fn read(&mut self) -> Option<Result<video::Packet>> {
         self.packet_rx.recv().await
     }
 
     pub async fn stop(&mut self) {
-        if let Ok(()) = self.stop_tx.send(()) {
-            if let Some(handle) = self.handle.take() {
-                tracing::trace!("sending stop signal to stream reader");
-                // We do not wait for the reader to actually stop since it may
-                // be blocking.
-                task::spawn(task::spawn_blocking(|| {
-                    let _ = handle.join();
-                    tracing::trace!("stopped stream reader");
-                }));
-            }
+        tracing::trace!("sending stop signal to stream reader");
+        // Sending fails if the reader already stopped by itself, for example
+        // at the end of a file that is played once.
+        let _ = self.stop_tx.send(());
+        if let Some(handle) = self.handle.take() {
+            // We do not wait for the reader to actually stop since it may
+            // be blocking.
+            task::spawn(task::spawn_blocking(|| {
+                let _ = handle.join();
+                tracing::trace!("stopped stream reader");
+            }));
         }
     }
 
     fn run(
         mut reader: video::Reader,
-        stream_index: usize,
+        stream_indices: Vec<usize>,
         packet_tx: mpsc::UnboundedSender<Result<video::Packet>>,
         mut stop_rx: mpsc::UnboundedReceiver<()>,
-        is_file: bool,
+        mode: ReadMode,
     ) {
-        let mut times = Times::new();
+        let mut times = stream_indices
+            .iter()
+            .map(|stream_index| (*stream_index, Times::new()))
+            .collect::<HashMap<_, _>>();
+        let mut start = Instant::now();
+        // Decode time of the first packet when playing once, since the file
+        // timestamps are kept and playback may start anywhere in the file.
+        let mut first_dts = None;
+
+        if let ReadMode::Once { start, .. } = mode {
+            if start > 0.0 {
+                tracing::trace!(start, "seeking in file");
+                if let Err(err) = reader.seek((start * 1000.0) as i64) {
+                    tracing::error!(%err, "failed to seek in file");
+                    let _ = packet_tx.send(Err(err));
+                    return;
+                }
+            }
+        }
 
         loop {
             match stop_rx.try_recv() {
@@ -80,27 +144,47 @@ impl StreamReader {
                 Err(mpsc::error::TryRecvError::Empty) => {}
             };
 
-            let read = reader.read(stream_index);
-
-            if is_file {
-                // To pretend the file is a live stream, we need to wait a bit after
-                // each packet or we'll overload the consumer.
-                if let Ok(packet) = read.as_ref() {
-                    thread::sleep(packet.duration().into());
-                }
-            }
-
-            let packet = match read {
+            let packet = match Self::read_packet(&mut reader, &stream_indices) {
                 // Forward OK packets.
                 Ok(mut packet) => {
-                    // Manually keep time for file-based streams. This way we can seek
-                    // in the file and pretend that time is still running linearly.
-                    if is_file {
-                        times.update(&mut packet);
+                    match mode {
+                        ReadMode::Live => {}
+                        ReadMode::Loop => {
+                            // Manually keep time for file-based streams. This way we can seek
+                            // in the file and pretend that time is still running linearly.
+                            if let Some(times) = times.get_mut(&packet.stream_index()) {
+                                times.update(&mut packet);
+                            }
+                            // To pretend the file is a live stream, we need to wait until
+                            // the packet is due or we'll overload the consumer. Packets of
+                            // all streams are interleaved, so they are paced by their decode
+                            // time rather than their duration.
+                            let due = start
+                                + Duration::from_secs_f64(packet.dts().as_secs_f64().max(0.0));
+                            thread::sleep(due.saturating_duration_since(Instant::now()));
+                        }
+                        ReadMode::Once { end, .. } => {
+                            let dts = packet.dts().as_secs_f64();
+                            if end.is_some_and(|end| dts >= end) {
+                                tracing::trace!("reached end of range");
+                                break;
+                            }
+                            let first_dts = *first_dts.get_or_insert_with(|| {
+                                start = Instant::now();
+                                dts
+                            });
+                            let due = start + Duration::from_secs_f64((dts - first_dts).max(0.0));
+                            thread::sleep(due.saturating_duration_since(Instant::now()));
+                        }
                     }
 
                     Some(Ok(packet))
                 }
+                // Files that are played once end here, which closes the channel.
+                Err(video::Error::ReadExhausted) if matches!(mode, ReadMode::Once { .. }) => {
+                    tracing::trace!("reached end of file");
+                    break;
+                }
                 // If the error was caused by an exhausted stream, try and see if we
                 // can seek to the beginning of the file and then just keep reading:
                 // we don't send a packet and just continue the loop in that case. If
@@ -127,6 +211,21 @@ impl StreamReader {
             }
         }
     }
+
+    /// Read the next packet of any of the given streams. Packets of other
+    /// streams are skipped.
+    fn read_packet(reader: &mut video::Reader, stream_indices: &[usize]) -> Result<video::Packet> {
+        loop {
+            let (stream, packet) = reader
+                .input
+                .packets()
+                .next()
+                .ok_or(video::Error::ReadExhausted)?;
+            if stream_indices.contains(&stream.index()) {
+                return Ok(video::Packet::new(packet, stream.time_base()));
+            }
+        }
+    }
 }
 
 impl Drop for StreamReader {
@@ -0,0 +1,807 @@
+//! Pure Rust RTP packetizer and depacketizer for H.264 (RFC 6184).
+//!
+//! Supports single NAL unit packets, STAP-A aggregation packets and FU-A
+//! fragmentation units. Input of the packetizer is either in Annex B
+//! format (start codes) or AVCC format (length prefixed NAL units). The
+//! depacketizer always produces Annex B.
+
+/// Default maximum size of a single RTP packet, including the RTP header.
+/// This leaves plenty of room for IP, UDP and RTSP interleaved headers on
+/// a regular 1500 byte Ethernet link.
+pub const DEFAULT_MTU: usize = 1400;
+
+/// Size of the fixed RTP header (RFC 3550 Section 5.1).
+const RTP_HEADER_LEN: usize = 12;
+
+/// RTP version 2, no padding, no extension, no CSRCs.
+const RTP_VERSION: u8 = 0x80;
+
+const NAL_TYPE_IDR: u8 = 5;
+const NAL_TYPE_SPS: u8 = 7;
+const NAL_TYPE_PPS: u8 = 8;
+const NAL_TYPE_AUD: u8 = 9;
+const NAL_TYPE_STAP_A: u8 = 24;
+const NAL_TYPE_FU_A: u8 = 28;
+
+/// Packetization mode as signaled in the SDP `fmtp` attribute (RFC 6184
+/// Section 6.2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketizationMode {
+    /// Only single NAL unit packets. NAL units that do not fit in a single
+    /// packet are sent as-is, and will be fragmented by IP.
+    SingleNal,
+    /// Single NAL unit packets, STAP-A and FU-A.
+    NonInterleaved,
+}
+
+impl PacketizationMode {
+    /// Packetization mode from its value in the SDP. Interleaved mode (2)
+    /// is not supported.
+    pub fn from_sdp_value(value: usize) -> Option<Self> {
+        match value {
+            0 => Some(PacketizationMode::SingleNal),
+            1 => Some(PacketizationMode::NonInterleaved),
+            _ => None,
+        }
+    }
+
+    /// Value of `packetization-mode` in the SDP.
+    pub fn as_sdp_value(&self) -> usize {
+        match self {
+            PacketizationMode::SingleNal => 0,
+            PacketizationMode::NonInterleaved => 1,
+        }
+    }
+}
+
+/// H.264 sequence and picture parameter sets of a stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParameterSets {
+    pub sps: Vec<u8>,
+    pub pps: Vec<Vec<u8>>,
+    /// Size of the NAL unit length prefix if the stream is in AVCC format,
+    /// or `None` if the stream uses Annex B start codes.
+    pub nal_length_size: Option<usize>,
+}
+
+impl ParameterSets {
+    /// Parse parameter sets from codec extradata. The extradata is either
+    /// an `AVCDecoderConfigurationRecord` (ISO/IEC 14496-15 Section 5.2.4)
+    /// or a sequence of NAL units in Annex B format.
+    pub fn from_extradata(extradata: &[u8]) -> Option<Self> {
+        if extradata.first() == Some(&1) {
+            Self::from_avcc(extradata)
+        } else {
+            Self::from_annex_b(extradata)
+        }
+    }
+
+    fn from_avcc(extradata: &[u8]) -> Option<Self> {
+        let nal_length_size = (*extradata.get(4)? & 0x03) as usize + 1;
+        let num_sps = (*extradata.get(5)? & 0x1f) as usize;
+        let mut offset = 6;
+
+        let mut sps = None;
+        for _ in 0..num_sps {
+            let (nal, next) = read_length_prefixed(extradata, offset, 2)?;
+            // Only the first SPS is used, just like most encoders do.
+            sps.get_or_insert_with(|| nal.to_vec());
+            offset = next;
+        }
+
+        let num_pps = *extradata.get(offset)? as usize;
+        offset += 1;
+        let mut pps = Vec::with_capacity(num_pps);
+        for _ in 0..num_pps {
+            let (nal, next) = read_length_prefixed(extradata, offset, 2)?;
+            pps.push(nal.to_vec());
+            offset = next;
+        }
+
+        Some(Self {
+            sps: sps?,
+            pps,
+            nal_length_size: Some(nal_length_size),
+        })
+    }
+
+    fn from_annex_b(extradata: &[u8]) -> Option<Self> {
+        let mut sps = None;
+        let mut pps = Vec::new();
+        for nal in split_annex_b(extradata) {
+            match nal_type(nal) {
+                NAL_TYPE_SPS => {
+                    sps.get_or_insert_with(|| nal.to_vec());
+                }
+                NAL_TYPE_PPS => pps.push(nal.to_vec()),
+                _ => {}
+            }
+        }
+
+        Some(Self {
+            sps: sps?,
+            pps,
+            nal_length_size: None,
+        })
+    }
+}
+
+/// Packetizes H.264 access units into RTP packets.
+pub struct H264Packetizer {
+    payload_type: u8,
+    ssrc: u32,
+    seq: u16,
+    mtu: usize,
+    mode: PacketizationMode,
+    parameter_sets: Option<ParameterSets>,
+    first_packet_sent: bool,
+}
+
+impl H264Packetizer {
+    pub fn new(payload_type: u8, ssrc: u32, initial_seq: u16) -> Self {
+        Self {
+            payload_type,
+            ssrc,
+            seq: initial_seq,
+            mtu: DEFAULT_MTU,
+            mode: PacketizationMode::NonInterleaved,
+            parameter_sets: None,
+            first_packet_sent: false,
+        }
+    }
+
+    /// Set the maximum size of RTP packets, including the RTP header.
+    pub fn with_mtu(mut self, mtu: usize) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    pub fn with_packetization_mode(mut self, mode: PacketizationMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Set the parameter sets of the stream. If set, the SPS and PPS are
+    /// sent in-band before every keyframe so that clients can start
+    /// decoding at any keyframe.
+    pub fn with_parameter_sets(mut self, parameter_sets: ParameterSets) -> Self {
+        self.parameter_sets = Some(parameter_sets);
+        self
+    }
+
+    /// Sequence number of the next packet.
+    #[inline]
+    pub fn seq(&self) -> u16 {
+        self.seq
+    }
+
+    /// Packetize a single access unit. All packets share the same RTP
+    /// timestamp, and the marker bit is set on the last packet.
+    pub fn packetize(&mut self, access_unit: &[u8], timestamp: u32, is_key: bool) -> Vec<Vec<u8>> {
+        let nal_length_size = self
+            .parameter_sets
+            .as_ref()
+            .and_then(|parameter_sets| parameter_sets.nal_length_size);
+        let nals = split_nal_units(access_unit, nal_length_size);
+
+        let has_parameter_sets = nals.iter().any(|nal| nal_type(nal) == NAL_TYPE_SPS);
+        let mut all_nals: Vec<&[u8]> = Vec::with_capacity(nals.len() + 2);
+        if (is_key || !self.first_packet_sent) && !has_parameter_sets {
+            if let Some(parameter_sets) = self.parameter_sets.as_ref() {
+                all_nals.push(&parameter_sets.sps);
+                all_nals.extend(parameter_sets.pps.iter().map(Vec::as_slice));
+            }
+        }
+        all_nals.extend(nals.into_iter().filter(|nal| nal_type(nal) != NAL_TYPE_AUD));
+
+        let payloads = match self.mode {
+            PacketizationMode::SingleNal => all_nals.iter().map(|nal| nal.to_vec()).collect(),
+            PacketizationMode::NonInterleaved => {
+                payloads_non_interleaved(&all_nals, self.mtu.saturating_sub(RTP_HEADER_LEN))
+            }
+        };
+
+        self.first_packet_sent = true;
+        let num_payloads = payloads.len();
+        payloads
+            .into_iter()
+            .enumerate()
+            .map(|(i, payload)| self.make_packet(&payload, timestamp, i + 1 == num_payloads))
+            .collect()
+    }
+
+    fn make_packet(&mut self, payload: &[u8], timestamp: u32, marker: bool) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(RTP_HEADER_LEN + payload.len());
+        packet.push(RTP_VERSION);
+        packet.push(((marker as u8) << 7) | (self.payload_type & 0x7f));
+        packet.extend_from_slice(&self.seq.to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        packet.extend_from_slice(payload);
+        self.seq = self.seq.wrapping_add(1);
+        packet
+    }
+}
+
+/// Access unit reassembled from RTP packets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessUnit {
+    /// NAL units of the access unit in Annex B format.
+    pub data: Vec<u8>,
+    pub timestamp: u32,
+    /// Whether or not the access unit contains an IDR picture.
+    pub is_key: bool,
+}
+
+/// Reassembles H.264 access units from RTP packets that were sent in
+/// packetization mode 0 or 1.
+///
+/// An access unit is complete when a packet with the marker bit comes
+/// in, or when the RTP timestamp changes (for senders that do not set
+/// the marker bit). Fragmented NAL units that lose a packet are dropped.
+#[derive(Default)]
+pub struct H264Depacketizer {
+    nals: Vec<Vec<u8>>,
+    fragment: Option<Vec<u8>>,
+    timestamp: Option<u32>,
+    last_seq: Option<u16>,
+}
+
+impl H264Depacketizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Depacketize a single RTP packet. Returns the access units that are
+    /// complete after this packet.
+    pub fn depacketize(&mut self, packet: &[u8]) -> Vec<AccessUnit> {
+        let mut access_units = Vec::new();
+        let (header, payload) = match parse_rtp(packet) {
+            Some(parsed) => parsed,
+            None => return access_units,
+        };
+
+        if self
+            .timestamp
+            .is_some_and(|timestamp| timestamp != header.timestamp)
+        {
+            access_units.extend(self.flush());
+        }
+        if self
+            .last_seq
+            .is_some_and(|last_seq| last_seq.wrapping_add(1) != header.seq)
+        {
+            // Without the missing packet, the fragment cannot be completed.
+            self.fragment = None;
+        }
+        self.last_seq = Some(header.seq);
+        self.timestamp = Some(header.timestamp);
+
+        match nal_type(payload) {
+            1..=23 => self.nals.push(payload.to_vec()),
+            NAL_TYPE_STAP_A => {
+                let mut offset = 1;
+                while let Some((nal, next)) = read_length_prefixed(payload, offset, 2) {
+                    if !nal.is_empty() {
+                        self.nals.push(nal.to_vec());
+                    }
+                    offset = next;
+                }
+            }
+            NAL_TYPE_FU_A if payload.len() > 2 => {
+                let fu_header = payload[1];
+                if fu_header & 0x80 != 0 {
+                    // Reconstruct the NAL unit header from the FU indicator and
+                    // FU header.
+                    let mut nal = vec![(payload[0] & 0xe0) | (fu_header & 0x1f)];
+                    nal.extend_from_slice(&payload[2..]);
+                    self.fragment = Some(nal);
+                } else if let Some(fragment) = self.fragment.as_mut() {
+                    fragment.extend_from_slice(&payload[2..]);
+                }
+                if fu_header & 0x40 != 0 {
+                    self.nals.extend(self.fragment.take());
+                }
+            }
+            // STAP-B, MTAP and FU-B are only used in interleaved mode.
+            _ => {}
+        }
+
+        if header.marker {
+            access_units.extend(self.flush());
+        }
+        access_units
+    }
+
+    fn flush(&mut self) -> Option<AccessUnit> {
+        let timestamp = self.timestamp.take()?;
+        if self.nals.is_empty() {
+            return None;
+        }
+        let is_key = self.nals.iter().any(|nal| nal_type(nal) == NAL_TYPE_IDR);
+        let mut data = Vec::new();
+        for nal in self.nals.drain(..) {
+            data.extend_from_slice(&[0, 0, 0, 1]);
+            data.extend_from_slice(&nal);
+        }
+        Some(AccessUnit {
+            data,
+            timestamp,
+            is_key,
+        })
+    }
+}
+
+struct RtpHeader {
+    marker: bool,
+    seq: u16,
+    timestamp: u32,
+}
+
+/// Parse the RTP header (RFC 3550 Section 5.1) and return it with the
+/// payload. CSRCs, header extensions and padding are skipped.
+fn parse_rtp(packet: &[u8]) -> Option<(RtpHeader, &[u8])> {
+    if packet.len() < RTP_HEADER_LEN || packet[0] >> 6 != 2 {
+        return None;
+    }
+    let num_csrcs = (packet[0] & 0x0f) as usize;
+    let mut offset = RTP_HEADER_LEN + 4 * num_csrcs;
+    if packet[0] & 0x10 != 0 {
+        let extension_len = packet.get(offset + 2..offset + 4)?;
+        offset += 4 + 4 * u16::from_be_bytes([extension_len[0], extension_len[1]]) as usize;
+    }
+    let mut end = packet.len();
+    if packet[0] & 0x20 != 0 {
+        end = end.checked_sub(*packet.last()? as usize)?;
+    }
+    let payload = packet
+        .get(offset..end)
+        .filter(|payload| !payload.is_empty())?;
+
+    Some((
+        RtpHeader {
+            marker: packet[1] & 0x80 != 0,
+            seq: u16::from_be_bytes([packet[2], packet[3]]),
+            timestamp: u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+        },
+        payload,
+    ))
+}
+
+/// Turn NAL units into RTP payloads for packetization mode 1. Small NAL
+/// units are aggregated into STAP-A packets, large ones are fragmented
+/// into FU-A packets.
+fn payloads_non_interleaved(nals: &[&[u8]], max_payload_len: usize) -> Vec<Vec<u8>> {
+    let mut payloads = Vec::new();
+    let mut aggregate: Vec<&[u8]> = Vec::new();
+    // Size of the STAP-A payload so far, including the STAP-A header.
+    let mut aggregate_len = 1;
+
+    for nal in nals.iter().filter(|nal| !nal.is_empty()) {
+        if nal.len() > max_payload_len {
+            flush_aggregate(&mut payloads, &mut aggregate);
+            aggregate_len = 1;
+            payloads.extend(fragment(nal, max_payload_len));
+            continue;
+        }
+
+        if aggregate_len + 2 + nal.len() > max_payload_len {
+            flush_aggregate(&mut payloads, &mut aggregate);
+            aggregate_len = 1;
+        }
+        aggregate.push(nal);
+        aggregate_len += 2 + nal.len();
+    }
+
+    flush_aggregate(&mut payloads, &mut aggregate);
+    payloads
+}
+
+/// Emit the pending NAL units as a STAP-A payload, or as a single NAL unit
+/// payload if there is just one (aggregating a single NAL unit is allowed
+/// but only adds overhead).
+fn flush_aggregate(payloads: &mut Vec<Vec<u8>>, aggregate: &mut Vec<&[u8]>) {
+    match aggregate.len() {
+        0 => {}
+        1 => payloads.push(aggregate[0].to_vec()),
+        _ => {
+            // F bit is the OR of all F bits and NRI is the maximum of all
+            // NRI values (RFC 6184 Section 5.7).
+            let f = aggregate.iter().fold(0, |f, nal| f | (nal[0] & 0x80));
+            let nri = aggregate.iter().map(|nal| nal[0] & 0x60).max().unwrap_or(0);
+            let mut payload = vec![f | nri | NAL_TYPE_STAP_A];
+            for nal in aggregate.iter() {
+                payload.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+                payload.extend_from_slice(nal);
+            }
+            payloads.push(payload);
+        }
+    }
+    aggregate.clear();
+}
+
+/// Fragment a NAL unit into FU-A payloads (RFC 6184 Section 5.8).
+fn fragment(nal: &[u8], max_payload_len: usize) -> Vec<Vec<u8>> {
+    let indicator = (nal[0] & 0xe0) | NAL_TYPE_FU_A;
+    let nal_type = nal[0] & 0x1f;
+    // FU indicator and FU header take up two bytes. The original NAL unit
+    // header is not sent, it is reconstructed from those two.
+    let chunk_len = max_payload_len.saturating_sub(2).max(1);
+    let chunks = nal[1..].chunks(chunk_len).collect::<Vec<_>>();
+    let num_chunks = chunks.len();
+
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let start = if i == 0 { 0x80 } else { 0x00 };
+            let end = if i + 1 == num_chunks { 0x40 } else { 0x00 };
+            let mut payload = Vec::with_capacity(2 + chunk.len());
+            payload.push(indicator);
+            payload.push(start | end | nal_type);
+            payload.extend_from_slice(chunk);
+            payload
+        })
+        .collect()
+}
+
+/// Split an access unit into NAL units. The NAL unit length size is known
+/// only if the extradata is AVCC, in which case the data is length-prefixed.
+/// Otherwise the data is Annex B. Length prefixes can look like start codes
+/// (a NAL unit of 1 or 256 bytes), so the data itself cannot tell.
+pub fn split_nal_units(data: &[u8], nal_length_size: Option<usize>) -> Vec<&[u8]> {
+    match nal_length_size {
+        Some(nal_length_size) => split_length_prefixed(data, nal_length_size),
+        None => split_annex_b(data),
+    }
+}
+
+fn split_annex_b(data: &[u8]) -> Vec<&[u8]> {
+    let mut nals = Vec::new();
+    let mut start = None;
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            if let Some(start) = start {
+                nals.push(trim_trailing_zeros(&data[start..i]));
+            }
+            i += 3;
+            start = Some(i);
+        } else {
+            i += 1;
+        }
+    }
+    match start {
+        Some(start) => nals.push(&data[start..]),
+        // No start codes at all, assume the data is a single NAL unit.
+        None => nals.push(data),
+    }
+    nals.into_iter().filter(|nal| !nal.is_empty()).collect()
+}
+
+fn split_length_prefixed(data: &[u8], nal_length_size: usize) -> Vec<&[u8]> {
+    let mut nals = Vec::new();
+    let mut offset = 0;
+    while let Some((nal, next)) = read_length_prefixed(data, offset, nal_length_size) {
+        if !nal.is_empty() {
+            nals.push(nal);
+        }
+        offset = next;
+    }
+    nals
+}
+
+fn read_length_prefixed(data: &[u8], offset: usize, length_size: usize) -> Option<(&[u8], usize)> {
+    let length_bytes = data.get(offset..offset + length_size)?;
+    let len = length_bytes
+        .iter()
+        .fold(0_usize, |len, byte| (len << 8) | *byte as usize);
+    let start = offset + length_size;
+    let nal = data.get(start..start + len)?;
+    Some((nal, start + len))
+}
+
+/// Four byte start codes show up as a trailing zero byte at the end of
+/// the previous NAL unit.
+fn trim_trailing_zeros(nal: &[u8]) -> &[u8] {
+    let end = nal
+        .iter()
+        .rposition(|byte| *byte != 0)
+        .map(|i| i + 1)
+        .unwrap_or(0);
+    &nal[..end]
+}
+
+#[inline]
+fn nal_type(nal: &[u8]) -> u8 {
+    nal.first().map(|header| header & 0x1f).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        split_nal_units, AccessUnit, H264Depacketizer, H264Packetizer, PacketizationMode,
+        ParameterSets, NAL_TYPE_FU_A, NAL_TYPE_STAP_A,
+    };
+
+    const SPS: [u8; 6] = [0x67, 0x42, 0xc0, 0x1f, 0xda, 0x01];
+    const PPS: [u8; 4] = [0x68, 0xce, 0x3c, 0x80];
+
+    fn idr(len: usize) -> Vec<u8> {
+        let mut nal = vec![0x65];
+        nal.extend((0..len - 1).map(|i| (i % 251) as u8 + 1));
+        nal
+    }
+
+    fn annex_b(nals: &[&[u8]]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for nal in nals {
+            data.extend_from_slice(&[0, 0, 0, 1]);
+            data.extend_from_slice(nal);
+        }
+        data
+    }
+
+    fn marker(packet: &[u8]) -> bool {
+        packet[1] & 0x80 != 0
+    }
+
+    fn seq(packet: &[u8]) -> u16 {
+        u16::from_be_bytes([packet[2], packet[3]])
+    }
+
+    #[test]
+    fn split_annex_b_three_and_four_byte_start_codes() {
+        let data = [
+            0, 0, 0, 1, 0x67, 0x42, 0, 0, 1, 0x68, 0xce, 0, 0, 0, 1, 0x65, 0x88,
+        ];
+        assert_eq!(
+            split_nal_units(&data, None),
+            vec![&[0x67, 0x42][..], &[0x68, 0xce][..], &[0x65, 0x88][..]],
+        );
+    }
+
+    #[test]
+    fn split_length_prefixed() {
+        let data = [0, 0, 0, 2, 0x67, 0x42, 0, 0, 0, 3, 0x65, 0x88, 0x84];
+        assert_eq!(
+            split_nal_units(&data, Some(4)),
+            vec![&[0x67, 0x42][..], &[0x65, 0x88, 0x84][..]],
+        );
+    }
+
+    #[test]
+    fn split_length_prefixed_that_looks_like_start_code() {
+        // A length of 300 is `00 00 01 2c`, and a length of 1 is
+        // `00 00 00 01`.
+        let slice = idr(300);
+        let mut data = vec![0, 0, 1, 0x2c];
+        data.extend_from_slice(&slice);
+        data.extend_from_slice(&[0, 0, 0, 1, 0x06]);
+        assert_eq!(
+            split_nal_units(&data, Some(4)),
+            vec![&slice[..], &[0x06][..]],
+        );
+
+        let mut data = vec![0, 0, 0, 1, 0x09];
+        data.extend_from_slice(&[0, 0, 1, 0x2c]);
+        data.extend_from_slice(&slice);
+        assert_eq!(
+            split_nal_units(&data, Some(4)),
+            vec![&[0x09][..], &slice[..]],
+        );
+    }
+
+    #[test]
+    fn parameter_sets_from_avcc_extradata() {
+        let mut extradata = vec![1, 0x42, 0xc0, 0x1f, 0xff, 0xe1];
+        extradata.extend_from_slice(&(SPS.len() as u16).to_be_bytes());
+        extradata.extend_from_slice(&SPS);
+        extradata.push(1);
+        extradata.extend_from_slice(&(PPS.len() as u16).to_be_bytes());
+        extradata.extend_from_slice(&PPS);
+        assert_eq!(
+            ParameterSets::from_extradata(&extradata),
+            Some(ParameterSets {
+                sps: SPS.to_vec(),
+                pps: vec![PPS.to_vec()],
+                nal_length_size: Some(4),
+            }),
+        );
+    }
+
+    #[test]
+    fn parameter_sets_from_annex_b_extradata() {
+        assert_eq!(
+            ParameterSets::from_extradata(&annex_b(&[&SPS, &PPS])),
+            Some(ParameterSets {
+                sps: SPS.to_vec(),
+                pps: vec![PPS.to_vec()],
+                nal_length_size: None,
+            }),
+        );
+    }
+
+    #[test]
+    fn packetize_single_nal() {
+        let mut packetizer = H264Packetizer::new(96, 0x12345678, 100);
+        let nal = [0x41, 0x9a, 0x02, 0x03];
+        let packets = packetizer.packetize(&annex_b(&[&nal]), 9000, false);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(
+            packets[0],
+            vec![
+                0x80, 0xe0, 0x00, 0x64, 0x00, 0x00, 0x23, 0x28, 0x12, 0x34, 0x56, 0x78, 0x41, 0x9a,
+                0x02, 0x03,
+            ],
+        );
+        assert_eq!(packetizer.seq(), 101);
+    }
+
+    #[test]
+    fn packetize_stap_a_with_parameter_sets() {
+        let parameter_sets = ParameterSets {
+            sps: SPS.to_vec(),
+            pps: vec![PPS.to_vec()],
+            nal_length_size: None,
+        };
+        let mut packetizer = H264Packetizer::new(96, 1, 0).with_parameter_sets(parameter_sets);
+        let nal = idr(20);
+        let packets = packetizer.packetize(&annex_b(&[&nal]), 0, true);
+        assert_eq!(packets.len(), 1);
+
+        let payload = &packets[0][12..];
+        assert_eq!(payload[0] & 0x1f, NAL_TYPE_STAP_A);
+        // NRI of the aggregation is the highest NRI of SPS, PPS and IDR.
+        assert_eq!(payload[0] & 0x60, 0x60);
+        let mut expected = vec![payload[0]];
+        for nal in [&SPS[..], &PPS[..], &nal[..]] {
+            expected.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+            expected.extend_from_slice(nal);
+        }
+        assert_eq!(payload, expected.as_slice());
+        assert!(marker(&packets[0]));
+    }
+
+    #[test]
+    fn packetize_fu_a() {
+        let mut packetizer = H264Packetizer::new(96, 1, u16::MAX).with_mtu(112);
+        let nal = idr(250);
+        let packets = packetizer.packetize(&annex_b(&[&nal]), 0, false);
+        assert_eq!(packets.len(), 3);
+
+        let mut reassembled = Vec::new();
+        for (i, packet) in packets.iter().enumerate() {
+            assert!(packet.len() <= 112);
+            let payload = &packet[12..];
+            assert_eq!(payload[0], (nal[0] & 0xe0) | NAL_TYPE_FU_A);
+            assert_eq!(payload[1] & 0x80 != 0, i == 0);
+            assert_eq!(payload[1] & 0x40 != 0, i == packets.len() - 1);
+            assert_eq!(payload[1] & 0x1f, nal[0] & 0x1f);
+            assert_eq!(marker(packet), i == packets.len() - 1);
+            if i == 0 {
+                reassembled.push((payload[0] & 0xe0) | (payload[1] & 0x1f));
+            }
+            reassembled.extend_from_slice(&payload[2..]);
+        }
+        assert_eq!(reassembled, nal);
+        assert_eq!(
+            packets.iter().map(|packet| seq(packet)).collect::<Vec<_>>(),
+            vec![u16::MAX, 0, 1],
+        );
+    }
+
+    #[test]
+    fn packetize_single_nal_mode_does_not_aggregate() {
+        let parameter_sets = ParameterSets {
+            sps: SPS.to_vec(),
+            pps: vec![PPS.to_vec()],
+            nal_length_size: None,
+        };
+        let mut packetizer = H264Packetizer::new(96, 1, 0)
+            .with_packetization_mode(PacketizationMode::SingleNal)
+            .with_parameter_sets(parameter_sets);
+        let nal = idr(20);
+        let packets = packetizer.packetize(&annex_b(&[&nal]), 0, true);
+        assert_eq!(packets.len(), 3);
+        assert_eq!(&packets[0][12..], &SPS);
+        assert_eq!(&packets[1][12..], &PPS);
+        assert_eq!(&packets[2][12..], nal.as_slice());
+        assert_eq!(
+            packets
+                .iter()
+                .map(|packet| marker(packet))
+                .collect::<Vec<_>>(),
+            vec![false, false, true],
+        );
+    }
+
+    #[test]
+    fn packetize_skips_parameter_sets_if_in_band() {
+        let parameter_sets = ParameterSets {
+            sps: SPS.to_vec(),
+            pps: vec![PPS.to_vec()],
+            nal_length_size: None,
+        };
+        let mut packetizer = H264Packetizer::new(96, 1, 0)
+            .with_packetization_mode(PacketizationMode::SingleNal)
+            .with_parameter_sets(parameter_sets);
+        let nal = idr(20);
+        let packets = packetizer.packetize(&annex_b(&[&SPS, &PPS, &nal]), 0, true);
+        assert_eq!(packets.len(), 3);
+        // Non-keyframes after the first packet never get parameter sets.
+        let packets = packetizer.packetize(&annex_b(&[&[0x41, 0x9a]]), 3000, false);
+        assert_eq!(packets.len(), 1);
+    }
+
+    #[test]
+    fn depacketize_packetized() {
+        let parameter_sets = ParameterSets {
+            sps: SPS.to_vec(),
+            pps: vec![PPS.to_vec()],
+            nal_length_size: None,
+        };
+        let mut packetizer = H264Packetizer::new(96, 1, u16::MAX - 1)
+            .with_mtu(112)
+            .with_parameter_sets(parameter_sets);
+        let mut depacketizer = H264Depacketizer::new();
+
+        // Parameter sets are aggregated into a STAP-A, and the IDR slice is
+        // fragmented.
+        let nal = idr(250);
+        let packets = packetizer.packetize(&annex_b(&[&nal]), 3000, true);
+        assert!(packets.len() > 2);
+        let access_units = packets
+            .iter()
+            .flat_map(|packet| depacketizer.depacketize(packet))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            access_units,
+            vec![AccessUnit {
+                data: annex_b(&[&SPS, &PPS, &nal]),
+                timestamp: 3000,
+                is_key: true,
+            }],
+        );
+
+        let nal = [0x41, 0x9a, 0x02, 0x03];
+        let packets = packetizer.packetize(&annex_b(&[&nal]), 6000, false);
+        assert_eq!(
+            depacketizer.depacketize(&packets[0]),
+            vec![AccessUnit {
+                data: annex_b(&[&nal]),
+                timestamp: 6000,
+                is_key: false,
+            }],
+        );
+    }
+
+    #[test]
+    fn depacketize_without_marker() {
+        let mut packetizer = H264Packetizer::new(96, 1, 0);
+        let mut depacketizer = H264Depacketizer::new();
+        let mut packet = packetizer.packetize(&annex_b(&[&[0x41, 0x01]]), 0, false)[0].clone();
+        // Clear the marker bit.
+        packet[1] &= 0x7f;
+        assert!(depacketizer.depacketize(&packet).is_empty());
+
+        let packets = packetizer.packetize(&annex_b(&[&[0x41, 0x02]]), 3000, false);
+        let access_units = depacketizer.depacketize(&packets[0]);
+        assert_eq!(access_units.len(), 2);
+        assert_eq!(access_units[0].data, annex_b(&[&[0x41, 0x01]]));
+        assert_eq!(access_units[0].timestamp, 0);
+        assert_eq!(access_units[1].data, annex_b(&[&[0x41, 0x02]]));
+        assert_eq!(access_units[1].timestamp, 3000);
+    }
+
+    #[test]
+    fn depacketize_drops_incomplete_fragment() {
+        let mut packetizer = H264Packetizer::new(96, 1, 0).with_mtu(112);
+        let mut depacketizer = H264Depacketizer::new();
+        let packets = packetizer.packetize(&annex_b(&[&idr(250)]), 0, true);
+        assert_eq!(packets.len(), 3);
+        // Lose the middle fragment.
+        assert!(depacketizer.depacketize(&packets[0]).is_empty());
+        assert!(depacketizer.depacketize(&packets[2]).is_empty());
+    }
+}
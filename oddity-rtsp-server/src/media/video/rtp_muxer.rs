@@ -1,97 +1,200 @@
-//! Async wrapper functions for [`video_rs::RtpMuxer`].
+//! RTP muxer built on the native H.264 packetizer in [`super::rtp_h264`]
+//! and the audio packetizer in [`crate::media::audio::rtp_audio`].
 
-use tokio::task;
+use rand::Rng;
 
 use video_rs as video;
-use video_rs::rtp::RtpMuxer as BlockingRtpMuxer;
-pub use video_rs::rtp::RtpMuxerBuilder;
+use video_rs::ffmpeg::Rational;
+
+use crate::media::audio::rtp_audio::{AudioPacketizer, AudioPayloadFormat};
+use crate::media::video::rtp_h264::{H264Packetizer, PacketizationMode, DEFAULT_MTU};
+use crate::media::{Codec, MediaInfo, StreamInfo, StreamState};
 
 type Result<T> = std::result::Result<T, video::Error>;
 
-pub async fn make_rtp_muxer_builder() -> Result<RtpMuxerBuilder> {
-    task::spawn_blocking(RtpMuxerBuilder::new).await.unwrap()
+/// Settings that control how packets are packetized into RTP. The same
+/// settings must be used to create the SDP, so that the packetization
+/// mode signaled to clients matches what the muxer does.
+#[derive(Debug, Clone, Copy)]
+pub struct RtpMuxerSettings {
+    pub mtu: usize,
+    pub packetization_mode: PacketizationMode,
 }
 
-pub struct RtpMuxer {
-    inner: BlockingRtpMuxer,
-    sps_packet_annex_b: Option<Vec<u8>>,
-    pps_packet_annex_b: Option<Vec<u8>>,
-    first_packet_sent: bool,
+impl Default for RtpMuxerSettings {
+    fn default() -> Self {
+        Self {
+            mtu: DEFAULT_MTU,
+            packetization_mode: PacketizationMode::NonInterleaved,
+        }
+    }
 }
 
-impl RtpMuxer {
-    pub fn from_builder(rtp_muxer_builder: RtpMuxerBuilder) -> Result<RtpMuxer> {
-        let blocking_muxer = rtp_muxer_builder.build();
+/// Create a muxer for all streams in the given media info.
+pub fn make_rtp_muxer(media_info: MediaInfo, settings: RtpMuxerSettings) -> Result<RtpMuxer> {
+    if media_info.streams.is_empty() {
+        return Err(video::Error::MissingCodecParameters);
+    }
+    Ok(RtpMuxer::new(media_info, settings))
+}
 
-        let mut parameter_sets = blocking_muxer.parameter_sets_h264();
-        let (sps_packet, pps_packet) = if !parameter_sets.is_empty() {
-            let (sps, ppss) = parameter_sets.remove(0)?;
+/// RTP packets produced by muxing a single media packet.
+pub struct MuxedPacket {
+    /// Index of the track in the media info.
+    pub track: usize,
+    pub bufs: Vec<video::rtp::RtpBuf>,
+    /// Whether or not clients can start decoding the track at this packet.
+    /// This is true for video keyframes, and for every audio frame.
+    pub is_key: bool,
+    /// RTP clock rate of the track.
+    pub clock_rate: u32,
+}
 
-            let mut sps_packet = (sps.len() as u32).to_be_bytes().to_vec(); // AVCC start code
-            sps_packet.extend_from_slice(sps);
+/// Muxes every stream of a source into its own RTP stream. Tracks are
+/// numbered in the order of the streams in the media info.
+pub struct RtpMuxer {
+    tracks: Vec<TrackMuxer>,
+}
 
-            let mut pps_packet = Vec::new();
-            for pps in ppss {
-                pps_packet.extend_from_slice(&(pps.len() as u32).to_be_bytes());
-                pps_packet.extend_from_slice(pps);
-            }
+impl RtpMuxer {
+    pub fn new(media_info: MediaInfo, settings: RtpMuxerSettings) -> RtpMuxer {
+        let tracks = media_info
+            .streams
+            .into_iter()
+            .map(|stream_info| {
+                tracing::trace!(stream_index = stream_info.index, "adding stream to muxer");
+                TrackMuxer::new(stream_info, settings)
+            })
+            .collect();
+        RtpMuxer { tracks }
+    }
 
-            (Some(sps_packet), Some(pps_packet))
-        } else {
-            (None, None)
-        };
+    /// Sequence number and timestamp of every track.
+    pub fn stream_states(&self) -> Vec<StreamState> {
+        self.tracks
+            .iter()
+            .map(|track| StreamState {
+                rtp_seq: track.packetizer.seq(),
+                rtp_timestamp: track.timestamp,
+            })
+            .collect()
+    }
+
+    /// Sequence number of the next packet and the timestamp at `time` (in
+    /// seconds of media time) of every track. This is where playback picks
+    /// up after seeking.
+    pub fn stream_states_at(&self, time: f64) -> Vec<StreamState> {
+        self.tracks
+            .iter()
+            .map(|track| StreamState {
+                rtp_seq: track.packetizer.seq(),
+                rtp_timestamp: track
+                    .timestamp_offset
+                    .wrapping_add((time * track.clock_rate as f64) as i64 as u32),
+            })
+            .collect()
+    }
 
-        Ok(RtpMuxer {
-            inner: blocking_muxer,
-            sps_packet_annex_b: sps_packet,
-            pps_packet_annex_b: pps_packet,
-            first_packet_sent: false,
+    /// Mux a packet into RTP. Returns `None` if the packet belongs to a
+    /// stream that is not muxed.
+    pub fn muxed(&mut self, packet: video::Packet) -> Option<MuxedPacket> {
+        let stream_index = packet.stream_index();
+        let track = self
+            .tracks
+            .iter()
+            .position(|track| track.stream_index == stream_index)?;
+        let (bufs, is_key) = self.tracks[track].muxed(packet);
+        Some(MuxedPacket {
+            track,
+            bufs,
+            is_key,
+            clock_rate: self.tracks[track].clock_rate,
         })
     }
+}
+
+enum Packetizer {
+    H264(H264Packetizer),
+    Audio(AudioPacketizer),
+}
 
-    #[inline]
-    pub fn seq_and_timestamp(&self) -> (u16, u32) {
-        self.inner.seq_and_timestamp()
+impl Packetizer {
+    fn seq(&self) -> u16 {
+        match self {
+            Packetizer::H264(packetizer) => packetizer.seq(),
+            Packetizer::Audio(packetizer) => packetizer.seq(),
+        }
     }
+}
 
-    pub async fn muxed(
-        mut self,
-        packet: video::Packet,
-    ) -> (RtpMuxer, Result<Vec<video::rtp::RtpBuf>>) {
-        task::spawn_blocking(move || {
-            let packet = if (packet.is_key() || !self.first_packet_sent)
-                && (self.sps_packet_annex_b.is_some() || self.pps_packet_annex_b.is_some())
-            {
-                let old_dts = packet.dts();
-                let old_pts = packet.pts();
-                let (old_packet, time_base) = packet.into_inner_parts();
-                let mut new_packet_data = Vec::new();
-                if let Some(sps_packet_data) = &self.sps_packet_annex_b {
-                    new_packet_data.extend_from_slice(sps_packet_data);
-                }
-                if let Some(pps_packet_data) = &self.pps_packet_annex_b {
-                    new_packet_data.extend_from_slice(pps_packet_data);
-                }
-                new_packet_data.extend_from_slice(old_packet.data().unwrap());
-                let mut new_packet =
-                    video::Packet::new(video::ffmpeg::Packet::copy(&new_packet_data), time_base);
-                new_packet.set_dts(old_dts);
-                new_packet.set_pts(old_pts);
-                new_packet
-            } else {
-                packet
-            };
-            let out = self.inner.mux(packet);
-            self.first_packet_sent = true;
-            (self, out)
-        })
-        .await
-        .unwrap()
+struct TrackMuxer {
+    stream_index: usize,
+    packetizer: Packetizer,
+    clock_rate: u32,
+    timestamp_offset: u32,
+    timestamp: u32,
+}
+
+impl TrackMuxer {
+    fn new(stream_info: StreamInfo, settings: RtpMuxerSettings) -> Self {
+        let payload_type = stream_info.codec.payload_type();
+        let clock_rate = stream_info.codec.clock_rate();
+
+        // Random initial SSRC, sequence number and timestamp as per RFC 3550
+        // Section 5.1.
+        let mut rng = rand::thread_rng();
+        let (ssrc, initial_seq) = (rng.gen(), rng.gen());
+        let audio = |format| {
+            Packetizer::Audio(
+                AudioPacketizer::new(format, payload_type, ssrc, initial_seq)
+                    .with_mtu(settings.mtu),
+            )
+        };
+        let packetizer = match stream_info.codec {
+            Codec::H264(parameter_sets) => Packetizer::H264(
+                H264Packetizer::new(payload_type, ssrc, initial_seq)
+                    .with_mtu(settings.mtu)
+                    .with_packetization_mode(settings.packetization_mode)
+                    .with_parameter_sets(parameter_sets),
+            ),
+            Codec::Aac { .. } => audio(AudioPayloadFormat::Aac),
+            Codec::Opus { .. } => audio(AudioPayloadFormat::Opus),
+            Codec::G711 { channels, .. } => audio(AudioPayloadFormat::G711 {
+                channels: channels as usize,
+            }),
+        };
+        let timestamp_offset = rng.gen();
+
+        Self {
+            stream_index: stream_info.index,
+            packetizer,
+            clock_rate,
+            timestamp_offset,
+            timestamp: timestamp_offset,
+        }
     }
 
-    pub async fn finish(mut self) -> Result<Option<Vec<video::rtp::RtpBuf>>> {
-        task::spawn_blocking(move || self.inner.finish())
-            .await
-            .unwrap()
+    fn muxed(&mut self, packet: video::Packet) -> (Vec<video::rtp::RtpBuf>, bool) {
+        let is_key = packet.is_key();
+        // Use the presentation timestamp and fall back to the decode timestamp
+        // (or the previous timestamp) if the packet does not have one.
+        let time = [packet.pts(), packet.dts()].into_iter().find_map(|time| {
+            time.aligned_with_rational(Rational::new(1, self.clock_rate as i32))
+                .into_value()
+        });
+        if let Some(time) = time {
+            self.timestamp = self.timestamp_offset.wrapping_add(time as u32);
+        }
+
+        let (packet, _) = packet.into_inner_parts();
+        let data = packet.data().unwrap_or_default();
+        let (payloads, is_key) = match &mut self.packetizer {
+            Packetizer::H264(packetizer) => {
+                (packetizer.packetize(data, self.timestamp, is_key), is_key)
+            }
+            Packetizer::Audio(packetizer) => (packetizer.packetize(data, self.timestamp), true),
+        };
+        let bufs = payloads.into_iter().map(video::rtp::RtpBuf::Rtp).collect();
+        (bufs, is_key)
     }
 }
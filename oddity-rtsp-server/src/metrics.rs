@@ -0,0 +1,264 @@
+//! Counters and gauges to monitor the server with, rendered in the
+//! Prometheus text exposition format.
+//!
+//! Counters of a source are kept by path, and live on after the source
+//! is gone so that they do not go backwards when a source with the same
+//! path comes back. Counters of sources that clients published to paths
+//! that are not configured are dropped with the source, since there is no
+//! end to such paths.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[derive(Default)]
+pub struct Metrics {
+    connections_active: AtomicI64,
+    connections_total: AtomicU64,
+    /// Number of RTSP responses by status code.
+    responses: Mutex<BTreeMap<usize, u64>>,
+    sources: Mutex<BTreeMap<String, Arc<SourceMetrics>>>,
+}
+
+/// Name and help text of the counters of a source, in the order of
+/// [`SourceMetrics::values`].
+const SOURCE_COUNTERS: [(&str, &str); 5] = [
+    (
+        "oddity_source_restarts_total",
+        "Times a source reconnected to its stream after it broke.",
+    ),
+    (
+        "oddity_source_packets_broadcast_total",
+        "RTP and RTCP packets broadcast to the sessions of a source.",
+    ),
+    (
+        "oddity_source_bytes_broadcast_total",
+        "Bytes of RTP and RTCP broadcast to the sessions of a source.",
+    ),
+    (
+        "oddity_source_mux_errors_total",
+        "Times the muxer of a source could not be initialized.",
+    ),
+    (
+        "oddity_source_lag_events_total",
+        "Times a receiver of the packets of a source fell behind.",
+    ),
+];
+
+/// Counters of a single source.
+#[derive(Default)]
+pub struct SourceMetrics {
+    restarts: AtomicU64,
+    packets_broadcast: AtomicU64,
+    bytes_broadcast: AtomicU64,
+    mux_errors: AtomicU64,
+    lag_events: AtomicU64,
+}
+
+impl SourceMetrics {
+    /// The source reconnected to its stream after it broke.
+    pub fn restarted(&self) {
+        self.restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// An RTP or RTCP packet of `len` bytes was broadcast to the sessions
+    /// of the source.
+    pub fn broadcast(&self, len: usize) {
+        self.packets_broadcast.fetch_add(1, Ordering::Relaxed);
+        self.bytes_broadcast
+            .fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// The muxer of the source could not be initialized.
+    pub fn mux_error(&self) {
+        self.mux_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A receiver of the packets of the source fell behind and missed
+    /// some of them.
+    pub fn lagged(&self) {
+        self.lag_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn values(&self) -> [u64; 5] {
+        [
+            self.restarts.load(Ordering::Relaxed),
+            self.packets_broadcast.load(Ordering::Relaxed),
+            self.bytes_broadcast.load(Ordering::Relaxed),
+            self.mux_errors.load(Ordering::Relaxed),
+            self.lag_events.load(Ordering::Relaxed),
+        ]
+    }
+}
+
+impl Metrics {
+    pub fn connection_opened(&self) {
+        self.connections_active.fetch_add(1, Ordering::Relaxed);
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections_active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn response(&self, status: usize) {
+        *lock(&self.responses).entry(status).or_default() += 1;
+    }
+
+    /// Counters of the source at `path`.
+    pub fn source(&self, path: &str) -> Arc<SourceMetrics> {
+        lock(&self.sources)
+            .entry(path.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Drop the counters of the source at `path`.
+    pub fn remove_source(&self, path: &str) {
+        let _ = lock(&self.sources).remove(path);
+    }
+
+    /// Render all metrics. The number of sessions per path is only known
+    /// to the caller, so it is passed in.
+    pub fn render(&self, sessions_per_path: &BTreeMap<String, usize>) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "oddity_connections_active",
+            "gauge",
+            "Open RTSP connections.",
+        );
+        let _ = writeln!(
+            out,
+            "oddity_connections_active {}",
+            self.connections_active.load(Ordering::Relaxed)
+        );
+        header(
+            &mut out,
+            "oddity_connections_total",
+            "counter",
+            "RTSP connections accepted.",
+        );
+        let _ = writeln!(
+            out,
+            "oddity_connections_total {}",
+            self.connections_total.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "oddity_sessions_active",
+            "gauge",
+            "Sessions by source path.",
+        );
+        for (path, count) in sessions_per_path {
+            let _ = writeln!(
+                out,
+                "oddity_sessions_active{{path=\"{}\"}} {}",
+                escape(path),
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "oddity_rtsp_responses_total",
+            "counter",
+            "RTSP responses by status code.",
+        );
+        for (status, count) in lock(&self.responses).iter() {
+            let _ = writeln!(
+                out,
+                "oddity_rtsp_responses_total{{status=\"{}\"}} {}",
+                status, count
+            );
+        }
+
+        let sources = lock(&self.sources)
+            .iter()
+            .map(|(path, metrics)| (path.clone(), metrics.values()))
+            .collect::<Vec<_>>();
+        for (index, (name, help)) in SOURCE_COUNTERS.iter().enumerate() {
+            header(&mut out, name, "counter", help);
+            for (path, values) in sources.iter() {
+                let _ = writeln!(
+                    out,
+                    "{}{{path=\"{}\"}} {}",
+                    name,
+                    escape(path),
+                    values[index]
+                );
+            }
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escape a label value (backslash, double quote and line feed).
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // Nothing panics while holding the lock, and the metrics are still
+    // good enough if something did.
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_label_value() {
+        assert_eq!(escape("/cam"), "/cam");
+        assert_eq!(escape("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+    }
+
+    #[test]
+    fn render_counters() {
+        let metrics = Metrics::default();
+        metrics.connection_opened();
+        metrics.connection_opened();
+        metrics.connection_closed();
+        metrics.response(200);
+        metrics.response(200);
+        metrics.response(404);
+        metrics.source("/cam").broadcast(100);
+        metrics.source("/cam").broadcast(50);
+        metrics.source("/pub\"lish").restarted();
+
+        let out = metrics.render(&BTreeMap::from([("/cam".to_string(), 3)]));
+        let lines = out.lines().collect::<Vec<_>>();
+        for line in [
+            "# HELP oddity_connections_active Open RTSP connections.",
+            "# TYPE oddity_connections_active gauge",
+            "oddity_connections_active 1",
+            "oddity_connections_total 2",
+            "oddity_sessions_active{path=\"/cam\"} 3",
+            "oddity_rtsp_responses_total{status=\"200\"} 2",
+            "oddity_rtsp_responses_total{status=\"404\"} 1",
+            "oddity_source_packets_broadcast_total{path=\"/cam\"} 2",
+            "oddity_source_bytes_broadcast_total{path=\"/cam\"} 150",
+            "oddity_source_restarts_total{path=\"/pub\\\"lish\"} 1",
+        ] {
+            assert!(lines.contains(&line), "missing line: {line}");
+        }
+
+        metrics.remove_source("/pub\"lish");
+        assert!(!metrics.render(&BTreeMap::new()).contains("/pub"));
+    }
+}
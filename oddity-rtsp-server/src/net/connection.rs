@@ -1,20 +1,35 @@
+use std::collections::HashMap;
 use std::fmt;
 use std::io::ErrorKind;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
+use bytes::{Bytes, BytesMut};
+
+use futures::future::{self, Future};
 use futures::SinkExt;
 
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net;
 use tokio::select;
 use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::{SendError, TrySendError};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::UnboundedReceiverStream;
 use tokio_stream::StreamExt;
 use tokio_util::codec;
+use tokio_util::io::StreamReader;
 
 use oddity_rtsp_protocol::{
     AsServer, Codec, Error, RequestMaybeInterleaved, ResponseMaybeInterleaved,
 };
 
 use crate::net::handler::Handler;
+use crate::net::tunnel::{self, Base64Decoder, HttpMethod, TunnelError, TunnelTx, Tunnels};
+use crate::net::websocket;
 use crate::runtime::task_manager::{Task, TaskContext};
 use crate::runtime::Runtime;
 
@@ -26,22 +41,159 @@ pub enum ConnectionState {
 pub type ConnectionStateTx = mpsc::UnboundedSender<ConnectionState>;
 pub type ConnectionStateRx = mpsc::UnboundedReceiver<ConnectionState>;
 
-pub type ResponseSenderTx = mpsc::UnboundedSender<ResponseMaybeInterleaved>;
-pub type ResponseSenderRx = mpsc::UnboundedReceiver<ResponseMaybeInterleaved>;
+pub type ResponseSenderRx = mpsc::Receiver<ResponseMaybeInterleaved>;
+
+/// Sends messages, such as interleaved media, to the client over the
+/// connection. The queue is bounded so that a client that does not read
+/// fast enough cannot make the server buffer without limit. What sessions
+/// do when the queue is full is up to the slow client policy.
+#[derive(Debug, Clone)]
+pub struct ResponseSenderTx {
+    tx: mpsc::Sender<ResponseMaybeInterleaved>,
+    policy: SlowClientPolicy,
+    disconnect: Arc<Notify>,
+}
+
+impl ResponseSenderTx {
+    fn new(tx: mpsc::Sender<ResponseMaybeInterleaved>, policy: SlowClientPolicy) -> Self {
+        Self {
+            tx,
+            policy,
+            disconnect: Arc::new(Notify::new()),
+        }
+    }
+
+    #[inline]
+    pub fn policy(&self) -> SlowClientPolicy {
+        self.policy
+    }
+
+    /// Send a message, waiting for room in the queue if it is full.
+    pub async fn send(
+        &self,
+        message: ResponseMaybeInterleaved,
+    ) -> Result<(), SendError<ResponseMaybeInterleaved>> {
+        self.tx.send(message).await
+    }
+
+    /// Send a message, unless the queue is full.
+    pub fn try_send(
+        &self,
+        message: ResponseMaybeInterleaved,
+    ) -> Result<(), TrySendError<ResponseMaybeInterleaved>> {
+        self.tx.try_send(message)
+    }
+
+    /// Whether or not the queue is at most half full.
+    pub fn is_draining(&self) -> bool {
+        self.tx.capacity() * 2 >= self.tx.max_capacity()
+    }
+
+    /// Number of messages in the queue.
+    pub fn queued(&self) -> usize {
+        self.tx.max_capacity() - self.tx.capacity()
+    }
+
+    /// Close the connection, because the client cannot keep up.
+    pub fn disconnect(&self) {
+        self.disconnect.notify_one();
+    }
+
+    /// Whether or not both senders send over the same connection.
+    pub fn same_channel(&self, other: &Self) -> bool {
+        self.tx.same_channel(&other.tx)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// Wait for the connection to close.
+    pub async fn closed(&self) {
+        self.tx.closed().await
+    }
+}
+
+/// What sessions do with media for a client whose send queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowClientPolicy {
+    /// Drop media until the next keyframe, at which the client can start
+    /// decoding again.
+    DropUntilKeyframe,
+    /// Drop media until the next keyframe, but disconnect the client if it
+    /// has not caught up after the given time.
+    Disconnect(Duration),
+    /// Wait for the client. The sessions of the client fall behind their
+    /// sources, and skip ahead to the next keyframe when they are too far
+    /// behind.
+    Block,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SendQueueSettings {
+    /// Maximum number of messages queued per connection.
+    pub size: usize,
+    pub slow_client_policy: SlowClientPolicy,
+}
+
+impl Default for SendQueueSettings {
+    fn default() -> Self {
+        Self {
+            size: 4096,
+            slow_client_policy: SlowClientPolicy::DropUntilKeyframe,
+        }
+    }
+}
+
+pub type InterleavedTx = mpsc::UnboundedSender<Bytes>;
+pub type InterleavedRx = mpsc::UnboundedReceiver<Bytes>;
+
+/// Where to deliver interleaved data that the client sends over the
+/// connection, by channel. Channels are added when the client sets up a
+/// session to publish media over the connection.
+pub type InterleavedRoutes = HashMap<u8, InterleavedTx>;
+
+/// What clients speak on a connection, by the listener it came in on.
+pub enum ConnectionKind {
+    /// RTSP, or RTSP tunneled over HTTP.
+    Plain,
+    /// RTSP over TLS (`rtsps://`), after the TLS handshake. Clients can
+    /// tunnel over HTTP inside TLS as well.
+    Tls(TlsAcceptor),
+    /// RTSP over WebSocket, after the WebSocket handshake. See
+    /// [`websocket`].
+    WebSocket,
+}
 
 pub struct Connection {
     worker: Task,
 }
 
 impl Connection {
+    /// Connections that the client has not sent anything over in this time
+    /// are closed. See RFC 2326 Section 12.37.
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// Clients that do not finish the TLS or WebSocket handshake in this
+    /// time are disconnected.
+    const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Start serving the client on `inner`, which speaks what `kind` says.
+    /// Clients can tunnel RTSP over HTTP on plain and TLS connections, see
+    /// [`tunnel`].
+    #[allow(clippy::too_many_arguments)]
     pub async fn start(
         id: ConnectionId,
         inner: net::TcpStream,
+        kind: ConnectionKind,
         handler: Arc<Handler>,
+        tunnels: Tunnels,
+        send_queue_settings: SendQueueSettings,
         state_tx: ConnectionStateTx,
         runtime: &Runtime,
     ) -> Self {
-        let (sender_tx, sender_rx) = mpsc::unbounded_channel();
+        let (sender_tx, sender_rx) = mpsc::channel(send_queue_settings.size);
+        let sender_tx = ResponseSenderTx::new(sender_tx, send_queue_settings.slow_client_policy);
 
         tracing::trace!(%id, "starting connection");
         let worker = runtime
@@ -50,7 +202,9 @@ impl Connection {
                 Self::run(
                     id,
                     inner,
+                    kind,
                     handler,
+                    tunnels,
                     state_tx,
                     sender_tx,
                     sender_rx,
@@ -69,120 +223,495 @@ impl Connection {
         tracing::trace!("closed connection");
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn run(
         id: ConnectionId,
         inner: net::TcpStream,
+        kind: ConnectionKind,
         handler: Arc<Handler>,
+        tunnels: Tunnels,
         state_tx: ConnectionStateTx,
         response_tx: ResponseSenderTx,
-        mut response_rx: ResponseSenderRx,
+        response_rx: ResponseSenderRx,
         mut task_context: TaskContext,
     ) {
-        let mut disconnected = false;
+        let peer_addr = inner.peer_addr().ok();
+        let worker = Worker {
+            id,
+            addr: peer_addr
+                .map(|peer_addr| peer_addr.to_string())
+                .unwrap_or("?".to_string()),
+            peer_addr,
+            secure: matches!(kind, ConnectionKind::Tls(_)),
+            handler,
+            tunnels,
+        };
+        let addr = worker.addr.as_str();
+
+        let disconnected = match kind {
+            ConnectionKind::Tls(acceptor) => {
+                select! {
+                    // CANCEL SAFETY: The handshake is simply abandoned when
+                    // the worker stops, and the connection with it.
+                    stream = tokio::time::timeout(Self::HANDSHAKE_TIMEOUT, acceptor.accept(inner)) => {
+                        match stream {
+                            Ok(Ok(stream)) => {
+                                tracing::trace!(%id, %addr, "connection: tls handshake done");
+                                worker
+                                    .serve_stream(stream, response_tx, response_rx, &mut task_context)
+                                    .await
+                            },
+                            Ok(Err(err)) => {
+                                tracing::info!(%err, %id, %addr, "connection: tls handshake failed");
+                                true
+                            },
+                            Err(_) => {
+                                tracing::info!(%id, %addr, "connection: timed out during tls handshake");
+                                true
+                            },
+                        }
+                    },
+                    // CANCEL SAFETY: `TaskContext::wait_for_stop` is cancel safe.
+                    _ = task_context.wait_for_stop() => {
+                        tracing::trace!(%id, %addr, "connection worker stopping");
+                        false
+                    },
+                }
+            }
+            ConnectionKind::WebSocket => {
+                select! {
+                    // CANCEL SAFETY: The handshake is simply abandoned when
+                    // the worker stops, and the connection with it.
+                    websocket = tokio::time::timeout(Self::HANDSHAKE_TIMEOUT, websocket::accept(inner)) => {
+                        match websocket {
+                            Ok(Ok(websocket)) => {
+                                tracing::trace!(%id, %addr, "connection: websocket handshake done");
+                                let (read, write) = websocket::split(websocket);
+                                let inbound = codec::FramedRead::new(read, Codec::<AsServer>::new());
+                                let outbound = codec::FramedWrite::new(write, Codec::<AsServer>::new());
+                                worker
+                                    .serve(
+                                        inbound,
+                                        outbound,
+                                        future::pending(),
+                                        response_tx,
+                                        response_rx,
+                                        &mut task_context,
+                                    )
+                                    .await
+                            },
+                            Ok(Err(err)) => {
+                                tracing::info!(%err, %id, %addr, "connection: websocket handshake failed");
+                                true
+                            },
+                            Err(_) => {
+                                tracing::info!(%id, %addr, "connection: timed out during websocket handshake");
+                                true
+                            },
+                        }
+                    },
+                    // CANCEL SAFETY: `TaskContext::wait_for_stop` is cancel safe.
+                    _ = task_context.wait_for_stop() => {
+                        tracing::trace!(%id, %addr, "connection worker stopping");
+                        false
+                    },
+                }
+            }
+            ConnectionKind::Plain => {
+                worker
+                    .serve_stream(inner, response_tx, response_rx, &mut task_context)
+                    .await
+            }
+        };
+
+        if disconnected {
+            // Client disconnected.
+            let _ = state_tx.send(ConnectionState::Disconnected(id));
+        } else {
+            // Reason for breaking out of loop was unexpected and not due to the
+            // client disconnecting.
+            let _ = state_tx.send(ConnectionState::Closed(id));
+        }
+        tracing::trace!(%id, %addr, "connection worker EOL");
+    }
+}
+
+/// Serves the client of a connection.
+struct Worker {
+    id: ConnectionId,
+    addr: String,
+    peer_addr: Option<SocketAddr>,
+    /// Whether the connection is encrypted.
+    secure: bool,
+    handler: Arc<Handler>,
+    tunnels: Tunnels,
+}
+
+impl Worker {
+    /// Serve plain RTSP on the stream, or one side of an HTTP tunnel if the
+    /// client starts with an HTTP request. Returns `true` if the client
+    /// disconnected.
+    async fn serve_stream<S: AsyncRead + AsyncWrite>(
+        &self,
+        inner: S,
+        response_tx: ResponseSenderTx,
+        response_rx: ResponseSenderRx,
+        task_context: &mut TaskContext,
+    ) -> bool {
+        let Worker { id, addr, .. } = self;
+        let (mut read, mut write) = io::split(inner);
+        let mut buffer = BytesMut::new();
 
-        let addr = inner
-            .peer_addr()
-            .map(|peer_addr| peer_addr.to_string())
-            .unwrap_or("?".to_string());
-        let (read, write) = inner.into_split();
-        let mut inbound = codec::FramedRead::new(read, Codec::<AsServer>::new());
-        let mut outbound = codec::FramedWrite::new(write, Codec::<AsServer>::new());
+        let http_request = select! {
+            // CANCEL SAFETY: Whatever was read is lost, but so is the
+            // connection when the worker stops.
+            http_request = tokio::time::timeout(Connection::TIMEOUT, tunnel::accept(&mut read, &mut buffer)) => {
+                match http_request {
+                    Ok(Ok(http_request)) => http_request,
+                    Ok(Err(err)) => {
+                        tracing::info!(%err, %id, %addr, "connection: failed to read http request");
+                        let _ = write.write_all(tunnel::error_response(&err)).await;
+                        return true;
+                    },
+                    Err(_) => {
+                        tracing::info!(%id, %addr, "connection: timed out reading request");
+                        return true;
+                    },
+                }
+            },
+            // CANCEL SAFETY: `TaskContext::wait_for_stop` is cancel safe.
+            _ = task_context.wait_for_stop() => {
+                tracing::trace!(%id, %addr, "connection worker stopping");
+                return false;
+            },
+        };
 
+        let http_request = match http_request {
+            Some(http_request) => http_request,
+            None => {
+                // What was read to tell RTSP from HTTP is the start of the
+                // first request.
+                let read = std::io::Cursor::new(buffer).chain(read);
+                let inbound = codec::FramedRead::new(read, Codec::<AsServer>::new());
+                let outbound = codec::FramedWrite::new(write, Codec::<AsServer>::new());
+                return self
+                    .serve(
+                        inbound,
+                        outbound,
+                        future::pending(),
+                        response_tx,
+                        response_rx,
+                        task_context,
+                    )
+                    .await;
+            }
+        };
+
+        let cookie = http_request.session_cookie.as_str();
+        match http_request.method {
+            HttpMethod::Get => {
+                let tunnel_rx = match self.tunnels.open(cookie).await {
+                    Some(tunnel_rx) => tunnel_rx,
+                    None => {
+                        tracing::info!(%id, %addr, "connection: http tunnel session cookie in use");
+                        let err = TunnelError::SessionInUse;
+                        let _ = write.write_all(tunnel::error_response(&err)).await;
+                        return true;
+                    }
+                };
+                if let Err(err) = write.write_all(tunnel::ok_response()).await {
+                    tracing::info!(%err, %id, %addr, "connection: failed to open http tunnel");
+                    self.tunnels.close(cookie).await;
+                    return true;
+                }
+                tracing::info!(%id, %addr, path = %http_request.path, "connection: opened http tunnel");
+
+                // Requests come from the `POST` side of the tunnel. The `GET`
+                // side only tells when the client goes away.
+                let requests = StreamReader::new(
+                    UnboundedReceiverStream::new(tunnel_rx).map(Ok::<_, std::io::Error>),
+                );
+                let inbound = codec::FramedRead::new(requests, Codec::<AsServer>::new());
+                let outbound = codec::FramedWrite::new(write, Codec::<AsServer>::new());
+                let disconnected = self
+                    .serve(
+                        inbound,
+                        outbound,
+                        read_until_closed(read),
+                        response_tx,
+                        response_rx,
+                        task_context,
+                    )
+                    .await;
+                self.tunnels.close(cookie).await;
+                disconnected
+            }
+            HttpMethod::Post => {
+                let tunnel_tx = match self.tunnels.get(cookie).await {
+                    Some(tunnel_tx) => tunnel_tx,
+                    None => {
+                        tracing::info!(%id, %addr, "connection: no http tunnel for session cookie");
+                        let err = TunnelError::UnknownSession;
+                        let _ = write.write_all(tunnel::error_response(&err)).await;
+                        return true;
+                    }
+                };
+                tracing::trace!(%id, %addr, "connection: joined http tunnel");
+                self.forward_tunnel_requests(read, buffer, tunnel_tx, task_context)
+                    .await
+            }
+        }
+    }
+
+    /// Decode the requests that the client sends in the body of the `POST`
+    /// request of a tunnel, and pass them on to the `GET` side.
+    async fn forward_tunnel_requests<R: AsyncRead + Unpin>(
+        &self,
+        mut read: R,
+        mut buffer: BytesMut,
+        tunnel_tx: TunnelTx,
+        task_context: &mut TaskContext,
+    ) -> bool {
+        let Worker { id, addr, .. } = self;
+        let mut decoder = Base64Decoder::new();
         loop {
+            if !buffer.is_empty() {
+                match decoder.decode(&buffer) {
+                    Ok(decoded) => {
+                        if !decoded.is_empty() && tunnel_tx.send(decoded).is_err() {
+                            tracing::trace!(%id, %addr, "connection: http tunnel closed");
+                            return false;
+                        }
+                    }
+                    Err(err) => {
+                        tracing::info!(%err, %id, %addr, "connection: failed to decode tunneled request");
+                        return true;
+                    }
+                }
+                buffer.clear();
+            }
+
             select! {
-                // CANCEL SAFETY: `mpsc::UnboundedReceiver::recv` is cancel safe.
-                message = response_rx.recv() => {
-                    match message {
-                        Some(message) => {
-                            match outbound.send(message).await {
-                                Ok(()) => {},
-                                Err(Error::Io(err)) if err.kind() == ErrorKind::ConnectionReset => {
-                                    disconnected = true;
-                                    tracing::info!(%id, %addr, "connection: client disconnected (reset)");
-                                    break;
-                                },
-                                Err(err) => {
-                                    tracing::error!(%err, %id, %addr, "connection: failed to send message");
-                                    break;
-                                }
-                            }
+                // CANCEL SAFETY: `AsyncReadExt::read_buf` is cancel safe.
+                read = read.read_buf(&mut buffer) => {
+                    match read {
+                        Ok(0) => {
+                            tracing::trace!(%id, %addr, "connection: client closed http tunnel post");
+                            return true;
                         },
-                        None => {
-                            break;
+                        Ok(_) => {},
+                        Err(err) => {
+                            tracing::info!(%err, %id, %addr, "connection: failed to read http tunnel post");
+                            return true;
                         },
                     }
                 },
+                // CANCEL SAFETY: `mpsc::UnboundedSender::closed` is cancel safe.
+                _ = tunnel_tx.closed() => {
+                    tracing::trace!(%id, %addr, "connection: http tunnel closed");
+                    return false;
+                },
+                // CANCEL SAFETY: `TaskContext::wait_for_stop` is cancel safe.
+                _ = task_context.wait_for_stop() => {
+                    tracing::trace!(%id, %addr, "connection worker stopping");
+                    return false;
+                },
+            }
+        }
+    }
+
+    /// Handle requests and send responses and interleaved data until the
+    /// client disconnects or the worker is stopped. Returns `true` if the
+    /// client disconnected, which is also the case when `closed` completes.
+    ///
+    /// Requests are read and handled while the writer keeps sending what is
+    /// queued. Handling a request may wait on a session that itself waits
+    /// for room in the send queue (see [`SlowClientPolicy::Block`]), so the
+    /// queue must not stop draining in the meantime.
+    async fn serve<R, W>(
+        &self,
+        inbound: codec::FramedRead<R, Codec<AsServer>>,
+        outbound: codec::FramedWrite<W, Codec<AsServer>>,
+        closed: impl Future<Output = ()>,
+        response_tx: ResponseSenderTx,
+        response_rx: ResponseSenderRx,
+        task_context: &mut TaskContext,
+    ) -> bool
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let Worker { id, addr, .. } = self;
+        let (reply_tx, reply_rx) = mpsc::unbounded_channel();
+        let reader = self.read_requests(inbound, &response_tx, reply_tx);
+        let writer = self.write_responses(outbound, reply_rx, response_rx);
+        tokio::pin!(closed);
+
+        select! {
+            // CANCEL SAFETY: The reader is only cancelled when the connection
+            // ends, requests that were not handled yet are lost with it.
+            disconnected = reader => disconnected,
+            // CANCEL SAFETY: The writer is only cancelled when the connection
+            // ends, messages that were not sent yet are lost with it.
+            disconnected = writer => disconnected,
+            // CANCEL SAFETY: The future is pinned outside of the select.
+            _ = &mut closed => {
+                tracing::info!(%id, %addr, "connection: client disconnected");
+                true
+            },
+            // CANCEL SAFETY: `Notify::notified` is cancel safe, a notification that
+            // arrives while the future is not polled is kept for the next one.
+            _ = response_tx.disconnect.notified() => {
+                tracing::info!(%id, %addr, "connection: disconnecting slow client");
+                false
+            },
+            // CANCEL SAFETY: `TaskContext::wait_for_stop` is cancel safe.
+            _ = task_context.wait_for_stop() => {
+                tracing::trace!(%id, %addr, "connection worker stopping");
+                false
+            },
+        }
+    }
+
+    /// Read and handle requests, and pass the responses on to the writer.
+    /// Returns `true` if the client disconnected.
+    async fn read_requests<R>(
+        &self,
+        mut inbound: codec::FramedRead<R, Codec<AsServer>>,
+        response_tx: &ResponseSenderTx,
+        reply_tx: mpsc::UnboundedSender<ResponseMaybeInterleaved>,
+    ) -> bool
+    where
+        R: AsyncRead + Unpin,
+    {
+        let Worker {
+            id,
+            addr,
+            peer_addr,
+            secure,
+            handler,
+            ..
+        } = self;
+        let peer_addr = *peer_addr;
+        let secure = *secure;
+
+        // Only traffic from the client moves the deadline. Outbound traffic
+        // (such as interleaved RTP) says nothing about whether the client is
+        // still there.
+        let mut deadline = Instant::now() + Connection::TIMEOUT;
+        let mut interleaved_routes = InterleavedRoutes::new();
+
+        loop {
+            select! {
                 // CANCEL SAFETY: `StreamExt:next` is always cancel safe.
                 request = inbound.next() => {
                     match request {
                         Some(Ok(request)) => {
+                            deadline = Instant::now() + Connection::TIMEOUT;
                             match request {
                                 RequestMaybeInterleaved::Message(request) => {
-                                    let response = handler.handle(&request, &response_tx).await;
-                                    let response = ResponseMaybeInterleaved::Message(response);
-
-                                    match outbound.send(response).await {
-                                        Ok(()) => {},
-                                        Err(Error::Io(err)) if err.kind() == ErrorKind::ConnectionReset => {
-                                            disconnected = true;
-                                            tracing::info!(%id, %addr, "connection: client disconnected (reset)");
-                                            break;
+                                    let response = handler
+                                        .handle(&request, peer_addr, secure, response_tx, &mut interleaved_routes)
+                                        .await;
+                                    if reply_tx.send(ResponseMaybeInterleaved::Message(response)).is_err() {
+                                        // The writer is gone, and with it the connection.
+                                        return false;
+                                    }
+                                },
+                                RequestMaybeInterleaved::Interleaved { channel, payload } => {
+                                    match interleaved_routes.get(&channel) {
+                                        Some(route) => {
+                                            if route.send(payload).is_err() {
+                                                // The session that the channel belonged to is gone.
+                                                let _ = interleaved_routes.remove(&channel);
+                                            }
                                         },
-                                        Err(err) => {
-                                            tracing::error!(%err, %id, %addr, "connection: failed to send response");
-                                            break;
+                                        None => {
+                                            tracing::debug!(%id, %addr, %channel, "ignored request with interleaved data");
                                         },
                                     }
                                 },
-                                RequestMaybeInterleaved::Interleaved { channel, .. } => {
-                                    tracing::debug!(%id, %addr, %channel, "ignored request with interleaved data");
-                                },
                             }
                         },
                         None => {
-                            disconnected = true;
                             tracing::info!(%id, %addr, "connection: client disconnected");
-                            break;
+                            return true;
                         },
                         Some(Err(Error::Io(err))) if err.kind() == ErrorKind::ConnectionReset => {
-                            disconnected = true;
                             tracing::info!(%id, %addr, "connection: client disconnected (reset)");
-                            break;
+                            return true;
+                        },
+                        // Over TLS, many clients close the connection without
+                        // sending close_notify first.
+                        Some(Err(Error::Io(err))) if err.kind() == ErrorKind::UnexpectedEof => {
+                            tracing::info!(%id, %addr, "connection: client disconnected");
+                            return true;
                         },
                         Some(Err(err)) => {
                             tracing::error!(%err, %id, %addr, "connection: failed to read request");
-                            break;
+                            return false;
                         },
                     }
                 },
                 // Timeout mechanism. The RFC mandates the timeout be applied to
-                // the maximum duration between commands. We are using
-                // interleaved TCP so I opted to apply the timeout
-                // bidirectionally. See RFC 2326 Section 12.37.
-                _ = tokio::time::sleep(std::time::Duration::from_secs(60)) => {
+                // the maximum duration between commands. Interleaved data (such
+                // as RTCP receiver reports) from the client counts as well.
+                // See RFC 2326 Section 12.37.
+                // CANCEL SAFETY: `sleep_until` is cancel safe, the deadline is
+                // kept outside of the loop.
+                _ = tokio::time::sleep_until(deadline) => {
                     tracing::info!(%id, %addr, "connection: timed out reading request");
-                    break;
-                },
-                // CANCEL SAFETY: `TaskContext::wait_for_stop` is cancel safe.
-                _ = task_context.wait_for_stop() => {
-                    tracing::trace!(%id, %addr, "connection worker stopping");
-                    break;
+                    return false;
                 },
             };
         }
+    }
 
-        if disconnected {
-            // Client disconnected.
-            let _ = state_tx.send(ConnectionState::Disconnected(id));
-        } else {
-            // Reason for breaking out of loop was unexpected and not due to the
-            // client disconnecting.
-            let _ = state_tx.send(ConnectionState::Closed(id));
+    /// Send responses and whatever sessions queue to the client. Responses
+    /// go first. Returns `true` if the client disconnected.
+    async fn write_responses<W>(
+        &self,
+        mut outbound: codec::FramedWrite<W, Codec<AsServer>>,
+        mut reply_rx: mpsc::UnboundedReceiver<ResponseMaybeInterleaved>,
+        mut response_rx: ResponseSenderRx,
+    ) -> bool
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let Worker { id, addr, .. } = self;
+        loop {
+            let message = select! {
+                biased;
+                // CANCEL SAFETY: `mpsc::UnboundedReceiver::recv` is cancel safe.
+                reply = reply_rx.recv() => reply,
+                // CANCEL SAFETY: `mpsc::Receiver::recv` is cancel safe.
+                message = response_rx.recv() => message,
+            };
+            let Some(message) = message else {
+                return false;
+            };
+            match outbound.send(message).await {
+                Ok(()) => {}
+                Err(Error::Io(err)) if err.kind() == ErrorKind::ConnectionReset => {
+                    tracing::info!(%id, %addr, "connection: client disconnected (reset)");
+                    return true;
+                }
+                Err(err) => {
+                    tracing::error!(%err, %id, %addr, "connection: failed to send message");
+                    return false;
+                }
+            }
         }
-        tracing::trace!(%id, %addr, "connection worker EOL");
     }
 }
 
+/// Read from the `GET` side of an HTTP tunnel until the client closes it.
+/// Clients do not send anything else over it.
+async fn read_until_closed<R: AsyncRead + Unpin>(mut read: R) {
+    let mut buffer = [0; 64];
+    while let Ok(1..) = read.read(&mut buffer).await {}
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Hash)]
 pub struct ConnectionId(usize);
 
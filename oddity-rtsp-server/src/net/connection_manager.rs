@@ -6,11 +6,13 @@ use tokio::select;
 use tokio::sync::mpsc;
 use tokio::sync::Mutex;
 
+use crate::metrics::Metrics;
 use crate::net::connection::{
-    Connection, ConnectionId, ConnectionIdGenerator, ConnectionState, ConnectionStateRx,
-    ConnectionStateTx,
+    Connection, ConnectionId, ConnectionIdGenerator, ConnectionKind, ConnectionState,
+    ConnectionStateRx, ConnectionStateTx, SendQueueSettings,
 };
 use crate::net::handler::Handler;
+use crate::net::tunnel::Tunnels;
 use crate::runtime::task_manager::{Task, TaskContext};
 use crate::runtime::Runtime;
 
@@ -21,12 +23,22 @@ pub struct ConnectionManager {
     connection_id_generator: ConnectionIdGenerator,
     connection_state_tx: ConnectionStateTx,
     handler: Arc<Handler>,
+    /// HTTP tunnels of all connections, so that `POST` connections can find
+    /// the `GET` connection they belong to.
+    tunnels: Tunnels,
+    send_queue_settings: SendQueueSettings,
+    metrics: Arc<Metrics>,
     worker: Task,
     runtime: Arc<Runtime>,
 }
 
 impl ConnectionManager {
-    pub async fn start(handler: Handler, runtime: Arc<Runtime>) -> Self {
+    pub async fn start(
+        handler: Handler,
+        send_queue_settings: SendQueueSettings,
+        metrics: Arc<Metrics>,
+        runtime: Arc<Runtime>,
+    ) -> Self {
         let connections = Arc::new(Mutex::new(HashMap::new()));
 
         let (connection_state_tx, connection_state_rx) = mpsc::unbounded_channel();
@@ -36,7 +48,8 @@ impl ConnectionManager {
             .task()
             .spawn({
                 let connections = connections.clone();
-                |task_context| Self::run(connections, connection_state_rx, task_context)
+                let metrics = metrics.clone();
+                |task_context| Self::run(connections, connection_state_rx, metrics, task_context)
             })
             .await;
         tracing::trace!("started connection manager");
@@ -46,6 +59,9 @@ impl ConnectionManager {
             connection_id_generator: ConnectionIdGenerator::new(),
             connection_state_tx,
             handler: Arc::new(handler),
+            tunnels: Tunnels::new(),
+            send_queue_settings,
+            metrics,
             worker,
             runtime,
         }
@@ -60,23 +76,29 @@ impl ConnectionManager {
         }
     }
 
-    pub async fn spawn(&mut self, stream: net::TcpStream) {
+    /// Start serving a client. See [`Connection::start`].
+    pub async fn spawn(&mut self, stream: net::TcpStream, kind: ConnectionKind) {
         let id = self.connection_id_generator.generate();
         let connection = Connection::start(
             id,
             stream,
+            kind,
             self.handler.clone(),
+            self.tunnels.clone(),
+            self.send_queue_settings,
             self.connection_state_tx.clone(),
             self.runtime.as_ref(),
         )
         .await;
 
         self.connections.lock().await.insert(id, connection);
+        self.metrics.connection_opened();
     }
 
     async fn run(
         connections: ConnectionMap,
         mut connection_state_rx: ConnectionStateRx,
+        metrics: Arc<Metrics>,
         mut task_context: TaskContext,
     ) {
         loop {
@@ -89,14 +111,18 @@ impl ConnectionManager {
                                 %connection_id,
                                 "connection manager: received disconnected",
                             );
-                            connections.lock().await.remove(&connection_id);
+                            if connections.lock().await.remove(&connection_id).is_some() {
+                                metrics.connection_closed();
+                            }
                         },
                         Some(ConnectionState::Closed(connection_id)) => {
                             tracing::trace!(
                                 %connection_id,
                                 "connection manager: received closed",
                             );
-                            connections.lock().await.remove(&connection_id);
+                            if connections.lock().await.remove(&connection_id).is_some() {
+                                metrics.connection_closed();
+                            }
                         },
                         None => {
                             tracing::error!("connection state channel broke unexpectedly");
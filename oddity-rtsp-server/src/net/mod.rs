@@ -2,3 +2,9 @@ pub mod connection;
 pub mod connection_manager;
 pub mod handler;
 pub mod server;
+pub mod srtp;
+pub mod tls;
+pub mod tunnel;
+pub mod udp;
+pub mod webrtc;
+pub mod websocket;
@@ -1,9 +1,12 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 
 use tokio::net;
 use tokio::select;
+use tokio_rustls::TlsAcceptor;
 
+use crate::metrics::Metrics;
+use crate::net::connection::{ConnectionKind, SendQueueSettings};
 use crate::net::connection_manager::ConnectionManager;
 use crate::net::handler::Handler;
 use crate::runtime::task_manager::{Task, TaskContext};
@@ -15,11 +18,22 @@ pub struct Server {
     worker: Task,
 }
 
+/// Listen for RTSP over TLS (`rtsps://`) on `port`, next to plain RTSP.
+pub struct TlsSettings {
+    pub port: u16,
+    pub acceptor: TlsAcceptor,
+}
+
 impl Server {
+    #[allow(clippy::too_many_arguments)]
     pub async fn start(
         host: IpAddr,
         port: u16,
+        tls: Option<TlsSettings>,
+        websocket_port: Option<u16>,
         handler: Handler,
+        send_queue_settings: SendQueueSettings,
+        metrics: Arc<Metrics>,
         runtime: Arc<Runtime>,
     ) -> Result<Self> {
         tracing::trace!(%host, port, "starting server");
@@ -32,11 +46,52 @@ impl Server {
         };
         tracing::info!(%host, port, "server listening for incoming connections");
 
+        let tls = match tls {
+            Some(TlsSettings { port, acceptor }) => {
+                match net::TcpListener::bind((host, port)).await {
+                    Ok(listener) => {
+                        tracing::info!(%host, port, "server listening for incoming tls connections");
+                        Some((listener, acceptor))
+                    }
+                    Err(err) => {
+                        tracing::error!(%err, %host, port, "failed to listen for tls connections");
+                        return Err(err);
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let websocket = match websocket_port {
+            Some(port) => match net::TcpListener::bind((host, port)).await {
+                Ok(listener) => {
+                    tracing::info!(%host, port, "server listening for incoming websocket connections");
+                    Some(listener)
+                }
+                Err(err) => {
+                    tracing::error!(%err, %host, port, "failed to listen for websocket connections");
+                    return Err(err);
+                }
+            },
+            None => None,
+        };
+
         let worker = runtime
             .task()
             .spawn({
                 let runtime = runtime.clone();
-                move |task_context| Self::run(listener, handler, runtime, task_context)
+                move |task_context| {
+                    Self::run(
+                        listener,
+                        tls,
+                        websocket,
+                        handler,
+                        send_queue_settings,
+                        metrics,
+                        runtime,
+                        task_context,
+                    )
+                }
             })
             .await;
         tracing::trace!(%host, port, "started server");
@@ -50,13 +105,19 @@ impl Server {
         tracing::trace!("server stopped");
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn run(
         listener: net::TcpListener,
+        tls: Option<(net::TcpListener, TlsAcceptor)>,
+        websocket: Option<net::TcpListener>,
         handler: Handler,
+        send_queue_settings: SendQueueSettings,
+        metrics: Arc<Metrics>,
         runtime: Arc<Runtime>,
         mut task_context: TaskContext,
     ) {
-        let mut connection_manager = ConnectionManager::start(handler, runtime).await;
+        let mut connection_manager =
+            ConnectionManager::start(handler, send_queue_settings, metrics, runtime).await;
         loop {
             select! {
                 // CANCEL SAFETY: `tokio::net::TcpListener::accept` is cancel safe.
@@ -64,13 +125,40 @@ impl Server {
                     match incoming {
                         Ok((incoming, peer_addr)) => {
                             tracing::trace!(%peer_addr, "accepted client");
-                            connection_manager.spawn(incoming).await;
+                            connection_manager.spawn(incoming, ConnectionKind::Plain).await;
                         },
                         Err(err) => {
                             tracing::error!(%err, "failed to accept connection");
                         },
                     }
                 },
+                // CANCEL SAFETY: `tokio::net::TcpListener::accept` is cancel safe.
+                incoming = accept(tls.as_ref().map(|(listener, _)| listener)) => {
+                    match incoming {
+                        Ok((incoming, peer_addr)) => {
+                            tracing::trace!(%peer_addr, "accepted tls client");
+                            if let Some((_, acceptor)) = tls.as_ref() {
+                                let kind = ConnectionKind::Tls(acceptor.clone());
+                                connection_manager.spawn(incoming, kind).await;
+                            }
+                        },
+                        Err(err) => {
+                            tracing::error!(%err, "failed to accept tls connection");
+                        },
+                    }
+                },
+                // CANCEL SAFETY: `tokio::net::TcpListener::accept` is cancel safe.
+                incoming = accept(websocket.as_ref()) => {
+                    match incoming {
+                        Ok((incoming, peer_addr)) => {
+                            tracing::trace!(%peer_addr, "accepted websocket client");
+                            connection_manager.spawn(incoming, ConnectionKind::WebSocket).await;
+                        },
+                        Err(err) => {
+                            tracing::error!(%err, "failed to accept websocket connection");
+                        },
+                    }
+                },
                 // CANCEL SAFETY: `TaskContext::wait_for_stop` is cancel safe.
                 _ = task_context.wait_for_stop() => {
                     tracing::trace!("server stopping");
@@ -82,3 +170,12 @@ impl Server {
         connection_manager.stop().await;
     }
 }
+
+/// Accept a connection on an optional listener, or wait forever if there
+/// is none.
+async fn accept(listener: Option<&net::TcpListener>) -> Result<(net::TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,268 @@
+//! Sending side of SRTP (RFC 3711) with the `AES_CM_128_HMAC_SHA1_80`
+//! protection profile, which every browser supports. Keys come from the
+//! DTLS handshake (RFC 5764 Section 4.2).
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+
+use aes::cipher::{KeyIvInit, StreamCipher};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+
+pub const MASTER_KEY_LEN: usize = 16;
+pub const MASTER_SALT_LEN: usize = 14;
+
+const AUTH_KEY_LEN: usize = 20;
+const AUTH_TAG_LEN: usize = 10;
+
+const LABEL_ENCRYPTION: u8 = 0x00;
+const LABEL_AUTHENTICATION: u8 = 0x01;
+const LABEL_SALT: u8 = 0x02;
+
+/// Protects the RTP packets sent to a single receiver.
+pub struct SrtpSender {
+    key: [u8; MASTER_KEY_LEN],
+    salt: [u8; MASTER_SALT_LEN],
+    auth: Hmac<Sha1>,
+    /// Rollover counter and last sequence number per SSRC.
+    streams: HashMap<u32, (u32, u16)>,
+}
+
+impl SrtpSender {
+    pub fn new(master_key: &[u8], master_salt: &[u8]) -> Result<Self, SrtpError> {
+        let master_key: [u8; MASTER_KEY_LEN] =
+            master_key.try_into().map_err(|_| SrtpError::InvalidKey)?;
+        let master_salt: [u8; MASTER_SALT_LEN] =
+            master_salt.try_into().map_err(|_| SrtpError::InvalidKey)?;
+
+        let mut key = [0; MASTER_KEY_LEN];
+        derive(&master_key, &master_salt, LABEL_ENCRYPTION, &mut key);
+        let mut salt = [0; MASTER_SALT_LEN];
+        derive(&master_key, &master_salt, LABEL_SALT, &mut salt);
+        let mut auth_key = [0; AUTH_KEY_LEN];
+        derive(
+            &master_key,
+            &master_salt,
+            LABEL_AUTHENTICATION,
+            &mut auth_key,
+        );
+        let auth = Hmac::<Sha1>::new_from_slice(&auth_key).map_err(|_| SrtpError::InvalidKey)?;
+
+        Ok(Self {
+            key,
+            salt,
+            auth,
+            streams: HashMap::new(),
+        })
+    }
+
+    /// Encrypt the payload of an RTP packet, and append the authentication
+    /// tag.
+    pub fn protect(&mut self, packet: &[u8]) -> Result<Vec<u8>, SrtpError> {
+        let header_len = header_len(packet).ok_or(SrtpError::InvalidPacket)?;
+        let sequence_number = u16::from_be_bytes([packet[2], packet[3]]);
+        let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+        let rollover_counter = self.rollover_counter(ssrc, sequence_number);
+        let index = ((rollover_counter as u64) << 16) | sequence_number as u64;
+        let iv = packet_iv(&self.salt, ssrc, index);
+
+        let mut protected = Vec::with_capacity(packet.len() + AUTH_TAG_LEN);
+        protected.extend_from_slice(packet);
+        Aes128Ctr::new(&self.key.into(), &iv.into()).apply_keystream(&mut protected[header_len..]);
+
+        let mut auth = self.auth.clone();
+        auth.update(&protected);
+        auth.update(&rollover_counter.to_be_bytes());
+        protected.extend_from_slice(&auth.finalize().into_bytes()[..AUTH_TAG_LEN]);
+        Ok(protected)
+    }
+
+    /// The receiver guesses the rollover counter from the sequence number,
+    /// so it must only change when the sequence number wraps around (RFC
+    /// 3711 Section 3.3.1).
+    fn rollover_counter(&mut self, ssrc: u32, sequence_number: u16) -> u32 {
+        let (rollover_counter, last) = self.streams.entry(ssrc).or_insert((0, sequence_number));
+        if sequence_number < *last && *last - sequence_number > 0x8000 {
+            *rollover_counter = rollover_counter.wrapping_add(1);
+            *last = sequence_number;
+        } else if sequence_number > *last && sequence_number - *last > 0x8000 {
+            // Late packet from before the sequence number wrapped around.
+            return rollover_counter.wrapping_sub(1);
+        } else if sequence_number > *last {
+            *last = sequence_number;
+        }
+        *rollover_counter
+    }
+}
+
+/// Session key derivation with a key derivation rate of zero (RFC 3711
+/// Section 4.3.1).
+fn derive(
+    master_key: &[u8; MASTER_KEY_LEN],
+    master_salt: &[u8; MASTER_SALT_LEN],
+    label: u8,
+    out: &mut [u8],
+) {
+    let mut iv = [0; 16];
+    iv[..MASTER_SALT_LEN].copy_from_slice(master_salt);
+    iv[7] ^= label;
+    out.fill(0);
+    Aes128Ctr::new(master_key.into(), &iv.into()).apply_keystream(out);
+}
+
+/// Initial counter block of AES-CM for the packet with `index` of `ssrc`
+/// (RFC 3711 Section 4.1.1).
+fn packet_iv(salt: &[u8; MASTER_SALT_LEN], ssrc: u32, index: u64) -> [u8; 16] {
+    let mut iv = [0; 16];
+    iv[..MASTER_SALT_LEN].copy_from_slice(salt);
+    for (byte, ssrc) in iv[4..8].iter_mut().zip(ssrc.to_be_bytes()) {
+        *byte ^= ssrc;
+    }
+    for (byte, index) in iv[8..14].iter_mut().zip(&index.to_be_bytes()[2..]) {
+        *byte ^= index;
+    }
+    iv
+}
+
+/// Length of the RTP header, including CSRCs and the header extension.
+fn header_len(packet: &[u8]) -> Option<usize> {
+    if packet.len() < 12 || packet[0] >> 6 != 2 {
+        return None;
+    }
+    let mut len = 12 + (packet[0] & 0x0f) as usize * 4;
+    if packet[0] & 0x10 != 0 {
+        let extension = packet.get(len..len + 4)?;
+        len += 4 + u16::from_be_bytes([extension[2], extension[3]]) as usize * 4;
+    }
+    (len <= packet.len()).then_some(len)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SrtpError {
+    InvalidKey,
+    InvalidPacket,
+}
+
+impl fmt::Display for SrtpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SrtpError::InvalidKey => write!(f, "invalid master key or salt"),
+            SrtpError::InvalidPacket => write!(f, "invalid rtp packet"),
+        }
+    }
+}
+
+impl error::Error for SrtpError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn derive_session_keys() {
+        // RFC 3711 Appendix B.3.
+        let master_key = hex("E1F97A0D3E018BE0D64FA32C06DE4139").try_into().unwrap();
+        let master_salt = hex("0EC675AD498AFEEBB6960B3AABE6").try_into().unwrap();
+        let mut key = [0; 16];
+        derive(&master_key, &master_salt, LABEL_ENCRYPTION, &mut key);
+        assert_eq!(key.to_vec(), hex("C61E7A93744F39EE10734AFE3FF7A087"));
+        let mut salt = [0; 14];
+        derive(&master_key, &master_salt, LABEL_SALT, &mut salt);
+        assert_eq!(salt.to_vec(), hex("30CBBC08863D8C85D49DB34A9AE1"));
+        let mut auth_key = [0; 20];
+        derive(
+            &master_key,
+            &master_salt,
+            LABEL_AUTHENTICATION,
+            &mut auth_key,
+        );
+        assert_eq!(
+            auth_key.to_vec(),
+            hex("CEBE321F6FF7716B6FD4AB49AF256A156D38BAA4")
+        );
+    }
+
+    #[test]
+    fn keystream() {
+        // RFC 3711 Appendix B.2.
+        let key: [u8; 16] = hex("2B7E151628AED2A6ABF7158809CF4F3C").try_into().unwrap();
+        let salt = hex("F0F1F2F3F4F5F6F7F8F9FAFBFCFD").try_into().unwrap();
+        let iv = packet_iv(&salt, 0, 0);
+        assert_eq!(iv.to_vec(), hex("F0F1F2F3F4F5F6F7F8F9FAFBFCFD0000"));
+        let mut keystream = [0; 48];
+        Aes128Ctr::new(&key.into(), &iv.into()).apply_keystream(&mut keystream);
+        assert_eq!(
+            keystream.to_vec(),
+            hex(concat!(
+                "E03EAD0935C95E80E166B16DD92B4EB4",
+                "D23513162B02D0F72A43A2FE4A5F97AB",
+                "41E95B3BB0A2E8DD477901E4FCA894C0",
+            )),
+        );
+    }
+
+    #[test]
+    fn protect() {
+        let mut sender = SrtpSender::new(&[0x01; 16], &[0x02; 14]).unwrap();
+        let mut packet = vec![0x80, 0x60, 0x12, 0x34, 0, 0, 0, 1, 0xde, 0xad, 0xbe, 0xef];
+        packet.extend_from_slice(b"payload");
+        let protected = sender.protect(&packet).unwrap();
+        assert_eq!(protected.len(), packet.len() + AUTH_TAG_LEN);
+        assert_eq!(protected[..12], packet[..12]);
+        assert_ne!(protected[12..19], packet[12..]);
+
+        // Decrypting is encrypting again with the same keystream.
+        let mut iv = [0; 16];
+        iv[..14].copy_from_slice(&sender.salt);
+        for (byte, ssrc) in iv[4..8].iter_mut().zip([0xde, 0xad, 0xbe, 0xef]) {
+            *byte ^= ssrc;
+        }
+        iv[12] ^= 0x12;
+        iv[13] ^= 0x34;
+        let mut payload = protected[12..19].to_vec();
+        Aes128Ctr::new(&sender.key.into(), &iv.into()).apply_keystream(&mut payload);
+        assert_eq!(payload, b"payload");
+
+        let mut auth = sender.auth.clone();
+        auth.update(&protected[..19]);
+        auth.update(&[0, 0, 0, 0]);
+        assert_eq!(
+            auth.finalize().into_bytes()[..AUTH_TAG_LEN],
+            protected[19..]
+        );
+    }
+
+    #[test]
+    fn rollover() {
+        let mut sender = SrtpSender::new(&[0x01; 16], &[0x02; 14]).unwrap();
+        assert_eq!(sender.rollover_counter(1, 65534), 0);
+        assert_eq!(sender.rollover_counter(1, 65535), 0);
+        assert_eq!(sender.rollover_counter(1, 0), 1);
+        // Reordered from before the wrap.
+        assert_eq!(sender.rollover_counter(1, 65535), 0);
+        assert_eq!(sender.rollover_counter(1, 1), 1);
+        assert_eq!(sender.rollover_counter(2, 0), 0);
+    }
+
+    #[test]
+    fn invalid_packet() {
+        let mut sender = SrtpSender::new(&[0x01; 16], &[0x02; 14]).unwrap();
+        assert_eq!(
+            sender.protect(&[0x80, 0x60, 0, 1]),
+            Err(SrtpError::InvalidPacket)
+        );
+        // Header extension longer than the packet.
+        let packet = [0x90, 0x60, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0xbe, 0xde, 0, 4];
+        assert_eq!(sender.protect(&packet), Err(SrtpError::InvalidPacket));
+    }
+}
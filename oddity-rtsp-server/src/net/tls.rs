@@ -0,0 +1,65 @@
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio_rustls::rustls;
+use tokio_rustls::TlsAcceptor;
+
+/// Load the certificate chain and private key (both PEM) to accept TLS
+/// connections with.
+pub fn load_acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor, TlsError> {
+    let certs = rustls_pemfile::certs(&mut open(cert)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| TlsError::Read(cert.to_path_buf(), err))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate(cert.to_path_buf()));
+    }
+    let private_key = rustls_pemfile::private_key(&mut open(key)?)
+        .map_err(|err| TlsError::Read(key.to_path_buf(), err))?
+        .ok_or_else(|| TlsError::NoPrivateKey(key.to_path_buf()))?;
+
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(TlsError::Config)?
+    .with_no_client_auth()
+    .with_single_cert(certs, private_key)
+    .map_err(TlsError::Config)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, TlsError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| TlsError::Read(path.to_path_buf(), err))
+}
+
+#[derive(Debug)]
+pub enum TlsError {
+    Read(PathBuf, io::Error),
+    NoCertificate(PathBuf),
+    NoPrivateKey(PathBuf),
+    Config(rustls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::Read(path, err) => write!(f, "failed to read {}: {}", path.display(), err),
+            TlsError::NoCertificate(path) => {
+                write!(f, "no certificate found in {}", path.display())
+            }
+            TlsError::NoPrivateKey(path) => {
+                write!(f, "no private key found in {}", path.display())
+            }
+            TlsError::Config(err) => write!(f, "invalid certificate or key: {}", err),
+        }
+    }
+}
+
+impl error::Error for TlsError {}
@@ -0,0 +1,321 @@
+//! RTSP over HTTP tunneling, the way QuickTime does it. The client opens
+//! two HTTP connections with the same `x-sessioncookie`: a `GET` over which
+//! the server sends RTSP responses and interleaved data, and a `POST` over
+//! which the client sends RTSP requests, base64 encoded. Together they act
+//! as a single RTSP connection. The client may close the `POST` connection
+//! after a request, and open a new one with the same cookie for the next.
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use base64::engine::Engine;
+
+use bytes::{Buf, Bytes, BytesMut};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::mpsc;
+use tokio::sync::Mutex;
+
+/// Upper bound on the size of the HTTP request line and headers.
+const MAX_HEADER_LEN: usize = 8192;
+
+/// Sends requests of `POST` connections, decoded, to the `GET` connection
+/// of the tunnel.
+pub type TunnelTx = mpsc::UnboundedSender<Bytes>;
+pub type TunnelRx = mpsc::UnboundedReceiver<Bytes>;
+
+/// Open tunnels by session cookie.
+#[derive(Clone, Default)]
+pub struct Tunnels(Arc<Mutex<HashMap<String, TunnelTx>>>);
+
+impl Tunnels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open a tunnel for a `GET` connection. Returns `None` if there already
+    /// is a tunnel with the cookie.
+    pub async fn open(&self, cookie: &str) -> Option<TunnelRx> {
+        let mut tunnels = self.0.lock().await;
+        if tunnels.get(cookie).is_some_and(|tx| !tx.is_closed()) {
+            return None;
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        tunnels.insert(cookie.to_string(), tx);
+        Some(rx)
+    }
+
+    /// Where to send the requests of a `POST` connection.
+    pub async fn get(&self, cookie: &str) -> Option<TunnelTx> {
+        self.0.lock().await.get(cookie).cloned()
+    }
+
+    pub async fn close(&self, cookie: &str) {
+        let _ = self.0.lock().await.remove(cookie);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpMethod {
+    Get,
+    Post,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub method: HttpMethod,
+    pub path: String,
+    pub session_cookie: String,
+}
+
+/// Find out whether the client speaks HTTP (to open a tunnel) or plain
+/// RTSP. Reads the first bytes of the connection into `buffer`, and for
+/// HTTP the rest of the request head as well. For HTTP, the request head is
+/// taken from `buffer`, and what is left is the start of the body.
+pub async fn accept<R: AsyncRead + Unpin>(
+    read: &mut R,
+    buffer: &mut BytesMut,
+) -> Result<Option<HttpRequest>, TunnelError> {
+    while buffer.len() < 5 {
+        if read.read_buf(buffer).await? == 0 {
+            return Ok(None);
+        }
+    }
+    if !is_http(buffer) {
+        return Ok(None);
+    }
+    loop {
+        if let Some(request) = parse_request(buffer)? {
+            return Ok(Some(request));
+        }
+        if buffer.len() > MAX_HEADER_LEN {
+            return Err(TunnelError::HeadTooLarge);
+        }
+        if read.read_buf(buffer).await? == 0 {
+            return Err(TunnelError::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+    }
+}
+
+/// Response to the `GET` request of a tunnel, after which the server sends
+/// RTSP over the connection. The `POST` request does not get a response.
+pub fn ok_response() -> &'static [u8] {
+    b"HTTP/1.0 200 OK\r\n\
+      Server: oddity-rtsp-server\r\n\
+      Connection: close\r\n\
+      Cache-Control: no-store\r\n\
+      Pragma: no-cache\r\n\
+      Content-Type: application/x-rtsp-tunnelled\r\n\
+      \r\n"
+}
+
+/// Response to a request that cannot open or join a tunnel.
+pub fn error_response(err: &TunnelError) -> &'static [u8] {
+    match err {
+        TunnelError::UnknownSession => b"HTTP/1.0 404 Not Found\r\nConnection: close\r\n\r\n",
+        TunnelError::SessionInUse => b"HTTP/1.0 409 Conflict\r\nConnection: close\r\n\r\n",
+        _ => b"HTTP/1.0 400 Bad Request\r\nConnection: close\r\n\r\n",
+    }
+}
+
+/// RTSP methods never end in a space, so `GET_PARAMETER` is not mistaken
+/// for HTTP.
+fn is_http(prefix: &[u8]) -> bool {
+    prefix.starts_with(b"GET ") || prefix.starts_with(b"POST ")
+}
+
+/// Parse the request head at the start of `buffer` and remove it. Returns
+/// `None` if the head is not complete yet.
+fn parse_request(buffer: &mut BytesMut) -> Result<Option<HttpRequest>, TunnelError> {
+    let end = match buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(end) => end,
+        None => return Ok(None),
+    };
+    let head = std::str::from_utf8(&buffer[..end])
+        .map_err(|_| TunnelError::Malformed)?
+        .to_string();
+    buffer.advance(end + 4);
+
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = match request_line.next() {
+        Some("GET") => HttpMethod::Get,
+        Some("POST") => HttpMethod::Post,
+        _ => return Err(TunnelError::Malformed),
+    };
+    let path = request_line
+        .next()
+        .ok_or(TunnelError::Malformed)?
+        .to_string();
+
+    let mut session_cookie = None;
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or(TunnelError::Malformed)?;
+        if name.trim().eq_ignore_ascii_case("x-sessioncookie") {
+            session_cookie = Some(value.trim().to_string());
+        }
+    }
+    let session_cookie = session_cookie
+        .filter(|cookie| !cookie.is_empty())
+        .ok_or(TunnelError::MissingSessionCookie)?;
+
+    Ok(Some(HttpRequest {
+        method,
+        path,
+        session_cookie,
+    }))
+}
+
+/// Decodes the body of a `POST` request as it comes in. Clients encode
+/// every request on its own, so padding may show up in the middle of the
+/// body. Line breaks are ignored.
+#[derive(Default)]
+pub struct Base64Decoder {
+    pending: Vec<u8>,
+}
+
+impl Base64Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn decode(&mut self, input: &[u8]) -> Result<Bytes, TunnelError> {
+        self.pending.extend(
+            input
+                .iter()
+                .copied()
+                .filter(|byte| !byte.is_ascii_whitespace()),
+        );
+        let complete = self.pending.len() - self.pending.len() % 4;
+        let mut output = Vec::with_capacity(complete / 4 * 3);
+        for group in self.pending[..complete].chunks(4) {
+            let decoded = base64::engine::general_purpose::STANDARD
+                .decode(group)
+                .map_err(TunnelError::Base64)?;
+            output.extend_from_slice(&decoded);
+        }
+        self.pending.drain(..complete);
+        Ok(output.into())
+    }
+}
+
+#[derive(Debug)]
+pub enum TunnelError {
+    Io(io::Error),
+    Malformed,
+    HeadTooLarge,
+    MissingSessionCookie,
+    UnknownSession,
+    SessionInUse,
+    Base64(base64::DecodeError),
+}
+
+impl fmt::Display for TunnelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TunnelError::Io(err) => write!(f, "{}", err),
+            TunnelError::Malformed => write!(f, "malformed http request"),
+            TunnelError::HeadTooLarge => write!(f, "http request head too large"),
+            TunnelError::MissingSessionCookie => write!(f, "missing x-sessioncookie header"),
+            TunnelError::UnknownSession => write!(f, "no tunnel with session cookie"),
+            TunnelError::SessionInUse => write!(f, "session cookie already in use"),
+            TunnelError::Base64(err) => write!(f, "invalid base64: {}", err),
+        }
+    }
+}
+
+impl error::Error for TunnelError {}
+
+impl From<io::Error> for TunnelError {
+    fn from(err: io::Error) -> Self {
+        TunnelError::Io(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_get_request() {
+        let mut buffer = BytesMut::from(
+            &b"GET /camera HTTP/1.0\r\n\
+               x-sessioncookie: 1dc37fa1\r\n\
+               Accept: application/x-rtsp-tunnelled\r\n\
+               \r\n"[..],
+        );
+        assert_eq!(
+            parse_request(&mut buffer).unwrap(),
+            Some(HttpRequest {
+                method: HttpMethod::Get,
+                path: "/camera".to_string(),
+                session_cookie: "1dc37fa1".to_string(),
+            })
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn parse_post_request_keeps_body() {
+        let mut buffer = BytesMut::from(
+            &b"POST /camera HTTP/1.0\r\n\
+               X-SessionCookie: 1dc37fa1\r\n\
+               Content-Type: application/x-rtsp-tunnelled\r\n\
+               Content-Length: 32767\r\n\
+               \r\n\
+               T1BUSU9O"[..],
+        );
+        let request = parse_request(&mut buffer).unwrap().unwrap();
+        assert_eq!(request.method, HttpMethod::Post);
+        assert_eq!(request.session_cookie, "1dc37fa1");
+        assert_eq!(&buffer[..], b"T1BUSU9O");
+    }
+
+    #[test]
+    fn parse_incomplete_request() {
+        let mut buffer = BytesMut::from(&b"GET /camera HTTP/1.0\r\nx-sessioncookie: 1"[..]);
+        assert_eq!(parse_request(&mut buffer).unwrap(), None);
+    }
+
+    #[test]
+    fn parse_request_without_cookie() {
+        let mut buffer = BytesMut::from(&b"GET /camera HTTP/1.0\r\n\r\n"[..]);
+        assert!(matches!(
+            parse_request(&mut buffer),
+            Err(TunnelError::MissingSessionCookie)
+        ));
+    }
+
+    #[test]
+    fn rtsp_is_not_http() {
+        assert!(is_http(b"GET / HTTP/1.0"));
+        assert!(is_http(b"POST / HTTP/1.0"));
+        assert!(!is_http(b"GET_PARAMETER rtsp://localhost/ RTSP/1.0"));
+        assert!(!is_http(b"OPTIONS * RTSP/1.0"));
+    }
+
+    #[test]
+    fn decode_base64_in_pieces() {
+        let mut decoder = Base64Decoder::new();
+        // "OPTIONS * RTSP/1.0\r\n" split at an odd place.
+        assert_eq!(&decoder.decode(b"T1BUSU9OUyAq").unwrap()[..], b"OPTIONS *");
+        assert_eq!(&decoder.decode(b"IFJUU1Av").unwrap()[..], b" RTSP/");
+        assert_eq!(&decoder.decode(b"MS4wD").unwrap()[..], b"1.0");
+        assert_eq!(&decoder.decode(b"Qo=").unwrap()[..], b"\r\n");
+    }
+
+    #[test]
+    fn decode_base64_with_padding_between_requests() {
+        let mut decoder = Base64Decoder::new();
+        assert_eq!(&decoder.decode(b"YQ==\r\nYmM=").unwrap()[..], b"abc");
+    }
+
+    #[test]
+    fn decode_invalid_base64() {
+        let mut decoder = Base64Decoder::new();
+        assert!(decoder.decode(b"Y!==").is_err());
+    }
+}
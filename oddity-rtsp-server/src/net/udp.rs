@@ -0,0 +1,170 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+
+use tokio::net;
+use tokio::sync::Mutex;
+
+type Result<T> = std::result::Result<T, io::Error>;
+
+/// Allocates pairs of UDP sockets for RTP and RTCP from a configured
+/// port range. RFC 3550 Section 11 says RTP should use an even port
+/// and RTCP the next (odd) port, so we only hand out pairs that line
+/// up that way.
+///
+/// The allocator does not keep track of which ports are in use. The
+/// operating system already does that for us: binding a port that is
+/// in use fails and we simply move on to the next pair. Dropping the
+/// sockets releases the ports again.
+pub struct UdpSocketPairAllocator {
+    host: IpAddr,
+    port_range: RangeInclusive<u16>,
+    next: Mutex<u16>,
+}
+
+impl UdpSocketPairAllocator {
+    pub fn new(host: IpAddr, port_range: RangeInclusive<u16>) -> Self {
+        let first = first_even_port(&port_range);
+        Self {
+            host,
+            port_range,
+            next: Mutex::new(first),
+        }
+    }
+
+    pub async fn allocate(&self) -> Result<UdpSocketPair> {
+        let mut next = self.next.lock().await;
+        // Every pair is tried at most once per call, starting with the pair
+        // after the one that was handed out last.
+        let num_pairs = (*self.port_range.end() as u32 + 1)
+            .saturating_sub(first_even_port(&self.port_range) as u32)
+            / 2;
+
+        for _ in 0..num_pairs {
+            let rtp_port = *next;
+            *next = self.next_pair_after(rtp_port);
+
+            let rtcp_port = match rtp_port.checked_add(1) {
+                Some(rtcp_port) if self.port_range.contains(&rtcp_port) => rtcp_port,
+                _ => continue,
+            };
+
+            let rtp = match net::UdpSocket::bind((self.host, rtp_port)).await {
+                Ok(rtp) => rtp,
+                Err(err) => {
+                    tracing::trace!(%err, rtp_port, "udp port unavailable");
+                    continue;
+                }
+            };
+            let rtcp = match net::UdpSocket::bind((self.host, rtcp_port)).await {
+                Ok(rtcp) => rtcp,
+                Err(err) => {
+                    tracing::trace!(%err, rtcp_port, "udp port unavailable");
+                    continue;
+                }
+            };
+
+            tracing::trace!(rtp_port, rtcp_port, "allocated udp socket pair");
+            return Ok(UdpSocketPair {
+                rtp,
+                rtcp,
+                rtp_port,
+                rtcp_port,
+            });
+        }
+
+        tracing::error!(
+            start = self.port_range.start(),
+            end = self.port_range.end(),
+            "no udp ports left in range",
+        );
+        Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "no udp ports left in range",
+        ))
+    }
+
+    fn next_pair_after(&self, rtp_port: u16) -> u16 {
+        match rtp_port.checked_add(2) {
+            Some(next) if next < *self.port_range.end() => next,
+            _ => first_even_port(&self.port_range),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UdpSocketPair {
+    pub rtp: net::UdpSocket,
+    pub rtcp: net::UdpSocket,
+    pub rtp_port: u16,
+    pub rtcp_port: u16,
+}
+
+impl UdpSocketPair {
+    pub async fn send_rtp(&self, payload: &[u8], target: SocketAddr) -> Result<()> {
+        self.rtp.send_to(payload, target).await.map(|_| ())
+    }
+
+    pub async fn send_rtcp(&self, payload: &[u8], target: SocketAddr) -> Result<()> {
+        self.rtcp.send_to(payload, target).await.map(|_| ())
+    }
+}
+
+#[inline]
+fn first_even_port(port_range: &RangeInclusive<u16>) -> u16 {
+    let start = *port_range.start();
+    start.saturating_add(start % 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[tokio::test]
+    async fn allocate_even_odd_pairs() {
+        // Starts at an odd port, so the first pair starts at the port after.
+        let allocator = UdpSocketPairAllocator::new(LOCALHOST, 47001..=47010);
+        let mut pairs = Vec::new();
+        for _ in 0..4 {
+            let pair = allocator.allocate().await.unwrap();
+            assert_eq!(pair.rtp_port % 2, 0);
+            assert_eq!(pair.rtcp_port, pair.rtp_port + 1);
+            assert!((47002..=47010).contains(&pair.rtp_port));
+            assert!((47002..=47010).contains(&pair.rtcp_port));
+            assert_eq!(pair.rtp.local_addr().unwrap().port(), pair.rtp_port);
+            assert_eq!(pair.rtcp.local_addr().unwrap().port(), pair.rtcp_port);
+            pairs.push(pair);
+        }
+        let mut rtp_ports = pairs.iter().map(|pair| pair.rtp_port).collect::<Vec<_>>();
+        rtp_ports.sort();
+        assert_eq!(rtp_ports, [47002, 47004, 47006, 47008]);
+    }
+
+    #[tokio::test]
+    async fn allocate_until_range_exhausted() {
+        // Port 47024 has no odd port after it in the range, so there is
+        // only a single pair.
+        let allocator = UdpSocketPairAllocator::new(LOCALHOST, 47021..=47024);
+        let pair = allocator.allocate().await.unwrap();
+        assert_eq!((pair.rtp_port, pair.rtcp_port), (47022, 47023));
+        let err = allocator.allocate().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+        // Dropping the pair releases its ports again.
+        drop(pair);
+        let pair = allocator.allocate().await.unwrap();
+        assert_eq!((pair.rtp_port, pair.rtcp_port), (47022, 47023));
+    }
+
+    #[tokio::test]
+    async fn allocate_skips_ports_in_use() {
+        let _taken = net::UdpSocket::bind((LOCALHOST, 47033)).await.unwrap();
+        let allocator = UdpSocketPairAllocator::new(LOCALHOST, 47032..=47035);
+        let pair = allocator.allocate().await.unwrap();
+        assert_eq!((pair.rtp_port, pair.rtcp_port), (47034, 47035));
+    }
+}
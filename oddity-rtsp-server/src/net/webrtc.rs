@@ -0,0 +1,719 @@
+//! Media transport for WebRTC viewers: ICE-lite (RFC 8445 Section 2.5) and
+//! DTLS-SRTP (RFC 5764) on a single UDP socket. The server never initiates
+//! anything. It answers the connectivity checks of the browser, accepts its
+//! DTLS handshake, and then sends it SRTP.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+use sha2::{Digest, Sha256};
+
+use stun::attributes::{ATTR_USERNAME, ATTR_USE_CANDIDATE};
+use stun::fingerprint::FINGERPRINT;
+use stun::integrity::MessageIntegrity;
+use stun::message::{Message, BINDING_REQUEST, BINDING_SUCCESS};
+use stun::textattrs::TextAttribute;
+use stun::xoraddr::XorMappedAddress;
+
+use tokio::net::UdpSocket;
+use tokio::select;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, Instant};
+
+use webrtc_dtls::config::{ClientAuthType, Config as DtlsConfig, ExtendedMasterSecretType};
+use webrtc_dtls::conn::DTLSConn;
+use webrtc_dtls::crypto::Certificate;
+use webrtc_dtls::extension::extension_use_srtp::SrtpProtectionProfile;
+use webrtc_util::{Conn, KeyingMaterialExporter};
+
+use crate::net::srtp::{self, SrtpError, SrtpSender};
+use crate::runtime::task_manager::{Task, TaskContext};
+use crate::runtime::Runtime;
+
+/// Browsers check consent every five seconds. Without a check for this
+/// long, the browser is gone (RFC 7675 Section 5.1).
+const CONSENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long the browser gets to complete ICE and the DTLS handshake.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Exporter label for the keys of DTLS-SRTP (RFC 5764 Section 4.2).
+const SRTP_EXPORTER_LABEL: &str = "EXTRACTOR-dtls_srtp";
+
+/// Large enough for any datagram on a network with a regular MTU.
+const MAX_DATAGRAM_SIZE: usize = 2048;
+
+/// Self-signed certificate that the server identifies itself with in DTLS
+/// handshakes. Browsers do not check certificates against a CA, only
+/// against the fingerprint in the session description.
+pub struct DtlsIdentity {
+    certificate: Certificate,
+    fingerprint: String,
+}
+
+impl DtlsIdentity {
+    pub fn generate() -> Result<Self, WebRtcError> {
+        let certificate = Certificate::generate_self_signed(vec!["oddity".to_string()])
+            .map_err(WebRtcError::Dtls)?;
+        let fingerprint = certificate
+            .certificate
+            .first()
+            .map(|certificate| fingerprint(&certificate.0))
+            .unwrap_or_default();
+        Ok(Self {
+            certificate,
+            fingerprint,
+        })
+    }
+
+    /// SHA-256 fingerprint of the certificate, as in the value of the SDP
+    /// `fingerprint` attribute (RFC 8122 Section 5).
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+}
+
+/// ICE username fragment and password of one side of a session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IceCredentials {
+    pub ufrag: String,
+    pub pwd: String,
+}
+
+impl IceCredentials {
+    /// Random credentials, with at least the 24 and 128 bits of randomness
+    /// that RFC 8445 Section 5.3 asks for.
+    pub fn generate() -> Self {
+        Self {
+            ufrag: random_string(8),
+            pwd: random_string(24),
+        }
+    }
+}
+
+/// What the server learned about the browser from its offer.
+#[derive(Debug, Clone)]
+pub struct RemoteDescription {
+    pub ice_ufrag: String,
+    /// Fingerprint of the certificate the browser uses in the DTLS
+    /// handshake, in the same form as [`DtlsIdentity::fingerprint`].
+    pub fingerprint: String,
+}
+
+/// Connected WebRTC transport that sends SRTP to a single browser.
+pub struct WebRtcTransport {
+    socket: Arc<UdpSocket>,
+    peer: Arc<Peer>,
+    dtls: Arc<DTLSConn>,
+    srtp: SrtpSender,
+    demuxer: Task,
+    /// Completes (with an error) when the demuxer stops, which is when the
+    /// browser stops checking consent.
+    gone_rx: oneshot::Receiver<()>,
+}
+
+impl WebRtcTransport {
+    /// Wait for the browser to connect to `socket`, and complete the DTLS
+    /// handshake with it.
+    pub async fn accept(
+        socket: UdpSocket,
+        local: IceCredentials,
+        remote: RemoteDescription,
+        identity: &DtlsIdentity,
+        runtime: &Runtime,
+    ) -> Result<Self, WebRtcError> {
+        let socket = Arc::new(socket);
+        let peer = Arc::new(Peer::default());
+        let (dtls_tx, dtls_rx) = mpsc::channel(64);
+        let (gone_tx, gone_rx) = oneshot::channel();
+
+        let mut demuxer = runtime
+            .task()
+            .spawn({
+                let socket = socket.clone();
+                let peer = peer.clone();
+                let remote_ufrag = remote.ice_ufrag.clone();
+                move |task_context| {
+                    Self::run(
+                        socket,
+                        peer,
+                        local,
+                        remote_ufrag,
+                        dtls_tx,
+                        gone_tx,
+                        task_context,
+                    )
+                }
+            })
+            .await;
+
+        let conn = DemuxConn::new(socket.clone(), peer.clone(), dtls_rx);
+        let connected = time::timeout(
+            CONNECT_TIMEOUT,
+            Self::handshake(conn, identity, &remote.fingerprint),
+        )
+        .await
+        .unwrap_or(Err(WebRtcError::Timeout));
+        let (dtls, srtp) = match connected {
+            Ok(connected) => connected,
+            Err(err) => {
+                demuxer.stop().await;
+                return Err(err);
+            }
+        };
+
+        Ok(Self {
+            socket,
+            peer,
+            dtls,
+            srtp,
+            demuxer,
+            gone_rx,
+        })
+    }
+
+    /// Encrypt an RTP packet and send it to the browser.
+    pub async fn send_rtp(&mut self, packet: &[u8]) -> Result<(), WebRtcError> {
+        let Some(peer) = self.peer.addr() else {
+            return Ok(());
+        };
+        let packet = self.srtp.protect(packet).map_err(WebRtcError::Srtp)?;
+        self.socket
+            .send_to(&packet, peer)
+            .await
+            .map_err(WebRtcError::Io)?;
+        Ok(())
+    }
+
+    /// Wait until the browser is gone.
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancel safe.
+    pub async fn gone(&mut self) {
+        let _ = (&mut self.gone_rx).await;
+    }
+
+    pub async fn close(&mut self) {
+        // Tells the browser right away, instead of waiting for consent to
+        // expire on its side.
+        if let Err(err) = self.dtls.close().await {
+            tracing::trace!(%err, "failed to close dtls connection");
+        }
+        self.demuxer.stop().await;
+    }
+
+    async fn handshake(
+        conn: DemuxConn,
+        identity: &DtlsIdentity,
+        remote_fingerprint: &str,
+    ) -> Result<(Arc<DTLSConn>, SrtpSender), WebRtcError> {
+        let config = DtlsConfig {
+            certificates: vec![identity.certificate.clone()],
+            srtp_protection_profiles: vec![SrtpProtectionProfile::Srtp_Aes128_Cm_Hmac_Sha1_80],
+            extended_master_secret: ExtendedMasterSecretType::Require,
+            // The certificate of the browser is self-signed too. It is checked
+            // against the fingerprint in its offer instead.
+            client_auth: ClientAuthType::RequireAnyClientCert,
+            insecure_skip_verify: true,
+            ..Default::default()
+        };
+        let dtls = DTLSConn::new(Arc::new(conn), config, false, None)
+            .await
+            .map_err(WebRtcError::Dtls)?;
+
+        let state = dtls.connection_state().await;
+        let peer_fingerprint = state
+            .peer_certificates
+            .first()
+            .map(|certificate| fingerprint(certificate));
+        if !peer_fingerprint.is_some_and(|peer_fingerprint| {
+            peer_fingerprint.eq_ignore_ascii_case(remote_fingerprint)
+        }) {
+            let _ = dtls.close().await;
+            return Err(WebRtcError::FingerprintMismatch);
+        }
+
+        if dtls.selected_srtpprotection_profile()
+            != SrtpProtectionProfile::Srtp_Aes128_Cm_Hmac_Sha1_80
+        {
+            let _ = dtls.close().await;
+            return Err(WebRtcError::NoSrtpProfile);
+        }
+        // The client keys come first, then the server keys, then the client
+        // salt and the server salt.
+        let keying_material = state
+            .export_keying_material(
+                SRTP_EXPORTER_LABEL,
+                &[],
+                2 * (srtp::MASTER_KEY_LEN + srtp::MASTER_SALT_LEN),
+            )
+            .await
+            .map_err(|err| WebRtcError::Dtls(err.into()))?;
+        let (keys, salts) = keying_material.split_at(2 * srtp::MASTER_KEY_LEN);
+        let srtp = SrtpSender::new(
+            &keys[srtp::MASTER_KEY_LEN..],
+            &salts[srtp::MASTER_SALT_LEN..],
+        )
+        .map_err(WebRtcError::Srtp)?;
+
+        Ok((Arc::new(dtls), srtp))
+    }
+
+    /// Receive everything the browser sends on the socket: connectivity
+    /// checks are answered here, and DTLS records go to the DTLS connection.
+    /// RTCP feedback is ignored, since the source cannot act on it anyway.
+    #[allow(clippy::too_many_arguments)]
+    async fn run(
+        socket: Arc<UdpSocket>,
+        peer: Arc<Peer>,
+        local: IceCredentials,
+        remote_ufrag: String,
+        dtls_tx: mpsc::Sender<Vec<u8>>,
+        _gone_tx: oneshot::Sender<()>,
+        mut task_context: TaskContext,
+    ) {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let mut last_check = Instant::now();
+        let mut consent_check = time::interval(CONSENT_TIMEOUT / 6);
+
+        loop {
+            select! {
+                // CANCEL SAFETY: `UdpSocket::recv_from` is cancel safe.
+                received = socket.recv_from(&mut buf) => {
+                    let (len, from) = match received {
+                        Ok(received) => received,
+                        Err(err) => {
+                            tracing::debug!(%err, "failed to receive from webrtc peer");
+                            continue;
+                        }
+                    };
+                    let datagram = &buf[..len];
+                    match classify(datagram) {
+                        Some(Datagram::Stun) => {
+                            let Some((response, nominated)) =
+                                answer_binding_request(datagram, from, &local, &remote_ufrag)
+                            else {
+                                tracing::trace!(%from, "ignoring stun message");
+                                continue;
+                            };
+                            last_check = Instant::now();
+                            if nominated || peer.addr().is_none() {
+                                peer.set_addr(from);
+                            }
+                            if let Err(err) = socket.send_to(&response, from).await {
+                                tracing::debug!(%err, %from, "failed to answer connectivity check");
+                            }
+                        },
+                        Some(Datagram::Dtls) if peer.addr() == Some(from) => {
+                            if let Err(err) = dtls_tx.try_send(datagram.to_vec()) {
+                                tracing::trace!(%from, %err, "dropping dtls record");
+                            }
+                        },
+                        _ => {},
+                    }
+                },
+                // CANCEL SAFETY: `Interval::tick` is cancel safe.
+                _ = consent_check.tick() => {
+                    if last_check.elapsed() > CONSENT_TIMEOUT {
+                        tracing::debug!(peer = ?peer.addr(), "webrtc peer consent expired");
+                        break;
+                    }
+                },
+                // CANCEL SAFETY: `TaskContext::wait_for_stop` is cancel safe.
+                _ = task_context.wait_for_stop() => {
+                    break;
+                },
+            }
+        }
+    }
+}
+
+/// Address of the browser, once it sent a valid connectivity check.
+#[derive(Default)]
+struct Peer {
+    addr: Mutex<Option<SocketAddr>>,
+}
+
+impl Peer {
+    fn addr(&self) -> Option<SocketAddr> {
+        *self
+            .addr
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn set_addr(&self, addr: SocketAddr) {
+        *self
+            .addr
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(addr);
+    }
+}
+
+/// Connection that the DTLS implementation runs over. It receives the DTLS
+/// records that the demuxer picked out, and sends to the browser.
+struct DemuxConn {
+    socket: Arc<UdpSocket>,
+    peer: Arc<Peer>,
+    dtls_rx: tokio::sync::Mutex<mpsc::Receiver<Vec<u8>>>,
+}
+
+impl DemuxConn {
+    fn new(socket: Arc<UdpSocket>, peer: Arc<Peer>, dtls_rx: mpsc::Receiver<Vec<u8>>) -> Self {
+        Self {
+            socket,
+            peer,
+            dtls_rx: tokio::sync::Mutex::new(dtls_rx),
+        }
+    }
+
+    fn peer_addr(&self) -> webrtc_util::Result<SocketAddr> {
+        self.peer
+            .addr()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected).into())
+    }
+}
+
+#[async_trait]
+impl Conn for DemuxConn {
+    async fn connect(&self, _addr: SocketAddr) -> webrtc_util::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported).into())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> webrtc_util::Result<usize> {
+        match self.dtls_rx.lock().await.recv().await {
+            Some(record) => {
+                let len = record.len().min(buf.len());
+                buf[..len].copy_from_slice(&record[..len]);
+                Ok(len)
+            }
+            // The DTLS reader tries again right away on errors, until the
+            // connection is closed. There is nothing more to receive.
+            None => std::future::pending().await,
+        }
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> webrtc_util::Result<(usize, SocketAddr)> {
+        let len = self.recv(buf).await?;
+        Ok((len, self.peer_addr()?))
+    }
+
+    async fn send(&self, buf: &[u8]) -> webrtc_util::Result<usize> {
+        let peer = self.peer_addr()?;
+        Ok(self.socket.send_to(buf, peer).await?)
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> webrtc_util::Result<usize> {
+        Ok(self.socket.send_to(buf, target).await?)
+    }
+
+    fn local_addr(&self) -> webrtc_util::Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.peer.addr()
+    }
+
+    async fn close(&self) -> webrtc_util::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Datagram {
+    Stun,
+    Dtls,
+    Rtp,
+}
+
+/// Tell apart the protocols that share the socket by the first byte
+/// (RFC 7983 Section 7).
+fn classify(datagram: &[u8]) -> Option<Datagram> {
+    match datagram.first()? {
+        0..=3 => Some(Datagram::Stun),
+        20..=63 => Some(Datagram::Dtls),
+        128..=191 => Some(Datagram::Rtp),
+        _ => None,
+    }
+}
+
+/// Answer a connectivity check of the browser, if it is a binding request
+/// with the credentials of the session. Also returns whether the browser
+/// nominated the address it came from (RFC 8445 Section 7.3.1.5).
+fn answer_binding_request(
+    datagram: &[u8],
+    from: SocketAddr,
+    local: &IceCredentials,
+    remote_ufrag: &str,
+) -> Option<(Vec<u8>, bool)> {
+    let mut request = Message::new();
+    request.unmarshal_binary(datagram).ok()?;
+    if request.typ != BINDING_REQUEST {
+        return None;
+    }
+    // The username of checks the browser sends is the username fragment of
+    // the server, then its own (RFC 8445 Section 7.2.2).
+    let username = TextAttribute::get_from_as(&request, ATTR_USERNAME).ok()?;
+    if username.text != format!("{}:{}", local.ufrag, remote_ufrag) {
+        return None;
+    }
+    let integrity = MessageIntegrity::new_short_term_integrity(local.pwd.clone());
+    integrity.check(&mut request).ok()?;
+
+    let mut response = Message::new();
+    response
+        .build(&[
+            Box::new(request.clone()),
+            Box::new(BINDING_SUCCESS),
+            Box::new(XorMappedAddress {
+                ip: from.ip(),
+                port: from.port(),
+            }),
+            Box::new(integrity),
+            Box::new(FINGERPRINT),
+        ])
+        .ok()?;
+    Some((response.raw, request.contains(ATTR_USE_CANDIDATE)))
+}
+
+fn fingerprint(certificate: &[u8]) -> String {
+    Sha256::digest(certificate)
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+#[derive(Debug)]
+pub enum WebRtcError {
+    Io(io::Error),
+    Dtls(webrtc_dtls::Error),
+    Srtp(SrtpError),
+    /// The browser did not connect in time.
+    Timeout,
+    /// The certificate of the browser is not the one in its offer.
+    FingerprintMismatch,
+    /// The browser does not support any of the SRTP protection profiles of
+    /// the server.
+    NoSrtpProfile,
+}
+
+impl fmt::Display for WebRtcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WebRtcError::Io(err) => write!(f, "i/o error: {}", err),
+            WebRtcError::Dtls(err) => write!(f, "dtls error: {}", err),
+            WebRtcError::Srtp(err) => write!(f, "srtp error: {}", err),
+            WebRtcError::Timeout => write!(f, "peer did not connect in time"),
+            WebRtcError::FingerprintMismatch => {
+                write!(f, "peer certificate does not match fingerprint")
+            }
+            WebRtcError::NoSrtpProfile => write!(f, "no srtp protection profile in common"),
+        }
+    }
+}
+
+impl error::Error for WebRtcError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use aes::cipher::{BlockEncrypt, KeyInit};
+    use hmac::{Hmac, Mac};
+    use sha1::Sha1;
+    use stun::agent::TransactionId;
+    use stun::message::Getter;
+
+    fn binding_request(username: &str, pwd: &str) -> Vec<u8> {
+        let mut request = Message::new();
+        request
+            .build(&[
+                Box::new(BINDING_REQUEST),
+                Box::new(TransactionId::new()),
+                Box::new(TextAttribute::new(ATTR_USERNAME, username.to_string())),
+                Box::new(MessageIntegrity::new_short_term_integrity(pwd.to_string())),
+                Box::new(FINGERPRINT),
+            ])
+            .unwrap();
+        request.raw
+    }
+
+    /// AES in counter mode, block by block, to check the SRTP of the
+    /// sender against something that does not share its code.
+    fn aes_cm(key: &[u8], iv: [u8; 16], data: &mut [u8]) {
+        let cipher = aes::Aes128::new_from_slice(key).unwrap();
+        for (counter, chunk) in data.chunks_mut(16).enumerate() {
+            let mut block = iv;
+            let low = u16::from_be_bytes([block[14], block[15]]).wrapping_add(counter as u16);
+            block[14..].copy_from_slice(&low.to_be_bytes());
+            let mut block = block.into();
+            cipher.encrypt_block(&mut block);
+            for (byte, key) in chunk.iter_mut().zip(block.iter()) {
+                *byte ^= key;
+            }
+        }
+    }
+
+    /// Check and decrypt an SRTP packet without a header extension or
+    /// CSRCs, with a rollover counter of zero (RFC 3711 Section 3.3).
+    fn unprotect(master_key: &[u8], master_salt: &[u8], packet: &[u8]) -> Vec<u8> {
+        let session = |label: u8, len: usize| {
+            let mut iv = [0; 16];
+            iv[..14].copy_from_slice(master_salt);
+            iv[7] ^= label;
+            let mut out = vec![0; len];
+            aes_cm(master_key, iv, &mut out);
+            out
+        };
+        let (key, auth_key, salt) = (session(0, 16), session(1, 20), session(2, 14));
+
+        let (authenticated, tag) = packet.split_at(packet.len() - 10);
+        let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(&auth_key).unwrap();
+        mac.update(authenticated);
+        mac.update(&[0, 0, 0, 0]);
+        assert_eq!(&mac.finalize().into_bytes()[..10], tag);
+
+        let mut iv = [0; 16];
+        iv[..14].copy_from_slice(&salt);
+        for i in 0..4 {
+            iv[4 + i] ^= packet[8 + i];
+        }
+        iv[12] ^= packet[2];
+        iv[13] ^= packet[3];
+        let mut plain = authenticated.to_vec();
+        aes_cm(&key, iv, &mut plain[12..]);
+        plain
+    }
+
+    fn credentials() -> (IceCredentials, String) {
+        let local = IceCredentials {
+            ufrag: "loca".to_string(),
+            pwd: "localpasswordlocalpasswo".to_string(),
+        };
+        (local, "remo".to_string())
+    }
+
+    #[test]
+    fn answer_connectivity_check() {
+        let (local, remote_ufrag) = credentials();
+        let from = "192.0.2.1:5000".parse().unwrap();
+        let request = binding_request("loca:remo", &local.pwd);
+        assert_eq!(classify(&request), Some(Datagram::Stun));
+        let (response, nominated) =
+            answer_binding_request(&request, from, &local, &remote_ufrag).unwrap();
+        assert!(!nominated);
+
+        let mut response_message = Message::new();
+        response_message.unmarshal_binary(&response).unwrap();
+        assert_eq!(response_message.typ, BINDING_SUCCESS);
+        let mut mapped = XorMappedAddress::default();
+        mapped.get_from(&response_message).unwrap();
+        assert_eq!(SocketAddr::new(mapped.ip, mapped.port), from);
+        MessageIntegrity::new_short_term_integrity(local.pwd.clone())
+            .check(&mut response_message)
+            .unwrap();
+    }
+
+    #[test]
+    fn ignore_connectivity_check_of_others() {
+        let (local, remote_ufrag) = credentials();
+        let from = "192.0.2.1:5000".parse().unwrap();
+        let request = binding_request("loca:othr", &local.pwd);
+        assert!(answer_binding_request(&request, from, &local, &remote_ufrag).is_none());
+        let request = binding_request("loca:remo", "wrongpasswordwrongpasswo");
+        assert!(answer_binding_request(&request, from, &local, &remote_ufrag).is_none());
+    }
+
+    #[tokio::test]
+    async fn accept() {
+        let (local, remote_ufrag) = credentials();
+        let server_identity = DtlsIdentity::generate().unwrap();
+        let client_identity = DtlsIdentity::generate().unwrap();
+        let runtime = Runtime::new();
+
+        let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server_socket.local_addr().unwrap();
+        let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client_socket.connect(server_addr).await.unwrap();
+
+        let client = async {
+            client_socket
+                .send(&binding_request("loca:remo", &local.pwd))
+                .await
+                .unwrap();
+            let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+            let len = client_socket.recv(&mut buf).await.unwrap();
+            assert_eq!(classify(&buf[..len]), Some(Datagram::Stun));
+
+            let config = DtlsConfig {
+                certificates: vec![client_identity.certificate.clone()],
+                srtp_protection_profiles: vec![SrtpProtectionProfile::Srtp_Aes128_Cm_Hmac_Sha1_80],
+                extended_master_secret: ExtendedMasterSecretType::Require,
+                insecure_skip_verify: true,
+                ..Default::default()
+            };
+            let client_socket: Arc<dyn Conn + Send + Sync> = Arc::new(client_socket);
+            let dtls = DTLSConn::new(client_socket.clone(), config, true, None)
+                .await
+                .unwrap();
+            (dtls, client_socket)
+        };
+        let server = WebRtcTransport::accept(
+            server_socket,
+            local.clone(),
+            RemoteDescription {
+                ice_ufrag: remote_ufrag,
+                fingerprint: client_identity.fingerprint().to_lowercase(),
+            },
+            &server_identity,
+            &runtime,
+        );
+        let (server, (client_dtls, client_socket)) = tokio::join!(server, client);
+        let mut server = server.unwrap();
+
+        let state = client_dtls.connection_state().await;
+        assert_eq!(
+            fingerprint(&state.peer_certificates[0]),
+            server_identity.fingerprint()
+        );
+        let keying_material = state
+            .export_keying_material(SRTP_EXPORTER_LABEL, &[], 60)
+            .await
+            .unwrap();
+        // The server is the DTLS server, so it sends with the server write
+        // key and salt (RFC 5764 Section 4.2).
+        let (server_key, server_salt) = (&keying_material[16..32], &keying_material[46..60]);
+
+        let mut packet = vec![0x80, 0x60, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1];
+        packet.extend_from_slice(b"a payload that spans more than one aes block");
+        server.send_rtp(&packet).await.unwrap();
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let len = client_socket.recv(&mut buf).await.unwrap();
+        assert_eq!(classify(&buf[..len]), Some(Datagram::Rtp));
+        assert_ne!(buf[12..len - 10], packet[12..]);
+        assert_eq!(unprotect(server_key, server_salt, &buf[..len]), packet);
+
+        server.close().await;
+        runtime.stop().await;
+    }
+}
@@ -0,0 +1,249 @@
+//! RTSP over WebSocket, for players in browsers. The client sends RTSP
+//! requests and interleaved data in binary (or text) messages, and gets
+//! responses and interleaved data back the same way, as if the WebSocket
+//! were a TCP connection. Messages do not have to line up with RTSP
+//! messages, but the server sends every message or frame in its own.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+
+use futures::stream::{SplitSink, SplitStream};
+use futures::{ready, Sink, Stream, StreamExt};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+use tokio_util::io::StreamReader;
+
+/// Subprotocol that players ask for.
+const SUBPROTOCOL: &str = "rtsp";
+
+/// Complete the WebSocket handshake of a client.
+pub async fn accept<S>(stream: S) -> Result<WebSocketStream<S>, io::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    tokio_tungstenite::accept_hdr_async(stream, select_subprotocol)
+        .await
+        .map_err(into_io_error)
+}
+
+/// Browsers close the WebSocket if they asked for a subprotocol and the
+/// server did not pick one.
+#[allow(clippy::result_large_err)]
+fn select_subprotocol(
+    request: &Request,
+    mut response: Response,
+) -> Result<Response, ErrorResponse> {
+    let offers_subprotocol = request
+        .headers()
+        .get_all("Sec-WebSocket-Protocol")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|subprotocol| subprotocol.trim().eq_ignore_ascii_case(SUBPROTOCOL));
+    if offers_subprotocol {
+        response.headers_mut().insert(
+            "Sec-WebSocket-Protocol",
+            HeaderValue::from_static(SUBPROTOCOL),
+        );
+    }
+    Ok(response)
+}
+
+/// Split the WebSocket into a reader of the data the client sends, and a
+/// writer that sends every write in a binary message.
+pub fn split<S>(websocket: WebSocketStream<S>) -> (WebSocketReader<S>, WebSocketWriter<S>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (sink, stream) = websocket.split();
+    (StreamReader::new(Messages(stream)), WebSocketWriter(sink))
+}
+
+pub type WebSocketReader<S> = StreamReader<Messages<S>, Bytes>;
+
+/// Data in the messages that the client sends. Control messages are
+/// handled by the WebSocket itself.
+pub struct Messages<S>(SplitStream<WebSocketStream<S>>);
+
+impl<S> Stream for Messages<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Item = Result<Bytes, io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let message = match ready!(self.0.poll_next_unpin(cx)) {
+                Some(Ok(message)) => message,
+                Some(Err(tungstenite::Error::ConnectionClosed)) | None => return Poll::Ready(None),
+                Some(Err(err)) => return Poll::Ready(Some(Err(into_io_error(err)))),
+            };
+            match message {
+                Message::Binary(data) => return Poll::Ready(Some(Ok(data.into()))),
+                Message::Text(text) => return Poll::Ready(Some(Ok(text.into_bytes().into()))),
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {}
+                Message::Close(_) => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+pub struct WebSocketWriter<S>(SplitSink<WebSocketStream<S>, Message>);
+
+impl<S> AsyncWrite for WebSocketWriter<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        ready!(Pin::new(&mut self.0).poll_ready(cx)).map_err(into_io_error)?;
+        Pin::new(&mut self.0)
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(into_io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.0).poll_flush(cx).map_err(into_io_error)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.0).poll_close(cx).map_err(into_io_error)
+    }
+}
+
+/// The connection treats resets as the client going away, which is what a
+/// closed WebSocket means too.
+fn into_io_error(err: tungstenite::Error) -> io::Error {
+    match err {
+        tungstenite::Error::Io(err) => err,
+        tungstenite::Error::ConnectionClosed
+        | tungstenite::Error::AlreadyClosed
+        | tungstenite::Error::Protocol(
+            tungstenite::error::ProtocolError::ResetWithoutClosingHandshake,
+        ) => io::ErrorKind::ConnectionReset.into(),
+        err => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::SinkExt;
+
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::handshake::client::Response as ClientResponse;
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    use oddity_rtsp_protocol::{
+        AsServer, Codec, Method, RequestMaybeInterleaved, ResponseMaybeInterleaved,
+    };
+
+    async fn connect(
+        subprotocol: Option<&str>,
+    ) -> (
+        WebSocketStream<TcpStream>,
+        ClientResponse,
+        WebSocketStream<TcpStream>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut request = format!("ws://{addr}/").into_client_request().unwrap();
+        if let Some(subprotocol) = subprotocol {
+            request.headers_mut().insert(
+                "Sec-WebSocket-Protocol",
+                HeaderValue::from_str(subprotocol).unwrap(),
+            );
+        }
+        let client = async {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            tokio_tungstenite::client_async(request, stream)
+                .await
+                .unwrap()
+        };
+        let server = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            accept(stream).await.unwrap()
+        };
+        let ((client, response), server) = tokio::join!(client, server);
+        (client, response, server)
+    }
+
+    #[tokio::test]
+    async fn subprotocol() {
+        let (_, response, _) = connect(Some("chat,rtsp")).await;
+        assert_eq!(
+            response.headers().get("Sec-WebSocket-Protocol").unwrap(),
+            "rtsp"
+        );
+        let (_, response, _) = connect(None).await;
+        assert!(response.headers().get("Sec-WebSocket-Protocol").is_none());
+    }
+
+    #[tokio::test]
+    async fn rtsp_over_websocket() {
+        let (mut client, _, server) = connect(Some("rtsp")).await;
+        let (read, write) = split(server);
+        let mut inbound = FramedRead::new(read, Codec::<AsServer>::new());
+        let mut outbound = FramedWrite::new(write, Codec::<AsServer>::new());
+
+        // Requests may be split over several messages.
+        client
+            .send(Message::Binary(
+                b"OPTIONS rtsp://localhost/ RTSP/1.0\r\n".to_vec(),
+            ))
+            .await
+            .unwrap();
+        client
+            .send(Message::Text("CSeq: 1\r\n\r\n".to_string()))
+            .await
+            .unwrap();
+        client
+            .send(Message::Binary(vec![b'$', 1, 0, 2, 0xaa, 0xbb]))
+            .await
+            .unwrap();
+        match inbound.next().await.unwrap().unwrap() {
+            RequestMaybeInterleaved::Message(request) => {
+                assert_eq!(request.method, Method::Options);
+            }
+            _ => panic!("expected request"),
+        }
+        match inbound.next().await.unwrap().unwrap() {
+            RequestMaybeInterleaved::Interleaved { channel, payload } => {
+                assert_eq!(channel, 1);
+                assert_eq!(payload.as_ref(), &[0xaa, 0xbb]);
+            }
+            _ => panic!("expected interleaved data"),
+        }
+
+        outbound
+            .send(ResponseMaybeInterleaved::Interleaved {
+                channel: 0,
+                payload: Bytes::from_static(&[0x80, 0x60]),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            Message::Binary(vec![b'$', 0, 0, 2, 0x80, 0x60])
+        );
+
+        client.close(None).await.unwrap();
+        assert!(inbound.next().await.is_none());
+    }
+}
@@ -0,0 +1,132 @@
+//! Feedback that clients send about the media they play: RTCP receiver
+//! reports with reception quality, and BYE when they leave.
+
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use bytes::Bytes;
+
+use futures::stream::{self, SelectAll, Stream, StreamExt};
+
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use crate::media::rtcp::{self, ReceivedRtcp, SenderReporter};
+use crate::net::udp::UdpSocketPair;
+use crate::session::setup::RtcpInput;
+use crate::session::{Activity, SessionId};
+
+/// Largest possible UDP payload.
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+type FeedbackStream = Pin<Box<dyn Stream<Item = (usize, Bytes)> + Send>>;
+
+/// Sockets to receive RTCP over UDP on, and the address of the client.
+type UdpInput = (Arc<UdpSocketPair>, IpAddr);
+
+/// RTCP packets that the client sends for all tracks of a session, along
+/// with the index of the track they were sent for.
+#[derive(Default)]
+pub(super) struct FeedbackInputs {
+    streams: SelectAll<FeedbackStream>,
+}
+
+impl FeedbackInputs {
+    pub fn add(&mut self, track: usize, input: RtcpInput) {
+        let stream: FeedbackStream = match input {
+            RtcpInput::Udp {
+                sockets,
+                peer_ip_addr,
+            } => Box::pin(
+                stream::unfold((sockets, peer_ip_addr), receive_datagram)
+                    .map(move |data| (track, data)),
+            ),
+            RtcpInput::Interleaved(interleaved_rx) => Box::pin(
+                UnboundedReceiverStream::new(interleaved_rx).map(move |data| (track, data)),
+            ),
+        };
+        self.streams.push(stream);
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.streams.is_empty()
+    }
+
+    /// Next RTCP packet. Returns `None` if there are no inputs left.
+    pub async fn next(&mut self) -> Option<(usize, Bytes)> {
+        self.streams.next().await
+    }
+}
+
+async fn receive_datagram((sockets, peer_ip_addr): UdpInput) -> Option<(Bytes, UdpInput)> {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        match sockets.rtcp.recv_from(&mut buf).await {
+            Ok((size, from)) => {
+                if from.ip() != peer_ip_addr {
+                    tracing::debug!(%from, "dropping rtcp packet from unknown sender");
+                    continue;
+                }
+                buf.truncate(size);
+                return Some((buf.into(), (sockets, peer_ip_addr)));
+            }
+            Err(err) => {
+                tracing::error!(%err, "failed to receive rtcp packet");
+                return None;
+            }
+        }
+    }
+}
+
+/// Act on an RTCP packet that the client sent for a track, of which
+/// `reporter` keeps track of what was sent. The reception quality in
+/// reports about the track is logged, and reports keep the session alive
+/// through `activity`.
+///
+/// Returns `true` if the client said BYE, which means it is gone and the
+/// session can be torn down right away.
+pub(super) fn handle(
+    id: &SessionId,
+    track: usize,
+    packet: &[u8],
+    reporter: &SenderReporter,
+    activity: &Activity,
+) -> bool {
+    let arrival = SystemTime::now();
+    let packets = match rtcp::parse(packet) {
+        Some(packets) => packets,
+        None => {
+            tracing::debug!(%id, track, "dropping malformed rtcp packet");
+            return false;
+        }
+    };
+
+    let mut bye = false;
+    for packet in packets {
+        match packet {
+            ReceivedRtcp::Report { blocks, .. } => {
+                activity.touch();
+                for stats in blocks
+                    .iter()
+                    .filter_map(|block| reporter.reception_stats(block, arrival))
+                {
+                    tracing::debug!(
+                        %id,
+                        track,
+                        fraction_lost = stats.fraction_lost,
+                        cumulative_lost = stats.cumulative_lost,
+                        jitter = ?stats.jitter,
+                        round_trip_time = ?stats.round_trip_time,
+                        "client reported reception quality",
+                    );
+                }
+            }
+            ReceivedRtcp::Bye { .. } => {
+                bye = true;
+            }
+        }
+    }
+    bye
+}
@@ -1,14 +1,26 @@
+mod feedback;
+pub mod rewrite;
 mod transport;
+mod vod;
 
+pub mod record;
 pub mod session_manager;
 pub mod setup;
+pub mod whep;
 
+use std::collections::BTreeMap;
 use std::error;
 use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 use tokio::select;
 use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
 
 use rand::Rng;
 
@@ -16,12 +28,18 @@ use oddity_rtsp_protocol as rtsp;
 use video_rs as video;
 
 use crate::media;
-use crate::media::video::rtp_muxer::{make_rtp_muxer_builder, RtpMuxer};
-use crate::media::StreamState;
+use crate::media::rtcp::{self, SenderReporter};
+use crate::net::connection::ResponseSenderTx;
 use crate::runtime::task_manager::{Task, TaskContext};
 use crate::runtime::Runtime;
-use crate::session::setup::{SessionSetup, SessionSetupTarget};
-use crate::source::SourceDelegate;
+use crate::session::feedback::FeedbackInputs;
+use crate::session::record::RecordTrack;
+use crate::session::rewrite::RtpRewriter;
+use crate::session::setup::{SendError, SessionSetup, SessionSetupTarget};
+use crate::source::multicast::MulticastHandle;
+use crate::source::packetizer::{GopCache, RtpPacket};
+use crate::source::publish::SourcePublisher;
+use crate::source::{SourceDelegate, SourcePath, SourcePathRef, SourceRtpRx};
 
 pub enum SessionState {
     Stopped(SessionId),
@@ -30,11 +48,29 @@ pub enum SessionState {
 pub type SessionStateTx = mpsc::UnboundedSender<SessionState>;
 pub type SessionStateRx = mpsc::UnboundedReceiver<SessionState>;
 
-pub type SessionStreamStateTx = broadcast::Sender<media::StreamState>;
+pub type SessionStreamStateTx = broadcast::Sender<SessionStreamState>;
+
+/// Reply of a session to a stream state query.
+#[derive(Clone)]
+pub struct SessionStreamState {
+    /// Stream state of every track of the session, by track index.
+    pub tracks: Vec<(usize, media::StreamState)>,
+    /// Range that is played, for media played on demand.
+    pub range: Option<rtsp::Range>,
+}
 
 pub enum SessionControlMessage {
     Play,
+    Pause,
+    /// Play media on demand from `start` up to `end` from now on.
+    Seek {
+        start: f64,
+        end: Option<f64>,
+    },
     StreamState,
+    SetupTrack(usize, SessionSetupTarget),
+    Record,
+    AddTrack(RecordTrack),
 }
 
 pub type SessionControlTx = mpsc::UnboundedSender<SessionControlMessage>;
@@ -44,22 +80,135 @@ pub struct Session {
     worker: Task,
     control_tx: SessionControlTx,
     stream_state_tx: SessionStreamStateTx,
+    last_activity: Arc<Activity>,
+    kind: SessionKind,
+    peer_addr: Option<SocketAddr>,
+    /// Transport of every track, by track index.
+    transports: BTreeMap<usize, rtsp::Transport>,
+    counters: Arc<SessionCounters>,
+}
+
+/// Snapshot of a session, for reporting.
+pub struct SessionInfo {
+    pub id: SessionId,
+    /// Path of the source that is played or published.
+    pub source: SourcePath,
+    pub recording: bool,
+    pub peer_addr: Option<SocketAddr>,
+    pub transports: Vec<(usize, rtsp::Transport)>,
+    /// Bytes of RTP and RTCP sent to the client. Media delivered over
+    /// multicast is sent by the source, so it is not counted here.
+    pub bytes_sent: u64,
+    pub packets_sent: u64,
+}
+
+/// What a session sent to the client so far.
+#[derive(Default)]
+pub struct SessionCounters {
+    bytes_sent: AtomicU64,
+    packets_sent: AtomicU64,
+}
+
+impl SessionCounters {
+    fn sent(&self, len: usize) {
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// When the client was last heard from. Requests that refer to the session
+/// count, and so do RTCP reports that the client sends while it plays.
+#[derive(Debug)]
+pub struct Activity(Mutex<Instant>);
+
+impl Activity {
+    fn new() -> Self {
+        Self(Mutex::new(Instant::now()))
+    }
+
+    fn touch(&self) {
+        *self.lock() = Instant::now();
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.lock().elapsed()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Instant> {
+        // Nothing can panic while the lock is held.
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// What the client does in a session.
+enum SessionKind {
+    /// The client plays the given tracks of the source at `source`. Either
+    /// all tracks are delivered over multicast, or none of them are.
+    Play {
+        source: SourcePath,
+        tracks: Vec<usize>,
+        multicast: bool,
+        playback: Playback,
+    },
+    /// The client publishes media to the source at `source`.
+    Record { source: SourcePath },
+}
+
+/// How the media of a play session is delivered.
+#[derive(Clone, Copy)]
+enum Playback {
+    /// The packets of the source are shared with all other sessions, as
+    /// they come in.
+    Live,
+    /// The session reads the media by itself, so that the client can seek
+    /// in it. The duration is `None` if the media does not know it.
+    OnDemand { duration: Option<f64> },
 }
 
 impl Session {
+    /// Sessions that the client has not sent any requests for in this
+    /// time are torn down. This is advertised to clients in the reply to
+    /// SETUP (RFC 2326 Section 12.37).
+    pub const TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// How long to wait for the session worker to reply to a control
+    /// message before giving up on it.
+    const CONTROL_REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
     /// Any more than 16 media/stream info messages on the queue probably means
     /// something is really wrong and the server is overloaded.
     const MAX_QUEUED_INFO: usize = 16;
 
+    /// Start a session that plays a track of the source at `source`. More
+    /// tracks of the same source can be added with [`Session::setup_track`].
+    #[allow(clippy::too_many_arguments)]
     pub async fn setup_and_start(
         id: SessionId,
-        source_delegate: SourceDelegate,
+        source: SourcePath,
+        mut source_delegate: SourceDelegate,
+        track: usize,
         setup: SessionSetup,
+        peer_addr: Option<SocketAddr>,
         state_tx: SessionStateTx,
         runtime: &Runtime,
     ) -> Self {
         let (control_tx, control_rx) = mpsc::unbounded_channel();
         let (stream_state_tx, _) = broadcast::channel(Self::MAX_QUEUED_INFO);
+        let counters = Arc::new(SessionCounters::default());
+        let last_activity = Arc::new(Activity::new());
+        let transports = BTreeMap::from([(track, setup.rtsp_transport.clone())]);
+        let multicast = matches!(setup.rtp_target, SessionSetupTarget::RtpMulticast(_));
+        let playback = match source_delegate.vod() {
+            Some(_) => Playback::OnDemand {
+                duration: source_delegate
+                    .media_info()
+                    .await
+                    .and_then(|media_info| media_info.duration),
+            },
+            None => Playback::Live,
+        };
 
         tracing::trace!(%id, "starting session");
         let worker = runtime
@@ -67,11 +216,16 @@ impl Session {
             .spawn({
                 let id = id.clone();
                 let stream_state_tx = stream_state_tx.clone();
-                |task_context| {
+                let counters = counters.clone();
+                let last_activity = last_activity.clone();
+                move |task_context| {
                     Self::run(
                         id,
                         source_delegate,
+                        track,
                         setup,
+                        counters,
+                        last_activity,
                         control_rx,
                         state_tx,
                         stream_state_tx,
@@ -86,19 +240,135 @@ impl Session {
             worker,
             control_tx,
             stream_state_tx,
+            last_activity,
+            kind: SessionKind::Play {
+                source,
+                tracks: vec![track],
+                multicast,
+                playback,
+            },
+            peer_addr,
+            transports,
+            counters,
+        }
+    }
+
+    /// Start a session that receives media that the client publishes. The
+    /// session ends when the connection of `owner` goes away.
+    pub async fn setup_record_and_start(
+        id: SessionId,
+        publisher: SourcePublisher,
+        track: RecordTrack,
+        owner: ResponseSenderTx,
+        peer_addr: Option<SocketAddr>,
+        state_tx: SessionStateTx,
+        runtime: &Runtime,
+    ) -> Self {
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
+        let (stream_state_tx, _) = broadcast::channel(Self::MAX_QUEUED_INFO);
+        let source = publisher.path().to_string();
+        let transports = BTreeMap::from([(track.index, track.transport.clone())]);
+
+        tracing::trace!(%id, "starting record session");
+        let worker = runtime
+            .task()
+            .spawn({
+                let id = id.clone();
+                |task_context| async move {
+                    record::run(
+                        id.clone(),
+                        publisher,
+                        track,
+                        owner,
+                        control_rx,
+                        task_context,
+                    )
+                    .await;
+                    let _ = state_tx.send(SessionState::Stopped(id));
+                }
+            })
+            .await;
+        tracing::trace!(%id, "started record session");
+
+        Self {
+            worker,
+            control_tx,
+            stream_state_tx,
+            last_activity: Arc::new(Activity::new()),
+            kind: SessionKind::Record { source },
+            peer_addr,
+            transports,
+            counters: Arc::new(SessionCounters::default()),
         }
     }
 
+    /// Set up another track of the same source. All tracks of a session
+    /// share the same kind of transport, and every track can only be set
+    /// up once.
+    pub fn setup_track(
+        &mut self,
+        source: &SourcePathRef,
+        track: usize,
+        setup: SessionSetup,
+    ) -> Result<(), SetupTrackError> {
+        let SessionKind::Play {
+            source: session_source,
+            tracks,
+            multicast,
+            ..
+        } = &mut self.kind
+        else {
+            return Err(SetupTrackError::NotAllowed);
+        };
+        let is_multicast = matches!(setup.rtp_target, SessionSetupTarget::RtpMulticast(_));
+        if session_source != source || tracks.contains(&track) || *multicast != is_multicast {
+            return Err(SetupTrackError::NotAllowed);
+        }
+
+        tracing::trace!(track, "sending setup track signal to session");
+        self.control_tx
+            .send(SessionControlMessage::SetupTrack(track, setup.rtp_target))
+            .map_err(|_| SetupTrackError::ControlBroken)?;
+        tracks.push(track);
+        let _ = self.transports.insert(track, setup.rtsp_transport);
+        Ok(())
+    }
+
+    /// Start or resume playing. Returns the range that is played and the
+    /// stream state of every track of the session.
+    ///
+    /// Live media can only be played from now on. Media played on demand
+    /// can be played from anywhere, and resumes where it was paused if no
+    /// range or `npt=now-` is given.
     pub async fn play(
         &mut self,
         range: Option<rtsp::Range>,
-    ) -> Result<media::StreamState, PlaySessionError> {
-        if let Some(range) = range.as_ref() {
-            tracing::trace!(%range, "checking if provided range is valid and supported");
-            if !Self::is_range_supported(range) {
-                tracing::error!(%range, "session does not support playing with this range");
-                return Err(PlaySessionError::RangeNotSupported);
+    ) -> Result<(rtsp::Range, Vec<(usize, media::StreamState)>), PlaySessionError> {
+        let playback = match &self.kind {
+            SessionKind::Play { playback, .. } => *playback,
+            SessionKind::Record { .. } => return Err(PlaySessionError::Recording),
+        };
+        let seek = match (playback, range.as_ref()) {
+            (Playback::Live, Some(range)) => {
+                tracing::trace!(%range, "checking if provided range is valid and supported");
+                if !Self::is_range_supported(range) {
+                    tracing::error!(%range, "session does not support playing with this range");
+                    return Err(PlaySessionError::RangeNotSupported);
+                }
+                None
+            }
+            (Playback::OnDemand { duration }, Some(range)) => {
+                tracing::trace!(%range, "checking if provided range is within media");
+                Self::range_to_seek(range, duration)?
             }
+            (_, None) => None,
+        };
+
+        if let Some((start, end)) = seek {
+            tracing::trace!(start, end, "sending seek signal to session");
+            self.control_tx
+                .send(SessionControlMessage::Seek { start, end })
+                .map_err(|_| PlaySessionError::ControlBroken)?;
         }
 
         let mut stream_state_rx = self.stream_state_tx.subscribe();
@@ -107,9 +377,9 @@ impl Session {
             .send(SessionControlMessage::StreamState)
             .map_err(|_| PlaySessionError::ControlBroken)?;
 
-        let stream_state = stream_state_rx
-            .recv()
+        let stream_state = time::timeout(Self::CONTROL_REPLY_TIMEOUT, stream_state_rx.recv())
             .await
+            .map_err(|_| PlaySessionError::ControlTimeout)?
             .map_err(|_| PlaySessionError::ControlBroken)?;
         tracing::trace!("received stream state");
 
@@ -119,7 +389,95 @@ impl Session {
             .map_err(|_| PlaySessionError::ControlBroken)?;
         tracing::trace!("session playing");
 
-        Ok(stream_state)
+        // Live media just echoes the range that the client requested, since
+        // it was accepted it will be correct, or a generic `now-` range.
+        let range = stream_state
+            .range
+            .or(range)
+            .unwrap_or_else(rtsp::Range::new_for_live);
+        Ok((range, stream_state.tracks))
+    }
+
+    pub async fn pause(&mut self) -> Result<(), PauseSessionError> {
+        if self.is_recording() {
+            return Err(PauseSessionError::Recording);
+        }
+        tracing::trace!("sending pause signal to session");
+        self.control_tx
+            .send(SessionControlMessage::Pause)
+            .map_err(|_| PauseSessionError::ControlBroken)?;
+        tracing::trace!("session paused");
+        Ok(())
+    }
+
+    /// Refresh the session timeout. Must be called for every request that
+    /// refers to the session.
+    pub fn keep_alive(&mut self) {
+        self.last_activity.touch();
+    }
+
+    pub async fn record(&mut self) -> Result<(), RecordSessionError> {
+        if !self.is_recording() {
+            return Err(RecordSessionError::NotRecording);
+        }
+        tracing::trace!("sending record signal to session");
+        self.control_tx
+            .send(SessionControlMessage::Record)
+            .map_err(|_| RecordSessionError::ControlBroken)?;
+        tracing::trace!("session recording");
+        Ok(())
+    }
+
+    /// Add another published track to the session.
+    pub fn add_track(&mut self, track: RecordTrack) -> Result<(), RecordSessionError> {
+        if !self.is_recording() {
+            return Err(RecordSessionError::NotRecording);
+        }
+        let (index, transport) = (track.index, track.transport.clone());
+        self.control_tx
+            .send(SessionControlMessage::AddTrack(track))
+            .map_err(|_| RecordSessionError::ControlBroken)?;
+        let _ = self.transports.insert(index, transport);
+        Ok(())
+    }
+
+    /// Whether or not the client has not shown any sign of life for
+    /// longer than [`Session::TIMEOUT`]. Requests that refer to the session
+    /// and RTCP reports of the client count. Record sessions keep themselves
+    /// alive as long as media comes in, so they never expire here.
+    pub fn is_expired(&self) -> bool {
+        !self.is_recording() && self.last_activity.elapsed() > Self::TIMEOUT
+    }
+
+    /// Whether the client publishes media in this session instead of
+    /// playing it.
+    #[inline]
+    fn is_recording(&self) -> bool {
+        matches!(self.kind, SessionKind::Record { .. })
+    }
+
+    /// Path of the source that is played or published.
+    pub fn source(&self) -> &SourcePathRef {
+        match &self.kind {
+            SessionKind::Play { source, .. } | SessionKind::Record { source } => source,
+        }
+    }
+
+    pub fn info(&self, id: &SessionId) -> SessionInfo {
+        let source = self.source().to_string();
+        SessionInfo {
+            id: id.clone(),
+            source,
+            recording: self.is_recording(),
+            peer_addr: self.peer_addr,
+            transports: self
+                .transports
+                .iter()
+                .map(|(track, transport)| (*track, transport.clone()))
+                .collect(),
+            bytes_sent: self.counters.bytes_sent.load(Ordering::Relaxed),
+            packets_sent: self.counters.packets_sent.load(Ordering::Relaxed),
+        }
     }
 
     pub async fn teardown(&mut self) {
@@ -128,154 +486,363 @@ impl Session {
         tracing::trace!("session torn down");
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn run(
         id: SessionId,
         source_delegate: SourceDelegate,
+        track: usize,
         setup: SessionSetup,
+        counters: Arc<SessionCounters>,
+        last_activity: Arc<Activity>,
         control_rx: SessionControlRx,
         state_tx: SessionStateTx,
         stream_state_tx: SessionStreamStateTx,
         task_context: TaskContext,
     ) {
-        let muxer = setup.rtp_muxer;
-
-        match setup.rtp_target {
+        match &setup.rtp_target {
             SessionSetupTarget::RtpUdp(target) => {
-                tracing::error!(%id, "started session with unsupported transport");
-                tracing::trace!(%id, rtp_remote = %target.rtp_remote, rtcp_remote = %target.rtcp_remote);
+                tracing::trace!(
+                    %id,
+                    rtp_remote = %target.rtp_remote,
+                    rtcp_remote = %target.rtcp_remote,
+                    "starting rtp over udp loop",
+                );
             }
-            SessionSetupTarget::RtpTcp(target) => {
+            SessionSetupTarget::RtpTcp(_) => {
                 tracing::trace!(%id, "starting rtp over tcp (interleaved) loop");
-                Self::run_tcp_interleaved(
+            }
+            SessionSetupTarget::RtpMulticast(target) => {
+                tracing::trace!(%id, group = %target.handle.group, "starting rtp over multicast loop");
+            }
+        };
+
+        match setup.rtp_target {
+            SessionSetupTarget::RtpMulticast(target) => {
+                // The source delegate is not needed: the multicast sender
+                // of the source does all of the sending.
+                drop(source_delegate);
+                Self::run_multicast(
+                    id.clone(),
+                    target.handle,
+                    track,
+                    control_rx,
+                    stream_state_tx,
+                    task_context,
+                )
+                .await;
+            }
+            target if source_delegate.vod().is_some() => {
+                let mut source_delegate = source_delegate;
+                let vod = source_delegate.vod().cloned();
+                match (vod, source_delegate.media_info().await) {
+                    (Some(vod), Some(media_info)) => {
+                        vod::run(
+                            id.clone(),
+                            vod,
+                            media_info,
+                            track,
+                            target,
+                            counters,
+                            last_activity,
+                            control_rx,
+                            stream_state_tx,
+                            task_context,
+                        )
+                        .await;
+                    }
+                    _ => {
+                        tracing::error!(%id, "media info of source unavailable");
+                    }
+                }
+            }
+            target => {
+                Self::run_target(
                     id.clone(),
                     source_delegate,
-                    muxer,
+                    track,
                     target,
+                    counters,
+                    last_activity,
                     control_rx,
                     stream_state_tx,
                     task_context,
                 )
                 .await;
             }
-        };
+        }
 
         let _ = state_tx.send(SessionState::Stopped(id));
     }
 
-    async fn run_tcp_interleaved(
+    #[allow(clippy::too_many_arguments)]
+    async fn run_target(
         id: SessionId,
         source_delegate: SourceDelegate,
-        mut muxer: RtpMuxer,
-        target: setup::SendInterleaved,
+        track: usize,
+        mut target: SessionSetupTarget,
+        counters: Arc<SessionCounters>,
+        last_activity: Arc<Activity>,
         mut control_rx: SessionControlRx,
         stream_state_tx: SessionStreamStateTx,
         mut task_context: TaskContext,
     ) {
         let mut state = SessionMediaState::Ready;
-        let mut stream_state = StreamState::default();
+        let cname = rtcp::generate_cname();
+        let mut feedback_inputs = FeedbackInputs::default();
+        if let Some(rtcp_input) = target.rtcp_input() {
+            feedback_inputs.add(track, rtcp_input);
+        }
+        let mut tracks = BTreeMap::from([(track, SessionTrack::new(target, &cname, &counters))]);
+        let mut report_interval = time::interval(rtcp::REPORT_INTERVAL);
+        // Packets to send when the session starts playing: the packets of the
+        // source since its last keyframe, followed by the ones that came in
+        // after. The first of them is what the stream state reports to the
+        // client.
+        let mut start: Option<Vec<RtpPacket>> = None;
 
-        let (mut source_reset_rx, mut source_packet_rx) = source_delegate.into_parts();
+        let gop_cache = source_delegate.gop_cache();
+        let source_metrics = source_delegate.metrics();
+        let (mut source_rtp_rx, source_stream_state_rx) = source_delegate.into_parts();
 
         'main: loop {
             select! {
                 // CANCEL SAFETY: `broadcast::Receiver::recv` is cancel safe.
-                reset = source_reset_rx.recv() => {
-                    // If the source reader had an error and reinitialized its reader, then regained
-                    // the connection, we must reinitialize our muxer as well to cope.
-                    match reset {
-                        Ok(media_info) => {
-                            tracing::trace!("reinitializing muxer");
-                            let new_muxer = make_rtp_muxer_builder()
-                                .await
-                                .and_then(|mut rtp_muxer_builder| {
-                                    for stream_info in media_info.streams {
-                                        tracing::trace!(
-                                            stream_index=stream_info.index,
-                                            "reinitializing muxer: adding stream to muxer",
-                                        );
-                                        rtp_muxer_builder = rtp_muxer_builder.with_stream(stream_info)?;
-                                    }
-                                    Ok(rtp_muxer_builder)
-                                })
-                                .and_then(RtpMuxer::from_builder);
-
-                            match new_muxer {
-                                Ok(new_muxer) => {
-                                    muxer = new_muxer;
-                                },
-                                Err(err) => {
-                                    tracing::error!(%err, %id, "failed to reinitialize muxer");
-                                },
-                            };
-                        },
-                        Err(_) => {
-                            tracing::error!(%id, "source broken");
-                            break;
-                        },
-                    }
-                },
-                // CANCEL SAFETY: `broadcast::Receiver::recv` is cancel safe.
-                packet = source_packet_rx.recv() => {
+                packet = source_rtp_rx.recv() => {
                     match packet {
                         Ok(packet) => {
-                            let (muxed, packet) = muxer.muxed(packet).await;
-                            muxer = muxed;
-
-                            let (rtp_seq, rtp_timestamp) = muxer.seq_and_timestamp();
-                            stream_state = media::StreamState {
-                                rtp_seq,
-                                rtp_timestamp,
-                            };
-
-                            let packet = match packet {
-                                Ok(packet) => packet,
-                                Err(err) => {
-                                    tracing::error!(%id, %err, "failed to mux packet");
-                                    break;
-                                }
+                            if let Some(start) = start.as_mut() {
+                                start.push(packet);
+                                continue;
+                            }
+                            if state != SessionMediaState::Playing {
+                                continue;
+                            }
+                            // The source delivers all of its tracks, but only the ones
+                            // that the client set up are sent.
+                            let track = match tracks.get_mut(&packet.track) {
+                                Some(track) => track,
+                                None => continue,
                             };
-
-                            if state == SessionMediaState::Playing {
-                                let messages = packet.into_iter().map(|item| match item {
-                                    video::rtp::RtpBuf::Rtp(payload) => {
-                                        rtsp::ResponseMaybeInterleaved::Interleaved {
-                                            channel: target.rtp_channel,
-                                            payload: payload.into(),
-                                        }
-                                    }
-                                    video::rtp::RtpBuf::Rtcp(payload) => {
-                                        rtsp::ResponseMaybeInterleaved::Interleaved {
-                                            channel: target.rtcp_channel,
-                                            payload: payload.into(),
-                                        }
-                                    }
-                                });
-
-                                for message in messages {
-                                    if let Err(err) = target.sender.send(message) {
-                                        tracing::trace!(%id, %err, "underlying connection closed");
-                                        break 'main;
-                                    }
-                                }
+                            if let Err(err) = track.send(&id, &packet).await {
+                                tracing::trace!(%id, %err, "failed to send to client");
+                                break 'main;
                             }
                         }
-                        Err(_) => {
+                        Err(RecvError::Lagged(skipped)) => {
+                            // Packets were lost, so the client has to start over at
+                            // the next keyframe.
+                            tracing::warn!(%id, skipped, "session lagging behind source");
+                            source_metrics.lagged();
+                            for track in tracks.values_mut() {
+                                track.awaiting_keyframe = true;
+                            }
+                        }
+                        Err(RecvError::Closed) => {
                             tracing::error!(%id, "source broken");
                             break;
                         }
                     }
                 },
+                // CANCEL SAFETY: `Interval::tick` is cancel safe.
+                _ = report_interval.tick() => {
+                    if state != SessionMediaState::Playing {
+                        continue;
+                    }
+                    let now = SystemTime::now();
+                    for track in tracks.values_mut() {
+                        let report = match track.reporter.report(now) {
+                            Some(report) => report,
+                            None => continue,
+                        };
+                        let len = report.len();
+                        match track.target.send(video::rtp::RtpBuf::Rtcp(report)).await {
+                            Ok(()) => track.counters.sent(len),
+                            Err(SendError::QueueFull) => {}
+                            Err(err) => {
+                                tracing::trace!(%id, %err, "failed to send sender report to client");
+                                break 'main;
+                            }
+                        }
+                    }
+                },
+                // CANCEL SAFETY: `FeedbackInputs::next` uses `StreamExt::next` internally
+                // which is cancel safe.
+                Some((index, packet)) = feedback_inputs.next(), if !feedback_inputs.is_empty() => {
+                    let track = match tracks.get(&index) {
+                        Some(track) => track,
+                        None => continue,
+                    };
+                    if feedback::handle(&id, index, &packet, &track.reporter, &last_activity) {
+                        tracing::info!(%id, "client left session (rtcp bye)");
+                        break 'main;
+                    }
+                },
                 // CANCEL SAFETY: `mpsc::UnboundedReceiver::recv` is cancel safe.
                 message = control_rx.recv() => {
                     match message {
                         Some(SessionControlMessage::Play) => {
+                            match state {
+                                SessionMediaState::Ready => {
+                                    let start = start
+                                        .take()
+                                        .unwrap_or_else(|| Self::start_at_keyframe(&gop_cache, &mut source_rtp_rx, &mut tracks));
+                                    tracing::trace!(%id, packets = start.len(), "sending packets since keyframe");
+                                    for packet in start {
+                                        let track = match tracks.get_mut(&packet.track) {
+                                            Some(track) => track,
+                                            None => continue,
+                                        };
+                                        if let Err(err) = track.send(&id, &packet).await {
+                                            tracing::trace!(%id, %err, "failed to send to client");
+                                            break 'main;
+                                        }
+                                    }
+                                },
+                                SessionMediaState::Paused => {
+                                    // After a pause we wait for a keyframe so that the client can
+                                    // start decoding right away.
+                                    for track in tracks.values_mut() {
+                                        track.awaiting_keyframe = true;
+                                    }
+                                },
+                                SessionMediaState::Playing => {},
+                            }
                             state = SessionMediaState::Playing;
                             tracing::info!(%id, "session now playing");
                         },
+                        Some(SessionControlMessage::Pause) => {
+                            if state != SessionMediaState::Ready {
+                                state = SessionMediaState::Paused;
+                                for track in tracks.values_mut() {
+                                    track.rewriter.pause();
+                                }
+                            }
+                            tracing::info!(%id, "session paused");
+                        },
+                        Some(SessionControlMessage::StreamState) => {
+                            if state == SessionMediaState::Ready && start.is_none() {
+                                start = Some(Self::start_at_keyframe(&gop_cache, &mut source_rtp_rx, &mut tracks));
+                            }
+                            let stream_state = {
+                                let source_stream_state = source_stream_state_rx.borrow();
+                                SessionStreamState {
+                                    tracks: tracks
+                                        .iter()
+                                        .map(|(index, track)| {
+                                            let source_stream_state = start
+                                                .iter()
+                                                .flatten()
+                                                .find(|packet| packet.track == *index)
+                                                .and_then(RtpPacket::stream_state)
+                                                .unwrap_or_else(|| source_stream_state.get(*index).cloned().unwrap_or_default());
+                                            (*index, track.rewriter.rewrite_stream_state(&source_stream_state))
+                                        })
+                                        .collect(),
+                                    range: None,
+                                }
+                            };
+                            let _ = stream_state_tx.send(stream_state);
+                            tracing::trace!(%id, "dispatched stream state over control channel");
+                        },
+                        Some(SessionControlMessage::SetupTrack(index, mut target)) => {
+                            if let Some(rtcp_input) = target.rtcp_input() {
+                                feedback_inputs.add(index, rtcp_input);
+                            }
+                            let _ = tracks.insert(index, SessionTrack::new(target, &cname, &counters));
+                            tracing::trace!(%id, track = index, "added track to session");
+                        },
+                        Some(SessionControlMessage::Seek { .. }) => {
+                            tracing::warn!(%id, "ignored seek control message for live session");
+                        },
+                        Some(SessionControlMessage::Record | SessionControlMessage::AddTrack(_)) => {
+                            tracing::warn!(%id, "ignored record control message for play session");
+                        },
+                        None => {
+                            tracing::error!(%id, "session control channel broke unexpectedly");
+                            break;
+                        },
+                    };
+                },
+                // CANCEL SAFETY: `TaskContext::wait_for_stop` is cancel safe.
+                _ = task_context.wait_for_stop() => {
+                    tracing::trace!("tearing down session");
+                    break;
+                },
+            }
+        }
+    }
+
+    /// Packets to start playing with, beginning at the last keyframe of the
+    /// source. If the source did not have a keyframe yet, the tracks wait
+    /// for the next one instead.
+    fn start_at_keyframe(
+        gop_cache: &GopCache,
+        source_rtp_rx: &mut SourceRtpRx,
+        tracks: &mut BTreeMap<usize, SessionTrack>,
+    ) -> Vec<RtpPacket> {
+        let packets = gop_cache.replay(source_rtp_rx);
+        if packets.is_empty() {
+            for track in tracks.values_mut() {
+                track.awaiting_keyframe = true;
+            }
+        }
+        packets
+    }
+
+    async fn run_multicast(
+        id: SessionId,
+        handle: MulticastHandle,
+        track: usize,
+        mut control_rx: SessionControlRx,
+        stream_state_tx: SessionStreamStateTx,
+        mut task_context: TaskContext,
+    ) {
+        // The multicast sender only sends packets to the group while there
+        // is at least one viewer. Dropping the viewer (when the session is
+        // torn down) unregisters the session.
+        let mut viewer = None;
+        // The multicast sender delivers all tracks of the source to the
+        // group, so the tracks only matter for the stream state.
+        let mut tracks = vec![track];
+
+        loop {
+            select! {
+                // CANCEL SAFETY: `mpsc::UnboundedReceiver::recv` is cancel safe.
+                message = control_rx.recv() => {
+                    match message {
+                        Some(SessionControlMessage::Play) => {
+                            if viewer.is_none() {
+                                viewer = Some(handle.join());
+                            }
+                            tracing::info!(%id, group = %handle.group, "session now playing");
+                        },
+                        Some(SessionControlMessage::Pause) => {
+                            // Leaving the group stops the multicast sender if this was the
+                            // last viewer.
+                            viewer = None;
+                            tracing::info!(%id, group = %handle.group, "session paused");
+                        },
                         Some(SessionControlMessage::StreamState) => {
-                            let _ = stream_state_tx.send(stream_state.clone());
+                            let stream_state = SessionStreamState {
+                                tracks: tracks
+                                    .iter()
+                                    .map(|index| (*index, handle.stream_state(*index)))
+                                    .collect(),
+                                range: None,
+                            };
+                            let _ = stream_state_tx.send(stream_state);
                             tracing::trace!(%id, "dispatched stream state over control channel");
                         },
+                        Some(SessionControlMessage::SetupTrack(index, _)) => {
+                            tracks.push(index);
+                            tracing::trace!(%id, track = index, "added track to session");
+                        },
+                        Some(SessionControlMessage::Seek { .. }) => {
+                            tracing::warn!(%id, "ignored seek control message for live session");
+                        },
+                        Some(SessionControlMessage::Record | SessionControlMessage::AddTrack(_)) => {
+                            tracing::warn!(%id, "ignored record control message for play session");
+                        },
                         None => {
                             tracing::error!(%id, "session control channel broke unexpectedly");
                             break;
@@ -290,11 +857,38 @@ impl Session {
             }
         }
 
-        tracing::trace!(%id, "finishing muxer");
-        // Throw away possible last RTP buffer (we don't care about
-        // it since this is real-time and there's no "trailer".
-        let _ = muxer.finish().await;
-        tracing::trace!(%id, "finished muxer");
+        drop(viewer);
+    }
+
+    /// Turn a range for media played on demand into the start and end
+    /// to seek to. Returns `None` if the session should resume where it
+    /// paused. The end is capped at the duration of the media, since the
+    /// range that clients got in the SDP is rounded.
+    fn range_to_seek(
+        range: &rtsp::Range,
+        duration: Option<f64>,
+    ) -> Result<Option<(f64, Option<f64>)>, PlaySessionError> {
+        let start = match range.start.as_ref() {
+            Some(rtsp::NptTime::Now) if range.end.is_none() => return Ok(None),
+            Some(rtsp::NptTime::Now) => return Err(PlaySessionError::RangeNotSupported),
+            Some(rtsp::NptTime::Time(start)) => *start,
+            None => 0.0,
+        };
+        let end = match range.end.as_ref() {
+            Some(rtsp::NptTime::Now) => return Err(PlaySessionError::RangeNotSupported),
+            Some(rtsp::NptTime::Time(end)) => {
+                Some(duration.map_or(*end, |duration| end.min(duration)))
+            }
+            None => None,
+        };
+        let is_valid = start >= 0.0
+            && duration.is_none_or(|duration| start < duration)
+            && end.is_none_or(|end| end > start);
+        if !is_valid {
+            tracing::error!(%range, duration, "range is not within media");
+            return Err(PlaySessionError::RangeInvalid);
+        }
+        Ok(Some((start, end)))
     }
 
     fn is_range_supported(range: &rtsp::Range) -> bool {
@@ -306,7 +900,7 @@ impl Session {
     }
 }
 
-#[derive(Clone, PartialEq, Eq, Hash)]
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct SessionId(String);
 
 impl SessionId {
@@ -335,25 +929,267 @@ impl From<&str> for SessionId {
     }
 }
 
+#[derive(Debug)]
+pub enum SetupTrackError {
+    /// The session is not playing the same source over the same kind of
+    /// transport, or the track was already set up.
+    NotAllowed,
+    ControlBroken,
+}
+
+impl fmt::Display for SetupTrackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SetupTrackError::NotAllowed => write!(f, "track cannot be added to session"),
+            SetupTrackError::ControlBroken => write!(f, "failed to control session"),
+        }
+    }
+}
+
+impl error::Error for SetupTrackError {}
+
 #[derive(Debug)]
 pub enum PlaySessionError {
     RangeNotSupported,
+    /// The range is not within the media played on demand.
+    RangeInvalid,
+    Recording,
     ControlBroken,
+    /// The session did not reply in time.
+    ControlTimeout,
 }
 
 impl fmt::Display for PlaySessionError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             PlaySessionError::RangeNotSupported => write!(f, "range not supported"),
+            PlaySessionError::RangeInvalid => write!(f, "range not within media"),
+            PlaySessionError::Recording => write!(f, "session is recording"),
             PlaySessionError::ControlBroken => write!(f, "failed to control session"),
+            PlaySessionError::ControlTimeout => write!(f, "session did not reply in time"),
         }
     }
 }
 
 impl error::Error for PlaySessionError {}
 
+#[derive(Debug)]
+pub enum PauseSessionError {
+    Recording,
+    ControlBroken,
+}
+
+impl fmt::Display for PauseSessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PauseSessionError::Recording => write!(f, "session is recording"),
+            PauseSessionError::ControlBroken => write!(f, "failed to control session"),
+        }
+    }
+}
+
+impl error::Error for PauseSessionError {}
+
+#[derive(Debug)]
+pub enum RecordSessionError {
+    NotRecording,
+    ControlBroken,
+}
+
+impl fmt::Display for RecordSessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordSessionError::NotRecording => write!(f, "session is not recording"),
+            RecordSessionError::ControlBroken => write!(f, "failed to control session"),
+        }
+    }
+}
+
+impl error::Error for RecordSessionError {}
+
 #[derive(PartialEq)]
 enum SessionMediaState {
     Ready,
     Playing,
+    Paused,
+}
+
+/// Track that the client set up in a unicast session.
+struct SessionTrack {
+    target: SessionSetupTarget,
+    /// The source muxes every packet once and shares the result with all
+    /// sessions. We only rewrite the headers so that every track of every
+    /// session has its own SSRC, sequence numbers and timestamps.
+    rewriter: RtpRewriter,
+    /// Waiting for the next keyframe before sending anything, when playing
+    /// again after a pause, when there was no keyframe to start with, or
+    /// when packets were dropped because the client is too slow.
+    awaiting_keyframe: bool,
+    reporter: SenderReporter,
+    counters: Arc<SessionCounters>,
+}
+
+impl SessionTrack {
+    fn new(target: SessionSetupTarget, cname: &str, counters: &Arc<SessionCounters>) -> Self {
+        Self {
+            target,
+            rewriter: RtpRewriter::new(),
+            awaiting_keyframe: false,
+            reporter: SenderReporter::new(cname),
+            counters: counters.clone(),
+        }
+    }
+
+    /// Rewrite a packet of the source and send it to the client, unless
+    /// the track is waiting for a keyframe. If the packet has to be dropped
+    /// because the client is too slow, the track waits for the next
+    /// keyframe, since the client cannot decode anything before it.
+    async fn send(&mut self, id: &SessionId, packet: &RtpPacket) -> Result<(), SendError> {
+        if self.awaiting_keyframe {
+            if !packet.is_key {
+                return Ok(());
+            }
+            self.awaiting_keyframe = false;
+            tracing::trace!(%id, track = packet.track, "reached keyframe");
+        }
+        for rtp_buf in packet.bufs.iter() {
+            let rtp_buf = match rtp_buf {
+                video::rtp::RtpBuf::Rtp(payload) => self
+                    .rewriter
+                    .rewrite_rtp(payload)
+                    .map(video::rtp::RtpBuf::Rtp),
+                video::rtp::RtpBuf::Rtcp(payload) => self
+                    .rewriter
+                    .rewrite_rtcp(payload)
+                    .map(video::rtp::RtpBuf::Rtcp),
+            };
+            let rtp_buf = match rtp_buf {
+                Some(rtp_buf) => rtp_buf,
+                None => {
+                    tracing::warn!(%id, "dropping malformed rtp packet");
+                    continue;
+                }
+            };
+            // Packets dropped because the queue is full already have a sequence
+            // number, so to the client they are lost on the way, and they count
+            // as sent.
+            let len = match &rtp_buf {
+                video::rtp::RtpBuf::Rtp(payload) => {
+                    self.reporter
+                        .sent(payload, packet.clock_rate, packet.received);
+                    payload.len()
+                }
+                video::rtp::RtpBuf::Rtcp(payload) => payload.len(),
+            };
+            match self.target.send(rtp_buf).await {
+                Ok(()) => self.counters.sent(len),
+                Err(SendError::QueueFull) => {
+                    self.awaiting_keyframe = true;
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: Option<rtsp::NptTime>, end: Option<rtsp::NptTime>) -> rtsp::Range {
+        rtsp::Range { start, end }
+    }
+
+    #[test]
+    fn range_to_seek_now() {
+        assert!(matches!(
+            Session::range_to_seek(&range(Some(rtsp::NptTime::Now), None), Some(60.0)),
+            Ok(None)
+        ));
+        assert!(matches!(
+            Session::range_to_seek(
+                &range(Some(rtsp::NptTime::Now), Some(rtsp::NptTime::Time(10.0))),
+                Some(60.0)
+            ),
+            Err(PlaySessionError::RangeNotSupported)
+        ));
+        assert!(matches!(
+            Session::range_to_seek(
+                &range(Some(rtsp::NptTime::Time(10.0)), Some(rtsp::NptTime::Now)),
+                Some(60.0)
+            ),
+            Err(PlaySessionError::RangeNotSupported)
+        ));
+    }
+
+    #[test]
+    fn range_to_seek_open_end() {
+        assert!(matches!(
+            Session::range_to_seek(&range(Some(rtsp::NptTime::Time(10.0)), None), Some(60.0)),
+            Ok(Some((start, None))) if start == 10.0
+        ));
+        assert!(matches!(
+            Session::range_to_seek(&range(Some(rtsp::NptTime::Time(10.0)), None), None),
+            Ok(Some((start, None))) if start == 10.0
+        ));
+        assert!(matches!(
+            Session::range_to_seek(&range(None, Some(rtsp::NptTime::Time(30.0))), Some(60.0)),
+            Ok(Some((start, Some(end)))) if start == 0.0 && end == 30.0
+        ));
+    }
+
+    #[test]
+    fn range_to_seek_end_beyond_duration() {
+        assert!(matches!(
+            Session::range_to_seek(
+                &range(
+                    Some(rtsp::NptTime::Time(10.0)),
+                    Some(rtsp::NptTime::Time(100.0))
+                ),
+                Some(60.0)
+            ),
+            Ok(Some((start, Some(end)))) if start == 10.0 && end == 60.0
+        ));
+        // Without a known duration, the end is taken as is.
+        assert!(matches!(
+            Session::range_to_seek(
+                &range(
+                    Some(rtsp::NptTime::Time(10.0)),
+                    Some(rtsp::NptTime::Time(100.0))
+                ),
+                None
+            ),
+            Ok(Some((start, Some(end)))) if start == 10.0 && end == 100.0
+        ));
+    }
+
+    #[test]
+    fn range_to_seek_invalid() {
+        // Start at or beyond the duration.
+        assert!(matches!(
+            Session::range_to_seek(&range(Some(rtsp::NptTime::Time(60.0)), None), Some(60.0)),
+            Err(PlaySessionError::RangeInvalid)
+        ));
+        assert!(matches!(
+            Session::range_to_seek(&range(Some(rtsp::NptTime::Time(70.0)), None), Some(60.0)),
+            Err(PlaySessionError::RangeInvalid)
+        ));
+        // End before start, also after clamping the end to the duration.
+        assert!(matches!(
+            Session::range_to_seek(
+                &range(
+                    Some(rtsp::NptTime::Time(20.0)),
+                    Some(rtsp::NptTime::Time(10.0))
+                ),
+                Some(60.0)
+            ),
+            Err(PlaySessionError::RangeInvalid)
+        ));
+        assert!(matches!(
+            Session::range_to_seek(&range(Some(rtsp::NptTime::Time(-1.0)), None), Some(60.0)),
+            Err(PlaySessionError::RangeInvalid)
+        ));
+    }
 }
@@ -0,0 +1,242 @@
+//! Record sessions receive media that a client publishes with RECORD.
+//! RTP packets of the announced tracks are depacketized and fed into the
+//! source, which muxes them for viewers like any other source.
+
+use std::pin::Pin;
+
+use bytes::Bytes;
+
+use futures::stream::{self, SelectAll, Stream, StreamExt};
+
+use tokio::select;
+use tokio::time::Instant;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use oddity_rtsp_protocol as rtsp;
+use video_rs as video;
+use video_rs::ffmpeg;
+
+use crate::media::video::rtp_h264::{H264Depacketizer, ParameterSets};
+use crate::media::{Codec, MediaInfo, StreamInfo};
+use crate::net::connection::ResponseSenderTx;
+use crate::runtime::task_manager::TaskContext;
+use crate::session::setup::{ReceiveOverSocket, RecordInput};
+use crate::session::{Session, SessionControlMessage, SessionControlRx, SessionId};
+use crate::source::publish::SourcePublisher;
+
+/// RTP clock rate of H.264 video (RFC 6184 Section 8.2.1).
+const CLOCK_RATE_H264: i32 = 90_000;
+
+/// Largest possible UDP payload.
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+/// Track that the client set up to publish.
+pub struct RecordTrack {
+    /// Index of the track in the announcement.
+    pub index: usize,
+    pub input: RecordInput,
+    pub transport: rtsp::Transport,
+}
+
+type TrackStream = Pin<Box<dyn Stream<Item = (usize, Bytes)> + Send>>;
+
+impl RecordTrack {
+    fn into_stream(self) -> TrackStream {
+        let index = self.index;
+        match self.input {
+            RecordInput::Udp(input) => {
+                Box::pin(stream::unfold(input, receive_datagram).map(move |data| (index, data)))
+            }
+            RecordInput::Interleaved(interleaved_rx) => Box::pin(
+                UnboundedReceiverStream::new(interleaved_rx).map(move |data| (index, data)),
+            ),
+        }
+    }
+}
+
+async fn receive_datagram(input: ReceiveOverSocket) -> Option<(Bytes, ReceiveOverSocket)> {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        match input.sockets.rtp.recv_from(&mut buf).await {
+            Ok((size, from)) => {
+                if input
+                    .peer_ip_addr
+                    .is_some_and(|peer_ip_addr| peer_ip_addr != from.ip())
+                {
+                    tracing::debug!(%from, "dropping rtp packet from unknown sender");
+                    continue;
+                }
+                buf.truncate(size);
+                return Some((buf.into(), input));
+            }
+            Err(err) => {
+                tracing::error!(%err, "failed to receive rtp packet");
+                return None;
+            }
+        }
+    }
+}
+
+pub(super) async fn run(
+    id: SessionId,
+    publisher: SourcePublisher,
+    track: RecordTrack,
+    owner: ResponseSenderTx,
+    mut control_rx: SessionControlRx,
+    mut task_context: TaskContext,
+) {
+    let mut inputs = SelectAll::new();
+    inputs.push(track.into_stream());
+
+    let mut ingest = Ingest::new(publisher);
+    let mut recording = false;
+    // Publishers do not have to send requests while they are sending
+    // media, so the media itself keeps the session alive.
+    let mut deadline = Instant::now() + Session::TIMEOUT;
+
+    loop {
+        select! {
+            // CANCEL SAFETY: `SelectAll::next` is cancel safe since every stream
+            // keeps its own state.
+            data = inputs.next(), if !inputs.is_empty() => {
+                if let Some((index, data)) = data {
+                    deadline = Instant::now() + Session::TIMEOUT;
+                    if recording {
+                        ingest.push(index, &data).await;
+                    }
+                }
+            },
+            // CANCEL SAFETY: `mpsc::UnboundedReceiver::recv` is cancel safe.
+            message = control_rx.recv() => {
+                match message {
+                    Some(SessionControlMessage::Record) => {
+                        recording = true;
+                        tracing::info!(%id, path = ingest.publisher.path(), "session now recording");
+                    },
+                    Some(SessionControlMessage::AddTrack(track)) => {
+                        tracing::trace!(%id, index = track.index, "added track to session");
+                        inputs.push(track.into_stream());
+                    },
+                    Some(_) => {
+                        tracing::warn!(%id, "ignored playback control message for record session");
+                    },
+                    None => {
+                        tracing::error!(%id, "session control channel broke unexpectedly");
+                        break;
+                    },
+                }
+            },
+            // CANCEL SAFETY: `mpsc::UnboundedSender::closed` is cancel safe.
+            _ = owner.closed() => {
+                tracing::info!(%id, "publisher disconnected");
+                break;
+            },
+            // CANCEL SAFETY: `sleep_until` is cancel safe, the deadline is kept
+            // outside of the loop.
+            _ = tokio::time::sleep_until(deadline) => {
+                tracing::info!(%id, "publisher stopped sending media");
+                break;
+            },
+            // CANCEL SAFETY: `TaskContext::wait_for_stop` is cancel safe.
+            _ = task_context.wait_for_stop() => {
+                tracing::trace!("tearing down session");
+                break;
+            },
+        }
+    }
+
+    ingest.publisher.finish().await;
+}
+
+/// Turns published RTP packets into packets for the source.
+struct Ingest {
+    publisher: SourcePublisher,
+    /// Index and RTP payload type of the track that is fed into the
+    /// source. Only the first supported track is used, the others are
+    /// dropped.
+    track: Option<(usize, u8)>,
+    depacketizer: H264Depacketizer,
+    /// Whether or not the source knows the parameter sets. If they were not
+    /// announced, they are taken from the first access unit that has them.
+    has_media_info: bool,
+    /// Last RTP timestamp and the time elapsed since the first one, so that
+    /// timestamps keep increasing when the RTP timestamp wraps around.
+    clock: Option<(u32, i64)>,
+}
+
+impl Ingest {
+    fn new(publisher: SourcePublisher) -> Self {
+        let announcement = publisher.announcement();
+        let track = announcement
+            .tracks
+            .iter()
+            .enumerate()
+            .find(|(_, track)| track.codec.is_some())
+            .map(|(index, track)| (index, track.payload_type));
+        let has_media_info = announcement.media_info().is_some();
+        Self {
+            publisher,
+            track,
+            depacketizer: H264Depacketizer::new(),
+            has_media_info,
+            clock: None,
+        }
+    }
+
+    async fn push(&mut self, index: usize, data: &[u8]) {
+        match self.track {
+            Some((track_index, payload_type)) if track_index == index => {
+                // RTCP multiplexed on the same channel or port is dropped here
+                // as well.
+                if data.get(1).map(|byte| byte & 0x7f) != Some(payload_type) {
+                    return;
+                }
+            }
+            _ => return,
+        }
+
+        for access_unit in self.depacketizer.depacketize(data) {
+            if !self.has_media_info {
+                match ParameterSets::from_extradata(&access_unit.data) {
+                    Some(parameter_sets) => {
+                        let media_info = MediaInfo {
+                            streams: vec![StreamInfo {
+                                index,
+                                codec: Codec::H264(parameter_sets),
+                            }],
+                            duration: None,
+                        };
+                        self.publisher.reset(media_info).await;
+                        self.has_media_info = true;
+                    }
+                    None => {
+                        tracing::trace!("dropping access unit, parameter sets not known yet");
+                        continue;
+                    }
+                }
+            }
+
+            let time = self.elapsed(access_unit.timestamp);
+            let mut packet = ffmpeg::Packet::copy(&access_unit.data);
+            packet.set_pts(Some(time));
+            packet.set_dts(Some(time));
+            packet.set_stream(index);
+            if access_unit.is_key {
+                packet.set_flags(ffmpeg::packet::Flags::KEY);
+            }
+            self.publisher.send(video::Packet::new(
+                packet,
+                ffmpeg::Rational::new(1, CLOCK_RATE_H264),
+            ));
+        }
+    }
+
+    fn elapsed(&mut self, timestamp: u32) -> i64 {
+        let elapsed = match self.clock {
+            Some((last, elapsed)) => elapsed + timestamp.wrapping_sub(last) as i32 as i64,
+            None => 0,
+        };
+        self.clock = Some((timestamp, elapsed));
+        elapsed
+    }
+}
@@ -0,0 +1,266 @@
+use rand::Rng;
+
+use crate::media::StreamState;
+
+/// Rewrites the shared RTP and RTCP packets of a source for a single
+/// session. Every session gets its own random SSRC, and random initial
+/// sequence number and timestamp, as recommended by RFC 3550 Section 5.1.
+///
+/// If the source restarts, it starts a new RTP stream with a different
+/// SSRC. When that happens, the offsets are moved so that the sequence
+/// numbers and timestamps of the session continue where they left off.
+pub struct RtpRewriter {
+    ssrc: u32,
+    seq_offset: u16,
+    timestamp_offset: u32,
+    source_ssrc: Option<u32>,
+    last: Option<(u16, u32)>,
+    paused: bool,
+}
+
+impl RtpRewriter {
+    /// Size of the fixed RTP header (RFC 3550 Section 5.1).
+    const RTP_HEADER_LEN: usize = 12;
+    /// Size of the RTCP header up to and including the SSRC (RFC 3550
+    /// Section 6.4.1).
+    const RTCP_HEADER_LEN: usize = 8;
+    /// RTCP packet type of sender reports.
+    const RTCP_SR: u8 = 200;
+
+    pub fn new() -> Self {
+        let mut rng = rand::thread_rng();
+        Self {
+            ssrc: rng.gen(),
+            seq_offset: rng.gen(),
+            timestamp_offset: rng.gen(),
+            source_ssrc: None,
+            last: None,
+            paused: false,
+        }
+    }
+
+    /// Rewrite an RTP packet of the source. Returns `None` if the packet
+    /// is too short to be valid RTP.
+    pub fn rewrite_rtp(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        if packet.len() < Self::RTP_HEADER_LEN {
+            return None;
+        }
+
+        let source_ssrc = read_u32(packet, 8);
+        let source_seq = read_u16(packet, 2);
+        let source_timestamp = read_u32(packet, 4);
+        if self.source_ssrc != Some(source_ssrc) {
+            self.rebase(source_seq, source_timestamp);
+            self.source_ssrc = Some(source_ssrc);
+        } else if self.paused {
+            self.rebase_seq(source_seq);
+        }
+        self.paused = false;
+
+        let seq = source_seq.wrapping_add(self.seq_offset);
+        let timestamp = source_timestamp.wrapping_add(self.timestamp_offset);
+        self.last = Some((seq, timestamp));
+
+        let mut packet = packet.to_vec();
+        packet[2..4].copy_from_slice(&seq.to_be_bytes());
+        packet[4..8].copy_from_slice(&timestamp.to_be_bytes());
+        packet[8..12].copy_from_slice(&self.ssrc.to_be_bytes());
+        Some(packet)
+    }
+
+    /// Rewrite a (compound) RTCP packet of the source. The SSRC of every
+    /// packet in the compound packet is replaced, as well as the RTP
+    /// timestamp in sender reports. Returns `None` if the packet is
+    /// malformed.
+    pub fn rewrite_rtcp(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        let mut packet = packet.to_vec();
+        let mut offset = 0;
+        while offset < packet.len() {
+            if packet.len() - offset < Self::RTCP_HEADER_LEN {
+                return None;
+            }
+            // Length is in 32-bit words minus one (RFC 3550 Section 6.4.1).
+            let len = (read_u16(&packet, offset + 2) as usize + 1) * 4;
+            if packet.len() - offset < len {
+                return None;
+            }
+
+            packet[offset + 4..offset + 8].copy_from_slice(&self.ssrc.to_be_bytes());
+            if packet[offset + 1] == Self::RTCP_SR && len >= 20 {
+                let timestamp = read_u32(&packet, offset + 16).wrapping_add(self.timestamp_offset);
+                packet[offset + 16..offset + 20].copy_from_slice(&timestamp.to_be_bytes());
+            }
+
+            offset += len;
+        }
+        Some(packet)
+    }
+
+    /// Mark the stream as paused. Packets of the source are skipped during
+    /// a pause, so when the session resumes, the sequence numbers are moved
+    /// to continue where they left off. Timestamps keep following the source
+    /// since they should reflect the time that passed during the pause.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Map the stream state of the source packetizer to the stream state
+    /// of this session.
+    pub fn rewrite_stream_state(&self, stream_state: &StreamState) -> StreamState {
+        let rtp_seq = match self.last {
+            Some((last_seq, _)) if self.paused => last_seq.wrapping_add(1),
+            _ => stream_state.rtp_seq.wrapping_add(self.seq_offset),
+        };
+        StreamState {
+            rtp_seq,
+            rtp_timestamp: stream_state
+                .rtp_timestamp
+                .wrapping_add(self.timestamp_offset),
+        }
+    }
+
+    fn rebase_seq(&mut self, source_seq: u16) {
+        if let Some((last_seq, _)) = self.last {
+            self.seq_offset = last_seq.wrapping_add(1).wrapping_sub(source_seq);
+        }
+    }
+
+    fn rebase(&mut self, source_seq: u16, source_timestamp: u32) {
+        if let Some((last_seq, last_timestamp)) = self.last {
+            tracing::trace!("source started new rtp stream, continuing where session left off");
+            self.seq_offset = last_seq.wrapping_add(1).wrapping_sub(source_seq);
+            self.timestamp_offset = last_timestamp
+                .wrapping_add(1)
+                .wrapping_sub(source_timestamp);
+        }
+    }
+}
+
+impl Default for RtpRewriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[inline]
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+#[inline]
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rtp(ssrc: u32, seq: u16, timestamp: u32) -> Vec<u8> {
+        let mut packet = vec![0x80, 96];
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&ssrc.to_be_bytes());
+        packet.extend_from_slice(&[1, 2, 3]);
+        packet
+    }
+
+    fn fields(packet: &[u8]) -> (u32, u16, u32) {
+        (
+            read_u32(packet, 8),
+            read_u16(packet, 2),
+            read_u32(packet, 4),
+        )
+    }
+
+    #[test]
+    fn rewrite_rtp() {
+        let mut rewriter = RtpRewriter::new();
+        let first = rewriter.rewrite_rtp(&rtp(1, 100, 1000)).unwrap();
+        let second = rewriter.rewrite_rtp(&rtp(1, 101, 4000)).unwrap();
+        let (ssrc, seq, timestamp) = fields(&first);
+        assert_eq!(ssrc, rewriter.ssrc);
+        assert_eq!(seq, 100u16.wrapping_add(rewriter.seq_offset));
+        assert_eq!(timestamp, 1000u32.wrapping_add(rewriter.timestamp_offset));
+        assert_eq!(
+            fields(&second),
+            (ssrc, seq.wrapping_add(1), timestamp.wrapping_add(3000))
+        );
+        // Header fields aside, the packet is left as is.
+        assert_eq!(first[..2], [0x80, 96]);
+        assert_eq!(first[12..], [1, 2, 3]);
+        assert!(rewriter.rewrite_rtp(&[0x80, 96, 0, 1]).is_none());
+    }
+
+    #[test]
+    fn rewrite_rtp_continues_after_source_change() {
+        let mut rewriter = RtpRewriter::new();
+        let (ssrc, seq, timestamp) = fields(&rewriter.rewrite_rtp(&rtp(1, 100, 1000)).unwrap());
+        // The source restarted with another SSRC and other numbering.
+        let after = rewriter.rewrite_rtp(&rtp(2, 5000, 90000)).unwrap();
+        assert_eq!(
+            fields(&after),
+            (ssrc, seq.wrapping_add(1), timestamp.wrapping_add(1))
+        );
+        let next = rewriter.rewrite_rtp(&rtp(2, 5001, 93000)).unwrap();
+        assert_eq!(
+            fields(&next),
+            (ssrc, seq.wrapping_add(2), timestamp.wrapping_add(3001))
+        );
+    }
+
+    #[test]
+    fn rewrite_rtp_continues_after_pause() {
+        let mut rewriter = RtpRewriter::new();
+        let (ssrc, seq, timestamp) = fields(&rewriter.rewrite_rtp(&rtp(1, 100, 1000)).unwrap());
+        rewriter.pause();
+        // While paused, the stream state continues from the last packet sent.
+        let stream_state = rewriter.rewrite_stream_state(&StreamState {
+            rtp_seq: 150,
+            rtp_timestamp: 31000,
+        });
+        assert_eq!(stream_state.rtp_seq, seq.wrapping_add(1));
+        assert_eq!(stream_state.rtp_timestamp, timestamp.wrapping_add(30000));
+        // Packets skipped during the pause are not missing to the client, but
+        // the timestamps reflect the time that passed.
+        let resumed = rewriter.rewrite_rtp(&rtp(1, 150, 31000)).unwrap();
+        assert_eq!(
+            fields(&resumed),
+            (ssrc, seq.wrapping_add(1), timestamp.wrapping_add(30000))
+        );
+        let next = rewriter.rewrite_rtp(&rtp(1, 151, 34000)).unwrap();
+        assert_eq!(
+            fields(&next),
+            (ssrc, seq.wrapping_add(2), timestamp.wrapping_add(33000))
+        );
+    }
+
+    #[test]
+    fn rewrite_rtcp_sender_report() {
+        let rewriter = RtpRewriter::new();
+        // Sender report without report blocks, followed by an empty receiver
+        // report of the same source.
+        let mut packet = vec![0x80, 200, 0, 6];
+        packet.extend_from_slice(&1u32.to_be_bytes());
+        packet.extend_from_slice(&[0; 8]);
+        packet.extend_from_slice(&1000u32.to_be_bytes());
+        packet.extend_from_slice(&[0; 8]);
+        packet.extend_from_slice(&[0x80, 201, 0, 1]);
+        packet.extend_from_slice(&1u32.to_be_bytes());
+
+        let rewritten = rewriter.rewrite_rtcp(&packet).unwrap();
+        assert_eq!(rewritten.len(), packet.len());
+        assert_eq!(read_u32(&rewritten, 4), rewriter.ssrc);
+        assert_eq!(
+            read_u32(&rewritten, 16),
+            1000u32.wrapping_add(rewriter.timestamp_offset)
+        );
+        assert_eq!(read_u32(&rewritten, 32), rewriter.ssrc);
+        assert!(rewriter.rewrite_rtcp(&packet[..30]).is_none());
+    }
+}
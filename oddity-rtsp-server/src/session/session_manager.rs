@@ -1,7 +1,9 @@
 use std::collections::{hash_map::Entry, HashMap};
 use std::error;
 use std::fmt;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use tokio::select;
 use tokio::sync::mpsc;
@@ -10,13 +12,17 @@ use tokio::sync::{Mutex, RwLock};
 use oddity_rtsp_protocol as rtsp;
 
 use crate::media;
+use crate::net::connection::ResponseSenderTx;
 use crate::runtime::task_manager::{Task, TaskContext};
 use crate::runtime::Runtime;
+use crate::session::record::RecordTrack;
 use crate::session::setup::SessionSetup;
 use crate::session::{
-    PlaySessionError, Session, SessionId, SessionState, SessionStateRx, SessionStateTx,
+    PauseSessionError, PlaySessionError, RecordSessionError, Session, SessionId, SessionInfo,
+    SessionState, SessionStateRx, SessionStateTx, SetupTrackError,
 };
-use crate::source::SourceDelegate;
+use crate::source::publish::SourcePublisher;
+use crate::source::{SourceDelegate, SourcePath, SourcePathRef};
 
 type SessionShared = Arc<Mutex<Session>>;
 type SessionMap = Arc<RwLock<HashMap<SessionId, SessionShared>>>;
@@ -29,6 +35,9 @@ pub struct SessionManager {
 }
 
 impl SessionManager {
+    /// How often to look for sessions that have timed out.
+    const REAP_INTERVAL: Duration = Duration::from_secs(5);
+
     pub async fn start(runtime: Arc<Runtime>) -> Self {
         let sessions = Arc::new(RwLock::new(HashMap::new()));
         let (session_state_tx, session_state_rx) = mpsc::unbounded_channel();
@@ -62,19 +71,53 @@ impl SessionManager {
 
     pub async fn setup(
         &self,
+        source: SourcePath,
         source_delegate: SourceDelegate,
+        track: usize,
         setup: SessionSetup,
+        peer_addr: Option<SocketAddr>,
     ) -> Result<SessionId, RegisterSessionError> {
         let session_id = SessionId::generate();
         let session = Session::setup_and_start(
             session_id.clone(),
+            source,
             source_delegate,
+            track,
             setup,
+            peer_addr,
             self.session_state_tx.clone(),
             self.runtime.as_ref(),
         )
         .await;
+        self.register(session_id, session).await
+    }
 
+    pub async fn setup_record(
+        &self,
+        publisher: SourcePublisher,
+        track: RecordTrack,
+        owner: ResponseSenderTx,
+        peer_addr: Option<SocketAddr>,
+    ) -> Result<SessionId, RegisterSessionError> {
+        let session_id = SessionId::generate();
+        let session = Session::setup_record_and_start(
+            session_id.clone(),
+            publisher,
+            track,
+            owner,
+            peer_addr,
+            self.session_state_tx.clone(),
+            self.runtime.as_ref(),
+        )
+        .await;
+        self.register(session_id, session).await
+    }
+
+    async fn register(
+        &self,
+        session_id: SessionId,
+        session: Session,
+    ) -> Result<SessionId, RegisterSessionError> {
         if let Entry::Vacant(entry) = self.sessions.write().await.entry(session_id.clone()) {
             let _ = entry.insert(Arc::new(Mutex::new(session)));
             tracing::trace!(%session_id, "registered new session");
@@ -85,11 +128,31 @@ impl SessionManager {
         }
     }
 
+    pub async fn setup_track(
+        &self,
+        id: &SessionId,
+        source: &SourcePathRef,
+        track: usize,
+        setup: SessionSetup,
+    ) -> Option<Result<(), SetupTrackError>> {
+        let session = self.sessions.read().await.get(id).cloned();
+        if let Some(session) = session {
+            tracing::trace!(session_id=%id, track, "setting up track");
+            Some(session.lock().await.setup_track(source, track, setup))
+        } else {
+            tracing::trace!(
+                session_id=%id,
+                "caller tried to set up track in session that does not exist",
+            );
+            None
+        }
+    }
+
     pub async fn play(
         &self,
         id: &SessionId,
         range: Option<rtsp::Range>,
-    ) -> Option<Result<media::StreamState, PlaySessionError>> {
+    ) -> Option<Result<(rtsp::Range, Vec<(usize, media::StreamState)>), PlaySessionError>> {
         let session = self.sessions.read().await.get(id).cloned();
         if let Some(session) = session {
             tracing::trace!(session_id=%id, "start playing");
@@ -103,6 +166,73 @@ impl SessionManager {
         }
     }
 
+    pub async fn pause(&self, id: &SessionId) -> Option<Result<(), PauseSessionError>> {
+        let session = self.sessions.read().await.get(id).cloned();
+        if let Some(session) = session {
+            tracing::trace!(session_id=%id, "pausing");
+            Some(session.lock().await.pause().await)
+        } else {
+            tracing::trace!(
+                session_id=%id,
+                "caller tried to pause session that does not exist",
+            );
+            None
+        }
+    }
+
+    pub async fn record(&self, id: &SessionId) -> Option<Result<(), RecordSessionError>> {
+        let session = self.sessions.read().await.get(id).cloned();
+        if let Some(session) = session {
+            tracing::trace!(session_id=%id, "start recording");
+            Some(session.lock().await.record().await)
+        } else {
+            tracing::trace!(
+                session_id=%id,
+                "caller tried to record session that does not exist",
+            );
+            None
+        }
+    }
+
+    pub async fn add_track(
+        &self,
+        id: &SessionId,
+        track: RecordTrack,
+    ) -> Option<Result<(), RecordSessionError>> {
+        let session = self.sessions.read().await.get(id).cloned();
+        if let Some(session) = session {
+            tracing::trace!(session_id=%id, index = track.index, "adding track");
+            Some(session.lock().await.add_track(track))
+        } else {
+            tracing::trace!(
+                session_id=%id,
+                "caller tried to add track to session that does not exist",
+            );
+            None
+        }
+    }
+
+    pub async fn info(&self, id: &SessionId) -> Option<SessionInfo> {
+        let session = self.sessions.read().await.get(id).cloned();
+        match session {
+            Some(session) => Some(session.lock().await.info(id)),
+            None => None,
+        }
+    }
+
+    /// Refresh the timeout of a session. Returns `false` if the session
+    /// does not exist.
+    pub async fn keep_alive(&self, id: &SessionId) -> bool {
+        let session = self.sessions.read().await.get(id).cloned();
+        if let Some(session) = session {
+            tracing::trace!(session_id=%id, "keep alive");
+            session.lock().await.keep_alive();
+            true
+        } else {
+            false
+        }
+    }
+
     pub async fn teardown(&self, id: &SessionId) -> bool {
         let session = self.sessions.read().await.get(id).cloned();
         if let Some(session) = session {
@@ -119,11 +249,51 @@ impl SessionManager {
         }
     }
 
+    /// Tear down all sessions that play or publish the source at `path`.
+    /// Returns the number of sessions torn down.
+    pub async fn teardown_source(&self, path: &SourcePathRef) -> usize {
+        let sessions = self
+            .sessions
+            .read()
+            .await
+            .iter()
+            .map(|(session_id, session)| (session_id.clone(), session.clone()))
+            .collect::<Vec<_>>();
+        let mut count = 0;
+        for (session_id, session) in sessions {
+            let mut session = session.lock().await;
+            if session.source() == path {
+                tracing::trace!(%session_id, %path, "tearing down session of source");
+                session.teardown().await;
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Snapshot of all sessions, ordered by ID.
+    pub async fn list(&self) -> Vec<SessionInfo> {
+        let sessions = self
+            .sessions
+            .read()
+            .await
+            .iter()
+            .map(|(session_id, session)| (session_id.clone(), session.clone()))
+            .collect::<Vec<_>>();
+        let mut infos = Vec::with_capacity(sessions.len());
+        for (session_id, session) in sessions {
+            infos.push(session.lock().await.info(&session_id));
+        }
+        infos.sort_by(|a, b| a.id.cmp(&b.id));
+        infos
+    }
+
     async fn run(
         sessions: SessionMap,
         mut session_state_rx: SessionStateRx,
         mut task_context: TaskContext,
     ) {
+        let mut reap_interval = tokio::time::interval(Self::REAP_INTERVAL);
         loop {
             select! {
                 // CANCEL SAFETY: `mpsc::UnboundedReceiver::recv` is cancel safe.
@@ -139,6 +309,10 @@ impl SessionManager {
                         },
                     }
                 },
+                // CANCEL SAFETY: `Interval::tick` is cancel safe.
+                _ = reap_interval.tick() => {
+                    Self::reap_expired(&sessions).await;
+                },
                 // CANCEL SAFETY: `TaskContext::wait_for_stop` is cancel safe.
                 _ = task_context.wait_for_stop() => {
                     tracing::trace!("stopping session manager");
@@ -147,6 +321,24 @@ impl SessionManager {
             }
         }
     }
+
+    /// Tear down all sessions that timed out. The sessions are removed from
+    /// the map when they report that they stopped.
+    async fn reap_expired(sessions: &SessionMap) {
+        let sessions = sessions
+            .read()
+            .await
+            .iter()
+            .map(|(session_id, session)| (session_id.clone(), session.clone()))
+            .collect::<Vec<_>>();
+        for (session_id, session) in sessions {
+            let mut session = session.lock().await;
+            if session.is_expired() {
+                tracing::info!(%session_id, "session timed out");
+                session.teardown().await;
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -1,115 +1,416 @@
 use std::error;
 use std::fmt;
-use std::net::SocketAddr;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::Instant;
 
 use oddity_rtsp_protocol as rtsp;
 use video_rs as video;
 
-use crate::media::video::rtp_muxer::{make_rtp_muxer_builder, RtpMuxer};
-use crate::media::MediaInfo;
-use crate::net::connection::ResponseSenderTx;
+use crate::net::connection::{
+    InterleavedRoutes, InterleavedRx, ResponseSenderTx, SlowClientPolicy,
+};
+use crate::net::udp::{UdpSocketPair, UdpSocketPairAllocator};
 use crate::session::transport;
+use crate::source::multicast::MulticastHandle;
 
 pub struct SessionSetup {
     pub rtsp_transport: rtsp::Transport,
-    pub rtp_muxer: RtpMuxer,
     pub rtp_target: SessionSetupTarget,
 }
 
 impl SessionSetup {
+    /// Select a transport to send media over. Over `secure` connections,
+    /// only interleaved transports are selected, since media sent over UDP
+    /// would not be encrypted.
+    #[allow(clippy::too_many_arguments)]
     pub async fn from_rtsp_candidate_transports(
         candidate_transports: impl IntoIterator<Item = rtsp::Transport>,
-        media_info: MediaInfo,
+        track: usize,
+        multicast: Option<&MulticastHandle>,
         sender: ResponseSenderTx,
+        interleaved_routes: &mut InterleavedRoutes,
+        peer_ip_addr: Option<IpAddr>,
+        secure: bool,
+        udp_allocator: &UdpSocketPairAllocator,
     ) -> Result<Self, SessionSetupError> {
         let transport = candidate_transports
             .into_iter()
-            .find(transport::is_supported)
+            .filter(|transport| !(secure && transport::is_udp(transport)))
+            .find(|transport| transport::is_supported(transport, multicast.is_some()))
             .ok_or(SessionSetupError::TransportNotSupported)?;
         tracing::trace!(%transport, "selected transport");
 
-        tracing::trace!("initializing muxer");
-        make_rtp_muxer_builder()
-            .await
-            .map_err(SessionSetupError::Media)
-            .and_then(|mut rtp_muxer_builder| {
-                let resolved_transport = transport::resolve_transport(&transport);
-                tracing::trace!(%resolved_transport, "resolved transport");
-                let rtp_target =
-                    SessionSetupTarget::from_rtsp_transport(&resolved_transport, sender)
-                        .ok_or(SessionSetupError::DestinationInvalid)?;
-                tracing::debug!(?rtp_target, "calculated target");
-
-                for stream_info in media_info.streams {
-                    tracing::trace!(stream_index = stream_info.index, "adding stream to muxer");
-                    rtp_muxer_builder = rtp_muxer_builder
-                        .with_stream(stream_info)
-                        .map_err(SessionSetupError::Media)?;
-                }
+        let resolved_transport = transport::resolve_transport(&transport, track);
+        tracing::trace!(%resolved_transport, "resolved transport");
+        let (rtp_target, resolved_transport) = SessionSetupTarget::from_rtsp_transport(
+            resolved_transport,
+            track,
+            multicast,
+            sender,
+            interleaved_routes,
+            peer_ip_addr,
+            udp_allocator,
+        )
+        .await?;
+        tracing::debug!(?rtp_target, "calculated target");
+
+        Ok(Self {
+            rtsp_transport: resolved_transport,
+            rtp_target,
+        })
+    }
+}
+
+/// Setup of a track that a client publishes to the server.
+pub struct RecordSetup {
+    pub rtsp_transport: rtsp::Transport,
+    pub input: RecordInput,
+}
+
+impl RecordSetup {
+    /// Whether or not the client wants to publish media with any of the
+    /// candidate transports.
+    pub fn is_requested(candidate_transports: &[rtsp::Transport]) -> bool {
+        candidate_transports.iter().any(transport::is_record)
+    }
 
-                let rtp_muxer =
-                    RtpMuxer::from_builder(rtp_muxer_builder).map_err(SessionSetupError::Media)?;
-                Ok(Self {
-                    rtsp_transport: resolved_transport,
-                    rtp_muxer,
-                    rtp_target,
-                })
+    /// Select a transport to receive published media over. For TCP, the
+    /// interleaved RTP channel is routed to the returned input. Over
+    /// `secure` connections, only interleaved transports are selected.
+    pub async fn from_rtsp_candidate_transports(
+        candidate_transports: impl IntoIterator<Item = rtsp::Transport>,
+        track: usize,
+        interleaved_routes: &mut InterleavedRoutes,
+        peer_ip_addr: Option<IpAddr>,
+        secure: bool,
+        udp_allocator: &UdpSocketPairAllocator,
+    ) -> Result<Self, SessionSetupError> {
+        let transport = candidate_transports
+            .into_iter()
+            .filter(|transport| !(secure && transport::is_udp(transport)))
+            .find(transport::is_record_supported)
+            .ok_or(SessionSetupError::TransportNotSupported)?;
+        tracing::trace!(%transport, "selected record transport");
+
+        let rtsp_transport = transport::resolve_transport(&transport, track);
+        if transport::is_udp(&rtsp_transport) {
+            let sockets = udp_allocator
+                .allocate()
+                .await
+                .map_err(SessionSetupError::Socket)?;
+            let rtsp_transport = rtsp_transport.with_parameter(rtsp::Parameter::ServerPort(
+                rtsp::Port::Range(sockets.rtp_port, sockets.rtcp_port),
+            ));
+
+            Ok(Self {
+                rtsp_transport,
+                input: RecordInput::Udp(ReceiveOverSocket {
+                    sockets,
+                    peer_ip_addr,
+                }),
             })
+        } else {
+            let rtp_channel = match rtsp_transport
+                .interleaved_channel()
+                .ok_or(SessionSetupError::DestinationInvalid)?
+            {
+                rtsp::Channel::Single(rtp_channel) => *rtp_channel,
+                rtsp::Channel::Range(rtp_channel, _) => *rtp_channel,
+            };
+
+            let (interleaved_tx, interleaved_rx) = mpsc::unbounded_channel();
+            let _ = interleaved_routes.insert(rtp_channel, interleaved_tx);
+
+            Ok(Self {
+                rtsp_transport,
+                input: RecordInput::Interleaved(interleaved_rx),
+            })
+        }
     }
 }
 
+/// Where published RTP packets for a track come from.
+#[derive(Debug)]
+pub enum RecordInput {
+    Udp(ReceiveOverSocket),
+    Interleaved(InterleavedRx),
+}
+
+/// Where RTCP packets that the client sends about a track it plays come
+/// from.
+#[derive(Debug)]
+pub enum RtcpInput {
+    Udp {
+        sockets: Arc<UdpSocketPair>,
+        /// Only packets from the client itself are accepted.
+        peer_ip_addr: IpAddr,
+    },
+    Interleaved(InterleavedRx),
+}
+
+#[derive(Debug)]
+pub struct ReceiveOverSocket {
+    pub sockets: UdpSocketPair,
+    /// Only packets from the client itself are accepted, if its address
+    /// is known.
+    pub peer_ip_addr: Option<IpAddr>,
+}
+
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum SessionSetupTarget {
     RtpUdp(SendOverSocket),
     RtpTcp(SendInterleaved),
+    RtpMulticast(SendMulticast),
 }
 
 #[derive(Debug)]
 pub struct SendOverSocket {
+    /// Shared with the RTCP input of the track.
+    pub sockets: Arc<UdpSocketPair>,
     pub rtp_remote: SocketAddr,
     pub rtcp_remote: SocketAddr,
 }
 
+#[derive(Debug)]
+pub struct SendMulticast {
+    pub handle: MulticastHandle,
+}
+
 #[derive(Debug)]
 pub struct SendInterleaved {
     pub sender: ResponseSenderTx,
     pub rtp_channel: u8,
     pub rtcp_channel: u8,
+    /// RTCP packets that the client sends on the RTCP channel, until it is
+    /// taken by the session.
+    pub rtcp_rx: Option<InterleavedRx>,
+    /// Set while the send queue of the connection is full.
+    lag: Option<Lag>,
+}
+
+/// How far behind the client is.
+#[derive(Debug)]
+struct Lag {
+    since: Instant,
+    dropped: usize,
+}
+
+impl SendInterleaved {
+    async fn send(&mut self, message: rtsp::ResponseMaybeInterleaved) -> Result<(), SendError> {
+        let policy = self.sender.policy();
+        if policy == SlowClientPolicy::Block {
+            return self
+                .sender
+                .send(message)
+                .await
+                .map_err(|_| SendError::ConnectionClosed);
+        }
+
+        match self.sender.try_send(message) {
+            Ok(()) => {
+                if self.lag.is_some() && self.sender.is_draining() {
+                    if let Some(lag) = self.lag.take() {
+                        tracing::info!(
+                            channel = self.rtp_channel,
+                            lag = ?lag.since.elapsed(),
+                            dropped = lag.dropped,
+                            "client caught up",
+                        );
+                    }
+                }
+                Ok(())
+            }
+            Err(TrySendError::Full(_)) => {
+                let lag = self.lag.get_or_insert_with(|| {
+                    tracing::warn!(
+                        channel = self.rtp_channel,
+                        "client is too slow, send queue is full, dropping media"
+                    );
+                    Lag {
+                        since: Instant::now(),
+                        dropped: 0,
+                    }
+                });
+                lag.dropped += 1;
+                let lag_duration = lag.since.elapsed();
+                match policy {
+                    SlowClientPolicy::Disconnect(after) if lag_duration > after => {
+                        tracing::warn!(
+                            channel = self.rtp_channel,
+                            lag = ?lag_duration,
+                            dropped = lag.dropped,
+                            queued = self.sender.queued(),
+                            "client did not catch up in time, disconnecting",
+                        );
+                        self.sender.disconnect();
+                        Err(SendError::ClientTooSlow(lag_duration))
+                    }
+                    _ => Err(SendError::QueueFull),
+                }
+            }
+            Err(TrySendError::Closed(_)) => Err(SendError::ConnectionClosed),
+        }
+    }
 }
 
 impl SessionSetupTarget {
-    pub fn from_rtsp_transport(
-        rtsp_transport: &rtsp::Transport,
+    /// Determine where RTP and RTCP packets should go based on the
+    /// transport the client selected. For UDP, this allocates a pair of
+    /// server ports, which are added to the returned transport so that
+    /// they can be echoed back to the client in the SETUP reply.
+    ///
+    /// For multicast, the server decides on the group, port and TTL, and
+    /// the returned transport describes them to the client. Every track
+    /// has its own ports in the group.
+    ///
+    /// For TCP, the interleaved RTCP channel is routed to the target, so
+    /// that the session receives the reports of the client.
+    pub async fn from_rtsp_transport(
+        rtsp_transport: rtsp::Transport,
+        track: usize,
+        multicast: Option<&MulticastHandle>,
         sender: ResponseSenderTx,
-    ) -> Option<Self> {
-        Some(match rtsp_transport.lower_protocol()? {
-            rtsp::Lower::Udp => {
-                let client_ip_addr = rtsp_transport.destination()?;
-                let (client_rtp_port, client_rtcp_port) = match rtsp_transport.client_port()? {
-                    rtsp::Port::Single(rtp_port) => (*rtp_port, rtp_port + 1),
-                    rtsp::Port::Range(rtp_port, rtcp_port) => (*rtp_port, *rtcp_port),
-                };
+        interleaved_routes: &mut InterleavedRoutes,
+        peer_ip_addr: Option<IpAddr>,
+        udp_allocator: &UdpSocketPairAllocator,
+    ) -> Result<(Self, rtsp::Transport), SessionSetupError> {
+        if transport::is_multicast(&rtsp_transport) {
+            let handle = multicast
+                .cloned()
+                .ok_or(SessionSetupError::TransportNotSupported)?;
+            let rtsp_transport = rtsp::Transport::new()
+                .with_lower_protocol(rtsp::Lower::Udp)
+                .with_parameter(rtsp::Parameter::Multicast)
+                .with_parameter(rtsp::Parameter::Destination(handle.group.address))
+                .with_parameter(rtsp::Parameter::Port(rtsp::Port::Range(
+                    handle.group.rtp_port(track),
+                    handle.group.rtcp_port(track),
+                )))
+                .with_parameter(rtsp::Parameter::Ttl(handle.group.ttl as usize));
 
+            Ok((
+                SessionSetupTarget::RtpMulticast(SendMulticast { handle }),
+                rtsp_transport,
+            ))
+        } else if transport::is_udp(&rtsp_transport) {
+            // Media only goes to the host the client is connected from. See
+            // `transport::is_supported`.
+            let client_ip_addr = peer_ip_addr.ok_or(SessionSetupError::DestinationInvalid)?;
+            let (client_rtp_port, client_rtcp_port) = match rtsp_transport
+                .client_port()
+                .ok_or(SessionSetupError::DestinationInvalid)?
+            {
+                rtsp::Port::Single(rtp_port) => (
+                    *rtp_port,
+                    rtp_port
+                        .checked_add(1)
+                        .ok_or(SessionSetupError::DestinationInvalid)?,
+                ),
+                rtsp::Port::Range(rtp_port, rtcp_port) => (*rtp_port, *rtcp_port),
+            };
+
+            let sockets = udp_allocator
+                .allocate()
+                .await
+                .map_err(SessionSetupError::Socket)?;
+            let rtsp_transport = rtsp_transport.with_parameter(rtsp::Parameter::ServerPort(
+                rtsp::Port::Range(sockets.rtp_port, sockets.rtcp_port),
+            ));
+
+            Ok((
                 SessionSetupTarget::RtpUdp(SendOverSocket {
-                    rtp_remote: (*client_ip_addr, client_rtp_port).into(),
-                    rtcp_remote: (*client_ip_addr, client_rtcp_port).into(),
-                })
-            }
-            rtsp::Lower::Tcp => {
-                let (rtp_channel, rtcp_channel) = match rtsp_transport.interleaved_channel()? {
-                    rtsp::Channel::Single(rtp_channel) => (*rtp_channel, rtp_channel + 1),
-                    rtsp::Channel::Range(rtp_channel, rtcp_channel) => {
-                        (*rtp_channel, *rtcp_channel)
-                    }
-                };
+                    sockets: Arc::new(sockets),
+                    rtp_remote: (client_ip_addr, client_rtp_port).into(),
+                    rtcp_remote: (client_ip_addr, client_rtcp_port).into(),
+                }),
+                rtsp_transport,
+            ))
+        } else {
+            let (rtp_channel, rtcp_channel) = match rtsp_transport
+                .interleaved_channel()
+                .ok_or(SessionSetupError::DestinationInvalid)?
+            {
+                rtsp::Channel::Single(rtp_channel) => (*rtp_channel, rtp_channel + 1),
+                rtsp::Channel::Range(rtp_channel, rtcp_channel) => (*rtp_channel, *rtcp_channel),
+            };
 
+            let (rtcp_tx, rtcp_rx) = mpsc::unbounded_channel();
+            let _ = interleaved_routes.insert(rtcp_channel, rtcp_tx);
+
+            Ok((
                 SessionSetupTarget::RtpTcp(SendInterleaved {
                     sender,
                     rtp_channel,
                     rtcp_channel,
-                })
+                    rtcp_rx: Some(rtcp_rx),
+                    lag: None,
+                }),
+                rtsp_transport,
+            ))
+        }
+    }
+
+    /// Where the RTCP packets that the client sends for this track come
+    /// from. Interleaved packets can only be received once, so this
+    /// returns `None` the second time. Multicast sessions do not receive
+    /// RTCP.
+    pub fn rtcp_input(&mut self) -> Option<RtcpInput> {
+        match self {
+            SessionSetupTarget::RtpUdp(target) => Some(RtcpInput::Udp {
+                sockets: Arc::clone(&target.sockets),
+                peer_ip_addr: target.rtcp_remote.ip(),
+            }),
+            SessionSetupTarget::RtpTcp(target) => target.rtcp_rx.take().map(RtcpInput::Interleaved),
+            SessionSetupTarget::RtpMulticast(_) => None,
+        }
+    }
+
+    /// Send a single RTP or RTCP buffer to the client over whatever
+    /// transport was set up for the session. Fails with
+    /// [`SendError::QueueFull`] when the buffer was dropped because the
+    /// client does not keep up.
+    pub async fn send(&mut self, rtp_buf: video::rtp::RtpBuf) -> Result<(), SendError> {
+        match self {
+            SessionSetupTarget::RtpUdp(target) => match rtp_buf {
+                video::rtp::RtpBuf::Rtp(payload) => target
+                    .sockets
+                    .send_rtp(&payload, target.rtp_remote)
+                    .await
+                    .map_err(SendError::Socket),
+                video::rtp::RtpBuf::Rtcp(payload) => target
+                    .sockets
+                    .send_rtcp(&payload, target.rtcp_remote)
+                    .await
+                    .map_err(SendError::Socket),
+            },
+            SessionSetupTarget::RtpTcp(target) => {
+                let message = match rtp_buf {
+                    video::rtp::RtpBuf::Rtp(payload) => {
+                        rtsp::ResponseMaybeInterleaved::Interleaved {
+                            channel: target.rtp_channel,
+                            payload: payload.into(),
+                        }
+                    }
+                    video::rtp::RtpBuf::Rtcp(payload) => {
+                        rtsp::ResponseMaybeInterleaved::Interleaved {
+                            channel: target.rtcp_channel,
+                            payload: payload.into(),
+                        }
+                    }
+                };
+                target.send(message).await
             }
-        })
+            // Packets for multicast sessions are sent by the source, not
+            // by the session.
+            SessionSetupTarget::RtpMulticast(_) => Ok(()),
+        }
     }
 }
 
@@ -117,7 +418,7 @@ impl SessionSetupTarget {
 pub enum SessionSetupError {
     TransportNotSupported,
     DestinationInvalid,
-    Media(video::Error),
+    Socket(io::Error),
 }
 
 impl fmt::Display for SessionSetupError {
@@ -125,9 +426,32 @@ impl fmt::Display for SessionSetupError {
         match self {
             SessionSetupError::TransportNotSupported => write!(f, "transport not supported"),
             SessionSetupError::DestinationInvalid => write!(f, "destination invalid"),
-            SessionSetupError::Media(error) => write!(f, "media error: {}", error),
+            SessionSetupError::Socket(error) => write!(f, "socket error: {}", error),
         }
     }
 }
 
 impl error::Error for SessionSetupError {}
+
+#[derive(Debug)]
+pub enum SendError {
+    ConnectionClosed,
+    QueueFull,
+    ClientTooSlow(Duration),
+    Socket(io::Error),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SendError::ConnectionClosed => write!(f, "underlying connection closed"),
+            SendError::QueueFull => write!(f, "send queue full"),
+            SendError::ClientTooSlow(lag) => {
+                write!(f, "client too slow (lagging for {:?})", lag)
+            }
+            SendError::Socket(error) => write!(f, "socket error: {}", error),
+        }
+    }
+}
+
+impl error::Error for SendError {}
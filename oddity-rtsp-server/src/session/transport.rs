@@ -1,52 +1,119 @@
 use oddity_rtsp_protocol as rtsp;
 
-pub fn resolve_transport(rtsp_transport: &rtsp::Transport) -> rtsp::Transport {
-    if rtsp_transport.interleaved_channel().is_some() {
+pub fn resolve_transport(rtsp_transport: &rtsp::Transport, track: usize) -> rtsp::Transport {
+    if is_udp(rtsp_transport) || rtsp_transport.interleaved_channel().is_some() {
         rtsp_transport.clone()
     } else {
-        // Use default channels 0 and 1 if client did not specify preferred
-        // interleaved channels.
+        // Use default channels `2n` and `2n + 1` for track `n` if client did
+        // not specify preferred interleaved channels, so that every track
+        // has its own pair of channels.
+        let rtp_channel = u8::try_from(track * 2).unwrap_or(u8::MAX - 1);
         rtsp_transport
             .clone()
-            .with_parameter(rtsp::Parameter::Interleaved(rtsp::Channel::Range(0, 1)))
+            .with_parameter(rtsp::Parameter::Interleaved(rtsp::Channel::Range(
+                rtp_channel,
+                rtp_channel + 1,
+            )))
     }
 }
 
-pub fn is_supported(transport: &rtsp::Transport) -> bool {
+/// Whether or not the transport uses UDP as its lower protocol. RFC
+/// 2326 Section 12.39 specifies that the lower protocol defaults to
+/// UDP if the client leaves it out.
+pub fn is_udp(rtsp_transport: &rtsp::Transport) -> bool {
+    matches!(
+        rtsp_transport.lower_protocol(),
+        Some(rtsp::Lower::Udp) | None
+    )
+}
+
+/// Whether or not the client asked for multicast delivery.
+pub fn is_multicast(rtsp_transport: &rtsp::Transport) -> bool {
+    rtsp_transport
+        .parameters_iter()
+        .any(|parameter| matches!(parameter, rtsp::Parameter::Multicast))
+}
+
+/// Whether or not the client asked to send media to the server rather
+/// than receive it.
+pub fn is_record(rtsp_transport: &rtsp::Transport) -> bool {
+    rtsp_transport
+        .parameters_iter()
+        .any(|parameter| matches!(parameter, rtsp::Parameter::Mode(rtsp::Method::Record)))
+}
+
+/// Whether or not the transport is supported for receiving published
+/// media. Only unicast is supported.
+pub fn is_record_supported(transport: &rtsp::Transport) -> bool {
+    is_record(transport)
+        && transport.parameters_iter().all(|parameter| {
+            matches!(
+                parameter,
+                rtsp::Parameter::Unicast
+                    | rtsp::Parameter::Destination(_)
+                    | rtsp::Parameter::Interleaved(_)
+                    | rtsp::Parameter::ClientPort(_)
+                    | rtsp::Parameter::Mode(rtsp::Method::Record)
+            )
+        })
+}
+
+/// Whether or not the transport is supported. Multicast transports are
+/// only supported if the source has a multicast group.
+///
+/// Unicast transports with a destination are not supported: media is only
+/// sent to the client itself, otherwise anyone could have the server flood
+/// another host with it. For multicast, the server picks the group and any
+/// destination is ignored.
+pub fn is_supported(transport: &rtsp::Transport, multicast_available: bool) -> bool {
+    if is_multicast(transport) {
+        if !(multicast_available && is_udp(transport)) {
+            return false;
+        }
+    } else if transport.destination().is_some() {
+        return false;
+    }
     return transport
         .lower_protocol()
         .map(is_lower_protocol_supported)
         .unwrap_or(true)
-        && transport.parameters_iter().all(is_parameter_supported);
+        && transport
+            .parameters_iter()
+            .all(|parameter| is_parameter_supported(parameter, multicast_available));
 }
 
 fn is_lower_protocol_supported(lower: &rtsp::Lower) -> bool {
     match lower {
-        rtsp::Lower::Udp => false,
+        rtsp::Lower::Udp => true,
         rtsp::Lower::Tcp => true,
     }
 }
 
-fn is_parameter_supported(parameter: &rtsp::Parameter) -> bool {
+fn is_parameter_supported(parameter: &rtsp::Parameter, multicast_available: bool) -> bool {
     /*
       Supported parameters are:
       - `unicast`
+      - `multicast` (if source has multicast group)
+      - `destination` (if multicast, see `is_supported`)
       - `interleaved`
+      - `ttl` (if source has multicast group)
+      - `port` (if source has multicast group)
+      - `client_port`
       - `mode` (if value is "PLAY")
     */
     match parameter {
         rtsp::Parameter::Unicast => true,
-        rtsp::Parameter::Multicast => false, // Multicast not supported
-        rtsp::Parameter::Destination(_) => false, // UDP not supported
+        rtsp::Parameter::Multicast => multicast_available,
+        rtsp::Parameter::Destination(_) => multicast_available, // Only for multicast
         rtsp::Parameter::Interleaved(_) => true,
-        rtsp::Parameter::Append => false,    // RECORD not supported
-        rtsp::Parameter::Ttl(_) => false,    // Multicast not supported
-        rtsp::Parameter::Layers(_) => false, // Multicast not supported
-        rtsp::Parameter::Port(_) => false,   // Multicast not supported
-        rtsp::Parameter::ClientPort(_) => false, // UDP not supported
+        rtsp::Parameter::Append => false, // RECORD not supported
+        rtsp::Parameter::Ttl(_) => multicast_available,
+        rtsp::Parameter::Layers(_) => false, // Layered multicast not supported
+        rtsp::Parameter::Port(_) => multicast_available,
+        rtsp::Parameter::ClientPort(_) => true,
         rtsp::Parameter::ServerPort(_) => false, // Client cannot choose server ports
-        rtsp::Parameter::Ssrc(_) => false,   // Client cannot choose ssrc
+        rtsp::Parameter::Ssrc(_) => false,       // Client cannot choose ssrc
         rtsp::Parameter::Mode(rtsp::Method::Play) => true,
-        rtsp::Parameter::Mode(_) => false, // Only PLAY is supported for session.
+        rtsp::Parameter::Mode(_) => false, // RECORD is handled by `is_record_supported`.
     }
 }
@@ -0,0 +1,259 @@
+//! Sessions that play media on demand read and mux the media by
+//! themselves, so that every client can seek and pause without affecting
+//! other clients.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use tokio::select;
+use tokio::time;
+
+use oddity_rtsp_protocol as rtsp;
+use video_rs as video;
+
+use crate::media::rtcp::{self, SenderReporter};
+use crate::media::video::reader::{ReadMode, StreamReader};
+use crate::media::video::rtp_muxer::RtpMuxer;
+use crate::media::MediaInfo;
+use crate::runtime::task_manager::TaskContext;
+use crate::session::feedback::{self, FeedbackInputs};
+use crate::session::setup::{SendError, SessionSetupTarget};
+use crate::session::{
+    Activity, SessionControlMessage, SessionControlRx, SessionCounters, SessionId,
+    SessionStreamState, SessionStreamStateTx,
+};
+use crate::source::VodSource;
+
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn run(
+    id: SessionId,
+    source: VodSource,
+    media_info: MediaInfo,
+    track: usize,
+    mut target: SessionSetupTarget,
+    counters: Arc<SessionCounters>,
+    last_activity: Arc<Activity>,
+    mut control_rx: SessionControlRx,
+    stream_state_tx: SessionStreamStateTx,
+    mut task_context: TaskContext,
+) {
+    let duration = media_info.duration;
+    let mut muxer = RtpMuxer::new(media_info, source.muxer_settings);
+    let cname = rtcp::generate_cname();
+    let mut feedback_inputs = FeedbackInputs::default();
+    if let Some(rtcp_input) = target.rtcp_input() {
+        feedback_inputs.add(track, rtcp_input);
+    }
+    let mut targets = BTreeMap::from([(track, VodTrack::new(target, &cname))]);
+    let mut report_interval = time::interval(rtcp::REPORT_INTERVAL);
+
+    // The reader only exists while playing. Pausing stops it, and playing
+    // again starts a new one at the position where the session paused.
+    let mut reader: Option<StreamReader> = None;
+    // Media time (in seconds) at which playback starts or resumes, and
+    // where it ends.
+    let mut position = 0.0;
+    let mut end = None;
+
+    'main: loop {
+        select! {
+            // CANCEL SAFETY: `StreamReader::read` uses `mpsc::UnboundedReceiver::recv`
+            // internally which is cancel safe.
+            packet = read(reader.as_mut()), if reader.is_some() => {
+                match packet {
+                    Some(Ok(packet)) => {
+                        if packet.pts().has_value() {
+                            position = packet.pts().as_secs_f64();
+                        }
+                        let muxed = match muxer.muxed(packet) {
+                            Some(muxed) => muxed,
+                            None => continue,
+                        };
+                        // Only the tracks that the client set up are sent.
+                        let target = match targets.get_mut(&muxed.track) {
+                            Some(target) => target,
+                            None => continue,
+                        };
+                        if target.awaiting_keyframe {
+                            if !muxed.is_key {
+                                continue;
+                            }
+                            target.awaiting_keyframe = false;
+                        }
+                        let received = SystemTime::now();
+                        for rtp_buf in muxed.bufs {
+                            let len = match &rtp_buf {
+                                video::rtp::RtpBuf::Rtp(payload) => {
+                                    target.reporter.sent(payload, muxed.clock_rate, received);
+                                    payload.len()
+                                }
+                                video::rtp::RtpBuf::Rtcp(payload) => payload.len(),
+                            };
+                            match target.target.send(rtp_buf).await {
+                                Ok(()) => counters.sent(len),
+                                // The client is too slow, so skip ahead to the next
+                                // keyframe.
+                                Err(SendError::QueueFull) => {
+                                    target.awaiting_keyframe = true;
+                                    break;
+                                }
+                                Err(err) => {
+                                    tracing::trace!(%id, %err, "failed to send to client");
+                                    break 'main;
+                                }
+                            }
+                        }
+                    },
+                    Some(Err(err)) => {
+                        tracing::error!(%id, %err, "failed to read media");
+                        stop(&mut reader).await;
+                    },
+                    None => {
+                        tracing::info!(%id, "session reached end of range");
+                        stop(&mut reader).await;
+                        position = end.or(duration).unwrap_or(position);
+                    },
+                }
+            },
+            // CANCEL SAFETY: `Interval::tick` is cancel safe.
+            _ = report_interval.tick() => {
+                // Reports are only sent while playing, just like the media.
+                if reader.is_none() {
+                    continue;
+                }
+                let now = SystemTime::now();
+                for target in targets.values_mut() {
+                    let report = match target.reporter.report(now) {
+                        Some(report) => report,
+                        None => continue,
+                    };
+                    let len = report.len();
+                    match target.target.send(video::rtp::RtpBuf::Rtcp(report)).await {
+                        Ok(()) => counters.sent(len),
+                        Err(SendError::QueueFull) => {}
+                        Err(err) => {
+                            tracing::trace!(%id, %err, "failed to send sender report to client");
+                            break 'main;
+                        }
+                    }
+                }
+            },
+            // CANCEL SAFETY: `FeedbackInputs::next` uses `StreamExt::next` internally
+            // which is cancel safe.
+            Some((index, packet)) = feedback_inputs.next(), if !feedback_inputs.is_empty() => {
+                let target = match targets.get(&index) {
+                    Some(target) => target,
+                    None => continue,
+                };
+                if feedback::handle(&id, index, &packet, &target.reporter, &last_activity) {
+                    tracing::info!(%id, "client left session (rtcp bye)");
+                    break 'main;
+                }
+            },
+            // CANCEL SAFETY: `mpsc::UnboundedReceiver::recv` is cancel safe.
+            message = control_rx.recv() => {
+                match message {
+                    Some(SessionControlMessage::Play) => {
+                        let is_at_end = end.or(duration).is_some_and(|end| position >= end);
+                        if reader.is_none() && !is_at_end {
+                            reader = start(&source, position, end).await;
+                        }
+                        tracing::info!(%id, position, "session now playing");
+                    },
+                    Some(SessionControlMessage::Pause) => {
+                        stop(&mut reader).await;
+                        tracing::info!(%id, position, "session paused");
+                    },
+                    Some(SessionControlMessage::Seek { start, end: new_end }) => {
+                        stop(&mut reader).await;
+                        position = start;
+                        end = new_end;
+                        tracing::trace!(%id, start, end, "session seeked");
+                    },
+                    Some(SessionControlMessage::StreamState) => {
+                        let stream_states = muxer.stream_states_at(position);
+                        let stream_state = SessionStreamState {
+                            tracks: targets
+                                .keys()
+                                .map(|index| (*index, stream_states.get(*index).cloned().unwrap_or_default()))
+                                .collect(),
+                            range: Some(rtsp::Range {
+                                start: Some(rtsp::NptTime::Time(position)),
+                                end: end.or(duration).map(rtsp::NptTime::Time),
+                            }),
+                        };
+                        let _ = stream_state_tx.send(stream_state);
+                        tracing::trace!(%id, "dispatched stream state over control channel");
+                    },
+                    Some(SessionControlMessage::SetupTrack(index, mut target)) => {
+                        if let Some(rtcp_input) = target.rtcp_input() {
+                            feedback_inputs.add(index, rtcp_input);
+                        }
+                        let _ = targets.insert(index, VodTrack::new(target, &cname));
+                        tracing::trace!(%id, track = index, "added track to session");
+                    },
+                    Some(SessionControlMessage::Record | SessionControlMessage::AddTrack(_)) => {
+                        tracing::warn!(%id, "ignored record control message for play session");
+                    },
+                    None => {
+                        tracing::error!(%id, "session control channel broke unexpectedly");
+                        break;
+                    },
+                }
+            },
+            // CANCEL SAFETY: `TaskContext::wait_for_stop` is cancel safe.
+            _ = task_context.wait_for_stop() => {
+                tracing::trace!("tearing down session");
+                break;
+            },
+        }
+    }
+
+    stop(&mut reader).await;
+}
+
+/// Track that the client set up in an on demand session.
+struct VodTrack {
+    target: SessionSetupTarget,
+    reporter: SenderReporter,
+    /// Waiting for the next keyframe after packets were dropped because
+    /// the client is too slow.
+    awaiting_keyframe: bool,
+}
+
+impl VodTrack {
+    fn new(target: SessionSetupTarget, cname: &str) -> Self {
+        Self {
+            target,
+            reporter: SenderReporter::new(cname),
+            awaiting_keyframe: false,
+        }
+    }
+}
+
+async fn start(source: &VodSource, start: f64, end: Option<f64>) -> Option<StreamReader> {
+    let location = source.descriptor.location()?;
+    match StreamReader::new_with_mode(&source.descriptor, location, ReadMode::Once { start, end })
+        .await
+    {
+        Ok(reader) => Some(reader),
+        Err(err) => {
+            tracing::error!(%err, descriptor = %source.descriptor, "failed to start reader");
+            None
+        }
+    }
+}
+
+async fn stop(reader: &mut Option<StreamReader>) {
+    if let Some(mut reader) = reader.take() {
+        reader.stop().await;
+    }
+}
+
+async fn read(reader: Option<&mut StreamReader>) -> Option<Result<video::Packet, video::Error>> {
+    match reader {
+        Some(reader) => reader.read().await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,562 @@
+use std::error;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::net::UdpSocket;
+use tokio::select;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+
+use oddity_sdp_protocol::{Kind, Media, Protocol, Sdp, Tag, TimeRange};
+use video_rs as video;
+
+use crate::net::webrtc::{
+    DtlsIdentity, IceCredentials, RemoteDescription, WebRtcError, WebRtcTransport,
+};
+use crate::runtime::task_manager::{Task, TaskContext};
+use crate::runtime::Runtime;
+use crate::session::rewrite::RtpRewriter;
+use crate::source::packetizer::RtpPacket;
+use crate::source::{SourceDelegate, SourcePath};
+
+pub type WhepSessionId = String;
+
+pub type WhepSessionEndedTx = mpsc::UnboundedSender<WhepSessionId>;
+pub type WhepSessionEndedRx = mpsc::UnboundedReceiver<WhepSessionId>;
+
+/// What the server answers the offer of a browser with, and what it needs
+/// to send the browser the video of the source.
+pub struct Negotiated {
+    pub answer: Sdp,
+    /// Track of the source that the browser receives.
+    pub track: usize,
+    /// Payload type that the browser picked for H.264.
+    pub payload_type: u8,
+    pub remote: RemoteDescription,
+}
+
+/// Answer the offer of a browser for the source described by `source`.
+/// The browser gets the first H.264 track of the source, in the first video
+/// media description of its offer. Everything else it offers is rejected,
+/// since only H.264 can be forwarded to browsers without transcoding.
+pub fn negotiate(
+    offer: &str,
+    source: &Sdp,
+    local: &IceCredentials,
+    fingerprint: &str,
+    candidate: SocketAddr,
+) -> Result<Negotiated, NegotiateError> {
+    let offer = offer.parse::<Sdp>().map_err(NegotiateError::Parse)?;
+
+    let (track, source_format) = source
+        .media
+        .iter()
+        .enumerate()
+        .find_map(|(track, media)| {
+            h264_payload_types(media)
+                .next()
+                .map(|payload_type| (track, fmtp(media, payload_type)))
+        })
+        .ok_or(NegotiateError::SourceNotSupported)?;
+    let source_parameters = source_format.unwrap_or_default();
+    let packetization_mode = format_parameter(source_parameters, "packetization-mode")
+        .unwrap_or("0")
+        .to_string();
+    let profile = format_parameter(source_parameters, "profile-level-id")
+        .and_then(|profile_level_id| profile_level_id.get(..2))
+        .map(str::to_ascii_lowercase);
+
+    // Some browsers only put the credentials and fingerprint at the session
+    // level.
+    let attribute = |media: &Media, name: &str| {
+        media
+            .attribute(name)
+            .or_else(|| offer.attribute(name))
+            .map(str::to_string)
+    };
+
+    let mut answer = Sdp::new(
+        candidate.ip(),
+        "oddity".to_string(),
+        candidate.ip(),
+        TimeRange::Live,
+    )
+    .with_tag(Tag::Property("ice-lite".to_string()));
+    let mut accepted = None;
+    for media in offer.media.iter() {
+        let mid = media.attribute("mid").unwrap_or_default().to_string();
+        let payload_type = if accepted.is_none() && matches!(media.kind, Kind::Video) {
+            // Browsers offer several H.264 profiles. Any of them decodes the
+            // source as long as the packetization mode matches, but the one
+            // with the same profile is the most likely to be hardware decoded.
+            let compatible = h264_payload_types(media)
+                .filter(|payload_type| {
+                    format_parameter(
+                        fmtp(media, *payload_type).unwrap_or_default(),
+                        "packetization-mode",
+                    )
+                    .unwrap_or("0")
+                        == packetization_mode
+                })
+                .collect::<Vec<_>>();
+            compatible
+                .iter()
+                .copied()
+                .find(|payload_type| {
+                    format_parameter(
+                        fmtp(media, *payload_type).unwrap_or_default(),
+                        "profile-level-id",
+                    )
+                    .and_then(|profile_level_id| profile_level_id.get(..2))
+                    .map(str::to_ascii_lowercase)
+                        == profile
+                })
+                .or(compatible.first().copied())
+        } else {
+            None
+        };
+
+        let Some(payload_type) = payload_type else {
+            // Rejected media descriptions have port zero, and keep only their
+            // identification (RFC 8843 Section 7.3.3).
+            answer.media.push(Media {
+                kind: media.kind.clone(),
+                port: 0,
+                protocol: media.protocol.clone(),
+                format: media.format,
+                tags: vec![Tag::Value("mid".to_string(), mid)],
+            });
+            continue;
+        };
+
+        let ice_ufrag = attribute(media, "ice-ufrag").ok_or(NegotiateError::IceMissing)?;
+        let remote_fingerprint = attribute(media, "fingerprint")
+            .and_then(|fingerprint| {
+                fingerprint
+                    .strip_prefix("sha-256 ")
+                    .map(|fingerprint| fingerprint.trim().to_string())
+            })
+            .ok_or(NegotiateError::FingerprintMissing)?;
+
+        answer = answer.with_tag(Tag::Value("group".to_string(), format!("BUNDLE {mid}")));
+        answer.media.push(Media {
+            kind: Kind::Video,
+            port: candidate.port(),
+            protocol: Protocol::UdpTlsRtpSAvpf,
+            format: payload_type as usize,
+            tags: vec![
+                Tag::Value("mid".to_string(), mid),
+                Tag::Value("ice-ufrag".to_string(), local.ufrag.clone()),
+                Tag::Value("ice-pwd".to_string(), local.pwd.clone()),
+                Tag::Value("fingerprint".to_string(), format!("sha-256 {fingerprint}")),
+                // The browser starts the DTLS handshake.
+                Tag::Value("setup".to_string(), "passive".to_string()),
+                Tag::Property("rtcp-mux".to_string()),
+                Tag::Property("sendonly".to_string()),
+                Tag::Value("rtpmap".to_string(), format!("{payload_type} H264/90000")),
+                Tag::Value(
+                    "fmtp".to_string(),
+                    format!("{payload_type} {source_parameters}"),
+                ),
+                Tag::Value(
+                    "candidate".to_string(),
+                    format!(
+                        "1 1 udp 2130706431 {} {} typ host",
+                        candidate.ip(),
+                        candidate.port()
+                    ),
+                ),
+                Tag::Property("end-of-candidates".to_string()),
+            ],
+        });
+        accepted = Some((
+            payload_type,
+            RemoteDescription {
+                ice_ufrag,
+                fingerprint: remote_fingerprint,
+            },
+        ));
+    }
+
+    let (payload_type, remote) = accepted.ok_or(NegotiateError::OfferNotSupported)?;
+    Ok(Negotiated {
+        answer,
+        track,
+        payload_type,
+        remote,
+    })
+}
+
+/// Payload types of H.264 formats in a media description.
+fn h264_payload_types(media: &Media) -> impl Iterator<Item = u8> + '_ {
+    media.attributes("rtpmap").filter_map(|rtpmap| {
+        let (payload_type, encoding) = rtpmap.split_once(' ')?;
+        encoding
+            .to_ascii_uppercase()
+            .starts_with("H264/90000")
+            .then(|| payload_type.parse().ok())
+            .flatten()
+    })
+}
+
+/// Format parameters of the format with the given payload type.
+fn fmtp(media: &Media, payload_type: u8) -> Option<&str> {
+    media.attributes("fmtp").find_map(|fmtp| {
+        let (format, parameters) = fmtp.split_once(' ')?;
+        (format.parse() == Ok(payload_type)).then_some(parameters.trim())
+    })
+}
+
+fn format_parameter<'a>(parameters: &'a str, name: &str) -> Option<&'a str> {
+    parameters.split(';').find_map(|parameter| {
+        let (key, value) = parameter.split_once('=')?;
+        (key.trim() == name).then_some(value.trim())
+    })
+}
+
+/// Session of a browser that receives a source over WebRTC. The session
+/// ends when the browser is gone, or when it is stopped.
+pub struct WhepSession {
+    worker: Task,
+}
+
+impl WhepSession {
+    #[allow(clippy::too_many_arguments)]
+    pub async fn start(
+        id: WhepSessionId,
+        path: SourcePath,
+        negotiated: &Negotiated,
+        source_delegate: SourceDelegate,
+        socket: UdpSocket,
+        local: IceCredentials,
+        identity: Arc<DtlsIdentity>,
+        ended_tx: WhepSessionEndedTx,
+        runtime: Arc<Runtime>,
+    ) -> Self {
+        tracing::trace!(%id, %path, "starting whep session");
+        let worker = runtime
+            .task()
+            .spawn({
+                let id = id.clone();
+                let track = negotiated.track;
+                let payload_type = negotiated.payload_type;
+                let remote = negotiated.remote.clone();
+                let runtime = runtime.clone();
+                move |task_context| async move {
+                    Self::run(
+                        &id,
+                        &path,
+                        track,
+                        payload_type,
+                        source_delegate,
+                        socket,
+                        local,
+                        remote,
+                        &identity,
+                        &runtime,
+                        task_context,
+                    )
+                    .await;
+                    let _ = ended_tx.send(id);
+                }
+            })
+            .await;
+        tracing::trace!(%id, "started whep session");
+
+        Self { worker }
+    }
+
+    pub async fn stop(&mut self) {
+        tracing::trace!("sending stop signal to whep session");
+        self.worker.stop().await;
+        tracing::trace!("stopped whep session");
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn run(
+        id: &str,
+        path: &str,
+        track: usize,
+        payload_type: u8,
+        source_delegate: SourceDelegate,
+        socket: UdpSocket,
+        local: IceCredentials,
+        remote: RemoteDescription,
+        identity: &DtlsIdentity,
+        runtime: &Runtime,
+        mut task_context: TaskContext,
+    ) {
+        let mut transport = select! {
+            // CANCEL SAFETY: The transport is dropped with the future, which stops
+            // its workers.
+            accepted = WebRtcTransport::accept(socket, local, remote, identity, runtime) => {
+                match accepted {
+                    Ok(transport) => transport,
+                    Err(err) => {
+                        tracing::info!(%id, %err, "whep peer failed to connect");
+                        return;
+                    },
+                }
+            },
+            // CANCEL SAFETY: `TaskContext::wait_for_stop` is cancel safe.
+            _ = task_context.wait_for_stop() => {
+                return;
+            },
+        };
+        tracing::info!(%id, %path, "whep session now playing");
+
+        let gop_cache = source_delegate.gop_cache();
+        let (mut source_rtp_rx, _) = source_delegate.into_parts();
+        let mut sender = WhepSender {
+            track,
+            payload_type,
+            rewriter: RtpRewriter::new(),
+            awaiting_keyframe: false,
+        };
+        // Browsers cannot decode anything before a keyframe, so they start at
+        // the last one.
+        let packets = gop_cache.replay(&mut source_rtp_rx);
+        sender.awaiting_keyframe = packets.is_empty();
+        for packet in packets.iter() {
+            if let Err(err) = sender.send(&mut transport, packet).await {
+                tracing::debug!(%id, %err, "failed to send to whep peer");
+            }
+        }
+
+        loop {
+            select! {
+                // CANCEL SAFETY: `broadcast::Receiver::recv` is cancel safe.
+                packet = source_rtp_rx.recv() => {
+                    match packet {
+                        Ok(packet) => {
+                            if let Err(err) = sender.send(&mut transport, &packet).await {
+                                tracing::debug!(%id, %err, "failed to send to whep peer");
+                            }
+                        },
+                        Err(RecvError::Lagged(skipped)) => {
+                            tracing::warn!(%id, skipped, "whep session lagging behind source");
+                            sender.awaiting_keyframe = true;
+                        },
+                        Err(RecvError::Closed) => {
+                            tracing::debug!(%id, "source stopped");
+                            break;
+                        },
+                    }
+                },
+                // CANCEL SAFETY: `WebRtcTransport::gone` is cancel safe.
+                _ = transport.gone() => {
+                    tracing::info!(%id, "whep peer gone");
+                    break;
+                },
+                // CANCEL SAFETY: `TaskContext::wait_for_stop` is cancel safe.
+                _ = task_context.wait_for_stop() => {
+                    tracing::info!(%id, "whep session stopped");
+                    break;
+                },
+            }
+        }
+
+        transport.close().await;
+    }
+}
+
+/// Forwards the video track of the source to the browser.
+struct WhepSender {
+    track: usize,
+    payload_type: u8,
+    /// The source muxes every packet once for all sessions. The headers are
+    /// rewritten for each session, like those of RTSP sessions.
+    rewriter: RtpRewriter,
+    awaiting_keyframe: bool,
+}
+
+impl WhepSender {
+    async fn send(
+        &mut self,
+        transport: &mut WebRtcTransport,
+        packet: &RtpPacket,
+    ) -> Result<(), WebRtcError> {
+        if packet.track != self.track {
+            return Ok(());
+        }
+        if self.awaiting_keyframe {
+            if !packet.is_key {
+                return Ok(());
+            }
+            self.awaiting_keyframe = false;
+        }
+        // Sender reports would have to go out as SRTCP. Browsers play fine
+        // without them, since there is no other track to synchronize with.
+        for rtp_buf in packet.bufs.iter() {
+            let video::rtp::RtpBuf::Rtp(payload) = rtp_buf else {
+                continue;
+            };
+            let Some(mut payload) = self.rewriter.rewrite_rtp(payload) else {
+                continue;
+            };
+            // The browser picked the payload type, and the marker bit shares
+            // the byte with it.
+            payload[1] = (payload[1] & 0x80) | (self.payload_type & 0x7f);
+            transport.send_rtp(&payload).await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum NegotiateError {
+    Parse(oddity_sdp_protocol::Error),
+    /// The source has no H.264 track.
+    SourceNotSupported,
+    /// The offer has no video with H.264 in the packetization mode of the
+    /// source.
+    OfferNotSupported,
+    IceMissing,
+    /// The offer has no SHA-256 certificate fingerprint.
+    FingerprintMissing,
+}
+
+impl fmt::Display for NegotiateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NegotiateError::Parse(err) => write!(f, "failed to parse offer: {}", err),
+            NegotiateError::SourceNotSupported => write!(f, "source has no h264 video"),
+            NegotiateError::OfferNotSupported => write!(f, "offer has no compatible h264 video"),
+            NegotiateError::IceMissing => write!(f, "offer has no ice credentials"),
+            NegotiateError::FingerprintMissing => {
+                write!(f, "offer has no sha-256 certificate fingerprint")
+            }
+        }
+    }
+}
+
+impl error::Error for NegotiateError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "v=0\r\n\
+        o=- 0 0 IN IP4 127.0.0.1\r\n\
+        s=camera\r\n\
+        c=IN IP4 127.0.0.1\r\n\
+        t=0 0\r\n\
+        m=audio 0 RTP/AVP 97\r\n\
+        a=rtpmap:97 MPEG4-GENERIC/48000/2\r\n\
+        m=video 0 RTP/AVP 96\r\n\
+        a=rtpmap:96 H264/90000\r\n\
+        a=fmtp:96 packetization-mode=1; sprop-parameter-sets=Z2QAH6zZQFAFuhAAAAMAEAAAAwPI8YMZYA==,aOvjyyLA; profile-level-id=64001F\r\n";
+
+    const OFFER: &str = "v=0\r\n\
+        o=- 4611731400430051336 2 IN IP4 127.0.0.1\r\n\
+        s=-\r\n\
+        t=0 0\r\n\
+        a=group:BUNDLE 0 1\r\n\
+        a=fingerprint:sha-256 AB:CD:EF\r\n\
+        m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n\
+        c=IN IP4 0.0.0.0\r\n\
+        a=ice-ufrag:remo\r\n\
+        a=ice-pwd:remotepasswordremotepass\r\n\
+        a=mid:0\r\n\
+        a=recvonly\r\n\
+        a=rtpmap:111 opus/48000/2\r\n\
+        m=video 9 UDP/TLS/RTP/SAVPF 96 102 106 127\r\n\
+        c=IN IP4 0.0.0.0\r\n\
+        a=ice-ufrag:remo\r\n\
+        a=ice-pwd:remotepasswordremotepass\r\n\
+        a=mid:1\r\n\
+        a=setup:actpass\r\n\
+        a=recvonly\r\n\
+        a=rtcp-mux\r\n\
+        a=rtpmap:96 VP8/90000\r\n\
+        a=rtpmap:102 H264/90000\r\n\
+        a=fmtp:102 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42001f\r\n\
+        a=rtpmap:106 H264/90000\r\n\
+        a=fmtp:106 level-asymmetry-allowed=1;packetization-mode=0;profile-level-id=42e01f\r\n\
+        a=rtpmap:127 H264/90000\r\n\
+        a=fmtp:127 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=640032\r\n";
+
+    fn local() -> IceCredentials {
+        IceCredentials {
+            ufrag: "loca".to_string(),
+            pwd: "localpasswordlocalpasswo".to_string(),
+        }
+    }
+
+    fn negotiate_offer(offer: &str) -> Result<Negotiated, NegotiateError> {
+        negotiate(
+            offer,
+            &SOURCE.parse().unwrap(),
+            &local(),
+            "01:23:45",
+            "192.0.2.1:50000".parse().unwrap(),
+        )
+    }
+
+    #[test]
+    fn negotiate_browser_offer() {
+        let negotiated = negotiate_offer(OFFER).unwrap();
+        assert_eq!(negotiated.track, 1);
+        // Same packetization mode and profile as the source.
+        assert_eq!(negotiated.payload_type, 127);
+        assert_eq!(negotiated.remote.ice_ufrag, "remo");
+        assert_eq!(negotiated.remote.fingerprint, "AB:CD:EF");
+
+        let answer = negotiated.answer;
+        assert!(answer
+            .tags
+            .iter()
+            .any(|tag| matches!(tag, Tag::Property(property) if property == "ice-lite")));
+        assert_eq!(answer.attribute("group"), Some("BUNDLE 1"));
+        assert_eq!(answer.media.len(), 2);
+        assert_eq!(answer.media[0].port, 0);
+        assert_eq!(answer.media[0].attribute("mid"), Some("0"));
+        assert_eq!(answer.media[0].attribute("ice-ufrag"), None);
+
+        let video = &answer.media[1];
+        assert_eq!(video.port, 50000);
+        assert!(matches!(video.protocol, Protocol::UdpTlsRtpSAvpf));
+        assert_eq!(video.format, 127);
+        assert_eq!(video.attribute("mid"), Some("1"));
+        assert_eq!(video.attribute("ice-ufrag"), Some("loca"));
+        assert_eq!(video.attribute("fingerprint"), Some("sha-256 01:23:45"));
+        assert_eq!(video.attribute("setup"), Some("passive"));
+        assert_eq!(video.attribute("rtpmap"), Some("127 H264/90000"));
+        assert!(video
+            .attribute("fmtp")
+            .unwrap()
+            .starts_with("127 packetization-mode=1;"));
+        assert_eq!(
+            video.attribute("candidate"),
+            Some("1 1 udp 2130706431 192.0.2.1 50000 typ host")
+        );
+        // The answer must be something that parses again.
+        assert!(answer.to_string().parse::<Sdp>().is_ok());
+    }
+
+    #[test]
+    fn negotiate_other_profile() {
+        let offer = OFFER.replace("profile-level-id=640032", "profile-level-id=4d0032");
+        // Any profile will do, as long as the packetization mode matches.
+        assert_eq!(negotiate_offer(&offer).unwrap().payload_type, 102);
+    }
+
+    #[test]
+    fn negotiate_without_h264() {
+        let offer = OFFER.replace("packetization-mode=1", "packetization-mode=0");
+        assert!(matches!(
+            negotiate_offer(&offer),
+            Err(NegotiateError::OfferNotSupported)
+        ));
+    }
+
+    #[test]
+    fn negotiate_without_fingerprint() {
+        let offer = OFFER.replace("a=fingerprint:sha-256 AB:CD:EF\r\n", "");
+        assert!(matches!(
+            negotiate_offer(&offer),
+            Err(NegotiateError::FingerprintMissing)
+        ));
+    }
+}
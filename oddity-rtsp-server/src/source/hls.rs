@@ -0,0 +1,672 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use tokio::select;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Notify;
+
+use crate::media::MediaInfo;
+use crate::runtime::task_manager::{Task, TaskContext};
+use crate::runtime::Runtime;
+use crate::source::segmenter::{Segmented, Segmenter, SegmenterSettings, Timeline};
+use crate::source::{SourcePacketRx, SourcePath, SourceResetRx};
+
+/// Number of segments that are kept after they drop off the playlist, for
+/// clients that are still working through an older copy of it.
+const EXTRA_SEGMENTS: usize = 2;
+
+/// Number of complete segments that parts are listed for, with low-latency
+/// HLS. Clients that play from parts are never further behind than this.
+const PART_SEGMENTS: usize = 2;
+
+/// Longest stretch of media in a single fragment, if keyframes are further
+/// apart than this (or there is no video).
+const MAX_FRAGMENT_DURATION: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HlsSettings {
+    /// Segments are cut at the first keyframe after this much media.
+    pub segment_duration: Duration,
+    /// Number of segments in the playlist.
+    pub playlist_size: usize,
+    /// Target duration of parts, for low-latency HLS. Segments are not
+    /// split into parts if not set.
+    pub part_duration: Option<Duration>,
+}
+
+/// Packages a source as HLS (RFC 8216) with fragmented MP4 segments, and
+/// keeps a rolling window of segments in memory to serve. Media is not
+/// transcoded, so clients must support the codecs of the source.
+pub struct HlsPackager {
+    playlist: HlsPlaylist,
+    worker: Task,
+}
+
+impl HlsPackager {
+    /// Start packaging. The receivers must be subscribed before
+    /// `media_info` is taken from the source, so that no reset goes
+    /// unnoticed in between.
+    pub async fn start(
+        path: SourcePath,
+        settings: HlsSettings,
+        reset_rx: SourceResetRx,
+        packet_rx: SourcePacketRx,
+        media_info: Option<MediaInfo>,
+        runtime: &Runtime,
+    ) -> Self {
+        let playlist = HlsPlaylist::new(settings);
+
+        tracing::trace!(%path, "starting hls packager");
+        let worker = runtime
+            .task()
+            .spawn({
+                let path = path.clone();
+                let playlist = playlist.clone();
+                move |task_context| {
+                    Self::run(
+                        path,
+                        settings,
+                        reset_rx,
+                        packet_rx,
+                        media_info,
+                        playlist,
+                        task_context,
+                    )
+                }
+            })
+            .await;
+        tracing::trace!(%path, "started hls packager");
+
+        Self { playlist, worker }
+    }
+
+    pub fn playlist(&self) -> HlsPlaylist {
+        self.playlist.clone()
+    }
+
+    pub async fn stop(&mut self) {
+        tracing::trace!("sending stop signal to hls packager");
+        self.worker.stop().await;
+        tracing::trace!("stopped hls packager");
+    }
+
+    async fn run(
+        path: SourcePath,
+        settings: HlsSettings,
+        mut reset_rx: SourceResetRx,
+        mut packet_rx: SourcePacketRx,
+        media_info: Option<MediaInfo>,
+        playlist: HlsPlaylist,
+        mut task_context: TaskContext,
+    ) {
+        let segmenter_settings = SegmenterSettings {
+            segment_duration: settings.segment_duration,
+            fragment_duration: settings.part_duration.unwrap_or(MAX_FRAGMENT_DURATION),
+            timeline: Timeline::Continuous,
+        };
+        let mut segmenter =
+            media_info.map(|media_info| Segmenter::new(&path, &media_info, segmenter_settings));
+
+        loop {
+            select! {
+                // CANCEL SAFETY: `broadcast::Receiver::recv` is cancel safe.
+                reset = reset_rx.recv() => {
+                    match reset {
+                        Ok(media_info) => {
+                            tracing::trace!(%path, "resetting hls packager");
+                            // The new stream gets a new initialization segment, and
+                            // clients are told about the discontinuity.
+                            if let Some(segmenter) = segmenter.as_mut() {
+                                playlist.push(segmenter.finish());
+                            }
+                            segmenter = Some(Segmenter::new(&path, &media_info, segmenter_settings));
+                        },
+                        Err(RecvError::Lagged(_)) => {
+                            tracing::warn!(%path, "hls packager missed reset");
+                        },
+                        Err(RecvError::Closed) => {
+                            tracing::trace!(%path, "source stopped");
+                            break;
+                        },
+                    }
+                },
+                // CANCEL SAFETY: `broadcast::Receiver::recv` is cancel safe.
+                packet = packet_rx.recv() => {
+                    let packet = match packet {
+                        Ok(packet) => packet,
+                        Err(RecvError::Lagged(skipped)) => {
+                            tracing::warn!(%path, skipped, "hls packager lagging behind source");
+                            continue;
+                        },
+                        Err(RecvError::Closed) => {
+                            tracing::trace!(%path, "source stopped");
+                            break;
+                        },
+                    };
+
+                    if let Some(segmenter) = segmenter.as_mut() {
+                        playlist.push(segmenter.push(packet));
+                    }
+                },
+                // CANCEL SAFETY: `TaskContext::wait_for_stop` is cancel safe.
+                _ = task_context.wait_for_stop() => {
+                    tracing::trace!(%path, "stopping hls packager");
+                    break;
+                },
+            }
+        }
+    }
+}
+
+/// Segments of a source that is packaged as HLS, shared between the
+/// packager and the HTTP server.
+#[derive(Clone)]
+pub struct HlsPlaylist {
+    playlist: Arc<Mutex<Playlist>>,
+    /// Wakes up requests that wait for a segment or part.
+    updated: Arc<Notify>,
+}
+
+struct Playlist {
+    settings: HlsSettings,
+    /// Complete segments, oldest first.
+    segments: VecDeque<HlsSegment>,
+    /// Segment that is being packaged.
+    current: Option<HlsSegment>,
+    next_sequence: u64,
+    /// Number of discontinuities in segments that were dropped.
+    discontinuity_sequence: u64,
+    /// Current initialization segment and its identifier.
+    init: Option<(u64, Arc<[u8]>)>,
+    /// Duration of the longest segment so far, and at least the segment
+    /// duration. Segments are cut at keyframes, so they may be longer than
+    /// asked for. The target duration must cover the longest one, and must
+    /// not change (RFC 8216 Section 4.3.3.1), so it only ever grows.
+    target_duration: Duration,
+}
+
+struct HlsSegment {
+    sequence: u64,
+    init_id: u64,
+    init: Arc<[u8]>,
+    /// Parts of the segment. Only kept for recent segments with low-latency
+    /// HLS, and for the current segment.
+    parts: Vec<HlsPart>,
+    /// Empty until the segment is complete.
+    data: Bytes,
+    duration: Duration,
+    /// Whether the segment follows a restart of the source.
+    discontinuity: bool,
+}
+
+struct HlsPart {
+    data: Bytes,
+    duration: Duration,
+    independent: bool,
+}
+
+impl HlsPlaylist {
+    fn new(settings: HlsSettings) -> Self {
+        Self {
+            playlist: Arc::new(Mutex::new(Playlist {
+                settings,
+                segments: VecDeque::new(),
+                current: None,
+                next_sequence: 0,
+                discontinuity_sequence: 0,
+                init: None,
+                target_duration: settings.segment_duration,
+            })),
+            updated: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Media playlist, or `None` if there are no complete segments yet.
+    pub fn media_playlist(&self) -> Option<String> {
+        self.lock().render()
+    }
+
+    /// Initialization segment with the given identifier.
+    pub fn init(&self, id: u64) -> Option<Bytes> {
+        let playlist = self.lock();
+        playlist
+            .segments
+            .iter()
+            .chain(playlist.current.as_ref())
+            .find(|segment| segment.init_id == id)
+            .map(|segment| Bytes::copy_from_slice(&segment.init))
+    }
+
+    /// Complete media segment with the given sequence number.
+    pub fn segment(&self, sequence: u64) -> Option<Bytes> {
+        self.lock()
+            .segments
+            .iter()
+            .find(|segment| segment.sequence == sequence)
+            .map(|segment| segment.data.clone())
+    }
+
+    /// Part of the media segment with the given sequence number.
+    pub fn part(&self, sequence: u64, part: usize) -> Option<Bytes> {
+        let playlist = self.lock();
+        playlist
+            .segments
+            .iter()
+            .chain(playlist.current.as_ref())
+            .find(|segment| segment.sequence == sequence)
+            .and_then(|segment| segment.parts.get(part))
+            .map(|part| part.data.clone())
+    }
+
+    /// Whether low-latency HLS is enabled, so that clients can wait for
+    /// segments and parts (blocking playlist reload).
+    pub fn is_low_latency(&self) -> bool {
+        self.lock().settings.part_duration.is_some()
+    }
+
+    /// How long clients may wait for a segment or part. Clients give up on
+    /// blocking requests after three target durations.
+    pub fn max_wait(&self) -> Duration {
+        self.lock().target_duration * 3
+    }
+
+    /// Wait until the segment with the given sequence number is complete,
+    /// or until it has the given part. Returns `false` right away if the
+    /// segment is too far in the future to wait for.
+    pub async fn wait_for(&self, sequence: u64, part: Option<usize>) -> bool {
+        loop {
+            // Registered before checking, so that an update in between is
+            // not missed.
+            let updated = self.updated.notified();
+            match self.lock().contains(sequence, part) {
+                Some(true) => return true,
+                Some(false) => {}
+                None => return false,
+            }
+            updated.await;
+        }
+    }
+
+    fn push(&self, output: Vec<Segmented>) {
+        if output.is_empty() {
+            return;
+        }
+        {
+            let mut playlist = self.lock();
+            for segmented in output {
+                playlist.push(segmented);
+            }
+        }
+        self.updated.notify_waiters();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Playlist> {
+        // Nothing that can panic happens while holding the lock, but if it
+        // does, the segments are still fine to serve.
+        self.playlist
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Playlist {
+    fn push(&mut self, segmented: Segmented) {
+        match segmented {
+            Segmented::Start { init, .. } => {
+                // Every segmenter has its own initialization segment, so a new
+                // one means the source restarted.
+                let discontinuity = match self.init.as_ref() {
+                    Some((_, current)) if Arc::ptr_eq(current, &init) => false,
+                    current => {
+                        let id = current.map(|(id, _)| id + 1).unwrap_or_default();
+                        let restarted = current.is_some();
+                        self.init = Some((id, init));
+                        restarted
+                    }
+                };
+                let Some((init_id, init)) = self.init.clone() else {
+                    return;
+                };
+                self.current = Some(HlsSegment {
+                    sequence: self.next_sequence,
+                    init_id,
+                    init,
+                    parts: Vec::new(),
+                    data: Bytes::new(),
+                    duration: Duration::ZERO,
+                    discontinuity,
+                });
+                self.next_sequence += 1;
+            }
+            Segmented::Fragment {
+                data,
+                duration,
+                independent,
+            } => {
+                if let Some(current) = self.current.as_mut() {
+                    current.parts.push(HlsPart {
+                        data: data.into(),
+                        duration,
+                        independent,
+                    });
+                    current.duration += duration;
+                }
+            }
+            Segmented::End => {
+                if let Some(mut current) = self.current.take() {
+                    let mut data =
+                        Vec::with_capacity(current.parts.iter().map(|part| part.data.len()).sum());
+                    for part in current.parts.iter() {
+                        data.extend_from_slice(&part.data);
+                    }
+                    current.data = data.into();
+                    self.target_duration = self.target_duration.max(current.duration);
+                    self.segments.push_back(current);
+                }
+                while self.segments.len() > self.settings.playlist_size + EXTRA_SEGMENTS {
+                    if let Some(dropped) = self.segments.pop_front() {
+                        self.discontinuity_sequence += dropped.discontinuity as u64;
+                    }
+                }
+                // Parts are only listed for the most recent segments, and not at
+                // all without low-latency HLS.
+                let keep_parts = if self.settings.part_duration.is_some() {
+                    PART_SEGMENTS
+                } else {
+                    0
+                };
+                let len = self.segments.len();
+                for segment in self
+                    .segments
+                    .iter_mut()
+                    .take(len.saturating_sub(keep_parts))
+                {
+                    segment.parts = Vec::new();
+                }
+            }
+        }
+    }
+
+    /// Whether the segment with the given sequence number is complete, or
+    /// has the given part. `None` if it is too far in the future.
+    fn contains(&self, sequence: u64, part: Option<usize>) -> Option<bool> {
+        // Clients may ask for at most two segments past the last complete one
+        // (RFC 8216bis Section 6.2.5.2).
+        if sequence > self.next_sequence + 1 {
+            return None;
+        }
+        Some(match self.current.as_ref() {
+            Some(current) if current.sequence == sequence => {
+                part.is_some_and(|part| part < current.parts.len())
+            }
+            Some(current) => sequence < current.sequence,
+            None => sequence < self.next_sequence,
+        })
+    }
+
+    fn render(&self) -> Option<String> {
+        if self.segments.is_empty() {
+            return None;
+        }
+        let skip = self
+            .segments
+            .len()
+            .saturating_sub(self.settings.playlist_size);
+        let listed = self.segments.iter().skip(skip).collect::<Vec<_>>();
+        let discontinuity_sequence = self.discontinuity_sequence
+            + self
+                .segments
+                .iter()
+                .take(skip)
+                .filter(|segment| segment.discontinuity)
+                .count() as u64;
+        let target_duration = self.target_duration.as_secs_f64().round().max(1.0) as u64;
+
+        let mut out = String::new();
+        let _ = writeln!(out, "#EXTM3U");
+        let _ = writeln!(
+            out,
+            "#EXT-X-VERSION:{}",
+            if self.settings.part_duration.is_some() {
+                9
+            } else {
+                7
+            }
+        );
+        let _ = writeln!(out, "#EXT-X-TARGETDURATION:{}", target_duration);
+        if let Some(part_duration) = self.settings.part_duration {
+            // Clients should stay at least three parts behind the live edge.
+            let _ = writeln!(
+                out,
+                "#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK={:.3}",
+                (part_duration * 3).as_secs_f64()
+            );
+            let _ = writeln!(
+                out,
+                "#EXT-X-PART-INF:PART-TARGET={:.3}",
+                part_duration.as_secs_f64()
+            );
+        }
+        let _ = writeln!(out, "#EXT-X-MEDIA-SEQUENCE:{}", listed[0].sequence);
+        let _ = writeln!(
+            out,
+            "#EXT-X-DISCONTINUITY-SEQUENCE:{}",
+            discontinuity_sequence
+        );
+        let _ = writeln!(out, "#EXT-X-INDEPENDENT-SEGMENTS");
+        let mut init_id = None;
+        for segment in listed {
+            segment.render_head(&mut out, &mut init_id);
+            let _ = writeln!(out, "#EXTINF:{:.3},", segment.duration.as_secs_f64());
+            let _ = writeln!(out, "{}.m4s", segment.sequence);
+        }
+        // With low-latency HLS, the segment that is being packaged is listed
+        // as far as it goes, followed by a hint for its next part.
+        if let Some(current) = self
+            .current
+            .as_ref()
+            .filter(|_| self.settings.part_duration.is_some())
+        {
+            current.render_head(&mut out, &mut init_id);
+            let _ = writeln!(
+                out,
+                "#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"{}.{}.m4s\"",
+                current.sequence,
+                current.parts.len()
+            );
+        }
+        Some(out)
+    }
+}
+
+impl HlsSegment {
+    /// Tags that come before the segment itself: discontinuity, the
+    /// initialization segment if it changed, and parts.
+    fn render_head(&self, out: &mut String, init_id: &mut Option<u64>) {
+        if self.discontinuity {
+            let _ = writeln!(out, "#EXT-X-DISCONTINUITY");
+        }
+        if *init_id != Some(self.init_id) {
+            let _ = writeln!(out, "#EXT-X-MAP:URI=\"init-{}.mp4\"", self.init_id);
+            *init_id = Some(self.init_id);
+        }
+        for (index, part) in self.parts.iter().enumerate() {
+            let _ = write!(
+                out,
+                "#EXT-X-PART:DURATION={:.3},URI=\"{}.{}.m4s\"",
+                part.duration.as_secs_f64(),
+                self.sequence,
+                index
+            );
+            if part.independent {
+                let _ = write!(out, ",INDEPENDENT=YES");
+            }
+            let _ = writeln!(out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::SystemTime;
+
+    fn settings() -> HlsSettings {
+        HlsSettings {
+            segment_duration: Duration::from_secs(2),
+            playlist_size: 3,
+            part_duration: None,
+        }
+    }
+
+    fn segment(init: &Arc<[u8]>, millis: u64) -> Vec<Segmented> {
+        vec![
+            Segmented::Start {
+                init: Arc::clone(init),
+                started: SystemTime::now(),
+            },
+            Segmented::Fragment {
+                data: vec![1, 2],
+                duration: Duration::from_millis(millis),
+                independent: true,
+            },
+            Segmented::Fragment {
+                data: vec![3],
+                duration: Duration::from_millis(millis),
+                independent: false,
+            },
+            Segmented::End,
+        ]
+    }
+
+    #[test]
+    fn no_playlist_before_first_segment() {
+        let playlist = HlsPlaylist::new(settings());
+        let init: Arc<[u8]> = Arc::from(&b"init"[..]);
+        let mut output = segment(&init, 1000);
+        output.pop();
+        playlist.push(output);
+        assert_eq!(playlist.media_playlist(), None);
+        assert_eq!(playlist.segment(0), None);
+        assert_eq!(playlist.init(0).as_deref(), Some(&b"init"[..]));
+    }
+
+    #[test]
+    fn rolling_window() {
+        let playlist = HlsPlaylist::new(settings());
+        let init: Arc<[u8]> = Arc::from(&b"init"[..]);
+        for _ in 0..5 {
+            playlist.push(segment(&init, 1000));
+        }
+        assert_eq!(
+            playlist.media_playlist().unwrap(),
+            "#EXTM3U\n\
+             #EXT-X-VERSION:7\n\
+             #EXT-X-TARGETDURATION:2\n\
+             #EXT-X-MEDIA-SEQUENCE:2\n\
+             #EXT-X-DISCONTINUITY-SEQUENCE:0\n\
+             #EXT-X-INDEPENDENT-SEGMENTS\n\
+             #EXT-X-MAP:URI=\"init-0.mp4\"\n\
+             #EXTINF:2.000,\n\
+             2.m4s\n\
+             #EXTINF:2.000,\n\
+             3.m4s\n\
+             #EXTINF:2.000,\n\
+             4.m4s\n"
+        );
+        assert_eq!(playlist.segment(4).as_deref(), Some(&[1, 2, 3][..]));
+        // Segments that just dropped off the playlist are still around.
+        assert!(playlist.segment(0).is_some());
+        playlist.push(segment(&init, 1000));
+        assert!(playlist.segment(0).is_none());
+    }
+
+    #[test]
+    fn discontinuity_after_restart() {
+        let playlist = HlsPlaylist::new(settings());
+        let first: Arc<[u8]> = Arc::from(&b"first"[..]);
+        let second: Arc<[u8]> = Arc::from(&b"second"[..]);
+        playlist.push(segment(&first, 1000));
+        playlist.push(segment(&second, 1500));
+        let media_playlist = playlist.media_playlist().unwrap();
+        assert!(media_playlist.contains("#EXT-X-TARGETDURATION:3\n"));
+        assert!(media_playlist.ends_with(
+            "#EXT-X-MAP:URI=\"init-0.mp4\"\n\
+             #EXTINF:2.000,\n\
+             0.m4s\n\
+             #EXT-X-DISCONTINUITY\n\
+             #EXT-X-MAP:URI=\"init-1.mp4\"\n\
+             #EXTINF:3.000,\n\
+             1.m4s\n"
+        ));
+        assert_eq!(playlist.init(1).as_deref(), Some(&b"second"[..]));
+
+        // Once the segment with the discontinuity drops off the playlist, the
+        // discontinuity sequence counts it. The target duration stays the
+        // same, even though the longest segment is gone.
+        for _ in 0..3 {
+            playlist.push(segment(&second, 1000));
+        }
+        let media_playlist = playlist.media_playlist().unwrap();
+        assert!(media_playlist.contains("#EXT-X-DISCONTINUITY-SEQUENCE:1\n"));
+        assert!(media_playlist.contains("#EXT-X-TARGETDURATION:3\n"));
+    }
+
+    #[test]
+    fn low_latency_parts() {
+        let playlist = HlsPlaylist::new(HlsSettings {
+            part_duration: Some(Duration::from_millis(500)),
+            ..settings()
+        });
+        let init: Arc<[u8]> = Arc::from(&b"init"[..]);
+        for _ in 0..4 {
+            playlist.push(segment(&init, 1000));
+        }
+        let mut output = segment(&init, 500);
+        output.truncate(2);
+        playlist.push(output);
+
+        assert_eq!(
+            playlist.media_playlist().unwrap(),
+            "#EXTM3U\n\
+             #EXT-X-VERSION:9\n\
+             #EXT-X-TARGETDURATION:2\n\
+             #EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK=1.500\n\
+             #EXT-X-PART-INF:PART-TARGET=0.500\n\
+             #EXT-X-MEDIA-SEQUENCE:1\n\
+             #EXT-X-DISCONTINUITY-SEQUENCE:0\n\
+             #EXT-X-INDEPENDENT-SEGMENTS\n\
+             #EXT-X-MAP:URI=\"init-0.mp4\"\n\
+             #EXTINF:2.000,\n\
+             1.m4s\n\
+             #EXT-X-PART:DURATION=1.000,URI=\"2.0.m4s\",INDEPENDENT=YES\n\
+             #EXT-X-PART:DURATION=1.000,URI=\"2.1.m4s\"\n\
+             #EXTINF:2.000,\n\
+             2.m4s\n\
+             #EXT-X-PART:DURATION=1.000,URI=\"3.0.m4s\",INDEPENDENT=YES\n\
+             #EXT-X-PART:DURATION=1.000,URI=\"3.1.m4s\"\n\
+             #EXTINF:2.000,\n\
+             3.m4s\n\
+             #EXT-X-PART:DURATION=0.500,URI=\"4.0.m4s\",INDEPENDENT=YES\n\
+             #EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"4.1.m4s\"\n"
+        );
+        assert_eq!(playlist.part(4, 0).as_deref(), Some(&[1, 2][..]));
+        assert_eq!(playlist.part(4, 1), None);
+        // Parts of older segments are gone, but the segments are not.
+        assert_eq!(playlist.part(1, 0), None);
+        assert!(playlist.segment(1).is_some());
+
+        let playlist = playlist.lock();
+        assert_eq!(playlist.contains(3, None), Some(true));
+        assert_eq!(playlist.contains(4, Some(0)), Some(true));
+        assert_eq!(playlist.contains(4, Some(1)), Some(false));
+        assert_eq!(playlist.contains(4, None), Some(false));
+        assert_eq!(playlist.contains(6, None), Some(false));
+        assert_eq!(playlist.contains(7, None), None);
+    }
+}
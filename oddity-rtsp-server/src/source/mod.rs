@@ -1,24 +1,56 @@
+pub mod hls;
+pub mod multicast;
+pub mod packetizer;
+pub mod publish;
+pub mod recorder;
+pub mod segmenter;
 pub mod source_manager;
 
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time;
 
 use tokio::select;
 use tokio::sync::broadcast;
 use tokio::sync::mpsc;
+use tokio::sync::watch;
 use tokio::sync::Mutex;
 use tokio::time::timeout;
 
 use video_rs as video;
+use video_rs::Location;
 
+use crate::media::sdp::Announcement;
 use crate::media::video::reader::StreamReader;
+use crate::media::video::rtp_muxer::RtpMuxerSettings;
 use crate::media::MediaInfo;
 use crate::media::{self, MediaDescriptor};
+use crate::metrics::{Metrics, SourceMetrics};
+use crate::net::connection::ResponseSenderTx;
 use crate::runtime::task_manager::{Task, TaskContext};
 use crate::runtime::Runtime;
+use crate::source::hls::{HlsPackager, HlsPlaylist, HlsSettings};
+use crate::source::multicast::{MulticastGroup, MulticastHandle, MulticastSender};
+use crate::source::packetizer::{GopCache, RtpPacket, SourcePacketizer};
+use crate::source::publish::{PublishClaim, PublishError, SourcePublisher};
+use crate::source::recorder::{RecordSettings, Recorder};
 
 pub enum SourceState {
-    Stopped(SourcePath),
+    Stopped(SourcePath, SourceId),
+}
+
+/// Tells apart the sources that were started for the same path one after
+/// the other, so that a source that stops late does not take the source
+/// that replaced it along.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceId(u64);
+
+impl SourceId {
+    fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
 }
 
 pub type SourceStateTx = mpsc::UnboundedSender<SourceState>;
@@ -27,16 +59,64 @@ pub type SourceStateRx = mpsc::UnboundedReceiver<SourceState>;
 pub type SourceResetTx = broadcast::Sender<media::MediaInfo>;
 pub type SourceResetRx = broadcast::Receiver<media::MediaInfo>;
 
-pub type SourcePacketTx = broadcast::Sender<media::Packet>;
-pub type SourcePacketRx = broadcast::Receiver<media::Packet>;
+pub type SourcePacketTx = broadcast::Sender<SourcePacket>;
+pub type SourcePacketRx = broadcast::Receiver<SourcePacket>;
+
+/// Media packet of a source, along with the wall-clock time at which the
+/// source received it. Sessions use the latter to map RTP timestamps to
+/// wall-clock time in their sender reports.
+#[derive(Clone)]
+pub struct SourcePacket {
+    pub packet: media::Packet,
+    pub received: time::SystemTime,
+}
+
+impl SourcePacket {
+    /// Packet that was received just now.
+    pub fn received_now(packet: media::Packet) -> Self {
+        Self {
+            packet,
+            received: time::SystemTime::now(),
+        }
+    }
+}
+
+/// Snapshot of a source, for reporting.
+pub struct SourceInfo {
+    pub name: String,
+    pub path: SourcePath,
+    pub descriptor: MediaDescriptor,
+    /// Codec of every track, or `None` if the source has no media yet. It
+    /// may still be connecting, or waiting for a publisher.
+    pub tracks: Option<Vec<&'static str>>,
+    pub multicast: Option<MulticastGroup>,
+    /// Whether a client is publishing to the source.
+    pub publishing: bool,
+    /// Whether the source is recorded to disk.
+    pub recording: bool,
+}
+
+pub type SourceRtpTx = broadcast::Sender<RtpPacket>;
+pub type SourceRtpRx = broadcast::Receiver<RtpPacket>;
 
 pub struct Source {
     pub name: String,
-    // pub path: SourcePath,
     pub descriptor: MediaDescriptor,
+    id: SourceId,
+    path: SourcePath,
     reset_tx: SourceResetTx,
     packet_tx: SourcePacketTx,
+    rtp_tx: SourceRtpTx,
+    stream_state_rx: watch::Receiver<Vec<media::StreamState>>,
+    gop_cache: GopCache,
     media_info: Arc<Mutex<Option<MediaInfo>>>,
+    packetizer: SourcePacketizer,
+    muxer_settings: RtpMuxerSettings,
+    multicast: Option<MulticastSender>,
+    recorder: Option<Recorder>,
+    hls: Option<HlsPackager>,
+    claim: Option<PublishClaim>,
+    metrics: Arc<SourceMetrics>,
     worker: Task,
 }
 
@@ -56,14 +136,36 @@ impl Source {
         name: &str,
         path: SourcePath,
         descriptor: MediaDescriptor,
+        muxer_settings: RtpMuxerSettings,
         state_tx: SourceStateTx,
+        metrics: &Metrics,
         runtime: &Runtime,
     ) -> Result<Self, video::Error> {
         let path = normalize_path(path);
+        let id = SourceId::next();
 
-        let (reset_tx, _) = broadcast::channel(Self::MAX_QUEUED_INFO);
-        let (packet_tx, _) = broadcast::channel(Self::MAX_QUEUED_PACKETS);
+        let (reset_tx, reset_rx) = broadcast::channel(Self::MAX_QUEUED_INFO);
+        let (packet_tx, packet_rx) = broadcast::channel(Self::MAX_QUEUED_PACKETS);
+        let (rtp_tx, _) = broadcast::channel(Self::MAX_QUEUED_PACKETS);
+        let (stream_state_tx, stream_state_rx) = watch::channel(Vec::new());
+        let gop_cache = GopCache::default();
         let media_info = Arc::new(Mutex::new(None));
+        let metrics = metrics.source(&path);
+
+        // The packetizer must be subscribed before the source starts so that
+        // it does not miss the first reset, which carries the media info.
+        let packetizer = SourcePacketizer::start(
+            path.clone(),
+            reset_rx,
+            packet_rx,
+            rtp_tx.clone(),
+            stream_state_tx,
+            gop_cache.clone(),
+            metrics.clone(),
+            muxer_settings,
+            runtime,
+        )
+        .await;
 
         tracing::trace!(name, %path, "starting source");
         let worker = runtime
@@ -74,14 +176,17 @@ impl Source {
                 let reset_tx = reset_tx.clone();
                 let packet_tx = packet_tx.clone();
                 let media_info = Arc::clone(&media_info);
+                let metrics = metrics.clone();
                 move |task_context| {
                     Self::run(
+                        id,
                         path,
                         descriptor,
                         state_tx,
                         reset_tx,
                         packet_tx,
                         media_info,
+                        metrics,
                         task_context,
                     )
                 }
@@ -91,16 +196,101 @@ impl Source {
 
         Ok(Self {
             name: name.to_string(),
-            // path,
             descriptor,
+            id,
+            path,
             reset_tx,
             packet_tx,
+            rtp_tx,
+            stream_state_rx,
+            gop_cache,
             media_info,
+            packetizer,
+            muxer_settings,
+            multicast: None,
+            recorder: None,
+            hls: None,
+            claim: None,
+            metrics,
             worker,
         })
     }
 
+    pub fn id(&self) -> SourceId {
+        self.id
+    }
+
+    /// Start delivering the source to the given multicast group. All
+    /// multicast sessions for this source share the same sender.
+    pub async fn start_multicast(
+        &mut self,
+        path: SourcePath,
+        group: MulticastGroup,
+        runtime: &Runtime,
+    ) -> Result<(), io::Error> {
+        let path = normalize_path(path);
+        let source_delegate = self.delegate();
+        let multicast = MulticastSender::start(path, group, source_delegate, runtime).await?;
+        self.multicast = Some(multicast);
+        Ok(())
+    }
+
+    /// Start recording the source to disk.
+    pub async fn start_recording(&mut self, settings: RecordSettings, runtime: &Runtime) {
+        let (reset_rx, packet_rx, media_info) = self.subscribe_packets().await;
+        let recorder = Recorder::start(
+            self.path.clone(),
+            settings,
+            reset_rx,
+            packet_rx,
+            media_info,
+            runtime,
+        )
+        .await;
+        self.recorder = Some(recorder);
+    }
+
+    /// Start packaging the source as HLS.
+    pub async fn start_hls(&mut self, settings: HlsSettings, runtime: &Runtime) {
+        let (reset_rx, packet_rx, media_info) = self.subscribe_packets().await;
+        let packager = HlsPackager::start(
+            self.path.clone(),
+            settings,
+            reset_rx,
+            packet_rx,
+            media_info,
+            runtime,
+        )
+        .await;
+        self.hls = Some(packager);
+    }
+
+    /// Segments of the source packaged as HLS, if it is.
+    pub fn hls_playlist(&self) -> Option<HlsPlaylist> {
+        self.hls.as_ref().map(HlsPackager::playlist)
+    }
+
+    /// Receivers for the media packets of the source, along with the current
+    /// media info. The receivers are subscribed before looking at the media
+    /// info, so that a reset in between is not missed.
+    async fn subscribe_packets(&self) -> (SourceResetRx, SourcePacketRx, Option<MediaInfo>) {
+        let reset_rx = self.reset_tx.subscribe();
+        let packet_rx = self.packet_tx.subscribe();
+        let media_info = self.media_info.lock().await.clone();
+        (reset_rx, packet_rx, media_info)
+    }
+
     pub async fn stop(&mut self) {
+        if let Some(multicast) = self.multicast.as_mut() {
+            multicast.stop().await;
+        }
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.stop().await;
+        }
+        if let Some(hls) = self.hls.as_mut() {
+            hls.stop().await;
+        }
+        self.packetizer.stop().await;
         tracing::trace!("sending stop signal to source");
         self.worker.stop().await;
         tracing::trace!("stopped source");
@@ -108,25 +298,161 @@ impl Source {
 
     pub fn delegate(&mut self) -> SourceDelegate {
         SourceDelegate {
-            reset_rx: self.reset_tx.subscribe(),
-            packet_rx: self.packet_tx.subscribe(),
+            rtp_rx: self.rtp_tx.subscribe(),
+            stream_state_rx: self.stream_state_rx.clone(),
+            gop_cache: self.gop_cache.clone(),
             media_info: Arc::clone(&self.media_info),
+            metrics: self.metrics.clone(),
+            multicast: self.multicast.as_ref().map(MulticastSender::handle),
+            vod: matches!(self.descriptor, MediaDescriptor::Vod(_)).then(|| VodSource {
+                descriptor: self.descriptor.clone(),
+                muxer_settings: self.muxer_settings,
+            }),
+        }
+    }
+
+    pub async fn info(&self) -> SourceInfo {
+        let tracks = self.media_info.lock().await.as_ref().map(|media_info| {
+            media_info
+                .streams
+                .iter()
+                .map(|stream| stream.codec.name())
+                .collect()
+        });
+        SourceInfo {
+            name: self.name.clone(),
+            path: self.path.clone(),
+            descriptor: self.descriptor.clone(),
+            tracks,
+            multicast: self
+                .multicast
+                .as_ref()
+                .map(|multicast| multicast.handle().group.clone()),
+            publishing: self.is_publishing(),
+            recording: self.recorder.is_some(),
+        }
+    }
+
+    /// Whether a client that is still connected publishes to the source.
+    pub fn is_publishing(&self) -> bool {
+        self.claim.as_ref().is_some_and(PublishClaim::is_active)
+    }
+
+    /// Claim the source for publishing on behalf of the connection of
+    /// `owner`. The media info of the source is replaced right away if the
+    /// announcement carries the parameter sets, otherwise it is replaced
+    /// once the publisher learns them from the stream.
+    pub async fn announce(
+        &mut self,
+        announcement: Announcement,
+        owner: &ResponseSenderTx,
+    ) -> Result<(), PublishError> {
+        if !matches!(self.descriptor, MediaDescriptor::Publish) {
+            return Err(PublishError::NotPublishable);
+        }
+        if let Some(claim) = self.claim.as_ref() {
+            if claim.is_held_by_other(owner) {
+                return Err(PublishError::AlreadyPublishing);
+            }
+        }
+
+        let publisher = SourcePublisher::new(
+            self.path.clone(),
+            announcement.clone(),
+            self.reset_tx.clone(),
+            self.packet_tx.clone(),
+            Arc::clone(&self.media_info),
+        );
+        match announcement.media_info() {
+            Some(media_info) => publisher.reset(media_info).await,
+            None => publisher.finish().await,
+        }
+
+        self.claim = Some(PublishClaim::new(owner, announcement));
+        tracing::info!(name = %self.name, path = %self.path, "source claimed by publisher");
+        Ok(())
+    }
+
+    /// Get a publisher for the media that the connection of `owner`
+    /// announced.
+    pub fn publisher(&self, owner: &ResponseSenderTx) -> Result<SourcePublisher, PublishError> {
+        match self.claim.as_ref() {
+            Some(claim) if claim.is_owned_by(owner) => Ok(SourcePublisher::new(
+                self.path.clone(),
+                claim.announcement().clone(),
+                self.reset_tx.clone(),
+                self.packet_tx.clone(),
+                Arc::clone(&self.media_info),
+            )),
+            _ => Err(PublishError::NotAnnounced),
         }
     }
 
     #[allow(clippy::too_many_arguments)]
     async fn run(
+        id: SourceId,
         path: SourcePath,
         descriptor: MediaDescriptor,
         state_tx: SourceStateTx,
         reset_tx: SourceResetTx,
         packet_tx: SourcePacketTx,
         media_info: Arc<Mutex<Option<MediaInfo>>>,
+        metrics: Arc<SourceMetrics>,
         mut task_context: TaskContext,
     ) {
-        let mut outer_stream_reader = match StreamReader::new(&descriptor).await {
+        match descriptor.location() {
+            Some(location) if matches!(descriptor, MediaDescriptor::Vod(_)) => {
+                // Every session reads media played on demand by itself, so the
+                // source only has to describe it.
+                match StreamReader::probe(&descriptor, location).await {
+                    Ok(info) => _ = media_info.lock().await.insert(info),
+                    Err(err) => tracing::error!(%err, %descriptor, "failed to probe media"),
+                }
+                task_context.wait_for_stop().await;
+                tracing::trace!(%path, "stopping source");
+            }
+            Some(location) => {
+                Self::run_reader(
+                    &path,
+                    &descriptor,
+                    location,
+                    reset_tx,
+                    packet_tx,
+                    media_info,
+                    &metrics,
+                    &mut task_context,
+                )
+                .await
+            }
+            None => {
+                // Published media is fed into the source by publishers, so
+                // there is nothing to do here but wait.
+                tracing::trace!(%path, "waiting for publisher");
+                task_context.wait_for_stop().await;
+                tracing::trace!(%path, "stopping source");
+            }
+        }
+
+        let _ = state_tx.send(SourceState::Stopped(path, id));
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn run_reader(
+        path: &SourcePathRef,
+        descriptor: &MediaDescriptor,
+        location: Location,
+        reset_tx: SourceResetTx,
+        packet_tx: SourcePacketTx,
+        media_info: Arc<Mutex<Option<MediaInfo>>>,
+        metrics: &SourceMetrics,
+        task_context: &mut TaskContext,
+    ) {
+        let mut outer_stream_reader = match StreamReader::new(descriptor, location.clone()).await {
             Ok(stream_reader) => {
                 _ = media_info.lock().await.insert(stream_reader.info.clone());
+                // Announce media information so that the packetizer can
+                // initialize its muxer.
+                let _ = reset_tx.send(stream_reader.info.clone());
                 Some(stream_reader)
             }
             Err(err) => {
@@ -143,12 +469,13 @@ impl Source {
                 Some(stream_reader) => stream_reader,
                 None => {
                     'restart: loop {
-                        match StreamReader::new(&descriptor).await {
+                        match StreamReader::new(descriptor, location.clone()).await {
                             Ok(new_stream_reader) => {
                                 // Send reset with new media information to listeners so they can
                                 // reset their muxers and continue playing.
                                 let _ = reset_tx.send(new_stream_reader.info.clone());
 
+                                metrics.restarted();
                                 tracing::info!(%path, "restarted stream");
                                 break new_stream_reader;
                             }
@@ -190,7 +517,7 @@ impl Source {
                     packet = stream_reader.read() => {
                         match packet {
                             Some(Ok(packet)) => {
-                                let _ = packet_tx.send(packet.clone());
+                                let _ = packet_tx.send(SourcePacket::received_now(packet));
                             },
                             Some(Err(err)) => {
                                 tracing::error!(%path, %err, "failed to read video stream");
@@ -219,37 +546,79 @@ impl Source {
             // the next outer loop cycle.
             outer_stream_reader = None;
         }
-
-        let _ = state_tx.send(SourceState::Stopped(path));
     }
 }
 
 pub struct SourceDelegate {
-    reset_rx: SourceResetRx,
-    packet_rx: SourcePacketRx,
+    rtp_rx: SourceRtpRx,
+    stream_state_rx: watch::Receiver<Vec<media::StreamState>>,
+    gop_cache: GopCache,
     media_info: Arc<Mutex<Option<MediaInfo>>>,
+    metrics: Arc<SourceMetrics>,
+    multicast: Option<MulticastHandle>,
+    vod: Option<VodSource>,
+}
+
+/// Media that every session plays on its own. Sessions read and mux the
+/// media themselves instead of receiving the packets of the source.
+#[derive(Clone)]
+pub struct VodSource {
+    pub descriptor: MediaDescriptor,
+    pub muxer_settings: RtpMuxerSettings,
 }
 
 impl SourceDelegate {
     pub async fn media_info(&mut self) -> Option<media::MediaInfo> {
-        const MAX_TRIES: usize = 4;
-        const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+        wait_for_media_info(&self.media_info).await
+    }
 
-        for try_count in 0..MAX_TRIES {
-            if try_count > 0 {
-                tokio::time::sleep(TIMEOUT).await;
-                tracing::trace!(try_count = %try_count + 1, max_tries = MAX_TRIES, "trying again for media info");
-            }
-            if let Some(media_info) = self.media_info.lock().await.as_ref() {
-                return Some(media_info.clone());
-            }
-        }
-        None
+    /// Handle to the multicast sender of the source, if the source is
+    /// delivered over multicast.
+    pub fn multicast(&self) -> Option<&MulticastHandle> {
+        self.multicast.as_ref()
+    }
+
+    /// Media to play on demand, if the source is not shared between
+    /// sessions.
+    pub fn vod(&self) -> Option<&VodSource> {
+        self.vod.as_ref()
     }
 
-    pub fn into_parts(self) -> (SourceResetRx, SourcePacketRx) {
-        (self.reset_rx, self.packet_rx)
+    #[inline]
+    pub fn metrics(&self) -> Arc<SourceMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Packets of the source since the last keyframe, for sessions to
+    /// start playing with.
+    pub fn gop_cache(&self) -> GopCache {
+        self.gop_cache.clone()
+    }
+
+    /// Split the delegate into the receiver for shared RTP packets of the
+    /// source, and the receiver for the RTP sequence number and timestamp
+    /// of the source packetizer.
+    pub fn into_parts(self) -> (SourceRtpRx, watch::Receiver<Vec<media::StreamState>>) {
+        (self.rtp_rx, self.stream_state_rx)
+    }
+}
+
+/// Wait for the source to have media info, which may take a while if the
+/// source is still starting or waiting for a publisher.
+async fn wait_for_media_info(media_info: &Mutex<Option<MediaInfo>>) -> Option<media::MediaInfo> {
+    const MAX_TRIES: usize = 4;
+    const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+    for try_count in 0..MAX_TRIES {
+        if try_count > 0 {
+            tokio::time::sleep(TIMEOUT).await;
+            tracing::trace!(try_count = %try_count + 1, max_tries = MAX_TRIES, "trying again for media info");
+        }
+        if let Some(media_info) = media_info.lock().await.as_ref() {
+            return Some(media_info.clone());
+        }
     }
+    None
 }
 
 pub type SourcePath = String;
@@ -0,0 +1,285 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use tokio::net;
+use tokio::select;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+use tokio::time;
+
+use video_rs as video;
+
+use crate::media;
+use crate::media::rtcp::{self, SenderReporter};
+use crate::metrics::SourceMetrics;
+use crate::runtime::task_manager::{Task, TaskContext};
+use crate::runtime::Runtime;
+use crate::source::{SourceDelegate, SourcePath, SourceRtpRx};
+
+/// Multicast group that a source is delivered to. Every track gets its
+/// own pair of ports: RTP of track `n` goes to `port + 2n` and RTCP goes
+/// to `port + 2n + 1`.
+#[derive(Debug, Clone)]
+pub struct MulticastGroup {
+    pub address: IpAddr,
+    pub port: u16,
+    pub ttl: u32,
+}
+
+impl MulticastGroup {
+    /// RTP port of the given track.
+    #[inline]
+    pub fn rtp_port(&self, track: usize) -> u16 {
+        self.port.saturating_add((track * 2) as u16)
+    }
+
+    /// RTCP port of the given track.
+    #[inline]
+    pub fn rtcp_port(&self, track: usize) -> u16 {
+        self.rtp_port(track).saturating_add(1)
+    }
+
+    #[inline]
+    pub fn rtp_remote(&self, track: usize) -> SocketAddr {
+        (self.address, self.rtp_port(track)).into()
+    }
+
+    #[inline]
+    pub fn rtcp_remote(&self, track: usize) -> SocketAddr {
+        (self.address, self.rtcp_port(track)).into()
+    }
+}
+
+impl fmt::Display for MulticastGroup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}-{} (ttl: {})",
+            self.address,
+            self.rtp_port(0),
+            self.rtcp_port(0),
+            self.ttl
+        )
+    }
+}
+
+/// Sends the packets of a single source to its multicast group. There
+/// is only ever one sender per source, no matter how many multicast
+/// sessions there are. Sessions register themselves as viewers, and the
+/// sender only sends packets while there is at least one.
+pub struct MulticastSender {
+    handle: MulticastHandle,
+    worker: Task,
+}
+
+impl MulticastSender {
+    pub async fn start(
+        path: SourcePath,
+        group: MulticastGroup,
+        source_delegate: SourceDelegate,
+        runtime: &Runtime,
+    ) -> Result<Self, io::Error> {
+        let socket = bind_multicast_socket(&group).await?;
+        let (viewers_tx, viewers_rx) = watch::channel(0);
+        let metrics = source_delegate.metrics();
+        let (source_rtp_rx, stream_state_rx) = source_delegate.into_parts();
+
+        tracing::trace!(%path, %group, "starting multicast sender");
+        let worker = runtime
+            .task()
+            .spawn({
+                let path = path.clone();
+                let group = group.clone();
+                move |task_context| {
+                    Self::run(
+                        path,
+                        group,
+                        socket,
+                        source_rtp_rx,
+                        viewers_rx,
+                        metrics,
+                        task_context,
+                    )
+                }
+            })
+            .await;
+        tracing::trace!(%path, %group, "started multicast sender");
+
+        Ok(Self {
+            handle: MulticastHandle {
+                group,
+                viewers: Arc::new(viewers_tx),
+                stream_state: stream_state_rx,
+            },
+            worker,
+        })
+    }
+
+    pub async fn stop(&mut self) {
+        tracing::trace!("sending stop signal to multicast sender");
+        self.worker.stop().await;
+        tracing::trace!("stopped multicast sender");
+    }
+
+    pub fn handle(&self) -> MulticastHandle {
+        self.handle.clone()
+    }
+
+    async fn run(
+        path: SourcePath,
+        group: MulticastGroup,
+        socket: net::UdpSocket,
+        mut source_rtp_rx: SourceRtpRx,
+        mut viewers_rx: watch::Receiver<usize>,
+        metrics: Arc<SourceMetrics>,
+        mut task_context: TaskContext,
+    ) {
+        // All viewers receive the same RTP streams, so they also share the
+        // sender reports, which are sent to the group like the media.
+        let cname = rtcp::generate_cname();
+        let mut reporters: Vec<SenderReporter> = Vec::new();
+        let mut report_interval = time::interval(rtcp::REPORT_INTERVAL);
+
+        loop {
+            select! {
+                // CANCEL SAFETY: `watch::Receiver::changed` is cancel safe.
+                changed = viewers_rx.changed() => {
+                    if changed.is_err() {
+                        tracing::trace!(%path, "multicast viewers channel closed");
+                        break;
+                    }
+                    let viewers = *viewers_rx.borrow_and_update();
+                    tracing::debug!(%path, %group, viewers, "multicast viewers changed");
+                },
+                // CANCEL SAFETY: `broadcast::Receiver::recv` is cancel safe.
+                packet = source_rtp_rx.recv() => {
+                    let packet = match packet {
+                        Ok(packet) => packet,
+                        Err(RecvError::Lagged(skipped)) => {
+                            tracing::warn!(%path, skipped, "multicast sender lagging behind source");
+                            metrics.lagged();
+                            continue;
+                        },
+                        Err(RecvError::Closed) => {
+                            tracing::error!(%path, "source broken");
+                            break;
+                        },
+                    };
+
+                    // Nobody is watching, so there is no need to send anything.
+                    if *viewers_rx.borrow() == 0 {
+                        continue;
+                    }
+
+                    if reporters.len() <= packet.track {
+                        reporters.resize_with(packet.track + 1, || SenderReporter::new(&cname));
+                    }
+
+                    // All viewers share the RTP stream of the source as-is, so
+                    // unlike unicast sessions there is nothing to rewrite.
+                    for rtp_buf in packet.bufs.iter() {
+                        let sent = match rtp_buf {
+                            video::rtp::RtpBuf::Rtp(payload) => {
+                                reporters[packet.track].sent(payload, packet.clock_rate, packet.received);
+                                socket.send_to(payload, group.rtp_remote(packet.track)).await
+                            },
+                            video::rtp::RtpBuf::Rtcp(payload) => {
+                                socket.send_to(payload, group.rtcp_remote(packet.track)).await
+                            },
+                        };
+                        if let Err(err) = sent {
+                            tracing::warn!(%path, %group, %err, "failed to send to multicast group");
+                        }
+                    }
+                },
+                // CANCEL SAFETY: `Interval::tick` is cancel safe.
+                _ = report_interval.tick() => {
+                    if *viewers_rx.borrow() == 0 {
+                        continue;
+                    }
+                    let now = SystemTime::now();
+                    for (track, reporter) in reporters.iter().enumerate() {
+                        let report = match reporter.report(now) {
+                            Some(report) => report,
+                            None => continue,
+                        };
+                        if let Err(err) = socket.send_to(&report, group.rtcp_remote(track)).await {
+                            tracing::warn!(%path, %group, %err, "failed to send sender report to multicast group");
+                        }
+                    }
+                },
+                // CANCEL SAFETY: `TaskContext::wait_for_stop` is cancel safe.
+                _ = task_context.wait_for_stop() => {
+                    tracing::trace!(%path, "stopping multicast sender");
+                    break;
+                },
+            }
+        }
+    }
+}
+
+/// Handle to the multicast sender of a source that can be passed to
+/// sessions.
+#[derive(Clone)]
+pub struct MulticastHandle {
+    pub group: MulticastGroup,
+    viewers: Arc<watch::Sender<usize>>,
+    stream_state: watch::Receiver<Vec<media::StreamState>>,
+}
+
+impl MulticastHandle {
+    /// Register a new viewer. The sender keeps sending packets to the
+    /// group for as long as at least one viewer is alive.
+    pub fn join(&self) -> MulticastViewer {
+        self.viewers.send_modify(|viewers| *viewers += 1);
+        MulticastViewer {
+            viewers: Arc::clone(&self.viewers),
+        }
+    }
+
+    /// Current RTP sequence number and timestamp of a track of the
+    /// source.
+    pub fn stream_state(&self, track: usize) -> media::StreamState {
+        self.stream_state
+            .borrow()
+            .get(track)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Debug for MulticastHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MulticastHandle {{ group: {} }}", self.group)
+    }
+}
+
+pub struct MulticastViewer {
+    viewers: Arc<watch::Sender<usize>>,
+}
+
+impl Drop for MulticastViewer {
+    fn drop(&mut self) {
+        self.viewers.send_modify(|viewers| *viewers -= 1);
+    }
+}
+
+async fn bind_multicast_socket(group: &MulticastGroup) -> Result<net::UdpSocket, io::Error> {
+    match group.address {
+        IpAddr::V4(_) => {
+            let socket = net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+            socket.set_multicast_ttl_v4(group.ttl)?;
+            Ok(socket)
+        }
+        IpAddr::V6(_) => {
+            let socket = net::UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?;
+            // Tokio only has the IPv4 variant, so the hop limit is set on the
+            // underlying socket.
+            socket2::SockRef::from(&socket).set_multicast_hops_v6(group.ttl)?;
+            Ok(socket)
+        }
+    }
+}
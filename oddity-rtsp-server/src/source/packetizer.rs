@@ -0,0 +1,260 @@
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use tokio::select;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+
+use video_rs as video;
+
+use crate::media;
+use crate::media::video::rtp_muxer::{make_rtp_muxer, RtpMuxer, RtpMuxerSettings};
+use crate::metrics::SourceMetrics;
+use crate::runtime::task_manager::{Task, TaskContext};
+use crate::runtime::Runtime;
+use crate::source::{SourcePacketRx, SourcePath, SourceResetRx, SourceRtpRx, SourceRtpTx};
+
+/// RTP and RTCP buffers produced by muxing a single media packet. The
+/// buffers are shared between all sessions of the source, so sessions
+/// must copy them before changing anything.
+#[derive(Clone)]
+pub struct RtpPacket {
+    /// Index of the track that the packets belong to.
+    pub track: usize,
+    pub bufs: Arc<Vec<video::rtp::RtpBuf>>,
+    /// Whether or not clients can start decoding the track at these
+    /// packets. This is true for video keyframes, and for all audio.
+    pub is_key: bool,
+    /// RTP clock rate of the track.
+    pub clock_rate: u32,
+    /// Wall-clock time at which the source received the media packet.
+    pub received: SystemTime,
+}
+
+impl RtpPacket {
+    /// Sequence number and timestamp of the first RTP packet.
+    pub fn stream_state(&self) -> Option<media::StreamState> {
+        self.bufs.iter().find_map(|rtp_buf| match rtp_buf {
+            video::rtp::RtpBuf::Rtp(payload) if payload.len() >= 8 => Some(media::StreamState {
+                rtp_seq: u16::from_be_bytes([payload[2], payload[3]]),
+                rtp_timestamp: u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]),
+            }),
+            _ => None,
+        })
+    }
+}
+
+/// Muxed packets of the source since the last video keyframe. Sessions
+/// that start playing send these first, so that clients can start
+/// decoding right away instead of waiting for the next keyframe.
+#[derive(Clone, Default)]
+pub struct GopCache {
+    packets: Arc<Mutex<Vec<RtpPacket>>>,
+}
+
+impl GopCache {
+    /// Any more packets than this since the last keyframe means the GOP is
+    /// unreasonably long. Caching stops until the next keyframe.
+    const MAX_PACKETS: usize = 2048;
+
+    /// Packets since the last video keyframe, or nothing if there was no
+    /// keyframe yet. The packets that are queued on `rtp_rx` are dropped,
+    /// so that it continues right after the last returned packet.
+    pub fn replay(&self, rtp_rx: &mut SourceRtpRx) -> Vec<RtpPacket> {
+        let packets = self.lock();
+        *rtp_rx = rtp_rx.resubscribe();
+        packets.clone()
+    }
+
+    /// Cache a packet and broadcast it to all sessions. This happens while
+    /// holding the lock, so that sessions that replay the cache see every
+    /// packet exactly once.
+    fn push_and_send(&self, packet: RtpPacket, is_video: bool, rtp_tx: &SourceRtpTx) {
+        let mut packets = self.lock();
+        if is_video && packet.is_key {
+            packets.clear();
+            packets.push(packet.clone());
+        } else if !packets.is_empty() {
+            if packets.len() < Self::MAX_PACKETS {
+                packets.push(packet.clone());
+            } else {
+                tracing::debug!("gop too long to cache");
+                packets.clear();
+            }
+        }
+        let _ = rtp_tx.send(packet);
+    }
+
+    fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<RtpPacket>> {
+        // The lock is never held across anything that can panic, but a
+        // poisoned cache is still just a cache.
+        self.packets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Muxes the packets of a single source into RTP once, and broadcasts
+/// the result to all sessions of the source. Muxing is relatively
+/// expensive, so doing it once per source instead of once per session
+/// means the cost no longer scales with the number of viewers.
+///
+/// Sessions rewrite the SSRC, sequence number and timestamp of the
+/// shared packets before sending them.
+pub struct SourcePacketizer {
+    worker: Task,
+}
+
+impl SourcePacketizer {
+    #[allow(clippy::too_many_arguments)]
+    pub async fn start(
+        path: SourcePath,
+        reset_rx: SourceResetRx,
+        packet_rx: SourcePacketRx,
+        rtp_tx: SourceRtpTx,
+        stream_state_tx: watch::Sender<Vec<media::StreamState>>,
+        gop_cache: GopCache,
+        metrics: Arc<SourceMetrics>,
+        muxer_settings: RtpMuxerSettings,
+        runtime: &Runtime,
+    ) -> Self {
+        tracing::trace!(%path, "starting source packetizer");
+        let worker = runtime
+            .task()
+            .spawn({
+                let path = path.clone();
+                move |task_context| {
+                    Self::run(
+                        path,
+                        reset_rx,
+                        packet_rx,
+                        rtp_tx,
+                        stream_state_tx,
+                        gop_cache,
+                        metrics,
+                        muxer_settings,
+                        task_context,
+                    )
+                }
+            })
+            .await;
+        tracing::trace!(%path, "started source packetizer");
+
+        Self { worker }
+    }
+
+    pub async fn stop(&mut self) {
+        tracing::trace!("sending stop signal to source packetizer");
+        self.worker.stop().await;
+        tracing::trace!("stopped source packetizer");
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn run(
+        path: SourcePath,
+        mut reset_rx: SourceResetRx,
+        mut packet_rx: SourcePacketRx,
+        rtp_tx: SourceRtpTx,
+        stream_state_tx: watch::Sender<Vec<media::StreamState>>,
+        gop_cache: GopCache,
+        metrics: Arc<SourceMetrics>,
+        muxer_settings: RtpMuxerSettings,
+        mut task_context: TaskContext,
+    ) {
+        // The muxer is initialized as soon as the source has media info,
+        // which it announces over the reset channel.
+        let mut muxer: Option<RtpMuxer> = None;
+        // Whether or not each track is video, by track index.
+        let mut is_video = Vec::new();
+
+        loop {
+            select! {
+                // CANCEL SAFETY: `broadcast::Receiver::recv` is cancel safe.
+                reset = reset_rx.recv() => {
+                    match reset {
+                        Ok(media_info) => {
+                            tracing::trace!(%path, "initializing source muxer");
+                            // Packets of the previous stream are of no use to
+                            // sessions of the new one.
+                            gop_cache.clear();
+                            is_video = media_info
+                                .streams
+                                .iter()
+                                .map(|stream_info| stream_info.codec.is_video())
+                                .collect();
+                            muxer = match make_rtp_muxer(media_info, muxer_settings) {
+                                Ok(new_muxer) => Some(new_muxer),
+                                Err(err) => {
+                                    tracing::error!(%path, %err, "failed to initialize source muxer");
+                                    metrics.mux_error();
+                                    None
+                                },
+                            };
+                        },
+                        Err(RecvError::Lagged(_)) => {
+                            tracing::warn!(%path, "source packetizer missed reset");
+                            metrics.lagged();
+                        },
+                        Err(RecvError::Closed) => {
+                            tracing::trace!(%path, "source stopped");
+                            break;
+                        },
+                    }
+                },
+                // CANCEL SAFETY: `broadcast::Receiver::recv` is cancel safe.
+                packet = packet_rx.recv() => {
+                    let packet = match packet {
+                        Ok(packet) => packet,
+                        Err(RecvError::Lagged(skipped)) => {
+                            tracing::warn!(%path, skipped, "source packetizer lagging behind source");
+                            metrics.lagged();
+                            continue;
+                        },
+                        Err(RecvError::Closed) => {
+                            tracing::trace!(%path, "source stopped");
+                            break;
+                        },
+                    };
+
+                    // Nobody is subscribed, so there is no need to spend any time muxing.
+                    // Video is still muxed to keep the GOP cache warm, so that the first
+                    // session does not have to wait for a keyframe either.
+                    if rtp_tx.receiver_count() == 0 && !is_video.contains(&true) {
+                        continue;
+                    }
+
+                    if let Some(muxer) = muxer.as_mut() {
+                        if let Some(muxed) = muxer.muxed(packet.packet) {
+                            let _ = stream_state_tx.send(muxer.stream_states());
+                            let is_video = is_video.get(muxed.track).copied().unwrap_or_default();
+                            for buf in muxed.bufs.iter() {
+                                match buf {
+                                    video::rtp::RtpBuf::Rtp(buf) | video::rtp::RtpBuf::Rtcp(buf) => {
+                                        metrics.broadcast(buf.len())
+                                    },
+                                }
+                            }
+                            let packet = RtpPacket {
+                                track: muxed.track,
+                                bufs: Arc::new(muxed.bufs),
+                                is_key: muxed.is_key,
+                                clock_rate: muxed.clock_rate,
+                                received: packet.received,
+                            };
+                            gop_cache.push_and_send(packet, is_video, &rtp_tx);
+                        }
+                    }
+                },
+                // CANCEL SAFETY: `TaskContext::wait_for_stop` is cancel safe.
+                _ = task_context.wait_for_stop() => {
+                    tracing::trace!(%path, "stopping source packetizer");
+                    break;
+                },
+            }
+        }
+    }
+}
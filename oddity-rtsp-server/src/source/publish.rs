@@ -0,0 +1,154 @@
+//! Clients publish media to a source with ANNOUNCE and RECORD. The client
+//! first claims the source by announcing the media it is going to send,
+//! and then feeds packets into the source through a [`SourcePublisher`].
+//! Subscribers of the source do not know the difference between published
+//! media and media read by the server itself.
+
+use std::error;
+use std::fmt;
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+
+use crate::media::sdp::Announcement;
+use crate::media::{self, MediaInfo};
+use crate::net::connection::ResponseSenderTx;
+use crate::source::{SourcePacket, SourcePacketTx, SourcePath, SourceResetTx};
+
+/// Claim of a connection on a source that it is going to publish to. The
+/// claim is released when the connection goes away, or when the same
+/// connection announces again.
+pub struct PublishClaim {
+    owner: ResponseSenderTx,
+    announcement: Announcement,
+}
+
+impl PublishClaim {
+    pub fn new(owner: &ResponseSenderTx, announcement: Announcement) -> Self {
+        Self {
+            owner: owner.clone(),
+            announcement,
+        }
+    }
+
+    pub fn is_owned_by(&self, owner: &ResponseSenderTx) -> bool {
+        self.owner.same_channel(owner)
+    }
+
+    /// Whether the connection that holds the claim is still there.
+    pub fn is_active(&self) -> bool {
+        !self.owner.is_closed()
+    }
+
+    /// Whether another live connection holds the claim.
+    pub fn is_held_by_other(&self, owner: &ResponseSenderTx) -> bool {
+        !self.owner.is_closed() && !self.is_owned_by(owner)
+    }
+
+    pub fn announcement(&self) -> &Announcement {
+        &self.announcement
+    }
+}
+
+/// Feeds published media into a source.
+pub struct SourcePublisher {
+    path: SourcePath,
+    announcement: Announcement,
+    reset_tx: SourceResetTx,
+    packet_tx: SourcePacketTx,
+    media_info: Arc<Mutex<Option<MediaInfo>>>,
+}
+
+impl SourcePublisher {
+    pub fn new(
+        path: SourcePath,
+        announcement: Announcement,
+        reset_tx: SourceResetTx,
+        packet_tx: SourcePacketTx,
+        media_info: Arc<Mutex<Option<MediaInfo>>>,
+    ) -> Self {
+        Self {
+            path,
+            announcement,
+            reset_tx,
+            packet_tx,
+            media_info,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn announcement(&self) -> &Announcement {
+        &self.announcement
+    }
+
+    /// Find the announced track that a SETUP request path refers to. The
+    /// control attribute of a track may be relative to the source path or
+    /// an absolute URL. If there is only a single track, the client may
+    /// also set it up with the source path itself.
+    pub fn track(&self, request_path: &str) -> Option<usize> {
+        let rest = request_path.strip_prefix(self.path.as_str())?;
+        if !rest.is_empty() && !rest.starts_with('/') {
+            return None;
+        }
+        let control = rest.trim_start_matches('/');
+
+        let tracks = &self.announcement.tracks;
+        tracks
+            .iter()
+            .position(|track| match track.control.as_deref() {
+                Some(track_control) => {
+                    track_control == control || control_path(track_control) == Some(request_path)
+                }
+                None => false,
+            })
+            .or_else(|| (control.is_empty() && tracks.len() == 1).then_some(0))
+    }
+
+    /// Start publishing media with the given media info. Subscribers of the
+    /// source reset their muxers when they receive it.
+    pub async fn reset(&self, media_info: MediaInfo) {
+        let _ = self.media_info.lock().await.insert(media_info.clone());
+        let _ = self.reset_tx.send(media_info);
+        tracing::trace!(path = %self.path, "publisher reset source");
+    }
+
+    pub fn send(&self, packet: media::Packet) {
+        let _ = self.packet_tx.send(SourcePacket::received_now(packet));
+    }
+
+    /// Stop publishing. The source does not have media info until the next
+    /// publisher starts.
+    pub async fn finish(self) {
+        let _ = self.media_info.lock().await.take();
+        tracing::trace!(path = %self.path, "publisher finished");
+    }
+}
+
+/// Path of an absolute control URL, without trailing slashes.
+fn control_path(control: &str) -> Option<&str> {
+    let (_, rest) = control.split_once("://")?;
+    let path = &rest[rest.find('/')?..];
+    Some(path.trim_end_matches('/'))
+}
+
+#[derive(Debug)]
+pub enum PublishError {
+    NotPublishable,
+    AlreadyPublishing,
+    NotAnnounced,
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PublishError::NotPublishable => write!(f, "source does not accept published media"),
+            PublishError::AlreadyPublishing => write!(f, "another client is publishing"),
+            PublishError::NotAnnounced => write!(f, "media was not announced"),
+        }
+    }
+}
+
+impl error::Error for PublishError {}
@@ -0,0 +1,396 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::select;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::media::MediaInfo;
+use crate::runtime::task_manager::{Task, TaskContext};
+use crate::runtime::Runtime;
+use crate::source::segmenter::{Segmented, Segmenter, SegmenterSettings, Timeline};
+use crate::source::{SourcePacket, SourcePacketRx, SourcePath, SourceResetRx};
+
+/// Extension of recorded segments. Retention only ever touches files
+/// with this extension, whose names start with the prefix of the source.
+const SEGMENT_EXTENSION: &str = "mp4";
+
+/// Length of the UTC start time in segment names, see [`utc_timestamp`].
+const TIMESTAMP_LEN: usize = 20;
+
+/// Longest stretch of media in a single fragment. Fragments are written
+/// to disk at every video keyframe, or after this much media if keyframes
+/// are further apart (or there is no video).
+const MAX_FRAGMENT_DURATION: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordSettings {
+    pub directory: PathBuf,
+    /// Segments are cut at the first keyframe after this much media.
+    pub segment_duration: Duration,
+    /// Number of finished segments to keep in the directory.
+    pub max_segments: Option<usize>,
+    /// Finished segments older than this are deleted.
+    pub max_age: Option<Duration>,
+}
+
+/// Records a source to disk as a series of fragmented MP4 segments.
+/// Every segment starts with a keyframe, so each one plays on its own.
+/// When the source restarts, the current segment is finished and a new
+/// one starts with the new stream.
+pub struct Recorder {
+    worker: Task,
+}
+
+impl Recorder {
+    /// Start recording. The receivers must be subscribed before
+    /// `media_info` is taken from the source, so that no reset goes
+    /// unnoticed in between.
+    pub async fn start(
+        path: SourcePath,
+        settings: RecordSettings,
+        reset_rx: SourceResetRx,
+        packet_rx: SourcePacketRx,
+        media_info: Option<MediaInfo>,
+        runtime: &Runtime,
+    ) -> Self {
+        tracing::trace!(%path, directory = %settings.directory.display(), "starting recorder");
+        let worker = runtime
+            .task()
+            .spawn({
+                let path = path.clone();
+                move |task_context| {
+                    Self::run(
+                        path,
+                        settings,
+                        reset_rx,
+                        packet_rx,
+                        media_info,
+                        task_context,
+                    )
+                }
+            })
+            .await;
+        tracing::trace!(%path, "started recorder");
+
+        Self { worker }
+    }
+
+    pub async fn stop(&mut self) {
+        tracing::trace!("sending stop signal to recorder");
+        self.worker.stop().await;
+        tracing::trace!("stopped recorder");
+    }
+
+    async fn run(
+        path: SourcePath,
+        settings: RecordSettings,
+        mut reset_rx: SourceResetRx,
+        mut packet_rx: SourcePacketRx,
+        media_info: Option<MediaInfo>,
+        mut task_context: TaskContext,
+    ) {
+        let mut recording =
+            media_info.map(|media_info| Recording::new(&path, &media_info, &settings));
+
+        loop {
+            select! {
+                // CANCEL SAFETY: `broadcast::Receiver::recv` is cancel safe.
+                reset = reset_rx.recv() => {
+                    match reset {
+                        Ok(media_info) => {
+                            tracing::trace!(%path, "resetting recorder");
+                            // Segments are never continued across a restart, since the
+                            // stream may have changed entirely.
+                            if let Some(recording) = recording.as_mut() {
+                                recording.finish(&path, &settings).await;
+                            }
+                            recording = Some(Recording::new(&path, &media_info, &settings));
+                        },
+                        Err(RecvError::Lagged(_)) => {
+                            tracing::warn!(%path, "recorder missed reset");
+                        },
+                        Err(RecvError::Closed) => {
+                            tracing::trace!(%path, "source stopped");
+                            break;
+                        },
+                    }
+                },
+                // CANCEL SAFETY: `broadcast::Receiver::recv` is cancel safe.
+                packet = packet_rx.recv() => {
+                    let packet = match packet {
+                        Ok(packet) => packet,
+                        Err(RecvError::Lagged(skipped)) => {
+                            // The samples that are missing may include a keyframe that
+                            // the ones after depend on. What was recorded so far is
+                            // fine, and the next segment starts at the next keyframe.
+                            tracing::warn!(%path, skipped, "recorder lagging behind source");
+                            if let Some(recording) = recording.as_mut() {
+                                recording.finish(&path, &settings).await;
+                            }
+                            continue;
+                        },
+                        Err(RecvError::Closed) => {
+                            tracing::trace!(%path, "source stopped");
+                            break;
+                        },
+                    };
+
+                    if let Some(recording) = recording.as_mut() {
+                        if let Err(err) = recording.push(packet, &settings).await {
+                            tracing::error!(%path, %err, "failed to write recording");
+                            recording.abort();
+                        }
+                    }
+                },
+                // CANCEL SAFETY: `TaskContext::wait_for_stop` is cancel safe.
+                _ = task_context.wait_for_stop() => {
+                    tracing::trace!(%path, "stopping recorder");
+                    break;
+                },
+            }
+        }
+
+        if let Some(recording) = recording.as_mut() {
+            recording.finish(&path, &settings).await;
+        }
+    }
+}
+
+/// Recording of a single stream of the source, which ends when the source
+/// restarts.
+struct Recording {
+    segmenter: Segmenter,
+    /// Start of the names of the segments of the source.
+    prefix: String,
+    /// File of the current segment, if any.
+    file: Option<(fs::File, PathBuf)>,
+}
+
+impl Recording {
+    fn new(path: &str, media_info: &MediaInfo, settings: &RecordSettings) -> Self {
+        Self {
+            prefix: segment_prefix(path),
+            segmenter: Segmenter::new(
+                path,
+                media_info,
+                SegmenterSettings {
+                    segment_duration: settings.segment_duration,
+                    fragment_duration: MAX_FRAGMENT_DURATION,
+                    timeline: Timeline::PerSegment,
+                },
+            ),
+            file: None,
+        }
+    }
+
+    async fn push(&mut self, packet: SourcePacket, settings: &RecordSettings) -> io::Result<()> {
+        let output = self.segmenter.push(packet);
+        self.write(output, settings).await
+    }
+
+    /// Finish the current segment, if any.
+    async fn finish(&mut self, path: &str, settings: &RecordSettings) {
+        let output = self.segmenter.finish();
+        if let Err(err) = self.write(output, settings).await {
+            tracing::error!(%path, %err, "failed to finish recording");
+        }
+    }
+
+    /// Drop the current segment, and start over with a new one at the next
+    /// keyframe.
+    fn abort(&mut self) {
+        self.segmenter.abort();
+        self.file = None;
+    }
+
+    async fn write(&mut self, output: Vec<Segmented>, settings: &RecordSettings) -> io::Result<()> {
+        for segmented in output {
+            match segmented {
+                Segmented::Start { init, started } => {
+                    fs::create_dir_all(&settings.directory).await?;
+                    let path = settings.directory.join(format!(
+                        "{}{}.{}",
+                        self.prefix,
+                        utc_timestamp(started),
+                        SEGMENT_EXTENSION
+                    ));
+                    let mut file = fs::File::create(&path).await?;
+                    file.write_all(&init).await?;
+                    tracing::debug!(path = %path.display(), "started segment");
+                    self.file = Some((file, path));
+                }
+                Segmented::Fragment { data, .. } => {
+                    if let Some((file, _)) = self.file.as_mut() {
+                        file.write_all(&data).await?;
+                    }
+                }
+                Segmented::End => {
+                    if let Some((mut file, path)) = self.file.take() {
+                        file.flush().await?;
+                        tracing::debug!(path = %path.display(), "finished segment");
+                        apply_retention(settings, &self.prefix).await;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Delete the oldest finished segments of a source in the directory, by
+/// count and by age. Segment names start with the prefix of the source
+/// followed by their UTC start time, so sorting them by name sorts them by
+/// age.
+async fn apply_retention(settings: &RecordSettings, prefix: &str) {
+    if settings.max_segments.is_none() && settings.max_age.is_none() {
+        return;
+    }
+    let mut segments = match list_segments(&settings.directory, prefix).await {
+        Ok(segments) => segments,
+        Err(err) => {
+            tracing::error!(%err, directory = %settings.directory.display(), "failed to list segments");
+            return;
+        }
+    };
+    segments.sort();
+
+    let excess = settings
+        .max_segments
+        .map(|max_segments| segments.len().saturating_sub(max_segments))
+        .unwrap_or_default();
+    let now = SystemTime::now();
+    for (index, path) in segments.iter().enumerate() {
+        let expired = match settings.max_age {
+            Some(max_age) => fs::metadata(path)
+                .await
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| now.duration_since(modified).unwrap_or_default() > max_age),
+            None => false,
+        };
+        if index < excess || expired {
+            match fs::remove_file(path).await {
+                Ok(()) => tracing::debug!(path = %path.display(), "deleted segment"),
+                Err(err) => {
+                    tracing::error!(%err, path = %path.display(), "failed to delete segment")
+                }
+            }
+        }
+    }
+}
+
+async fn list_segments(directory: &Path, prefix: &str) -> io::Result<Vec<PathBuf>> {
+    let mut segments = Vec::new();
+    let mut entries = fs::read_dir(directory).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| is_segment_of(name, prefix))
+        {
+            segments.push(entry.path());
+        }
+    }
+    Ok(segments)
+}
+
+/// Start of the names of the segments of the source at `path`. Sources
+/// can share a directory, so the path is in there, encoded such that no
+/// two paths end up with the same prefix.
+fn segment_prefix(path: &str) -> String {
+    let mut prefix = String::new();
+    for byte in path.trim_start_matches('/').bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.') {
+            prefix.push(byte as char);
+        } else {
+            prefix.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    prefix.push('-');
+    prefix
+}
+
+/// Whether `name` is the name of a segment with `prefix`, rather than of a
+/// segment of another source or some other file.
+fn is_segment_of(name: &str, prefix: &str) -> bool {
+    name.strip_prefix(prefix)
+        .and_then(|name| name.strip_suffix(SEGMENT_EXTENSION))
+        .and_then(|name| name.strip_suffix('.'))
+        .is_some_and(|timestamp| {
+            timestamp.len() == TIMESTAMP_LEN
+                && timestamp.ends_with('Z')
+                && timestamp
+                    .bytes()
+                    .all(|byte| byte.is_ascii_digit() || matches!(byte, b'T' | b'.' | b'Z'))
+        })
+}
+
+/// Format a time as UTC, like `20261018T012247.123Z`.
+fn utc_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() as i64;
+    let (days, secs_of_day) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+
+    // Civil date from days since the epoch, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3_600,
+        secs_of_day % 3_600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_utc_timestamp() {
+        assert_eq!(
+            utc_timestamp(UNIX_EPOCH + Duration::from_millis(1_792_286_567_123)),
+            "20261018T012247.123Z"
+        );
+        // Leap day.
+        assert_eq!(
+            utc_timestamp(UNIX_EPOCH + Duration::from_secs(951_868_799)),
+            "20000229T235959.000Z"
+        );
+        assert_eq!(utc_timestamp(UNIX_EPOCH), "19700101T000000.000Z");
+        assert_eq!(utc_timestamp(UNIX_EPOCH).len(), TIMESTAMP_LEN);
+    }
+
+    #[test]
+    fn segment_names_per_source() {
+        assert_eq!(segment_prefix("/cam"), "cam-");
+        assert_eq!(segment_prefix("/cams/front"), "cams%2Ffront-");
+        assert_eq!(segment_prefix("/cams_front"), "cams_front-");
+        assert_eq!(segment_prefix("/a b%"), "a%20b%25-");
+
+        let prefix = segment_prefix("/cam");
+        assert!(is_segment_of("cam-20261018T012247.123Z.mp4", &prefix));
+        // Segment of `/cam-b`, whose prefix starts with the one of `/cam`.
+        assert!(!is_segment_of("cam-b-20261018T012247.123Z.mp4", &prefix));
+        assert!(!is_segment_of("other-20261018T012247.123Z.mp4", &prefix));
+        assert!(!is_segment_of("20261018T012247.123Z.mp4", &prefix));
+        assert!(!is_segment_of("cam-20261018T012247.123Z.mp4.part", &prefix));
+        assert!(!is_segment_of("cam-notes.mp4", &prefix));
+    }
+}
@@ -0,0 +1,368 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use video_rs::ffmpeg::Rational;
+
+use crate::media::mp4::{self, Mp4Track, Sample, TrackFragment};
+use crate::media::MediaInfo;
+use crate::source::SourcePacket;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SegmenterSettings {
+    /// Segments are cut at the first keyframe after this much media.
+    pub segment_duration: Duration,
+    /// Fragments are cut at every video keyframe, and before they get longer
+    /// than this if keyframes are further apart (or there is no video).
+    pub fragment_duration: Duration,
+    pub timeline: Timeline,
+}
+
+/// Where decode times of fragments start counting from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timeline {
+    /// Every segment starts at zero, so that it plays on its own.
+    PerSegment,
+    /// Segments continue where the previous one ended, so that they can be
+    /// played one after the other.
+    Continuous,
+}
+
+/// What the segmenter produced from a packet.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segmented {
+    /// A new segment starts. It must start with the initialization segment,
+    /// which is the same for all segments of the segmenter.
+    Start {
+        init: Arc<[u8]>,
+        /// Wall-clock time at which the source received the first keyframe.
+        started: SystemTime,
+    },
+    /// Fragment (`moof` and `mdat`) of the current segment.
+    Fragment {
+        data: Vec<u8>,
+        duration: Duration,
+        /// Whether the fragment starts with a keyframe, so that clients can
+        /// start decoding at it.
+        independent: bool,
+    },
+    /// The current segment is complete.
+    End,
+}
+
+/// Cuts the packets of a single stream of a source into fragmented MP4
+/// segments that start with a keyframe. A source that restarts needs a
+/// new segmenter, since the stream may have changed entirely.
+pub struct Segmenter {
+    settings: SegmenterSettings,
+    tracks: Vec<SegmenterTrack>,
+    init: Arc<[u8]>,
+    /// Track that decides where segments are cut: the first video track,
+    /// or the first track if there is no video.
+    key_track: Option<usize>,
+    /// Decode time of the first keyframe of the first segment, in the
+    /// timescale of the key track.
+    timeline_origin: Option<i64>,
+    segment: Option<Segment>,
+}
+
+struct SegmenterTrack {
+    stream_index: usize,
+    mp4: Mp4Track,
+    /// Last sample, which is not complete until the next sample arrives
+    /// and its duration is known.
+    pending: Option<PendingSample>,
+    /// Complete samples that go into the next fragment.
+    samples: Vec<Sample>,
+    /// Decode time of the first of `samples`, on the timeline.
+    fragment_start: u64,
+    /// Duration of the last complete sample, to guess the duration of the
+    /// last sample in a segment with.
+    last_duration: u32,
+}
+
+struct PendingSample {
+    data: Vec<u8>,
+    dts: i64,
+    pts: i64,
+    is_sync: bool,
+}
+
+struct Segment {
+    /// Decode time of the first keyframe of the segment, in the timescale
+    /// of the key track.
+    origin: i64,
+    sequence_number: u32,
+    /// Whether the next fragment starts with a keyframe.
+    independent: bool,
+}
+
+impl Segmenter {
+    /// Tracks with codecs that cannot be stored in MP4 are left out.
+    pub fn new(path: &str, media_info: &MediaInfo, settings: SegmenterSettings) -> Self {
+        let tracks = media_info
+            .streams
+            .iter()
+            .filter_map(|stream_info| match Mp4Track::new(&stream_info.codec) {
+                Some(mp4) => Some(SegmenterTrack {
+                    stream_index: stream_info.index,
+                    mp4,
+                    pending: None,
+                    samples: Vec::new(),
+                    fragment_start: 0,
+                    last_duration: 0,
+                }),
+                None => {
+                    tracing::warn!(
+                        %path, codec = stream_info.codec.name(),
+                        "codec cannot be stored in mp4 (skipping track)",
+                    );
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        let init = mp4::init_segment(
+            &tracks
+                .iter()
+                .map(|track| track.mp4.clone())
+                .collect::<Vec<_>>(),
+        );
+        let key_track = tracks
+            .iter()
+            .position(|track| track.mp4.is_video())
+            .or_else(|| (!tracks.is_empty()).then_some(0));
+        Self {
+            settings,
+            tracks,
+            init: init.into(),
+            key_track,
+            timeline_origin: None,
+            segment: None,
+        }
+    }
+
+    pub fn push(&mut self, packet: SourcePacket) -> Vec<Segmented> {
+        let mut output = Vec::new();
+        let SourcePacket { packet, received } = packet;
+        let Some(track) = self
+            .tracks
+            .iter()
+            .position(|track| track.stream_index == packet.stream_index())
+        else {
+            return output;
+        };
+        let timescale = self.tracks[track].mp4.timescale;
+        let time_base = Rational::new(1, timescale as i32);
+        let pts = packet.pts().aligned_with_rational(time_base).into_value();
+        let dts = packet.dts().aligned_with_rational(time_base).into_value();
+        let Some((dts, pts)) = dts.or(pts).zip(pts.or(dts)) else {
+            tracing::trace!("dropping packet without timestamp");
+            return output;
+        };
+        let is_key_track = self.key_track == Some(track);
+        let is_video = self.tracks[track].mp4.is_video();
+        let is_sync = !is_video || packet.is_key();
+
+        if is_key_track && is_sync {
+            let cut = match self.segment.as_ref() {
+                Some(segment) => {
+                    dts - segment.origin >= to_timescale(self.settings.segment_duration, timescale)
+                }
+                None => true,
+            };
+            if cut {
+                self.finish_segment(Some(dts), &mut output);
+                self.start_segment(dts, received, &mut output);
+            }
+        }
+
+        let Some(segment) = self.segment.as_ref() else {
+            // Waiting for the first keyframe.
+            return output;
+        };
+        let key_timescale = self.key_timescale();
+        if dts < rescale(segment.origin, key_timescale, timescale) {
+            // Belongs to the previous segment, which is complete already.
+            return output;
+        }
+        let timeline_origin = self.timeline_origin_in(timescale);
+
+        let segmenter_track = &mut self.tracks[track];
+        segmenter_track.finish_pending(Some(dts), timeline_origin);
+        // The fragment is cut before another sample would make it longer than
+        // the fragment duration.
+        let flush = is_key_track
+            && ((is_video && is_sync)
+                || segmenter_track.buffered() + segmenter_track.last_duration as u64
+                    > to_timescale(self.settings.fragment_duration, timescale) as u64);
+        let (raw, _) = packet.into_inner_parts();
+        segmenter_track.pending = Some(PendingSample {
+            data: segmenter_track
+                .mp4
+                .sample_data(raw.data().unwrap_or_default()),
+            dts,
+            pts,
+            is_sync,
+        });
+
+        if flush {
+            self.push_fragment(&mut output);
+            if let Some(segment) = self.segment.as_mut() {
+                segment.independent = is_sync;
+            }
+        }
+        output
+    }
+
+    /// Complete the current segment, if any. What comes after starts with a
+    /// new segment at the next keyframe.
+    pub fn finish(&mut self) -> Vec<Segmented> {
+        let mut output = Vec::new();
+        self.finish_segment(None, &mut output);
+        output
+    }
+
+    /// Drop the current segment without completing it, for example because
+    /// it could not be stored. What comes after starts with a new segment at
+    /// the next keyframe.
+    pub fn abort(&mut self) {
+        self.segment = None;
+        for track in self.tracks.iter_mut() {
+            track.pending = None;
+            track.samples.clear();
+        }
+    }
+
+    fn start_segment(&mut self, origin: i64, started: SystemTime, output: &mut Vec<Segmented>) {
+        for track in self.tracks.iter_mut() {
+            track.pending = None;
+            track.samples.clear();
+        }
+        if self.timeline_origin.is_none() || self.settings.timeline == Timeline::PerSegment {
+            self.timeline_origin = Some(origin);
+        }
+        self.segment = Some(Segment {
+            origin,
+            sequence_number: 1,
+            independent: true,
+        });
+        output.push(Segmented::Start {
+            init: Arc::clone(&self.init),
+            started,
+        });
+    }
+
+    /// Push what is left of the current segment. `end` is the decode time
+    /// (in the timescale of the key track) at which the next segment starts,
+    /// if known.
+    fn finish_segment(&mut self, end: Option<i64>, output: &mut Vec<Segmented>) {
+        if self.segment.is_none() {
+            return;
+        }
+        for index in 0..self.tracks.len() {
+            let timeline_origin = self.timeline_origin_in(self.tracks[index].mp4.timescale);
+            // Only the key track knows exactly where its last sample ends.
+            let end = end.filter(|_| self.key_track == Some(index));
+            self.tracks[index].finish_pending(end, timeline_origin);
+        }
+        self.push_fragment(output);
+        self.segment = None;
+        output.push(Segmented::End);
+    }
+
+    fn push_fragment(&mut self, output: &mut Vec<Segmented>) {
+        let Some(segment) = self.segment.as_mut() else {
+            return;
+        };
+        let fragments = self
+            .tracks
+            .iter()
+            .enumerate()
+            .filter(|(_, track)| !track.samples.is_empty())
+            .map(|(index, track)| TrackFragment {
+                track: index,
+                base_decode_time: track.fragment_start,
+                samples: &track.samples,
+            })
+            .collect::<Vec<_>>();
+        if fragments.is_empty() {
+            return;
+        }
+        // Fragments of other tracks may start or end a little off, so the key
+        // track decides how long the fragment is.
+        let duration = self
+            .key_track
+            .map(|track| &self.tracks[track])
+            .map(|track| {
+                Duration::from_secs_f64(track.buffered() as f64 / track.mp4.timescale as f64)
+            })
+            .unwrap_or_default();
+        let data = mp4::fragment(segment.sequence_number, &fragments);
+        segment.sequence_number += 1;
+        output.push(Segmented::Fragment {
+            data,
+            duration,
+            independent: segment.independent,
+        });
+
+        for track in self.tracks.iter_mut() {
+            track.samples.clear();
+        }
+    }
+
+    fn key_timescale(&self) -> u32 {
+        self.key_track
+            .map(|track| self.tracks[track].mp4.timescale)
+            .unwrap_or(1)
+    }
+
+    /// Start of the timeline in the given timescale.
+    fn timeline_origin_in(&self, timescale: u32) -> i64 {
+        rescale(
+            self.timeline_origin.unwrap_or_default(),
+            self.key_timescale(),
+            timescale,
+        )
+    }
+}
+
+impl SegmenterTrack {
+    /// Move the pending sample to the samples of the next fragment, now
+    /// that the next sample starts at `next_dts`. Without a next sample,
+    /// the last sample is assumed to be as long as the one before it.
+    fn finish_pending(&mut self, next_dts: Option<i64>, timeline_origin: i64) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        let duration = match next_dts.map(|next_dts| next_dts - pending.dts) {
+            Some(duration) if duration > 0 => duration.min(u32::MAX as i64) as u32,
+            _ => self.last_duration,
+        };
+        self.last_duration = duration;
+        if self.samples.is_empty() {
+            self.fragment_start = (pending.dts - timeline_origin).max(0) as u64;
+        }
+        self.samples.push(Sample {
+            data: pending.data,
+            duration,
+            composition_offset: (pending.pts - pending.dts).clamp(i32::MIN as i64, i32::MAX as i64)
+                as i32,
+            is_sync: pending.is_sync,
+        });
+    }
+
+    /// Duration of the complete samples that wait for the next fragment.
+    fn buffered(&self) -> u64 {
+        self.samples
+            .iter()
+            .map(|sample| sample.duration as u64)
+            .sum()
+    }
+}
+
+fn to_timescale(duration: Duration, timescale: u32) -> i64 {
+    (duration.as_secs_f64() * timescale as f64) as i64
+}
+
+fn rescale(time: i64, from: u32, to: u32) -> i64 {
+    (time as i128 * to as i128 / from.max(1) as i128) as i64
+}
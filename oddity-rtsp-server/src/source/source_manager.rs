@@ -1,57 +1,112 @@
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
 use std::error;
 use std::fmt;
+use std::io;
 use std::sync::Arc;
 
+use futures::stream::{FuturesUnordered, StreamExt};
+
 use tokio::select;
 use tokio::sync::mpsc;
 use tokio::sync::{Mutex, RwLock};
 
 use video_rs::Error as MediaError;
 
-use crate::media::sdp::{self, Sdp, SdpError};
+use crate::media::sdp::{self, Announcement, Sdp, SdpError};
+use crate::media::video::rtp_muxer::RtpMuxerSettings;
 use crate::media::MediaDescriptor;
+use crate::metrics::Metrics;
+use crate::net::connection::ResponseSenderTx;
 use crate::runtime::task_manager::{Task, TaskContext};
 use crate::runtime::Runtime;
+use crate::source::hls::{HlsPlaylist, HlsSettings};
+use crate::source::multicast::MulticastGroup;
+use crate::source::publish::{PublishError, SourcePublisher};
+use crate::source::recorder::RecordSettings;
 use crate::source::{
-    self, Source, SourceDelegate, SourcePath, SourcePathRef, SourceState, SourceStateRx,
-    SourceStateTx,
+    self, Source, SourceDelegate, SourceId, SourceInfo, SourcePath, SourcePathRef, SourceState,
+    SourceStateRx, SourceStateTx,
 };
 
 type SourceShared = Arc<Mutex<Source>>;
 type SourceMap = Arc<RwLock<HashMap<SourcePath, SourceShared>>>;
 
-type SourceDescriptionsCache = Arc<RwLock<HashMap<SourcePath, (std::time::Instant, Sdp)>>>;
+/// Paths of the publish sources that were created when a client announced
+/// media for them, rather than configured.
+type DynamicPaths = Arc<Mutex<HashSet<SourcePath>>>;
+
+type ReleaseTx = mpsc::UnboundedSender<(SourcePath, ResponseSenderTx)>;
+type ReleaseRx = mpsc::UnboundedReceiver<(SourcePath, ResponseSenderTx)>;
+
+/// Clients can publish to paths that are not configured if they are below
+/// `prefix`. A publish source is created for such a path when a client
+/// announces media for it, and removed again when the client goes away.
+/// At most `max_sources` of those exist at a time.
+#[derive(Debug, Clone)]
+pub struct PublishSettings {
+    pub prefix: SourcePath,
+    pub max_sources: usize,
+}
 
 pub struct SourceManager {
     sources: SourceMap,
-    source_descriptions_cache: SourceDescriptionsCache,
+    dynamic_paths: DynamicPaths,
     source_state_tx: SourceStateTx,
+    release_tx: ReleaseTx,
+    muxer_settings: RtpMuxerSettings,
+    hls_settings: Option<HlsSettings>,
+    publish_settings: Option<PublishSettings>,
+    metrics: Arc<Metrics>,
     worker: Task,
     runtime: Arc<Runtime>,
 }
 
 impl SourceManager {
-    pub async fn start(runtime: Arc<Runtime>) -> Self {
+    /// Every source is packaged as HLS if `hls_settings` is set. Clients
+    /// can only publish to configured publish sources, unless
+    /// `publish_settings` is set.
+    pub async fn start(
+        runtime: Arc<Runtime>,
+        muxer_settings: RtpMuxerSettings,
+        hls_settings: Option<HlsSettings>,
+        publish_settings: Option<PublishSettings>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
         let sources = Arc::new(RwLock::new(HashMap::new()));
+        let dynamic_paths = Arc::new(Mutex::new(HashSet::new()));
         let (source_state_tx, source_state_rx) = mpsc::unbounded_channel();
-
-        let source_descriptions_cache = Arc::new(RwLock::new(HashMap::new()));
+        let (release_tx, release_rx) = mpsc::unbounded_channel();
 
         tracing::trace!("starting source manager");
         let worker = runtime
             .task()
             .spawn({
                 let sources = sources.clone();
-                move |task_context| Self::run(sources.clone(), source_state_rx, task_context)
+                let dynamic_paths = dynamic_paths.clone();
+                let metrics = metrics.clone();
+                move |task_context| {
+                    Self::run(
+                        sources,
+                        dynamic_paths,
+                        source_state_rx,
+                        release_rx,
+                        metrics,
+                        task_context,
+                    )
+                }
             })
             .await;
         tracing::trace!("started source manager");
 
         Self {
             sources,
-            source_descriptions_cache,
+            dynamic_paths,
             source_state_tx,
+            release_tx,
+            muxer_settings,
+            hls_settings,
+            publish_settings,
+            metrics,
             worker,
             runtime,
         }
@@ -71,67 +126,187 @@ impl SourceManager {
         name: &str,
         path: SourcePath,
         descriptor: MediaDescriptor,
+        multicast: Option<MulticastGroup>,
+        record: Option<RecordSettings>,
     ) -> Result<(), RegisterSourceError> {
         let path = source::normalize_path(path);
-        let source = Source::start(
-            name,
-            path.clone(),
-            descriptor,
-            self.source_state_tx.clone(),
-            self.runtime.as_ref(),
-        )
-        .await
-        .map_err(RegisterSourceError::Media)?;
+        // The lock is held while the source starts so that nothing is started
+        // for a path that is taken, and no other source takes the path in the
+        // meantime.
+        let mut sources = self.sources.write().await;
+        let entry = match sources.entry(path.clone()) {
+            Entry::Vacant(entry) => entry,
+            Entry::Occupied(_) => {
+                tracing::error!(name, %path, "source with given path already registered");
+                return Err(RegisterSourceError::AlreadyRegistered);
+            }
+        };
 
-        if let Entry::Vacant(entry) = self.sources.write().await.entry(path.clone()) {
-            let _ = entry.insert(Arc::new(Mutex::new(source)));
-            tracing::trace!(name, %path, "registered and started source");
-            tracing::trace!("requesting SDP for source to prime cache");
-        } else {
-            tracing::error!(name, %path, "source with given path already registered");
-            return Err(RegisterSourceError::AlreadyRegistered);
+        let mut source = self
+            .start_source(name, path.clone(), descriptor)
+            .await
+            .map_err(RegisterSourceError::Media)?;
+
+        if let Some(group) = multicast {
+            tracing::trace!(name, %path, %group, "starting multicast for source");
+            if let Err(err) = source
+                .start_multicast(path.clone(), group, self.runtime.as_ref())
+                .await
+            {
+                tracing::error!(name, %path, %err, "failed to start multicast for source");
+                source.stop().await;
+                return Err(RegisterSourceError::Multicast(err));
+            }
         }
 
-        if let Err(err) = self
-            .describe(&path)
-            .await
-            .unwrap()
-            .map_err(RegisterSourceError::Sdp)
-        {
-            tracing::warn!(name, %path, %err, "failed to request SDP to prime cache");
+        if let Some(settings) = record {
+            tracing::trace!(name, %path, directory = %settings.directory.display(), "starting recording for source");
+            source
+                .start_recording(settings, self.runtime.as_ref())
+                .await;
         }
+
+        let _ = entry.insert(Arc::new(Mutex::new(source)));
+        tracing::trace!(name, %path, "registered and started source");
         Ok(())
     }
 
-    pub async fn describe(&self, path: &SourcePathRef) -> Option<Result<Sdp, SdpError>> {
-        self.reap_cache().await;
-        let cached_description = self
-            .source_descriptions_cache
+    /// Stop the source at `path` and forget about it. Sessions that play
+    /// the source end when it stops. Returns `false` if there is no source
+    /// at `path`.
+    pub async fn unregister(&self, path: &SourcePathRef) -> bool {
+        let source = self.sources.write().await.remove(path);
+        Self::forget_dynamic(&self.dynamic_paths, &self.metrics, path).await;
+        if let Some(source) = source {
+            tracing::trace!(%path, "stopping unregistered source");
+            source.lock().await.stop().await;
+            tracing::trace!(%path, "unregistered source");
+            true
+        } else {
+            tracing::trace!(%path, "tried to unregister source that does not exist");
+            false
+        }
+    }
+
+    /// Snapshot of all sources, ordered by path.
+    pub async fn list(&self) -> Vec<SourceInfo> {
+        let sources = self
+            .sources
             .read()
             .await
-            .get(path)
-            .cloned();
-        if let Some((_, description)) = cached_description {
-            tracing::trace!(%path, "pulled SDP from cache");
-            Some(Ok(description))
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut infos = Vec::with_capacity(sources.len());
+        for source in sources {
+            infos.push(source.lock().await.info().await);
+        }
+        infos.sort_by(|a, b| a.path.cmp(&b.path));
+        infos
+    }
+
+    pub async fn describe(&self, path: &SourcePathRef) -> Option<Result<Sdp, SdpError>> {
+        let source = self.sources.read().await.get(path).cloned();
+        if let Some(source) = source {
+            // The source lock is not held while waiting, since it may take a
+            // while before the source has media info.
+            let (source_name, media_info) = {
+                let source = source.lock().await;
+                (source.name.clone(), Arc::clone(&source.media_info))
+            };
+            let description = match source::wait_for_media_info(&media_info).await {
+                Some(media_info) => sdp::create(
+                    &source_name,
+                    &media_info,
+                    self.muxer_settings.packetization_mode,
+                ),
+                None => Err(SdpError::MediaInfoUnavailable),
+            };
+            Some(description)
         } else {
-            let source = self.sources.read().await.get(path).cloned();
-            if let Some(source) = source {
-                let source_name = source.lock().await.name.clone();
-                let source_descriptor = source.lock().await.descriptor.clone();
-                let description = sdp::create(&source_name, &source_descriptor).await;
-                if let Ok(description) = description.as_ref() {
-                    self.source_descriptions_cache.write().await.insert(
-                        path.into(),
-                        (std::time::Instant::now(), description.clone()),
+            tracing::trace!(path, "tried to query SDP for source that does not exist");
+            None
+        }
+    }
+
+    /// Claim the source at `path` for publishing on behalf of the
+    /// connection of `owner`. If there is no source at `path` yet, and the
+    /// path is below the publish prefix, a publish source is created for
+    /// it. That source is removed when its publisher goes away.
+    pub async fn announce(
+        &self,
+        path: &SourcePathRef,
+        announcement: Announcement,
+        owner: &ResponseSenderTx,
+    ) -> Result<(), AnnounceSourceError> {
+        // The lock is held while the source starts so that two clients that
+        // announce at the same time do not both create a source.
+        let (source, is_dynamic) = match self.sources.write().await.entry(path.to_string()) {
+            Entry::Occupied(entry) => (
+                entry.get().clone(),
+                self.dynamic_paths.lock().await.contains(path),
+            ),
+            Entry::Vacant(entry) => {
+                let settings = self
+                    .publish_settings
+                    .as_ref()
+                    .filter(|settings| is_path_within(path, &settings.prefix))
+                    .ok_or(AnnounceSourceError::NotFound)?;
+                let mut dynamic_paths = self.dynamic_paths.lock().await;
+                if dynamic_paths.len() >= settings.max_sources {
+                    tracing::warn!(
+                        %path,
+                        max_sources = settings.max_sources,
+                        "too many published sources"
                     );
-                    tracing::trace!(%path, "cached SDP");
+                    return Err(AnnounceSourceError::TooManySources);
                 }
-                Some(description)
-            } else {
-                tracing::trace!(path, "tried to query SDP for source that does not exist");
-                None
+                let source = self
+                    .start_source(path, path.to_string(), MediaDescriptor::Publish)
+                    .await
+                    .map_err(AnnounceSourceError::Media)?;
+                let _ = dynamic_paths.insert(path.to_string());
+                tracing::trace!(%path, "registered and started publish source");
+                (entry.insert(Arc::new(Mutex::new(source))).clone(), true)
             }
+        };
+
+        source
+            .lock()
+            .await
+            .announce(announcement, owner)
+            .await
+            .map_err(AnnounceSourceError::Publish)?;
+        if is_dynamic {
+            let _ = self.release_tx.send((path.to_string(), owner.clone()));
+        }
+        Ok(())
+    }
+
+    /// Get a publisher for the source that `request_path` points into, if
+    /// the connection of `owner` announced media for it. The request path
+    /// may point to a track of the source.
+    pub async fn publisher(
+        &self,
+        request_path: &str,
+        owner: &ResponseSenderTx,
+    ) -> Option<Result<SourcePublisher, PublishError>> {
+        let source = self
+            .sources
+            .read()
+            .await
+            .iter()
+            .filter(|(path, _)| is_path_within(request_path, path))
+            .max_by_key(|(path, _)| path.len())
+            .map(|(_, source)| source.clone());
+        if let Some(source) = source {
+            Some(source.lock().await.publisher(owner))
+        } else {
+            tracing::trace!(
+                request_path,
+                "tried to publish to source that does not exist"
+            );
+            None
         }
     }
 
@@ -146,19 +321,58 @@ impl SourceManager {
         }
     }
 
+    /// Segments of the source at `path` packaged as HLS, if it is.
+    pub async fn hls(&self, path: &SourcePathRef) -> Option<HlsPlaylist> {
+        let source = self.sources.read().await.get(path).cloned();
+        match source {
+            Some(source) => source.lock().await.hls_playlist(),
+            None => None,
+        }
+    }
+
+    /// Start a source, and package it as HLS if enabled. Media played on
+    /// demand has no shared stream to package.
+    async fn start_source(
+        &self,
+        name: &str,
+        path: SourcePath,
+        descriptor: MediaDescriptor,
+    ) -> Result<Source, MediaError> {
+        let is_vod = matches!(descriptor, MediaDescriptor::Vod(_));
+        let mut source = Source::start(
+            name,
+            path,
+            descriptor,
+            self.muxer_settings,
+            self.source_state_tx.clone(),
+            self.metrics.as_ref(),
+            self.runtime.as_ref(),
+        )
+        .await?;
+        if let Some(settings) = self.hls_settings.filter(|_| !is_vod) {
+            source.start_hls(settings, self.runtime.as_ref()).await;
+        }
+        Ok(source)
+    }
+
     async fn run(
         sources: SourceMap,
+        dynamic_paths: DynamicPaths,
         mut source_state_rx: SourceStateRx,
+        mut release_rx: ReleaseRx,
+        metrics: Arc<Metrics>,
         mut task_context: TaskContext,
     ) {
+        // Paths of dynamic publish sources, once their publisher is gone.
+        let mut released = FuturesUnordered::new();
         loop {
             select! {
                 // CANCEL SAFETY: `mpsc::UnboundedReceiver::recv` is cancel safe.
                 state = source_state_rx.recv() => {
                     match state {
-                        Some(SourceState::Stopped(source_id)) => {
-                            tracing::trace!(%source_id, "source manager: received stopped");
-                            let _ = sources.write().await.remove(&source_id);
+                        Some(SourceState::Stopped(path, id)) => {
+                            tracing::trace!(%path, ?id, "source manager: received stopped");
+                            Self::remove_stopped(&sources, &dynamic_paths, &metrics, &path, id).await;
                         },
                         None => {
                             tracing::error!("source state channel broke unexpectedly");
@@ -166,6 +380,17 @@ impl SourceManager {
                         },
                     }
                 },
+                // CANCEL SAFETY: `mpsc::UnboundedReceiver::recv` is cancel safe.
+                Some((path, owner)) = release_rx.recv() => {
+                    released.push(async move {
+                        owner.closed().await;
+                        path
+                    });
+                },
+                // CANCEL SAFETY: `StreamExt::next` is always cancel safe.
+                Some(path) = released.next(), if !released.is_empty() => {
+                    Self::release(&sources, &dynamic_paths, &metrics, &path).await;
+                },
                 // CANCEL SAFETY: `TaskContext::wait_for_stop` is cancel safe.
                 _ = task_context.wait_for_stop() => {
                     tracing::trace!("stopping source manager");
@@ -175,19 +400,71 @@ impl SourceManager {
         }
     }
 
-    async fn reap_cache(&self) {
-        let now = std::time::Instant::now();
-        self.source_descriptions_cache
-            .write()
-            .await
-            .retain(|path, (timestamp, _)| {
-                let should_reap =
-                    now.duration_since(*timestamp) > std::time::Duration::from_secs(120);
-                if should_reap {
-                    tracing::trace!(%path, "reaping cached SDP");
+    /// Remove the source at `path` now that it stopped by itself. By the
+    /// time the source manager hears of it, the source may have been
+    /// unregistered already, and another source may have taken the path.
+    /// That source is left alone.
+    async fn remove_stopped(
+        sources: &SourceMap,
+        dynamic_paths: &DynamicPaths,
+        metrics: &Metrics,
+        path: &SourcePathRef,
+        id: SourceId,
+    ) {
+        let mut sources = sources.write().await;
+        let is_current = match sources.get(path) {
+            Some(source) => source.lock().await.id() == id,
+            None => false,
+        };
+        if is_current {
+            let _ = sources.remove(path);
+            Self::forget_dynamic(dynamic_paths, metrics, path).await;
+        }
+    }
+
+    /// Remove the dynamic publish source at `path` now that a publisher of
+    /// it went away, unless another client publishes to it by now.
+    async fn release(
+        sources: &SourceMap,
+        dynamic_paths: &DynamicPaths,
+        metrics: &Metrics,
+        path: &SourcePathRef,
+    ) {
+        let source = {
+            let mut sources = sources.write().await;
+            let mut dynamic_paths = dynamic_paths.lock().await;
+            if !dynamic_paths.contains(path) {
+                return;
+            }
+            if let Some(source) = sources.get(path) {
+                if source.lock().await.is_publishing() {
+                    return;
                 }
-                !should_reap
-            });
+            }
+            let _ = dynamic_paths.remove(path);
+            metrics.remove_source(path);
+            sources.remove(path)
+        };
+        if let Some(source) = source {
+            tracing::info!(%path, "publisher went away, removing published source");
+            source.lock().await.stop().await;
+        }
+    }
+
+    /// The source at `path` is gone. If it was a dynamic publish source,
+    /// its counters go with it.
+    async fn forget_dynamic(dynamic_paths: &DynamicPaths, metrics: &Metrics, path: &SourcePathRef) {
+        if dynamic_paths.lock().await.remove(path) {
+            metrics.remove_source(path);
+        }
+    }
+}
+
+/// Whether `request_path` is `path` itself or a path below it.
+pub fn is_path_within(request_path: &str, path: &SourcePathRef) -> bool {
+    match request_path.strip_prefix(path) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
     }
 }
 
@@ -195,7 +472,7 @@ impl SourceManager {
 pub enum RegisterSourceError {
     AlreadyRegistered,
     Media(MediaError),
-    Sdp(SdpError),
+    Multicast(io::Error),
 }
 
 impl fmt::Display for RegisterSourceError {
@@ -203,9 +480,32 @@ impl fmt::Display for RegisterSourceError {
         match self {
             RegisterSourceError::AlreadyRegistered => write!(f, "already registered"),
             RegisterSourceError::Media(err) => write!(f, "media error: {}", err),
-            RegisterSourceError::Sdp(err) => write!(f, "sdp error: {}", err),
+            RegisterSourceError::Multicast(err) => write!(f, "multicast error: {}", err),
         }
     }
 }
 
 impl error::Error for RegisterSourceError {}
+
+#[derive(Debug)]
+pub enum AnnounceSourceError {
+    /// There is no source at the path, and clients cannot create one there.
+    NotFound,
+    /// Clients already publish to as many new paths as allowed.
+    TooManySources,
+    Media(MediaError),
+    Publish(PublishError),
+}
+
+impl fmt::Display for AnnounceSourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AnnounceSourceError::NotFound => write!(f, "not found"),
+            AnnounceSourceError::TooManySources => write!(f, "too many published sources"),
+            AnnounceSourceError::Media(err) => write!(f, "media error: {}", err),
+            AnnounceSourceError::Publish(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for AnnounceSourceError {}
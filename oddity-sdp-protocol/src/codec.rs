@@ -10,6 +10,9 @@ pub trait MediaAttributes {
 
 pub enum CodecInfo<'params> {
     H264(H264CodecParameters<'params>),
+    Aac(AacCodecParameters<'params>),
+    Opus(OpusCodecParameters),
+    G711(G711CodecParameters),
 }
 
 impl<'params> CodecInfo<'params> {
@@ -24,6 +27,42 @@ impl<'params> CodecInfo<'params> {
             packetization_mode,
         })
     }
+
+    /// AAC in the `AAC-hbr` mode of RFC 3640. `config` is the
+    /// `AudioSpecificConfig` of the stream.
+    pub fn aac(sample_rate: u32, channels: u32, config: &'params [u8]) -> Self {
+        Self::Aac(AacCodecParameters {
+            sample_rate,
+            channels,
+            config,
+        })
+    }
+
+    pub fn opus(channels: u32) -> Self {
+        Self::Opus(OpusCodecParameters { channels })
+    }
+
+    pub fn g711(law: G711Law, sample_rate: u32, channels: u32) -> Self {
+        Self::G711(G711CodecParameters {
+            law,
+            sample_rate,
+            channels,
+        })
+    }
+
+    /// RTP payload type of the codec. G.711 at 8 kHz mono has a static
+    /// payload type (RFC 3551 Section 6), everything else is dynamic.
+    pub fn payload_type(&self) -> usize {
+        match self {
+            CodecInfo::G711(params) if params.sample_rate == 8000 && params.channels == 1 => {
+                match params.law {
+                    G711Law::MuLaw => FMT_RTP_PAYLOAD_PCMU,
+                    G711Law::ALaw => FMT_RTP_PAYLOAD_PCMA,
+                }
+            }
+            _ => FMT_RTP_PAYLOAD_DYNAMIC,
+        }
+    }
 }
 
 pub struct H264CodecParameters<'params> {
@@ -32,32 +71,93 @@ pub struct H264CodecParameters<'params> {
     packetization_mode: usize,
 }
 
+pub struct AacCodecParameters<'params> {
+    sample_rate: u32,
+    channels: u32,
+    config: &'params [u8],
+}
+
+pub struct OpusCodecParameters {
+    channels: u32,
+}
+
+pub struct G711CodecParameters {
+    law: G711Law,
+    sample_rate: u32,
+    channels: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum G711Law {
+    MuLaw,
+    ALaw,
+}
+
+/// Static payload type of G.711 mu-law (RFC 3551 Section 6).
+const FMT_RTP_PAYLOAD_PCMU: usize = 0;
+/// Static payload type of G.711 A-law (RFC 3551 Section 6).
+const FMT_RTP_PAYLOAD_PCMA: usize = 8;
+
 impl MediaAttributes for CodecInfo<'_> {
     fn media_attributes(&self) -> Vec<Tag> {
+        let payload_type = self.payload_type();
         match self {
             CodecInfo::H264(params) => vec![
                 h264_rtpmap(),
                 h264_fmtp(params.packetization_mode, params.sps, params.pps),
             ],
+            CodecInfo::Aac(params) => vec![
+                rtpmap(
+                    payload_type,
+                    &format!("MPEG4-GENERIC/{}/{}", params.sample_rate, params.channels),
+                ),
+                aac_fmtp(params.config),
+            ],
+            CodecInfo::Opus(params) => vec![
+                // Opus is always signaled with two channels, and the clock
+                // rate is always 48 kHz (RFC 7587 Section 7).
+                rtpmap(payload_type, "opus/48000/2"),
+                Tag::Value(
+                    "fmtp".to_string(),
+                    format!(
+                        "{} sprop-stereo={}",
+                        payload_type,
+                        u32::from(params.channels > 1)
+                    ),
+                ),
+            ],
+            CodecInfo::G711(params) => {
+                let encoding_name = match params.law {
+                    G711Law::MuLaw => "PCMU",
+                    G711Law::ALaw => "PCMA",
+                };
+                let encoding = if params.channels > 1 {
+                    format!(
+                        "{}/{}/{}",
+                        encoding_name, params.sample_rate, params.channels
+                    )
+                } else {
+                    format!("{}/{}", encoding_name, params.sample_rate)
+                };
+                vec![rtpmap(payload_type, &encoding)]
+            }
         }
     }
 }
 
-fn h264_rtpmap() -> Tag {
+fn rtpmap(payload_type: usize, encoding: &str) -> Tag {
     Tag::Value(
         "rtpmap".to_string(),
-        format!("{} H264/90000", FMT_RTP_PAYLOAD_DYNAMIC),
+        format!("{} {}", payload_type, encoding),
     )
 }
 
+fn h264_rtpmap() -> Tag {
+    rtpmap(FMT_RTP_PAYLOAD_DYNAMIC, "H264/90000")
+}
+
 fn h264_fmtp(packetization_mode: usize, sps: &[u8], pps: &[&[u8]]) -> Tag {
-    let profile_level_id_bytes = &sps[1..4];
-    let profile_level_id = profile_level_id_bytes
-        .iter()
-        .fold(String::new(), |mut output, b| {
-            let _ = write!(output, "{b:02X}");
-            output
-        });
+    let profile_level_id = hex(&sps[1..4]);
 
     let mut parameter_sets = Vec::with_capacity(1 + pps.len());
     parameter_sets.push(base64::engine::general_purpose::STANDARD.encode(sps));
@@ -75,3 +175,24 @@ fn h264_fmtp(packetization_mode: usize, sps: &[u8], pps: &[&[u8]]) -> Tag {
         ),
     )
 }
+
+/// Parameters of the `AAC-hbr` mode: every AU header has a 13-bit size
+/// and a 3-bit index (RFC 3640 Section 3.3.6).
+fn aac_fmtp(config: &[u8]) -> Tag {
+    Tag::Value(
+        "fmtp".to_string(),
+        format!(
+            "{} streamtype=5; profile-level-id=1; mode=AAC-hbr; sizelength=13; \
+             indexlength=3; indexdeltalength=3; config={}",
+            FMT_RTP_PAYLOAD_DYNAMIC,
+            hex(config),
+        ),
+    )
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut output, b| {
+        let _ = write!(output, "{b:02X}");
+        output
+    })
+}
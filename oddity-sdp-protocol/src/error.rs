@@ -0,0 +1,51 @@
+use std::error;
+use std::fmt;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// A line is not of the form `<type>=<value>`.
+    LineMalformed { line: String },
+    /// A line that is required in every session description is missing.
+    LineMissing { kind: char },
+    /// The version is not `0`, which is the only version there is.
+    VersionUnknown { version: String },
+    /// The origin line does not have all six fields.
+    OriginMalformed { line: String },
+    /// The connection line does not have all three fields.
+    ConnectionMalformed { line: String },
+    /// The timing line does not consist of two integers.
+    TimingMalformed { line: String },
+    /// The media line does not have a media type, port, protocol and at
+    /// least one format.
+    MediaMalformed { line: String },
+    /// The network type is not `IN`.
+    NetworkTypeUnknown { value: String },
+    /// The address type is neither `IP4` nor `IP6`.
+    AddressTypeUnknown { value: String },
+    /// The media type is not one of the media types defined in RFC 8866.
+    KindUnknown { value: String },
+    /// The transport protocol is not supported.
+    ProtocolUnknown { value: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::LineMalformed { line } => write!(f, "line malformed: {line}"),
+            Error::LineMissing { kind } => write!(f, "line missing: {kind}="),
+            Error::VersionUnknown { version } => write!(f, "version unknown: {version}"),
+            Error::OriginMalformed { line } => write!(f, "origin malformed: {line}"),
+            Error::ConnectionMalformed { line } => write!(f, "connection malformed: {line}"),
+            Error::TimingMalformed { line } => write!(f, "timing malformed: {line}"),
+            Error::MediaMalformed { line } => write!(f, "media malformed: {line}"),
+            Error::NetworkTypeUnknown { value } => write!(f, "network type unknown: {value}"),
+            Error::AddressTypeUnknown { value } => write!(f, "address type unknown: {value}"),
+            Error::KindUnknown { value } => write!(f, "media type unknown: {value}"),
+            Error::ProtocolUnknown { value } => write!(f, "protocol unknown: {value}"),
+        }
+    }
+}
+
+impl error::Error for Error {}
@@ -1,10 +1,16 @@
 mod codec;
+mod error;
 mod fmt;
 mod ip;
+mod parse;
 mod sdp;
 mod time;
 mod timing;
 
-pub use codec::CodecInfo;
-pub use sdp::{AddressType, Direction, Kind, NetworkType, Protocol, Sdp, Tag, Timing, Version};
+pub use codec::{CodecInfo, G711Law};
+pub use error::{Error, Result};
+pub use fmt::FMT_RTP_PAYLOAD_DYNAMIC;
+pub use sdp::{
+    AddressType, Direction, Kind, Media, NetworkType, Protocol, Sdp, Tag, Timing, Version,
+};
 pub use timing::TimeRange;
@@ -0,0 +1,393 @@
+use std::str::FromStr;
+
+use super::{
+    error::{Error, Result},
+    sdp::{AddressType, Kind, Media, NetworkType, Protocol, Sdp, Tag, Version},
+};
+
+impl FromStr for Sdp {
+    type Err = Error;
+
+    /// Parse a session description (RFC 8866). Lines that the server has
+    /// no use for (such as bandwidth and repeat times) are skipped, and
+    /// so are media-level information and connection lines.
+    fn from_str(s: &str) -> Result<Sdp> {
+        let mut version = None;
+        let mut origin = None;
+        let mut session_name = None;
+        let mut session_description = None;
+        let mut connection = None;
+        let mut timing = None;
+        let mut tags = Vec::new();
+        let mut media: Vec<Media> = Vec::new();
+
+        for line in s.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let (kind, value) = match line.split_once('=') {
+                Some((kind, value)) if kind.len() == 1 => (kind, value),
+                _ => {
+                    return Err(Error::LineMalformed {
+                        line: line.to_string(),
+                    })
+                }
+            };
+
+            match (kind, media.last_mut()) {
+                ("v", _) => version = Some(value.parse()?),
+                ("o", _) => origin = Some(parse_origin(line, value)?),
+                ("s", _) => session_name = Some(value.to_string()),
+                ("i", None) => session_description = Some(value.to_string()),
+                ("c", None) => connection = Some(parse_connection(line, value)?),
+                ("t", _) if timing.is_none() => timing = Some(parse_timing(line, value)?),
+                ("a", None) => tags.push(value.parse()?),
+                ("a", Some(media)) => media.tags.push(value.parse()?),
+                ("m", _) => media.push(parse_media(line, value)?),
+                _ => {}
+            }
+        }
+
+        let (
+            origin_username,
+            origin_session_id,
+            origin_session_version,
+            origin_network_type,
+            origin_address_type,
+            origin_unicast_address,
+        ) = origin.ok_or(Error::LineMissing { kind: 'o' })?;
+        // The connection line may be left out if every media description
+        // has its own, but those are not kept.
+        let (connection_network_type, connection_address_type, connection_address) = connection
+            .unwrap_or((
+                NetworkType::Internet,
+                AddressType::IpV4,
+                "0.0.0.0".to_string(),
+            ));
+
+        Ok(Sdp {
+            version: version.ok_or(Error::LineMissing { kind: 'v' })?,
+            origin_username,
+            origin_session_id,
+            origin_session_version,
+            origin_network_type,
+            origin_address_type,
+            origin_unicast_address,
+            session_name: session_name.ok_or(Error::LineMissing { kind: 's' })?,
+            session_description,
+            connection_network_type,
+            connection_address_type,
+            connection_address,
+            timing: timing.unwrap_or((0, 0)),
+            tags,
+            media,
+        })
+    }
+}
+
+type Origin = (String, String, String, NetworkType, AddressType, String);
+
+fn parse_origin(line: &str, value: &str) -> Result<Origin> {
+    let malformed = || Error::OriginMalformed {
+        line: line.to_string(),
+    };
+    let parts = value.split_whitespace().collect::<Vec<_>>();
+    match parts.as_slice() {
+        [username, session_id, session_version, network_type, address_type, unicast_address] => {
+            Ok((
+                username.to_string(),
+                session_id.to_string(),
+                session_version.to_string(),
+                network_type.parse()?,
+                address_type.parse()?,
+                unicast_address.to_string(),
+            ))
+        }
+        _ => Err(malformed()),
+    }
+}
+
+fn parse_connection(line: &str, value: &str) -> Result<(NetworkType, AddressType, String)> {
+    let parts = value.split_whitespace().collect::<Vec<_>>();
+    match parts.as_slice() {
+        [network_type, address_type, address] => Ok((
+            network_type.parse()?,
+            address_type.parse()?,
+            address.to_string(),
+        )),
+        _ => Err(Error::ConnectionMalformed {
+            line: line.to_string(),
+        }),
+    }
+}
+
+fn parse_timing(line: &str, value: &str) -> Result<(u64, u64)> {
+    let malformed = || Error::TimingMalformed {
+        line: line.to_string(),
+    };
+    let (start, stop) = value.split_once(' ').ok_or_else(malformed)?;
+    Ok((
+        start.trim().parse().map_err(|_| malformed())?,
+        stop.trim().parse().map_err(|_| malformed())?,
+    ))
+}
+
+fn parse_media(line: &str, value: &str) -> Result<Media> {
+    let malformed = || Error::MediaMalformed {
+        line: line.to_string(),
+    };
+    let mut parts = value.split_whitespace();
+    let kind = parts.next().ok_or_else(malformed)?.parse()?;
+    // The port may be followed by the number of ports, which is only
+    // relevant for layered encodings.
+    let port = parts
+        .next()
+        .and_then(|port| port.split('/').next())
+        .and_then(|port| port.parse().ok())
+        .ok_or_else(malformed)?;
+    let protocol = parts.next().ok_or_else(malformed)?.parse()?;
+    // Only the first format is kept. Senders use a single format per media
+    // description in practice, and offers that list more (such as those of
+    // browsers) have an `rtpmap` attribute for each format.
+    let format = parts
+        .next()
+        .and_then(|format| format.parse().ok())
+        .ok_or_else(malformed)?;
+
+    Ok(Media {
+        kind,
+        port,
+        protocol,
+        format,
+        tags: Vec::new(),
+    })
+}
+
+impl FromStr for Version {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "0" => Ok(Version::V0),
+            _ => Err(Error::VersionUnknown {
+                version: s.to_string(),
+            }),
+        }
+    }
+}
+
+impl FromStr for NetworkType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "IN" => Ok(NetworkType::Internet),
+            _ => Err(Error::NetworkTypeUnknown {
+                value: s.to_string(),
+            }),
+        }
+    }
+}
+
+impl FromStr for AddressType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "IP4" => Ok(AddressType::IpV4),
+            "IP6" => Ok(AddressType::IpV6),
+            _ => Err(Error::AddressTypeUnknown {
+                value: s.to_string(),
+            }),
+        }
+    }
+}
+
+impl FromStr for Kind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "video" => Ok(Kind::Video),
+            "audio" => Ok(Kind::Audio),
+            "text" => Ok(Kind::Text),
+            "application" => Ok(Kind::Application),
+            "message" => Ok(Kind::Message),
+            _ => Err(Error::KindUnknown {
+                value: s.to_string(),
+            }),
+        }
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "RTP/AVP" => Ok(Protocol::RtpAvp),
+            "RTP/SAVP" => Ok(Protocol::RtpSAvp),
+            "UDP/TLS/RTP/SAVPF" => Ok(Protocol::UdpTlsRtpSAvpf),
+            _ => Err(Error::ProtocolUnknown {
+                value: s.to_string(),
+            }),
+        }
+    }
+}
+
+impl FromStr for Tag {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.split_once(':') {
+            Some((variable, value)) => Tag::Value(variable.to_string(), value.to_string()),
+            None => Tag::Property(s.to_string()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{CodecInfo, Direction, G711Law, TimeRange};
+
+    const EXAMPLE_ANNOUNCE_SDP: &str = "v=0\r\n\
+o=- 0 0 IN IP4 127.0.0.1\r\n\
+s=No Name\r\n\
+c=IN IP4 192.168.1.10\r\n\
+t=0 0\r\n\
+a=tool:libavformat 60.16.100\r\n\
+m=video 0 RTP/AVP 96\r\n\
+b=AS:2000\r\n\
+a=rtpmap:96 H264/90000\r\n\
+a=fmtp:96 packetization-mode=1; sprop-parameter-sets=Z0LAH9oB,aM48gA==; profile-level-id=42C01F\r\n\
+a=control:streamid=0\r\n\
+m=audio 0 RTP/AVP 97\r\n\
+a=rtpmap:97 MPEG4-GENERIC/48000/2\r\n\
+a=control:streamid=1\r\n";
+
+    #[test]
+    fn parse_announce() {
+        let sdp = EXAMPLE_ANNOUNCE_SDP.parse::<Sdp>().unwrap();
+        assert_eq!(sdp.origin_unicast_address, "127.0.0.1");
+        assert_eq!(sdp.session_name, "No Name");
+        assert_eq!(sdp.connection_address, "192.168.1.10");
+        assert_eq!(sdp.timing, (0, 0));
+        assert_eq!(sdp.attribute("tool"), Some("libavformat 60.16.100"));
+        assert_eq!(sdp.media.len(), 2);
+
+        let video = &sdp.media[0];
+        assert!(matches!(video.kind, Kind::Video));
+        assert!(matches!(video.protocol, Protocol::RtpAvp));
+        assert_eq!(video.format, 96);
+        assert_eq!(video.attribute("rtpmap"), Some("96 H264/90000"));
+        assert_eq!(video.attribute("control"), Some("streamid=0"));
+        assert!(video.attribute("fmtp").is_some());
+
+        let audio = &sdp.media[1];
+        assert!(matches!(audio.kind, Kind::Audio));
+        assert_eq!(audio.format, 97);
+        assert_eq!(audio.attribute("control"), Some("streamid=1"));
+    }
+
+    #[test]
+    fn parse_generated() {
+        let sdp = Sdp::new(
+            [0, 0, 0, 0].into(),
+            "Example".to_string(),
+            [0, 0, 0, 0].into(),
+            TimeRange::Live,
+        )
+        .with_media(
+            Kind::Video,
+            0,
+            Protocol::RtpAvp,
+            CodecInfo::h264(&[0x67, 0x42, 0xc0, 0x1f], &[&[0x68, 0xce]], 1),
+            Direction::ReceiveOnly,
+        )
+        .with_media_tag(Tag::Value("control".to_string(), "trackID=0".to_string()))
+        .with_media(
+            Kind::Audio,
+            0,
+            Protocol::RtpAvp,
+            CodecInfo::aac(48000, 2, &[0x11, 0x90]),
+            Direction::ReceiveOnly,
+        )
+        .with_media_tag(Tag::Value("control".to_string(), "trackID=1".to_string()))
+        .with_media(
+            Kind::Audio,
+            0,
+            Protocol::RtpAvp,
+            CodecInfo::g711(G711Law::ALaw, 8000, 1),
+            Direction::ReceiveOnly,
+        )
+        .with_media_tag(Tag::Value("control".to_string(), "trackID=2".to_string()));
+        let parsed = sdp.to_string().parse::<Sdp>().unwrap();
+        assert_eq!(parsed.to_string(), sdp.to_string());
+
+        assert_eq!(parsed.media[1].format, 96);
+        assert_eq!(
+            parsed.media[1].attribute("rtpmap"),
+            Some("96 MPEG4-GENERIC/48000/2"),
+        );
+        assert_eq!(parsed.media[1].attribute("control"), Some("trackID=1"));
+        assert_eq!(parsed.media[2].format, 8);
+        assert_eq!(parsed.media[2].attribute("rtpmap"), Some("8 PCMA/8000"));
+    }
+
+    #[test]
+    fn parse_webrtc_offer() {
+        let sdp = "v=0\r\n\
+o=- 4611731400430051336 2 IN IP4 127.0.0.1\r\n\
+s=-\r\n\
+t=0 0\r\n\
+a=group:BUNDLE 0\r\n\
+m=video 9 UDP/TLS/RTP/SAVPF 96 102\r\n\
+c=IN IP4 0.0.0.0\r\n\
+a=ice-ufrag:EsAw\r\n\
+a=fingerprint:sha-256 0F:74:31:25:CB:A2:13:EC\r\n\
+a=setup:actpass\r\n\
+a=mid:0\r\n\
+a=recvonly\r\n\
+a=rtcp-mux\r\n\
+a=rtpmap:96 VP8/90000\r\n\
+a=rtpmap:102 H264/90000\r\n\
+a=fmtp:102 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42001f\r\n"
+            .parse::<Sdp>()
+            .unwrap();
+        let video = &sdp.media[0];
+        assert!(matches!(video.protocol, Protocol::UdpTlsRtpSAvpf));
+        assert_eq!(video.format, 96);
+        assert_eq!(
+            video.attributes("rtpmap").collect::<Vec<_>>(),
+            ["96 VP8/90000", "102 H264/90000"],
+        );
+        assert_eq!(video.attribute("mid"), Some("0"));
+        assert_eq!(
+            video.attribute("fingerprint"),
+            Some("sha-256 0F:74:31:25:CB:A2:13:EC")
+        );
+    }
+
+    #[test]
+    fn parse_missing_origin() {
+        assert_eq!(
+            "v=0\r\ns=-\r\nt=0 0\r\n".parse::<Sdp>().unwrap_err(),
+            Error::LineMissing { kind: 'o' },
+        );
+    }
+
+    #[test]
+    fn parse_malformed_media() {
+        assert!(matches!(
+            "v=0\r\no=- 0 0 IN IP4 127.0.0.1\r\ns=-\r\nm=video 0\r\n".parse::<Sdp>(),
+            Err(Error::MediaMalformed { .. }),
+        ));
+    }
+
+    #[test]
+    fn parse_unknown_protocol() {
+        assert!(matches!(
+            "v=0\r\no=- 0 0 IN IP4 127.0.0.1\r\ns=-\r\nm=message 9 TCP/MSRP *\r\n".parse::<Sdp>(),
+            Err(Error::ProtocolUnknown { .. }),
+        ));
+    }
+}
@@ -3,7 +3,6 @@ use std::net::IpAddr;
 
 use super::{
     codec::{CodecInfo, MediaAttributes},
-    fmt::FMT_RTP_PAYLOAD_DYNAMIC,
     ip::ip_addr_type,
     time::unix_epoch_timestamp,
     timing::TimeRange,
@@ -82,6 +81,11 @@ impl Sdp {
         self
     }
 
+    /// Value of the first session-level attribute with the given name.
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        find_attribute(&self.tags, name)
+    }
+
     pub fn with_media(
         mut self,
         kind: Kind,
@@ -97,11 +101,19 @@ impl Sdp {
             kind,
             port,
             protocol,
-            format: FMT_RTP_PAYLOAD_DYNAMIC,
+            format: codec_info.payload_type(),
             tags,
         });
         self
     }
+
+    /// Add an attribute to the media description that was added last.
+    pub fn with_media_tag(mut self, tag: Tag) -> Self {
+        if let Some(media) = self.media.last_mut() {
+            media.tags.push(tag);
+        }
+        self
+    }
 }
 
 impl fmt::Display for Sdp {
@@ -154,6 +166,22 @@ pub struct Media {
     pub tags: Vec<Tag>,
 }
 
+impl Media {
+    /// Value of the first media-level attribute with the given name.
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        find_attribute(&self.tags, name)
+    }
+
+    /// Values of all media-level attributes with the given name, such as
+    /// the `rtpmap` of every format that is offered.
+    pub fn attributes<'tags>(&'tags self, name: &'tags str) -> impl Iterator<Item = &'tags str> {
+        self.tags.iter().filter_map(move |tag| match tag {
+            Tag::Value(variable, value) if variable == name => Some(value.as_str()),
+            _ => None,
+        })
+    }
+}
+
 impl fmt::Display for Media {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(
@@ -238,6 +266,13 @@ impl fmt::Display for Tag {
     }
 }
 
+fn find_attribute<'tags>(tags: &'tags [Tag], name: &str) -> Option<&'tags str> {
+    tags.iter().find_map(|tag| match tag {
+        Tag::Value(variable, value) if variable == name => Some(value.as_str()),
+        _ => None,
+    })
+}
+
 #[derive(Debug, Clone)]
 pub enum Direction {
     ReceiveOnly,
@@ -280,6 +315,8 @@ impl fmt::Display for Kind {
 pub enum Protocol {
     RtpAvp,
     RtpSAvp,
+    /// Secure RTP with feedback over DTLS, as used by WebRTC (RFC 5764).
+    UdpTlsRtpSAvpf,
 }
 
 impl fmt::Display for Protocol {
@@ -287,6 +324,7 @@ impl fmt::Display for Protocol {
         match self {
             Protocol::RtpAvp => write!(f, "RTP/AVP"),
             Protocol::RtpSAvp => write!(f, "RTP/SAVP"),
+            Protocol::UdpTlsRtpSAvpf => write!(f, "UDP/TLS/RTP/SAVPF"),
         }
     }
 }